@@ -1,2 +1,16 @@
+pub mod attachments;
+pub mod autocomplete;
+pub mod cache;
+pub mod contacts;
+pub mod conversations;
+pub mod edit;
+pub mod identities;
 pub mod init;
+pub mod outbox;
+pub mod reachability;
+pub mod receipts;
+pub mod receive;
+pub mod schedule;
+pub mod search;
 pub mod send;
+pub mod typing;
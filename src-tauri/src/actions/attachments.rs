@@ -0,0 +1,247 @@
+use std::{path::Path, sync::Arc};
+
+use rustpush::Attachment;
+use tauri::ipc::InvokeError;
+use tokio::sync::Mutex;
+
+use crate::{
+    events::{AppEvent, EventBus},
+    imessage::attachments::{from_remote, AttachmentTransport},
+    media::{derive, derive_video, is_processable, is_video, prepare_outgoing},
+    state::{
+        attachmentstore::{AttachmentStore, StoredAttachment},
+        settings::MediaSettings,
+    },
+};
+
+/// Transfers smaller than this do not report progress
+const PROGRESS_THRESHOLD: usize = 1024 * 1024;
+
+/**
+ * Publish progress events for a transfer, at most one per percent
+ */
+fn progress_reporter(
+    events: EventBus,
+    id: String,
+    upload: bool,
+) -> impl FnMut(usize, usize) + Send {
+    let mut last_percent = None;
+    move |transferred, total| {
+        if total < PROGRESS_THRESHOLD {
+            return;
+        }
+        let percent = transferred * 100 / total;
+        if last_percent == Some(percent) {
+            return;
+        }
+        last_percent = Some(percent);
+        events.publish(AppEvent::AttachmentProgress {
+            id: id.clone(),
+            transferred: transferred as u64,
+            total: total as u64,
+            upload,
+        });
+    }
+}
+
+/**
+ * The file name to use once content has been converted to another type
+ */
+pub fn rename_for_mime(name: &str, mime: &str) -> String {
+    let extension = match mime {
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        "image/webp" => "webp",
+        _ => return name.to_owned(),
+    };
+    let stem = Path::new(name)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "attachment".to_owned());
+    format!("{}.{}", stem, extension)
+}
+
+/**
+ * Make the thumbnail and placeholder for an attachment that is on disk, if
+ * it is an image or video and that was not tried yet
+ */
+async fn derive_media(
+    attachments: Arc<Mutex<AttachmentStore>>,
+    media: MediaSettings,
+    stored: StoredAttachment,
+) -> StoredAttachment {
+    let derived = stored.thumbnail.is_some() || stored.video.is_some() || stored.derive_failed;
+    if derived || !(is_processable(&stored.mime) || is_video(&stored.mime)) {
+        return stored;
+    }
+    let Some(path) = attachments.lock().await.path(&stored) else {
+        return stored;
+    };
+    let mime = stored.mime.clone();
+    let derived = tokio::task::spawn_blocking(move || {
+        if is_video(&mime) {
+            return derive_video(&path, &media).map_err(|e| format!("{:?}", e));
+        }
+        let data = std::fs::read(path).map_err(|e| format!("{:?}", e))?;
+        derive(&data, &mime, &media).map_err(|e| format!("{:?}", e))
+    })
+    .await;
+    match derived {
+        Ok(Ok(Some(derived))) => match attachments.lock().await.set_derived(&stored.id, &derived) {
+            Ok(updated) => updated,
+            Err(e) => {
+                log::error!("Error saving derived media for {}: {:?}", stored.id, e);
+                stored
+            }
+        },
+        Ok(Ok(None)) => stored,
+        Ok(Err(e)) => {
+            log::warn!("Could not process attachment {}: {}", stored.id, e);
+            match attachments.lock().await.set_derive_failed(&stored.id) {
+                Ok(updated) => updated,
+                Err(e) => {
+                    log::error!("Error saving attachment {}: {:?}", stored.id, e);
+                    stored
+                }
+            }
+        }
+        Err(e) => {
+            log::error!("Media processing task failed: {:?}", e);
+            stored
+        }
+    }
+}
+
+/**
+ * Add content to the attachment store for sending, shrinking images to the
+ * configured limits first
+ */
+pub async fn do_import_attachment(
+    attachments: Arc<Mutex<AttachmentStore>>,
+    media: MediaSettings,
+    data: Vec<u8>,
+    name: String,
+    mime: String,
+) -> Result<StoredAttachment, InvokeError> {
+    let settings = media.clone();
+    // Never fall back to the original, it may still carry its location
+    let (data, name, mime) = tokio::task::spawn_blocking(move || {
+        prepare_outgoing(&data, &mime, &settings).map(|encoded| match encoded {
+            Some(encoded) => (
+                encoded.data,
+                rename_for_mime(&name, encoded.mime),
+                encoded.mime.to_owned(),
+            ),
+            None => (data, name, mime),
+        })
+    })
+    .await
+    .map_err(|e| InvokeError::from(e.to_string()))?
+    .map_err(|e| InvokeError::from(format!("Could not process image: {:?}", e)))?;
+    let stored = attachments
+        .lock()
+        .await
+        .import_bytes(&data, &name, &mime)
+        .map_err(|e| InvokeError::from(e.to_string()))?;
+    Ok(derive_media(attachments, media, stored).await)
+}
+
+/**
+ * Add a local file to the attachment store for sending
+ */
+pub async fn do_attach_file(
+    attachments: Arc<Mutex<AttachmentStore>>,
+    media: MediaSettings,
+    path: &Path,
+) -> Result<StoredAttachment, std::io::Error> {
+    let data = tokio::fs::read(path).await?;
+    let mime = mime_guess::from_path(path)
+        .first_or_octet_stream()
+        .essence_str()
+        .to_owned();
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "attachment".to_owned());
+    do_import_attachment(attachments, media, data, name, mime)
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", e)))
+}
+
+/**
+ * Upload attachments from the store so they can be put in a message
+ */
+pub async fn upload_attachments(
+    attachments: &Mutex<AttachmentStore>,
+    transport: &dyn AttachmentTransport,
+    events: &EventBus,
+    attachment_ids: &[String],
+) -> Result<Vec<Attachment>, InvokeError> {
+    let mut uploaded = Vec::new();
+    for id in attachment_ids {
+        let (stored, data) = {
+            let store = attachments.lock().await;
+            let stored = store
+                .get(id)
+                .cloned()
+                .ok_or_else(|| InvokeError::from(format!("Unknown attachment {}", id)))?;
+            let path = store
+                .path(&stored)
+                .ok_or_else(|| InvokeError::from(format!("Attachment {} is not on disk", id)))?;
+            let data = std::fs::read(path).map_err(|e| InvokeError::from(e.to_string()))?;
+            (stored, data)
+        };
+        let mut progress = progress_reporter(events.clone(), stored.id.clone(), true);
+        let attachment = transport
+            .upload(data, &stored.mime, &stored.uti, &stored.name, &mut progress)
+            .await
+            .map_err(|e| InvokeError::from(format!("{:?}", e)))?;
+        uploaded.push(attachment);
+    }
+    Ok(uploaded)
+}
+
+/**
+ * Make sure the content of an attachment is on disk, downloading it if we
+ * only know where it is, along with its thumbnail if it is an image
+ */
+pub async fn do_fetch_attachment(
+    attachments: Arc<Mutex<AttachmentStore>>,
+    transport: Arc<dyn AttachmentTransport>,
+    events: EventBus,
+    media: MediaSettings,
+    id: &str,
+) -> Result<StoredAttachment, InvokeError> {
+    let stored = {
+        let mut store = attachments.lock().await;
+        let stored = store
+            .get(id)
+            .cloned()
+            .ok_or_else(|| InvokeError::from(format!("Unknown attachment {}", id)))?;
+        if store.path(&stored).is_some() {
+            store.touch(id);
+            drop(store);
+            return Ok(derive_media(attachments, media, stored).await);
+        }
+        stored
+    };
+    let remote = stored
+        .remote
+        .as_ref()
+        .ok_or_else(|| InvokeError::from("Attachment has nowhere to download from"))?;
+    let attachment = from_remote(remote).map_err(|e| InvokeError::from(format!("{:?}", e)))?;
+    let mut progress = progress_reporter(events, stored.id.clone(), false);
+    let data = transport
+        .download(&attachment, &mut progress)
+        .await
+        .map_err(|e| InvokeError::from(format!("{:?}", e)))?;
+    let stored = {
+        let mut store = attachments.lock().await;
+        let stored = store
+            .set_content(id, &data)
+            .map_err(|e| InvokeError::from(e.to_string()))?;
+        store.touch(id);
+        stored
+    };
+    Ok(derive_media(attachments, media, stored).await)
+}
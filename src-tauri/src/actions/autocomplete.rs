@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+
+use crate::{
+    actions::send::resolve_handle,
+    state::{
+        messagestore::{now_millis, ConversationSummary, MessageStore},
+        TauriState,
+    },
+};
+
+/// Suggestions returned if no limit is asked for
+const DEFAULT_LIMIT: usize = 8;
+const MAX_LIMIT: usize = 50;
+/// How much more the match counts than recency, frequency and reachability
+const MATCH_WEIGHT: f64 = 2.0;
+/// After how long a conversation counts half as recent
+const RECENCY_HALF_LIFE: u64 = 14 * 24 * 60 * 60 * 1000;
+/// Messages exchanged at which frequency stops counting for more
+const FREQUENCY_SATURATION: f64 = 500.0;
+const REACHABILITY_WEIGHT: f64 = 0.25;
+
+/**
+ * A recipient to offer in the compose field
+ */
+pub struct Suggestion {
+    /// Normalized, like `tel:+15551234567`
+    pub handle: String,
+    pub name: Option<String>,
+    pub contact_id: Option<String>,
+    /// From the reachability cache, if the handle was checked recently
+    pub reachable: Option<bool>,
+    /// When the last message in a conversation with them was
+    pub last_message: Option<u64>,
+}
+
+/**
+ * How much we talk with a handle
+ */
+#[derive(Default)]
+struct History {
+    last_message: u64,
+    /// Messages in conversations with them, shared out between everyone in
+    /// a group conversation
+    messages: f64,
+}
+
+/**
+ * How much we talk with each handle, kept between keystrokes until a
+ * message is added or removed
+ */
+#[derive(Default)]
+pub struct RecipientHistory {
+    /// The `MessageStore::generation` and own handles it was worked out for
+    source: Option<(u64, Vec<String>)>,
+    history: HashMap<String, History>,
+}
+
+impl RecipientHistory {
+    fn get(
+        &mut self,
+        messages: &MessageStore,
+        own_handles: &[String],
+    ) -> &HashMap<String, History> {
+        let source = (messages.generation(), own_handles.to_vec());
+        if self.source.as_ref() != Some(&source) {
+            self.history = handle_history(messages.conversations(), own_handles);
+            self.source = Some(source);
+        }
+        &self.history
+    }
+}
+
+fn handle_history(
+    conversations: Vec<ConversationSummary>,
+    own_handles: &[String],
+) -> HashMap<String, History> {
+    let mut history: HashMap<String, History> = HashMap::new();
+    for conversation in conversations {
+        let others: Vec<&String> = conversation
+            .participants
+            .iter()
+            .filter(|participant| !own_handles.contains(participant))
+            .collect();
+        for handle in others.iter() {
+            let entry = history.entry((*handle).clone()).or_default();
+            entry.last_message = entry.last_message.max(conversation.last_message.timestamp);
+            entry.messages += conversation.message_count as f64 / others.len() as f64;
+        }
+    }
+    history
+}
+
+/**
+ * How well a query matches some text, from 0 for not at all to 1 for a
+ * prefix of it. The query must be lower-cased.
+ */
+fn match_score(query: &str, text: &str) -> f64 {
+    let text = text.to_lowercase();
+    if text.starts_with(query) {
+        return 1.0;
+    }
+    if text
+        .split(|c: char| !c.is_alphanumeric())
+        .any(|word| word.starts_with(query))
+    {
+        return 0.8;
+    }
+    if text.contains(query) {
+        return 0.6;
+    }
+    fuzzy_score(query, &text).map_or(0.0, |score| 0.4 * score)
+}
+
+/**
+ * Whether the characters of the query all appear in the text in order,
+ * scored by how close together they are
+ */
+fn fuzzy_score(query: &str, text: &str) -> Option<f64> {
+    let mut chars = text.chars().enumerate();
+    let mut first = None;
+    let mut last = 0;
+    for wanted in query.chars() {
+        let (position, _) = chars.find(|(_, c)| *c == wanted)?;
+        first.get_or_insert(position);
+        last = position;
+    }
+    let span = last - first? + 1;
+    Some(query.chars().count() as f64 / span as f64)
+}
+
+/**
+ * How well the query matches a handle, comparing only digits if the query
+ * looks like part of a phone number
+ */
+fn handle_score(query: &str, handle: &str) -> f64 {
+    if let Some(number) = handle.strip_prefix("tel:") {
+        let is_number = query
+            .chars()
+            .all(|c| c.is_ascii_digit() || " +-().".contains(c));
+        let digits: String = query.chars().filter(char::is_ascii_digit).collect();
+        if is_number && !digits.is_empty() {
+            let number: String = number.chars().filter(char::is_ascii_digit).collect();
+            return match_score(&digits, &number);
+        }
+        return match_score(query, number);
+    }
+    match_score(query, handle.strip_prefix("mailto:").unwrap_or(handle))
+}
+
+fn rank(matched: f64, history: Option<&History>, reachable: Option<bool>, now: u64) -> f64 {
+    let (recency, frequency) = match history {
+        Some(history) => {
+            let age = now.saturating_sub(history.last_message) as f64;
+            (
+                0.5f64.powf(age / RECENCY_HALF_LIFE as f64),
+                (history.messages.ln_1p() / FREQUENCY_SATURATION.ln_1p()).min(1.0),
+            )
+        }
+        None => (0.0, 0.0),
+    };
+    let reachability = match reachable {
+        Some(true) => REACHABILITY_WEIGHT,
+        Some(false) => -REACHABILITY_WEIGHT,
+        None => 0.0,
+    };
+    MATCH_WEIGHT * matched + recency + frequency + reachability
+}
+
+/**
+ * Recipients for what has been typed in the compose field so far, best
+ * first. Contacts and everyone we have talked with are matched by name and
+ * handle, and ranked by how well they match, how recently and how much we
+ * talk with them, and whether they are on iMessage. With nothing typed,
+ * the people we talk with most are suggested.
+ *
+ * Reachability is only taken from the cache, so this never waits on IDS.
+ */
+pub async fn do_suggest_recipients(
+    state: &TauriState,
+    query: &str,
+    limit: usize,
+) -> Vec<Suggestion> {
+    let (rust_push, messages, contacts, reachability, settings, recipients) = {
+        let state = state.0.lock().await;
+        (
+            state.rust_push.clone(),
+            state.messages.clone(),
+            state.contacts.clone(),
+            state.reachability.clone(),
+            state.settings.clone(),
+            state.recipients.clone(),
+        )
+    };
+    let own_handles = rust_push.lock().await.client.get_handles();
+    let now = now_millis();
+    let query = query.trim().to_lowercase();
+
+    let mut recipients = recipients.lock().await;
+    let history = recipients.get(&*messages.lock().await, &own_handles);
+
+    // A handle typed in full is offered even if we have never seen it
+    let typed = if query.is_empty() {
+        None
+    } else {
+        resolve_handle(&settings, &query).await.ok()
+    };
+    let mut handles: Vec<String> = history.keys().cloned().chain(typed.clone()).collect();
+    let contacts = contacts.lock().await;
+    for contact in contacts.entries() {
+        handles.extend(contact.handles.iter().cloned());
+    }
+    handles.sort();
+    handles.dedup();
+
+    let reachability = reachability.lock().await;
+    let mut ranked: Vec<(f64, Suggestion)> = handles
+        .into_iter()
+        .filter(|handle| !own_handles.contains(handle))
+        .filter_map(|handle| {
+            let contact = contacts.find_by_handle(&handle);
+            let matched = if query.is_empty() {
+                // Only suggest people we have talked with
+                history.get(&handle)?;
+                0.0
+            } else if typed.as_ref() == Some(&handle) {
+                1.0
+            } else {
+                let name_score = contact.map_or(0.0, |contact| match_score(&query, &contact.name));
+                let score = name_score.max(handle_score(&query, &handle));
+                if score == 0.0 {
+                    return None;
+                }
+                score
+            };
+            let reachable = reachability
+                .get(&handle, now)
+                .map(|reachability| reachability.reachable);
+            let handle_history = history.get(&handle);
+            let score = rank(matched, handle_history, reachable, now);
+            Some((
+                score,
+                Suggestion {
+                    name: contact.map(|contact| contact.name.clone()),
+                    contact_id: contact.map(|contact| contact.id.clone()),
+                    reachable,
+                    last_message: handle_history.map(|history| history.last_message),
+                    handle,
+                },
+            ))
+        })
+        .collect();
+    ranked.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+
+    let limit = match limit {
+        0 => DEFAULT_LIMIT,
+        limit => limit.min(MAX_LIMIT),
+    };
+    ranked
+        .into_iter()
+        .take(limit)
+        .map(|(_, suggestion)| suggestion)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{messagestore::StoredMessage, test_dir};
+
+    const ME: &str = "mailto:me@example.com";
+    const DAY: u64 = 24 * 60 * 60 * 1000;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn matches_score_by_where_they_are() {
+        let cases = [
+            ("ali", "Alice Smith", 1.0),
+            ("smi", "Alice Smith", 0.8),
+            ("o'b", "Mary O'Brien", 0.6),
+            ("ice", "Alice Smith", 0.6),
+            // a, i and e spread over five characters
+            ("aie", "Alice", 0.4 * 3.0 / 5.0),
+            ("xyz", "Alice Smith", 0.0),
+            ("", "Alice", 1.0),
+        ];
+        for (query, text, score) in cases {
+            let matched = match_score(query, text);
+            assert!(close(matched, score), "{} in {}: {}", query, text, matched);
+        }
+    }
+
+    #[test]
+    fn fuzzy_matches_need_every_character_in_order() {
+        let cases = [
+            ("abc", "abc", Some(1.0)),
+            ("ac", "abc", Some(2.0 / 3.0)),
+            ("aa", "banana", Some(2.0 / 3.0)),
+            ("ca", "abc", None),
+            ("abcd", "abc", None),
+            ("", "abc", None),
+            ("é", "josé", Some(1.0)),
+        ];
+        for (query, text, score) in cases {
+            let fuzzy = fuzzy_score(query, text);
+            assert_eq!(fuzzy.is_some(), score.is_some(), "{} in {}", query, text);
+            if let (Some(fuzzy), Some(score)) = (fuzzy, score) {
+                assert!(close(fuzzy, score), "{} in {}: {}", query, text, fuzzy);
+            }
+        }
+    }
+
+    #[test]
+    fn phone_numbers_match_by_digits() {
+        let number = "tel:+14155552671";
+        assert!(close(handle_score("+1 (415)", number), 1.0));
+        assert!(close(handle_score("415-555", number), 0.6));
+        assert!(close(handle_score("bob", number), 0.0));
+        let email = "mailto:alice@example.com";
+        assert!(close(handle_score("alice", email), 1.0));
+        assert!(close(handle_score("example", email), 0.8));
+        // The scheme is not part of what is matched
+        assert!(close(handle_score("mailto", email), 0.0));
+    }
+
+    #[test]
+    fn recent_and_frequent_recipients_rank_higher() {
+        let now = 100 * DAY;
+        assert!(close(rank(1.0, None, None, now), MATCH_WEIGHT));
+        let recent = History {
+            last_message: now,
+            messages: 0.0,
+        };
+        assert!(close(rank(0.0, Some(&recent), None, now), 1.0));
+        let older = History {
+            last_message: now - RECENCY_HALF_LIFE,
+            messages: 0.0,
+        };
+        assert!(close(rank(0.0, Some(&older), None, now), 0.5));
+        let frequent = History {
+            last_message: 0,
+            messages: 10_000.0,
+        };
+        let recency = 0.5f64.powf(100.0 / 14.0);
+        assert!(close(rank(0.0, Some(&frequent), None, now), 1.0 + recency));
+        assert!(close(
+            rank(0.0, None, Some(true), now) - rank(0.0, None, Some(false), now),
+            2.0 * REACHABILITY_WEIGHT
+        ));
+    }
+
+    fn message(id: &str, participants: &[&str], timestamp: u64) -> StoredMessage {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "conversationId": participants.join(","),
+            "participants": participants,
+            "sender": participants[1],
+            "fromMe": false,
+            "text": "hello",
+            "timestamp": timestamp,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn history_is_kept_until_messages_change() {
+        let dir = test_dir();
+        let mut messages = MessageStore::load_from(&dir).unwrap();
+        messages.add(message("a", &[ME, "tel:+1"], 1)).unwrap();
+        messages.add(message("b", &[ME, "tel:+1"], 3)).unwrap();
+        messages
+            .add(message("c", &[ME, "tel:+1", "tel:+2"], 2))
+            .unwrap();
+        let own_handles = vec![ME.to_owned()];
+
+        let mut recipients = RecipientHistory::default();
+        let history = recipients.get(&messages, &own_handles);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history["tel:+1"].last_message, 3);
+        // Shared with the other participant of the group
+        assert!(close(history["tel:+1"].messages, 2.5));
+        assert!(close(history["tel:+2"].messages, 0.5));
+
+        messages.add(message("d", &[ME, "tel:+3"], 4)).unwrap();
+        let history = recipients.get(&messages, &own_handles);
+        assert_eq!(history["tel:+3"].last_message, 4);
+    }
+}
@@ -0,0 +1,189 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use tokio::{sync::Mutex, task::JoinHandle};
+
+use crate::state::{
+    attachmentstore::{AttachmentStore, OrphanReport},
+    messagestore::MessageStore,
+    schedule::ScheduleStore,
+    settings::Settings,
+    TauriState,
+};
+
+/// How often the quota is enforced and orphans are looked for
+const CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// How long an attachment may sit in the store without being sent
+const UNSENT_GRACE: u64 = 24 * 60 * 60 * 1000;
+
+/**
+ * Evict the least recently used originals of every account until each is
+ * within the quota
+ *
+ * Returns the bytes freed
+ */
+pub async fn do_enforce_quota(
+    attachments: Arc<Mutex<AttachmentStore>>,
+    quota: u64,
+) -> Result<u64, std::io::Error> {
+    if quota == 0 {
+        return Ok(0);
+    }
+    let mut store = attachments.lock().await;
+    let mut freed = 0;
+    for account in store.accounts() {
+        let mut usage = store.account_usage(account.as_deref());
+        if usage <= quota {
+            continue;
+        }
+        for id in store.eviction_order(account.as_deref()) {
+            let evicted = store.evict(&[id])?;
+            usage = usage.saturating_sub(evicted);
+            freed += evicted;
+            if usage <= quota {
+                break;
+            }
+        }
+        if usage > quota {
+            log::warn!(
+                "Attachments of {:?} use {} bytes, over the quota of {}, and nothing else can be evicted",
+                account,
+                usage,
+                quota
+            );
+        }
+    }
+    Ok(freed)
+}
+
+/**
+ * Delete the downloaded originals in one conversation, keeping thumbnails
+ * so they can be downloaded again when opened
+ *
+ * Returns the bytes freed
+ */
+pub async fn do_free_conversation(
+    attachments: Arc<Mutex<AttachmentStore>>,
+    conversation_id: &str,
+) -> Result<u64, std::io::Error> {
+    let mut store = attachments.lock().await;
+    let ids: Vec<String> = store
+        .for_conversation(conversation_id)
+        .into_iter()
+        .map(|attachment| attachment.id.clone())
+        .collect();
+    store.evict(&ids)
+}
+
+/**
+ * Remove attachments that belong to neither a message nor a scheduled
+ * message
+ */
+pub async fn do_clean_orphans(
+    attachments: Arc<Mutex<AttachmentStore>>,
+    messages: Arc<Mutex<MessageStore>>,
+    schedule: Arc<Mutex<ScheduleStore>>,
+) -> Result<OrphanReport, std::io::Error> {
+    // Taken before the messages, as sending a scheduled message locks the
+    // schedule first. Messages are queued before they leave the schedule,
+    // so each one is still in one or the other by the time we look.
+    let scheduled: HashSet<String> = schedule
+        .lock()
+        .await
+        .entries()
+        .into_iter()
+        .map(|scheduled| scheduled.id.clone())
+        .collect();
+    let messages = messages.lock().await;
+    attachments.lock().await.remove_orphans(
+        |id| messages.get(id).is_some() || scheduled.contains(id),
+        UNSENT_GRACE,
+    )
+}
+
+async fn check(
+    attachments: Arc<Mutex<AttachmentStore>>,
+    messages: Arc<Mutex<MessageStore>>,
+    schedule: Arc<Mutex<ScheduleStore>>,
+    settings: Arc<Mutex<Settings>>,
+) {
+    if let Err(e) = attachments.lock().await.save_touched() {
+        log::error!("Error saving attachments: {:?}", e);
+    }
+    match do_clean_orphans(attachments.clone(), messages, schedule).await {
+        Ok(report) if report.files > 0 || report.attachments > 0 => {
+            log::info!("Removed orphaned attachments: {:?}", report)
+        }
+        Ok(_) => {}
+        Err(e) => log::error!("Error removing orphaned attachments: {:?}", e),
+    }
+    let quota = settings.lock().await.attachments.quota_bytes;
+    match do_enforce_quota(attachments, quota).await {
+        Ok(0) => {}
+        Ok(freed) => log::info!("Evicted {} bytes of attachments", freed),
+        Err(e) => log::error!("Error evicting attachments: {:?}", e),
+    }
+}
+
+/**
+ * Keep the attachment store within its quota and free of orphans, checking
+ * at startup and then periodically
+ */
+pub fn spawn_cache_manager(state: TauriState) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let (attachments, messages, schedule, settings) = {
+            let state = state.0.lock().await;
+            (
+                state.attachments.clone(),
+                state.messages.clone(),
+                state.schedule.clone(),
+                state.settings.clone(),
+            )
+        };
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            check(
+                attachments.clone(),
+                messages.clone(),
+                schedule.clone(),
+                settings.clone(),
+            )
+            .await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_dir;
+
+    #[tokio::test]
+    async fn cleaning_orphans_does_not_hold_messages_while_waiting_for_the_schedule() {
+        let dir = test_dir();
+        let attachments = Arc::new(Mutex::new(AttachmentStore::load_from(&dir).unwrap()));
+        let messages = Arc::new(Mutex::new(MessageStore::load_from(&dir).unwrap()));
+        let schedule = Arc::new(Mutex::new(ScheduleStore::load_from(&dir).unwrap()));
+
+        // A scheduled message being sent holds the schedule and then
+        // queues the message, which needs the messages
+        let sending = schedule.lock().await;
+        let cleaning = tokio::spawn(do_clean_orphans(
+            attachments,
+            messages.clone(),
+            schedule.clone(),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let queueing = tokio::time::timeout(Duration::from_secs(5), messages.lock()).await;
+        assert!(queueing.is_ok(), "deadlocked with the orphan cleanup");
+        drop(queueing);
+        drop(sending);
+
+        let report = tokio::time::timeout(Duration::from_secs(5), cleaning)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.attachments, 0);
+    }
+}
@@ -0,0 +1,434 @@
+use std::path::Path;
+
+use base64::{engine::general_purpose, Engine};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    actions::send::resolve_handle,
+    imessage::handle::{normalize_handle, HandleError},
+    state::{
+        contacts::{Contact, ContactPhoto, ContactStore, ImportReport},
+        messagestore::now_millis,
+        settings::Settings,
+    },
+    vcard::{self, Property, VCardError, Version},
+};
+
+#[derive(Debug)]
+pub enum ContactError {
+    NotFound,
+    NoName,
+    InvalidHandle(HandleError),
+    NotAnImage,
+    InvalidVCard(VCardError),
+    IOError(std::io::Error),
+}
+
+impl std::fmt::Display for ContactError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContactError::NotFound => write!(f, "Contact not found"),
+            ContactError::NoName => write!(f, "Contacts need a name"),
+            ContactError::InvalidHandle(e) => write!(f, "{}", e),
+            ContactError::NotAnImage => write!(f, "Contact photos must be images"),
+            ContactError::InvalidVCard(e) => write!(f, "{}", e),
+            ContactError::IOError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for ContactError {
+    fn from(e: std::io::Error) -> Self {
+        ContactError::IOError(e)
+    }
+}
+
+impl From<VCardError> for ContactError {
+    fn from(e: VCardError) -> Self {
+        ContactError::InvalidVCard(e)
+    }
+}
+
+/**
+ * A photo from a PHOTO property, either vCard 3 inline base64 or a vCard 4
+ * data URL. Photos that are links are left alone.
+ */
+fn photo_from_property(property: &Property) -> Option<ContactPhoto> {
+    if let Some(url) = property.value.strip_prefix("data:") {
+        let (media_type, data) = url.split_once(";base64,")?;
+        return Some(ContactPhoto {
+            media_type: media_type.to_owned(),
+            data: data.to_owned(),
+        });
+    }
+    let encoding = property.param("ENCODING")?;
+    if !encoding.eq_ignore_ascii_case("b") && !encoding.eq_ignore_ascii_case("base64") {
+        return None;
+    }
+    let media_type = match property.param("TYPE") {
+        Some(kind) if kind.contains('/') => kind.to_lowercase(),
+        Some(kind) => format!("image/{}", kind.to_lowercase()),
+        None => "image/jpeg".to_owned(),
+    };
+    Some(ContactPhoto {
+        media_type,
+        data: property.value.split_whitespace().collect(),
+    })
+}
+
+/**
+ * Make a contact out of the properties of a card, normalizing its phone
+ * numbers and email addresses. Those that cannot be normalized are kept as
+ * they were so they are still exported.
+ *
+ * Returns None for a card with neither a name nor a handle
+ */
+pub fn contact_from_card(properties: &[Property], default_region: &str) -> Option<Contact> {
+    let mut uid = None;
+    let mut formatted_name = None;
+    let mut structured_name = None;
+    let mut organization = None;
+    let mut handles: Vec<String> = Vec::new();
+    let mut photo = None;
+    let mut extra = Vec::new();
+    for property in properties {
+        match property.name.as_str() {
+            "VERSION" | "PRODID" | "REV" => continue,
+            "UID" => {
+                uid = Some(property.text());
+                continue;
+            }
+            "FN" => {
+                formatted_name = Some(property.text());
+                continue;
+            }
+            "N" => {
+                let components = property.components();
+                let parts: Vec<&str> = [components.get(1), components.first()]
+                    .into_iter()
+                    .flatten()
+                    .map(|part| part.trim())
+                    .filter(|part| !part.is_empty())
+                    .collect();
+                structured_name = Some(parts.join(" "));
+                // Written from the name on export, so it can't go stale
+                continue;
+            }
+            "ORG" => organization = property.components().into_iter().next(),
+            "TEL" | "EMAIL" => {
+                if let Ok(handle) = normalize_handle(&property.text(), default_region) {
+                    if !handles.contains(&handle) {
+                        handles.push(handle);
+                    }
+                    continue;
+                }
+            }
+            "PHOTO" if photo.is_none() => {
+                photo = photo_from_property(property);
+                if photo.is_some() {
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        extra.push(property.to_line());
+    }
+    let name = [formatted_name, structured_name, organization]
+        .into_iter()
+        .flatten()
+        .map(|name| name.trim().to_owned())
+        .find(|name| !name.is_empty())
+        .or_else(|| {
+            handles.first().map(|handle| {
+                let address = handle.split_once(':').map_or(handle.as_str(), |(_, a)| a);
+                address.to_owned()
+            })
+        })?;
+    let now = now_millis();
+    Some(Contact {
+        id: Uuid::new_v4().to_string().to_uppercase(),
+        uid: uid.unwrap_or_else(|| Uuid::new_v4().to_string().to_uppercase()),
+        name,
+        handles,
+        photo,
+        extra,
+        created: now,
+        updated: now,
+    })
+}
+
+/**
+ * An N property for a name, taking its last word to be the family name
+ */
+fn structured_name(name: &str) -> Property {
+    let name = name.trim();
+    let (given, family) = match name.rsplit_once(char::is_whitespace) {
+        Some((given, family)) => (given.trim_end(), family),
+        None => (name, ""),
+    };
+    Property::new(
+        "N",
+        format!("{};{};;;", vcard::escape(family), vcard::escape(given)),
+    )
+}
+
+/**
+ * Write a contact out as a vCard of the given version
+ */
+pub fn contact_to_card(contact: &Contact, version: Version) -> String {
+    // Contacts imported before N was left out of `extra` may have one that
+    // no longer matches their name
+    let extra: Vec<Property> = contact
+        .extra
+        .iter()
+        .filter_map(|line| Property::parse(line))
+        .filter(|property| property.name != "N")
+        .collect();
+    let mut properties = vec![
+        Property::new("VERSION", version.as_str().to_owned()),
+        Property::new("PRODID", "-//cross-messenger//EN".to_owned()),
+        Property::new("UID", vcard::escape(&contact.uid)),
+        Property::new("FN", vcard::escape(&contact.name)),
+        // Required by vCard 3
+        structured_name(&contact.name),
+    ];
+    for handle in contact.handles.iter() {
+        let property = match (handle.split_once(':'), version) {
+            (Some(("mailto", address)), Version::V3) => {
+                Property::new("EMAIL", address.to_owned()).with_param("TYPE", "INTERNET")
+            }
+            (Some(("mailto", address)), Version::V4) => Property::new("EMAIL", address.to_owned()),
+            (Some(("tel", number)), Version::V3) => {
+                Property::new("TEL", number.to_owned()).with_param("TYPE", "CELL")
+            }
+            (Some(("tel", _)), Version::V4) => Property::new("TEL", handle.clone())
+                .with_param("VALUE", "uri")
+                .with_param("TYPE", "cell"),
+            _ => continue,
+        };
+        properties.push(property);
+    }
+    if let Some(photo) = &contact.photo {
+        properties.push(match version {
+            Version::V3 => {
+                let kind = photo
+                    .media_type
+                    .strip_prefix("image/")
+                    .unwrap_or(&photo.media_type)
+                    .to_uppercase();
+                Property::new("PHOTO", photo.data.clone())
+                    .with_param("ENCODING", "b")
+                    .with_param("TYPE", &kind)
+            }
+            Version::V4 => Property::new("PHOTO", photo.to_data_url()),
+        });
+    }
+    properties.extend(extra);
+    vcard::write_card(&properties)
+}
+
+/**
+ * Import every card in a vCard file, updating contacts that were imported
+ * before
+ */
+pub async fn do_import_vcards(
+    contacts: &Mutex<ContactStore>,
+    settings: &Mutex<Settings>,
+    path: &Path,
+) -> Result<ImportReport, ContactError> {
+    let text = tokio::fs::read_to_string(path).await?;
+    let cards = vcard::parse(&text)?;
+    let default_region = settings.lock().await.handles.default_region.clone();
+    let imported: Vec<Contact> = cards
+        .iter()
+        .filter_map(|card| contact_from_card(card, &default_region))
+        .collect();
+    let skipped = cards.len() - imported.len();
+    if skipped > 0 {
+        log::warn!("Skipped {} vCards without a name or handle", skipped);
+    }
+    Ok(contacts.lock().await.import(imported)?)
+}
+
+/**
+ * Write contacts to a vCard file, all of them if no IDs are given
+ *
+ * Returns how many were written
+ */
+pub async fn do_export_vcards(
+    contacts: &Mutex<ContactStore>,
+    path: &Path,
+    ids: Option<Vec<String>>,
+    version: Version,
+) -> Result<u32, ContactError> {
+    let text = {
+        let contacts = contacts.lock().await;
+        let selected: Vec<&Contact> = match ids {
+            Some(ids) => ids
+                .iter()
+                .map(|id| contacts.get(id).ok_or(ContactError::NotFound))
+                .collect::<Result<_, _>>()?,
+            None => contacts.entries(),
+        };
+        selected
+            .iter()
+            .map(|contact| contact_to_card(contact, version))
+            .collect::<Vec<String>>()
+    };
+    tokio::fs::write(path, text.concat()).await?;
+    Ok(text.len() as u32)
+}
+
+/**
+ * Add a contact, or change the name and handles of an existing one
+ */
+pub async fn do_save_contact(
+    contacts: &Mutex<ContactStore>,
+    settings: &Mutex<Settings>,
+    id: Option<String>,
+    name: String,
+    handles: Vec<String>,
+) -> Result<Contact, ContactError> {
+    let name = name.trim().to_owned();
+    if name.is_empty() {
+        return Err(ContactError::NoName);
+    }
+    let mut normalized: Vec<String> = Vec::new();
+    for handle in handles {
+        let handle = resolve_handle(settings, &handle)
+            .await
+            .map_err(ContactError::InvalidHandle)?;
+        if !normalized.contains(&handle) {
+            normalized.push(handle);
+        }
+    }
+    let mut contacts = contacts.lock().await;
+    let mut contact = match id {
+        Some(id) => contacts.get(&id).cloned().ok_or(ContactError::NotFound)?,
+        None => {
+            let now = now_millis();
+            Contact {
+                id: Uuid::new_v4().to_string().to_uppercase(),
+                uid: Uuid::new_v4().to_string().to_uppercase(),
+                name: String::new(),
+                handles: Vec::new(),
+                photo: None,
+                extra: Vec::new(),
+                created: now,
+                updated: now,
+            }
+        }
+    };
+    contact.name = name;
+    contact.handles = normalized;
+    Ok(contacts.put(contact)?)
+}
+
+/**
+ * Set the photo of a contact from an image file, or remove it
+ */
+pub async fn do_set_contact_photo(
+    contacts: &Mutex<ContactStore>,
+    id: &str,
+    path: Option<&Path>,
+) -> Result<Contact, ContactError> {
+    let photo = match path {
+        Some(path) => {
+            let media_type = mime_guess::from_path(path)
+                .first_or_octet_stream()
+                .essence_str()
+                .to_owned();
+            if !media_type.starts_with("image/") {
+                return Err(ContactError::NotAnImage);
+            }
+            let data = tokio::fs::read(path).await?;
+            Some(ContactPhoto {
+                media_type,
+                data: general_purpose::STANDARD.encode(data),
+            })
+        }
+        None => None,
+    };
+    let mut contacts = contacts.lock().await;
+    let mut contact = contacts.get(id).cloned().ok_or(ContactError::NotFound)?;
+    contact.photo = photo;
+    Ok(contacts.put(contact)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn card(lines: &[&str]) -> Vec<Property> {
+        lines
+            .iter()
+            .filter_map(|line| Property::parse(line))
+            .collect()
+    }
+
+    fn exported(contact: &Contact) -> Vec<Property> {
+        vcard::parse(&contact_to_card(contact, Version::V3))
+            .unwrap()
+            .remove(0)
+    }
+
+    #[test]
+    fn the_structured_name_is_written_from_the_name() {
+        let mut contact = contact_from_card(
+            &card(&[
+                "FN:Jane Doe",
+                "N:Doe;Jane;;;",
+                "TEL;TYPE=CELL:+1 415 555 2671",
+                "NOTE:Met at the conference",
+            ]),
+            "US",
+        )
+        .unwrap();
+        assert_eq!(contact.handles, ["tel:+14155552671"]);
+        assert_eq!(contact.extra, ["NOTE:Met at the conference"]);
+
+        contact.name = "Jane Q. Smith".to_owned();
+        // As imported before N was left out of the extra lines
+        contact.extra.push("N:Doe;Jane;;;".to_owned());
+        let names: Vec<String> = exported(&contact)
+            .into_iter()
+            .filter(|property| property.name == "N")
+            .map(|property| property.value)
+            .collect();
+        assert_eq!(names, ["Smith;Jane Q.;;;"]);
+    }
+
+    #[test]
+    fn a_contact_round_trips_through_a_card() {
+        let contact = contact_from_card(
+            &card(&[
+                "UID:1234",
+                "FN:Cher",
+                "EMAIL:Cher@Example.com",
+                "TEL:not a number",
+            ]),
+            "US",
+        )
+        .unwrap();
+        let properties = exported(&contact);
+        assert!(properties.contains(&Property::new("N", ";Cher;;;".to_owned())));
+        let imported = contact_from_card(&properties, "US").unwrap();
+        assert_eq!(imported.uid, "1234");
+        assert_eq!(imported.name, "Cher");
+        assert_eq!(imported.handles, ["mailto:cher@example.com"]);
+        assert_eq!(imported.extra, ["TEL:not a number"]);
+    }
+
+    #[test]
+    fn a_card_is_named_after_whatever_it_has() {
+        let named = |lines: &[&str]| contact_from_card(&card(lines), "US").map(|c| c.name);
+        assert_eq!(named(&["N:Doe;Jane;;;"]).as_deref(), Some("Jane Doe"));
+        assert_eq!(named(&["ORG:Acme;Sales"]).as_deref(), Some("Acme"));
+        assert_eq!(
+            named(&["EMAIL:jane@example.com"]).as_deref(),
+            Some("jane@example.com")
+        );
+        assert_eq!(named(&["NOTE:nothing else"]), None);
+    }
+}
@@ -0,0 +1,562 @@
+use std::time::Duration;
+
+use rustpush::ConversationData;
+use tokio::{
+    sync::{broadcast::error::RecvError, Mutex},
+    task::JoinHandle,
+};
+
+use crate::{
+    actions::contacts::ContactError,
+    events::AppEvent,
+    imessage::messenger::conversation_data,
+    state::{
+        attachmentstore::AttachmentStore,
+        contacts::ContactStore,
+        conversationflags::ConversationFlags,
+        messagestore::{now_millis, ConversationSummary, StoredMessage, UnreadConversation},
+        settings::{ConversationSettings, Settings},
+        TauriState,
+    },
+};
+
+/// Prefix of the IDs of conversations merged across a contact's handles,
+/// followed by the contact's ID
+pub const CONTACT_PREFIX: &str = "contact:";
+/// Longest preview of a conversation's last message, in characters
+const PREVIEW_LENGTH: usize = 100;
+/// How often the unread count is checked, for mutes that ran out
+const UNREAD_CHECK: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum ConversationError {
+    NotFound,
+    IOError(std::io::Error),
+}
+
+impl From<std::io::Error> for ConversationError {
+    fn from(error: std::io::Error) -> Self {
+        ConversationError::IOError(error)
+    }
+}
+
+/**
+ * A conversation as the conversation list shows it
+ */
+pub struct ConversationListing {
+    pub summary: ConversationSummary,
+    pub flags: ConversationFlags,
+    /// Whether it is muted now, which it stops being once `muted_until`
+    /// has passed
+    pub muted: bool,
+    /// The last message on one line, or the names of its attachments
+    pub preview: String,
+}
+
+/**
+ * The other participant of a one-to-one conversation
+ */
+fn direct_handle<'a>(participants: &'a [String], own_handles: &[String]) -> Option<&'a str> {
+    let mut others = participants
+        .iter()
+        .filter(|participant| !own_handles.contains(participant));
+    match (others.next(), others.next()) {
+        (Some(other), None) => Some(other),
+        _ => None,
+    }
+}
+
+/**
+ * The ID of the contact's conversation a stored conversation is merged
+ * into, if it is one-to-one with a contact that merges them
+ */
+fn merged_id(
+    participants: &[String],
+    contacts: &ContactStore,
+    settings: &ConversationSettings,
+    own_handles: &[String],
+) -> Option<String> {
+    direct_handle(participants, own_handles)
+        .and_then(|handle| contacts.find_by_handle(handle))
+        .filter(|contact| settings.merges(&contact.id))
+        .map(|contact| format!("{}{}", CONTACT_PREFIX, contact.id))
+}
+
+/**
+ * Replace the one-to-one conversations with each contact that merges them
+ * with one conversation holding all of them, keeping the order
+ */
+fn merge_conversations(
+    conversations: Vec<ConversationSummary>,
+    contacts: &ContactStore,
+    settings: &ConversationSettings,
+    own_handles: &[String],
+) -> Vec<ConversationSummary> {
+    let mut merged: Vec<ConversationSummary> = Vec::new();
+    for conversation in conversations {
+        let Some(id) = merged_id(&conversation.participants, contacts, settings, own_handles)
+        else {
+            merged.push(conversation);
+            continue;
+        };
+        // Conversations come most recently active first, so the first one
+        // has the last message
+        match merged.iter_mut().find(|existing| existing.id == id) {
+            Some(existing) => {
+                existing.message_count += conversation.message_count;
+                existing.unread_count += conversation.unread_count;
+                for participant in conversation.participants {
+                    if !existing.participants.contains(&participant) {
+                        existing.participants.push(participant);
+                    }
+                }
+                existing.merged.push(conversation.id);
+            }
+            None => merged.push(ConversationSummary {
+                id,
+                participants: conversation.participants,
+                last_message: conversation.last_message,
+                message_count: conversation.message_count,
+                unread_count: conversation.unread_count,
+                merged: vec![conversation.id],
+            }),
+        }
+    }
+    merged
+}
+
+/**
+ * Every conversation we have history for, most recently active first, with
+ * each contact's conversations merged if that is turned on
+ */
+pub async fn list_conversations(state: &TauriState) -> Vec<ConversationSummary> {
+    let (rust_push, messages, contacts, settings) = {
+        let state = state.0.lock().await;
+        (
+            state.rust_push.clone(),
+            state.messages.clone(),
+            state.contacts.clone(),
+            state.settings.clone(),
+        )
+    };
+    let own_handles = rust_push.lock().await.client.get_handles();
+    let conversations = messages.lock().await.conversations();
+    let settings = settings.lock().await.conversations.clone();
+    if !settings.merge_contacts {
+        return conversations;
+    }
+    let contacts = contacts.lock().await;
+    merge_conversations(conversations, &contacts, &settings, &own_handles)
+}
+
+/**
+ * A conversation by its ID, which is either a stored conversation or a
+ * contact's merged one. Stored conversations can still be found by their
+ * own ID while they are merged, like the ID in an event.
+ */
+pub async fn find_conversation(state: &TauriState, id: &str) -> Option<ConversationSummary> {
+    if id.starts_with(CONTACT_PREFIX) {
+        return list_conversations(state)
+            .await
+            .into_iter()
+            .find(|conversation| conversation.id == id);
+    }
+    let messages = state.0.lock().await.messages.clone();
+    let conversations = messages.lock().await.conversations();
+    conversations
+        .into_iter()
+        .find(|conversation| conversation.id == id)
+}
+
+/**
+ * The IDs of the stored conversations a conversation ID stands for, which
+ * are several for a contact's merged conversation
+ */
+pub async fn stored_conversation_ids(state: &TauriState, id: &str) -> Vec<String> {
+    match find_conversation(state, id).await {
+        Some(conversation) if !conversation.merged.is_empty() => conversation.merged,
+        Some(conversation) => vec![conversation.id],
+        None => Vec::new(),
+    }
+}
+
+/**
+ * The conversation to send to when replying in a conversation. For a
+ * contact's merged conversation that is the one most recently active, so
+ * replies go to the handle they last used.
+ */
+pub async fn reply_conversation(state: &TauriState, id: &str) -> Option<ConversationData> {
+    let id = stored_conversation_ids(state, id)
+        .await
+        .into_iter()
+        .next()?;
+    let messages = state.0.lock().await.messages.clone();
+    let participants = messages
+        .lock()
+        .await
+        .conversation(&id)
+        .last()
+        .map(|message| message.participants.clone())?;
+    Some(conversation_data(&id, participants))
+}
+
+/**
+ * Keep a contact's conversations apart even while merging is turned on,
+ * or merge them again
+ */
+pub async fn do_set_contact_merged(
+    settings: &Mutex<Settings>,
+    contacts: &Mutex<ContactStore>,
+    contact_id: &str,
+    merged: bool,
+) -> Result<(), ContactError> {
+    if contacts.lock().await.get(contact_id).is_none() {
+        return Err(ContactError::NotFound);
+    }
+    let mut settings = settings.lock().await;
+    let unmerged = &mut settings.conversations.unmerged;
+    unmerged.retain(|id| id != contact_id);
+    if !merged {
+        unmerged.push(contact_id.to_owned());
+    }
+    Ok(settings.save()?)
+}
+
+fn preview(message: &StoredMessage, attachments: &AttachmentStore) -> String {
+    if message.unsent_at.is_some() {
+        return String::new();
+    }
+    let text = message
+        .text
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ");
+    if text.is_empty() {
+        return message
+            .attachments
+            .iter()
+            .filter_map(|id| attachments.get(id))
+            .map(|attachment| attachment.name.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ");
+    }
+    match text.char_indices().nth(PREVIEW_LENGTH) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text,
+    }
+}
+
+/**
+ * The conversation list: pinned conversations first, then the rest, each
+ * most recently active first. Archived ones are left out unless asked for.
+ */
+pub async fn conversation_list(
+    state: &TauriState,
+    include_archived: bool,
+) -> Vec<ConversationListing> {
+    let (attachments, flags) = {
+        let state = state.0.lock().await;
+        (state.attachments.clone(), state.flags.clone())
+    };
+    let conversations = list_conversations(state).await;
+    let now = now_millis();
+    let attachments = attachments.lock().await;
+    let flags = flags.lock().await;
+    let mut listings: Vec<ConversationListing> = conversations
+        .into_iter()
+        .map(|summary| {
+            let flags = flags.get(&summary.id);
+            ConversationListing {
+                muted: flags.is_muted(now),
+                preview: preview(&summary.last_message, &attachments),
+                flags,
+                summary,
+            }
+        })
+        .filter(|listing| include_archived || !listing.flags.archived)
+        .collect();
+    // Stable, so both stay in order of last activity
+    listings.sort_by_key(|listing| !listing.flags.pinned);
+    listings
+}
+
+/**
+ * Messages we received and have not read, leaving out muted conversations
+ */
+pub async fn unread_count(state: &TauriState) -> u32 {
+    let (rust_push, messages, contacts, settings, flags) = {
+        let state = state.0.lock().await;
+        (
+            state.rust_push.clone(),
+            state.messages.clone(),
+            state.contacts.clone(),
+            state.settings.clone(),
+            state.flags.clone(),
+        )
+    };
+    // Only the conversations with unread messages, which are usually few
+    let unread = messages.lock().await.unread_conversations();
+    if unread.is_empty() {
+        return 0;
+    }
+    let settings = settings.lock().await.conversations.clone();
+    let listed = if settings.merge_contacts {
+        let own_handles = rust_push.lock().await.client.get_handles();
+        let contacts = contacts.lock().await;
+        listed_unread(unread, &contacts, &settings, &own_handles)
+    } else {
+        unread
+    };
+    let now = now_millis();
+    let flags = flags.lock().await;
+    listed
+        .iter()
+        .filter(|conversation| !flags.get(&conversation.id).is_muted(now))
+        .map(|conversation| conversation.unread_count as u32)
+        .sum()
+}
+
+/**
+ * Unread conversations by the ID they are listed with, which is the
+ * contact's for merged ones, so a muted merged conversation is left out
+ */
+fn listed_unread(
+    unread: Vec<UnreadConversation>,
+    contacts: &ContactStore,
+    settings: &ConversationSettings,
+    own_handles: &[String],
+) -> Vec<UnreadConversation> {
+    unread
+        .into_iter()
+        .map(|conversation| UnreadConversation {
+            id: merged_id(&conversation.participants, contacts, settings, own_handles)
+                .unwrap_or(conversation.id),
+            ..conversation
+        })
+        .collect()
+}
+
+/**
+ * Pin, mute or archive a conversation by the ID it is listed with, or undo
+ * that, publishing its flags if they changed
+ */
+pub async fn do_update_flags(
+    state: &TauriState,
+    conversation_id: &str,
+    change: impl FnOnce(&mut ConversationFlags),
+) -> Result<(), ConversationError> {
+    let (flags, events) = {
+        let state = state.0.lock().await;
+        (state.flags.clone(), state.events.clone())
+    };
+    // Stored conversations in a merged one are not listed on their own
+    let listed = list_conversations(state)
+        .await
+        .iter()
+        .any(|conversation| conversation.id == conversation_id);
+    if !listed {
+        return Err(ConversationError::NotFound);
+    }
+    let changed = flags
+        .lock()
+        .await
+        .update(conversation_id, change, now_millis())?;
+    if let Some(flags) = changed {
+        events.publish(AppEvent::ConversationFlagsChanged {
+            conversation_id: conversation_id.to_owned(),
+            flags,
+        });
+    }
+    Ok(())
+}
+
+/**
+ * Turn off the mutes that ran out, publishing the flags of each
+ * conversation that was muted
+ */
+async fn clear_expired_mutes(state: &TauriState) -> Result<(), std::io::Error> {
+    let (flags, events) = {
+        let state = state.0.lock().await;
+        (state.flags.clone(), state.events.clone())
+    };
+    let cleared = flags.lock().await.clear_expired_mutes(now_millis())?;
+    for (conversation_id, flags) in cleared {
+        events.publish(AppEvent::ConversationFlagsChanged {
+            conversation_id,
+            flags,
+        });
+    }
+    Ok(())
+}
+
+/**
+ * Publish the unread count whenever it changes, for the window badge and
+ * tray, and clear mutes once they run out
+ */
+pub fn spawn_unread_counter(state: TauriState) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let events = state.0.lock().await.events.clone();
+        let mut receiver = events.subscribe();
+        let mut interval = tokio::time::interval(UNREAD_CHECK);
+        let mut published = None;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if let Err(e) = clear_expired_mutes(&state).await {
+                        log::error!("Error clearing expired mutes: {:?}", e);
+                    }
+                }
+                event = receiver.recv() => match event {
+                    Ok(AppEvent::MessageReceived { .. })
+                    | Ok(AppEvent::MessageUnsent { .. })
+                    | Ok(AppEvent::ConversationRead { .. })
+                    | Ok(AppEvent::ConversationFlagsChanged { .. })
+                    | Err(RecvError::Lagged(_)) => {}
+                    Ok(_) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+            }
+            let count = unread_count(&state).await;
+            if published != Some(count) {
+                published = Some(count);
+                events.publish(AppEvent::UnreadCountChanged { count });
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::state::contacts::Contact;
+    use crate::state::test_dir;
+
+    const ME: &str = "mailto:me@example.com";
+    const PHONE: &str = "tel:+14155552671";
+    const EMAIL: &str = "mailto:alice@example.com";
+
+    fn contacts(dir: &Path) -> ContactStore {
+        let mut contacts = ContactStore::load_from(dir).unwrap();
+        contacts
+            .put(Contact {
+                id: "alice".to_owned(),
+                uid: "alice".to_owned(),
+                name: "Alice".to_owned(),
+                handles: vec![PHONE.to_owned(), EMAIL.to_owned()],
+                photo: None,
+                extra: Vec::new(),
+                created: 0,
+                updated: 0,
+            })
+            .unwrap();
+        contacts
+    }
+
+    fn summary(id: &str, others: &[&str], timestamp: u64, unread: usize) -> ConversationSummary {
+        let participants: Vec<String> = std::iter::once(ME)
+            .chain(others.iter().copied())
+            .map(str::to_owned)
+            .collect();
+        let last_message: StoredMessage = serde_json::from_value(serde_json::json!({
+            "id": format!("{}-last", id),
+            "conversationId": id,
+            "participants": participants,
+            "sender": others[0],
+            "fromMe": false,
+            "text": "hello",
+            "timestamp": timestamp,
+        }))
+        .unwrap();
+        ConversationSummary {
+            id: id.to_owned(),
+            participants,
+            last_message,
+            message_count: 2,
+            unread_count: unread,
+            merged: Vec::new(),
+        }
+    }
+
+    fn settings(unmerged: &[&str]) -> ConversationSettings {
+        ConversationSettings {
+            merge_contacts: true,
+            unmerged: unmerged.iter().map(|id| id.to_string()).collect(),
+        }
+    }
+
+    fn ids(conversations: &[ConversationSummary]) -> Vec<&str> {
+        conversations.iter().map(|c| c.id.as_str()).collect()
+    }
+
+    #[test]
+    fn a_contacts_conversations_are_merged_in_place() {
+        let dir = test_dir();
+        let contacts = contacts(&dir);
+        let conversations = vec![
+            summary("by-email", &[EMAIL], 4, 1),
+            summary("stranger", &["tel:+442079460958"], 3, 0),
+            summary("by-phone", &[PHONE], 2, 2),
+            summary("group", &[PHONE, "tel:+442079460958"], 1, 0),
+        ];
+        let own_handles = vec![ME.to_owned()];
+
+        let merged = merge_conversations(conversations, &contacts, &settings(&[]), &own_handles);
+        assert_eq!(ids(&merged), ["contact:alice", "stranger", "group"]);
+        let alice = &merged[0];
+        assert_eq!(alice.merged, ["by-email", "by-phone"]);
+        assert_eq!(alice.last_message.id, "by-email-last");
+        assert_eq!(alice.message_count, 4);
+        assert_eq!(alice.unread_count, 3);
+        assert_eq!(alice.participants, [ME, EMAIL, PHONE]);
+        assert!(merged[1].merged.is_empty());
+    }
+
+    #[test]
+    fn unmerged_contacts_are_kept_apart() {
+        let dir = test_dir();
+        let contacts = contacts(&dir);
+        let conversations = vec![
+            summary("by-email", &[EMAIL], 2, 0),
+            summary("by-phone", &[PHONE], 1, 0),
+        ];
+        let own_handles = vec![ME.to_owned()];
+
+        let merged = merge_conversations(
+            conversations.clone(),
+            &contacts,
+            &settings(&["alice"]),
+            &own_handles,
+        );
+        assert_eq!(ids(&merged), ["by-email", "by-phone"]);
+
+        let off = ConversationSettings::default();
+        let merged = merge_conversations(conversations, &contacts, &off, &own_handles);
+        assert_eq!(ids(&merged), ["by-email", "by-phone"]);
+    }
+
+    #[test]
+    fn unread_conversations_are_counted_under_their_listed_id() {
+        let dir = test_dir();
+        let contacts = contacts(&dir);
+        let unread = |id: &str, other: &str| UnreadConversation {
+            id: id.to_owned(),
+            participants: vec![ME.to_owned(), other.to_owned()],
+            unread_count: 1,
+        };
+        let own_handles = vec![ME.to_owned()];
+
+        let listed = listed_unread(
+            vec![
+                unread("by-phone", PHONE),
+                unread("stranger", "tel:+442079460958"),
+            ],
+            &contacts,
+            &settings(&[]),
+            &own_handles,
+        );
+        let ids: Vec<&str> = listed.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, ["contact:alice", "stranger"]);
+    }
+}
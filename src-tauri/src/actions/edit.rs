@@ -0,0 +1,143 @@
+use std::sync::Arc;
+
+use rustpush::IMClient;
+use tauri::ipc::InvokeError;
+use tokio::sync::Mutex;
+
+use crate::{
+    actions::identities::check_not_held,
+    events::{AppEvent, EventBus},
+    imessage::messenger::{
+        conversation_data, edit_message, send_text_message, text_part, unsend_message,
+    },
+    state::{
+        attachmentstore::AttachmentStore,
+        keytrust::KeyTrustStore,
+        messagestore::{now_millis, MessageStore, StoredMessage, EDIT_WINDOW, UNSEND_WINDOW},
+        rustpushstate::RustPushState,
+    },
+};
+
+/**
+ * A message we sent that may still be changed, along with the client and
+ * handle to send the change from
+ */
+async fn changeable_message(
+    state: &Mutex<RustPushState>,
+    keys: &Mutex<KeyTrustStore>,
+    messages: &Mutex<MessageStore>,
+    message_id: &str,
+    window: u64,
+) -> Result<(StoredMessage, Arc<IMClient>, String), InvokeError> {
+    let target = messages
+        .lock()
+        .await
+        .get(message_id)
+        .cloned()
+        .ok_or_else(|| InvokeError::from("Message not found"))?;
+    target
+        .check_change(window, now_millis())
+        .map_err(|e| InvokeError::from(format!("Message cannot be changed: {:?}", e)))?;
+    check_not_held(keys, &target.participants).await?;
+    let mut state = state.lock().await;
+    match state.get_active_user().await {
+        Some((_, handle)) => Ok((target, state.client.clone(), handle)),
+        None => Err(InvokeError::from("No logged in users")),
+    }
+}
+
+/**
+ * Replace the text of a message we sent within the last 15 minutes
+ *
+ * Returns the updated message
+ */
+pub async fn do_edit_message(
+    state: Arc<Mutex<RustPushState>>,
+    keys: Arc<Mutex<KeyTrustStore>>,
+    messages: Arc<Mutex<MessageStore>>,
+    events: EventBus,
+    message_id: &str,
+    text: String,
+) -> Result<StoredMessage, InvokeError> {
+    let (target, client, handle) =
+        changeable_message(&state, &keys, &messages, message_id, EDIT_WINDOW).await?;
+    if target.text == text {
+        return Ok(target);
+    }
+    send_text_message(
+        client,
+        &handle,
+        conversation_data(&target.conversation_id, target.participants.clone()),
+        edit_message(&target.id, text_part(&target), text.clone()),
+    )
+    .await
+    .map_err(|e| InvokeError::from(e.to_string()))?;
+    let updated = messages
+        .lock()
+        .await
+        .edit(&target.id, text, now_millis())
+        .map_err(|e| InvokeError::from(e.to_string()))?
+        .unwrap_or(target);
+    events.publish(AppEvent::MessageEdited {
+        message: updated.clone(),
+    });
+    Ok(updated)
+}
+
+/**
+ * Take back a message we sent within the last 2 minutes, along with its
+ * attachments
+ */
+pub async fn do_unsend_message(
+    state: Arc<Mutex<RustPushState>>,
+    keys: Arc<Mutex<KeyTrustStore>>,
+    messages: Arc<Mutex<MessageStore>>,
+    attachments: Arc<Mutex<AttachmentStore>>,
+    events: EventBus,
+    message_id: &str,
+) -> Result<(), InvokeError> {
+    let (target, client, handle) =
+        changeable_message(&state, &keys, &messages, message_id, UNSEND_WINDOW).await?;
+    // Every attachment and the text are parts of their own
+    let parts = text_part(&target) + u64::from(!target.text.is_empty());
+    for part in 0..parts.max(1) {
+        send_text_message(
+            client.clone(),
+            &handle,
+            conversation_data(&target.conversation_id, target.participants.clone()),
+            unsend_message(&target.id, part),
+        )
+        .await
+        .map_err(|e| InvokeError::from(e.to_string()))?;
+    }
+    if let Some(message) = apply_unsend(&messages, &attachments, &target.id).await {
+        events.publish(AppEvent::MessageUnsent { message });
+    }
+    Ok(())
+}
+
+/**
+ * Drop the content of an unsent message from the history and the
+ * attachment store
+ *
+ * Returns the message as it is now, or None if there was nothing to unsend
+ */
+pub async fn apply_unsend(
+    messages: &Mutex<MessageStore>,
+    attachments: &Mutex<AttachmentStore>,
+    message_id: &str,
+) -> Option<StoredMessage> {
+    let mut messages = messages.lock().await;
+    let before = match messages.unsend(message_id, now_millis()) {
+        Ok(Some(before)) => before,
+        Ok(None) => return None,
+        Err(e) => {
+            log::error!("Error saving unsent message: {:?}", e);
+            return None;
+        }
+    };
+    if let Err(e) = attachments.lock().await.remove(&before.attachments) {
+        log::error!("Error removing unsent attachments: {:?}", e);
+    }
+    messages.get(message_id).cloned()
+}
@@ -0,0 +1,252 @@
+use std::{collections::HashMap, time::Duration};
+
+use tauri::ipc::InvokeError;
+use tokio::{
+    sync::{broadcast::error::RecvError, Mutex},
+    task::JoinHandle,
+};
+
+use crate::{
+    events::{AppEvent, EventBus},
+    imessage::identities::resolve_identities,
+    state::{
+        keytrust::KeyTrustStore, messagestore::now_millis, outbox::OutboxStore,
+        rustpushstate::RustPushState, settings::Settings, TauriState,
+    },
+};
+
+/// How often expired identities are dropped when nothing is being sent
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// How long the keys of a recipient are trusted to be current before a
+/// send, so retries do not look them up every time
+const CHECK_INTERVAL: u64 = 60 * 1000;
+
+/**
+ * Add the keys of freshly looked up handles to their history, warning
+ * about verified handles whose keys changed
+ */
+async fn record_keys(
+    rust_push: &Mutex<RustPushState>,
+    keys: &Mutex<KeyTrustStore>,
+    settings: &Mutex<Settings>,
+    events: &EventBus,
+    handles: Vec<String>,
+) {
+    if handles.is_empty() {
+        return;
+    }
+    let hold = settings.lock().await.keys.hold_on_change;
+    let now = now_millis();
+    let fingerprints: Vec<(String, Vec<String>)> = {
+        let state = rust_push.lock().await;
+        handles
+            .into_iter()
+            .filter_map(|handle| {
+                let cached = state.identities.get(&handle)?;
+                let fingerprints = cached
+                    .identities
+                    .iter()
+                    .filter_map(|identity| identity.fingerprint())
+                    .collect();
+                Some((handle, fingerprints))
+            })
+            .collect()
+    };
+    let mut keys = keys.lock().await;
+    for (handle, fingerprints) in fingerprints {
+        match keys.observe(&handle, &fingerprints, now, hold) {
+            Ok(Some(change)) => {
+                log::warn!(
+                    "Keys of verified handle {} changed: {} added, {} removed",
+                    change.handle,
+                    change.added.len(),
+                    change.removed.len()
+                );
+                events.publish(AppEvent::KeysChanged {
+                    handle: change.handle,
+                    added: change.added,
+                    removed: change.removed,
+                    held: change.held,
+                });
+            }
+            Ok(None) => {}
+            Err(e) => log::error!("Error saving keys: {:?}", e),
+        }
+    }
+}
+
+/**
+ * Persist the identities the client has looked up or forgotten since the
+ * last time, writing the file after the state is unlocked
+ *
+ * Returns the handles that were looked up
+ */
+pub async fn save_identities(
+    rust_push: &Mutex<RustPushState>,
+) -> Result<Vec<String>, std::io::Error> {
+    let (looked_up, snapshot) = rust_push.lock().await.sync_identities().await;
+    if let Some(snapshot) = snapshot {
+        snapshot.save()?;
+    }
+    Ok(looked_up)
+}
+
+/**
+ * Look up any of these handles we have no current keys for and record what
+ * came back, along with any whose keys have not been recorded yet
+ */
+pub async fn do_lookup_keys(
+    rust_push: &Mutex<RustPushState>,
+    keys: &Mutex<KeyTrustStore>,
+    settings: &Mutex<Settings>,
+    events: &EventBus,
+    handles: &[String],
+) -> Result<(), InvokeError> {
+    let (client, handle) = {
+        let mut state = rust_push.lock().await;
+        match state.get_active_user().await {
+            Some((_, handle)) => (state.client.clone(), handle),
+            None => return Err(InvokeError::from("No logged in users")),
+        }
+    };
+    resolve_identities(&client, handles, &handle)
+        .await
+        .map_err(|e| InvokeError::from(e.to_string()))?;
+    let mut looked_up = save_identities(rust_push)
+        .await
+        .map_err(|e| InvokeError::from(e.to_string()))?;
+    // Identities cached before their keys were tracked have no history yet
+    {
+        let keys = keys.lock().await;
+        for handle in handles {
+            if keys.get(handle).is_none() && !looked_up.contains(handle) {
+                looked_up.push(handle.clone());
+            }
+        }
+    }
+    record_keys(rust_push, keys, settings, events, looked_up).await;
+    Ok(())
+}
+
+/**
+ * Check the keys of a message's recipients right before it is sent, so a
+ * change to a verified handle's keys is caught before anything is
+ * encrypted to the new ones
+ *
+ * Recipients in `checked` within the last `CHECK_INTERVAL` are not looked
+ * up again. Returns the recipients whose messages are held. A failed
+ * lookup holds nothing, since the send will fail the same way.
+ */
+pub async fn do_check_recipient_keys(
+    rust_push: &Mutex<RustPushState>,
+    keys: &Mutex<KeyTrustStore>,
+    settings: &Mutex<Settings>,
+    events: &EventBus,
+    checked: &mut HashMap<String, u64>,
+    participants: &[String],
+) -> Vec<String> {
+    let now = now_millis();
+    checked.retain(|_, at| now.saturating_sub(*at) < CHECK_INTERVAL);
+    let stale: Vec<String> = participants
+        .iter()
+        .filter(|participant| !checked.contains_key(*participant))
+        .cloned()
+        .collect();
+    if !stale.is_empty() {
+        match do_lookup_keys(rust_push, keys, settings, events, &stale).await {
+            Ok(_) => checked.extend(stale.into_iter().map(|handle| (handle, now))),
+            Err(e) => log::warn!("Error looking up recipient keys: {:?}", e),
+        }
+    }
+    keys.lock().await.held_among(participants)
+}
+
+/**
+ * Refuse to send anything to recipients whose messages are held, since it
+ * would be encrypted to keys the user has not confirmed
+ */
+pub async fn check_not_held(
+    keys: &Mutex<KeyTrustStore>,
+    participants: &[String],
+) -> Result<(), InvokeError> {
+    let held = keys.lock().await.held_among(participants);
+    if held.is_empty() {
+        return Ok(());
+    }
+    Err(InvokeError::from(format!(
+        "Keys of {} changed and are not confirmed",
+        held.join(", ")
+    )))
+}
+
+/**
+ * Mark a handle as verified with the keys it has now, or not verified, and
+ * send anything that was held for it
+ *
+ * Returns false if no keys are known for the handle
+ */
+pub async fn do_set_key_verified(
+    keys: &Mutex<KeyTrustStore>,
+    outbox: &Mutex<OutboxStore>,
+    events: &EventBus,
+    handle: &str,
+    verified: bool,
+) -> Result<bool, InvokeError> {
+    let found = keys
+        .lock()
+        .await
+        .set_verified(handle, verified)
+        .map_err(|e| InvokeError::from(e.to_string()))?;
+    if !found {
+        return Ok(false);
+    }
+    let released = outbox
+        .lock()
+        .await
+        .release(&[handle.to_owned()])
+        .map_err(|e| InvokeError::from(e.to_string()))?;
+    for entry in released {
+        events.publish(AppEvent::OutboxChanged { entry });
+    }
+    Ok(true)
+}
+
+/**
+ * Persist recipient identities after every send, since that is when the
+ * client looks them up or forgets them, and drop expired ones periodically
+ *
+ * Keys looked up along the way are added to their handle's history.
+ */
+pub fn spawn_identity_saver(state: TauriState) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let (rust_push, keys, settings, events) = {
+            let state = state.0.lock().await;
+            (
+                state.rust_push.clone(),
+                state.keys.clone(),
+                state.settings.clone(),
+                state.events.clone(),
+            )
+        };
+        let mut receiver = events.subscribe();
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                event = receiver.recv() => match event {
+                    Ok(AppEvent::MessageSent { .. }) | Ok(AppEvent::OutboxChanged { .. }) => {}
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                },
+            }
+            let saved = save_identities(&rust_push).await;
+            match saved {
+                Ok(looked_up) => {
+                    record_keys(&rust_push, &keys, &settings, &events, looked_up).await
+                }
+                Err(e) => log::error!("Error saving identities: {:?}", e),
+            }
+        }
+    })
+}
@@ -24,18 +24,18 @@ pub async fn do_login(
     .await
     {
         Ok(user) => {
-            println!("Logged in as {:?}", user.user_id);
+            log::info!("Logged in as {:?}", user.user_id);
             state.add_user(user).await?;
-            println!("Updated users");
+            log::info!("Updated users");
             Ok(true)
         }
         Err(LoginError::PushError(PushError::TwoFaError)) => {
-            println!("2FA required");
+            log::info!("2FA required");
             Ok(false)
         }
         Err(e) => match e {
             LoginError::PushError(error) => {
-                println!("Error logging in: {:?}", error);
+                log::error!("Error logging in: {:?}", error);
                 Err(IMClientError::PushError(error))
             }
         },
@@ -0,0 +1,510 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use rustpush::{ConversationData, Message};
+use tauri::ipc::InvokeError;
+use tokio::{
+    sync::{broadcast::error::RecvError, Mutex},
+    task::JoinHandle,
+};
+use uuid::Uuid;
+
+use crate::{
+    actions::{attachments::upload_attachments, identities::do_check_recipient_keys},
+    events::{AppEvent, EventBus},
+    imessage::{
+        attachments::{message_with_attachments, to_remote, AttachmentTransport},
+        messenger::{conversation_data, conversation_id, send_with_id, set_reply_to},
+    },
+    state::{
+        attachmentstore::AttachmentStore,
+        contacts::ContactStore,
+        messagestore::{now_millis, MessageStatus, MessageStore, ReplyTo, StoredMessage},
+        outbox::{OutboxEntry, OutboxState, OutboxStore},
+        rustpushstate::RustPushState,
+        TauriState,
+    },
+};
+
+/// How often sent messages are pruned from the outbox when nothing is due
+const IDLE_WAIT: Duration = Duration::from_secs(60 * 60);
+
+/**
+ * A message to put in the outbox
+ */
+pub struct OutgoingMessage {
+    pub conversation: ConversationData,
+    pub text: String,
+    /// IDs in the attachment store
+    pub attachments: Vec<String>,
+    pub reply_to: Option<ReplyTo>,
+}
+
+impl OutgoingMessage {
+    pub fn text(conversation: ConversationData, text: String) -> OutgoingMessage {
+        OutgoingMessage {
+            conversation,
+            text,
+            attachments: Vec::new(),
+            reply_to: None,
+        }
+    }
+
+    /**
+     * An inline reply to a part of a stored message
+     *
+     * Replying to a reply continues the thread it is in, since threads
+     * always point at the message that started them
+     */
+    pub async fn reply(
+        messages: &Mutex<MessageStore>,
+        message_id: &str,
+        part: u64,
+        text: String,
+    ) -> Result<OutgoingMessage, InvokeError> {
+        let target = messages
+            .lock()
+            .await
+            .get(message_id)
+            .cloned()
+            .ok_or_else(|| InvokeError::from("Message not found"))?;
+        let reply_to = target.reply_to.clone().unwrap_or_else(|| ReplyTo {
+            message_id: target.id.clone(),
+            part,
+        });
+        Ok(OutgoingMessage {
+            conversation: conversation_data(&target.conversation_id, target.participants),
+            text,
+            attachments: Vec::new(),
+            reply_to: Some(reply_to),
+        })
+    }
+}
+
+/**
+ * Commit a message to the history and the outbox, to be sent from the
+ * active handle as soon as we are online
+ *
+ * This will return the message ID
+ */
+pub async fn do_queue_message(
+    state: Arc<Mutex<RustPushState>>,
+    messages: Arc<Mutex<MessageStore>>,
+    attachments: Arc<Mutex<AttachmentStore>>,
+    outbox: Arc<Mutex<OutboxStore>>,
+    events: EventBus,
+    message: OutgoingMessage,
+) -> Result<String, InvokeError> {
+    let id = Uuid::new_v4().to_string().to_uppercase();
+    do_queue_message_with_id(state, messages, attachments, outbox, events, message, id).await
+}
+
+/**
+ * Like `do_queue_message`, with an ID chosen beforehand
+ *
+ * Queueing the same ID again does not send the message twice, so this can
+ * be retried after a crash part way through
+ */
+pub async fn do_queue_message_with_id(
+    state: Arc<Mutex<RustPushState>>,
+    messages: Arc<Mutex<MessageStore>>,
+    attachments: Arc<Mutex<AttachmentStore>>,
+    outbox: Arc<Mutex<OutboxStore>>,
+    events: EventBus,
+    message: OutgoingMessage,
+    id: String,
+) -> Result<String, InvokeError> {
+    let (account, handle) = match state.lock().await.get_active_user().await {
+        Some((user, handle)) => (user.user_id, handle),
+        None => return Err(InvokeError::from("No logged in users")),
+    };
+    let conversation_id = conversation_id(&message.conversation);
+    let now = now_millis();
+    let stored = StoredMessage {
+        id: id.clone(),
+        conversation_id: conversation_id.clone(),
+        participants: message.conversation.participants.clone(),
+        sender: Some(handle),
+        from_me: true,
+        text: message.text.clone(),
+        timestamp: now,
+        attachments: message.attachments.clone(),
+        reactions: Vec::new(),
+        reply_to: message.reply_to.clone(),
+        delivered_at: None,
+        read_at: None,
+        edits: Vec::new(),
+        unsent_at: None,
+        send_status: Some(MessageStatus::Queued),
+    };
+    messages
+        .lock()
+        .await
+        .add(stored)
+        .map_err(|e| InvokeError::from(e.to_string()))?;
+    // Linked now so the attachments are not cleaned up while we wait
+    let remote = vec![None; message.attachments.len()];
+    attachments
+        .lock()
+        .await
+        .set_message(
+            &message.attachments,
+            &id,
+            &conversation_id,
+            Some(account),
+            remote,
+        )
+        .map_err(|e| InvokeError::from(e.to_string()))?;
+    let entry = OutboxEntry {
+        id: id.clone(),
+        conversation_id,
+        participants: message.conversation.participants,
+        text: message.text,
+        attachments: message.attachments,
+        reply_to: message.reply_to,
+        state: OutboxState::Queued,
+        attempts: 0,
+        next_attempt: now,
+        last_error: None,
+        created: now,
+        updated: now,
+    };
+    let queued = outbox
+        .lock()
+        .await
+        .enqueue(entry.clone())
+        .map_err(|e| InvokeError::from(e.to_string()))?;
+    if queued {
+        events.publish(AppEvent::OutboxChanged { entry });
+    }
+    Ok(id)
+}
+
+/**
+ * Upload the attachments of an outbox entry and send it with its own guid
+ */
+async fn send_entry(
+    state: &Mutex<RustPushState>,
+    messages: &Mutex<MessageStore>,
+    attachments: &Mutex<AttachmentStore>,
+    transport: &dyn AttachmentTransport,
+    events: &EventBus,
+    entry: &OutboxEntry,
+) -> Result<(), InvokeError> {
+    let (client, account, handle) = {
+        let mut state = state.lock().await;
+        match state.get_active_user().await {
+            Some((user, handle)) => (state.client.clone(), user.user_id, handle),
+            None => return Err(InvokeError::from("No logged in users")),
+        }
+    };
+    let uploaded = upload_attachments(attachments, transport, events, &entry.attachments).await?;
+    let remote = uploaded.iter().map(to_remote).collect();
+    let mut normal = message_with_attachments(entry.text.clone(), uploaded);
+    if let Some(reply_to) = &entry.reply_to {
+        let originator_text = messages
+            .lock()
+            .await
+            .get(&reply_to.message_id)
+            .map(|originator| originator.text.clone())
+            .unwrap_or_default();
+        set_reply_to(&mut normal, reply_to, &originator_text);
+    }
+    send_with_id(
+        client,
+        &handle,
+        conversation_data(&entry.conversation_id, entry.participants.clone()),
+        Message::Message(normal),
+        &entry.id,
+    )
+    .await
+    .map_err(|e| InvokeError::from(e.to_string()))?;
+    if let Err(e) = attachments.lock().await.set_message(
+        &entry.attachments,
+        &entry.id,
+        &entry.conversation_id,
+        Some(account),
+        remote,
+    ) {
+        log::error!("Error saving attachments: {:?}", e);
+    }
+    Ok(())
+}
+
+/**
+ * Move a message along in the outbox and the history, publishing both
+ */
+async fn set_state(
+    messages: &Mutex<MessageStore>,
+    outbox: &Mutex<OutboxStore>,
+    contacts: &Mutex<ContactStore>,
+    events: &EventBus,
+    id: &str,
+    state: OutboxState,
+) {
+    let send_status = match state {
+        OutboxState::Queued | OutboxState::Held => Some(MessageStatus::Queued),
+        OutboxState::Sending => Some(MessageStatus::Sending),
+        OutboxState::Failed => Some(MessageStatus::Failed),
+        OutboxState::Sent | OutboxState::Delivered => None,
+    };
+    match messages.lock().await.set_send_status(id, send_status) {
+        Ok(Some(message)) if state == OutboxState::Sent => {
+            let contact = contacts.lock().await.message_contact(&message);
+            events.publish(AppEvent::MessageSent { message, contact })
+        }
+        Ok(_) => {}
+        Err(e) => log::error!("Error saving message: {:?}", e),
+    }
+    match outbox.lock().await.set_state(id, state) {
+        Ok(Some(entry)) => events.publish(AppEvent::OutboxChanged { entry }),
+        Ok(None) => {}
+        Err(e) => log::error!("Error saving outbox: {:?}", e),
+    }
+}
+
+/**
+ * Make one attempt at sending a queued message, holding it instead if a
+ * recipient's verified keys changed and the user wants to confirm them
+ */
+async fn attempt(
+    state: &TauriState,
+    messages: &Mutex<MessageStore>,
+    outbox: &Mutex<OutboxStore>,
+    events: &EventBus,
+    checked: &mut HashMap<String, u64>,
+    entry: OutboxEntry,
+) {
+    let (rust_push, attachments, transport, keys, settings, contacts) = {
+        let state = state.0.lock().await;
+        (
+            state.rust_push.clone(),
+            state.attachments.clone(),
+            state.transport.clone(),
+            state.keys.clone(),
+            state.settings.clone(),
+            state.contacts.clone(),
+        )
+    };
+    let held = do_check_recipient_keys(
+        &rust_push,
+        &keys,
+        &settings,
+        events,
+        checked,
+        &entry.participants,
+    )
+    .await;
+    if !held.is_empty() {
+        log::warn!(
+            "Holding {} until the keys of {:?} are confirmed",
+            entry.id,
+            held
+        );
+        set_state(
+            messages,
+            outbox,
+            &contacts,
+            events,
+            &entry.id,
+            OutboxState::Held,
+        )
+        .await;
+        return;
+    }
+    set_state(
+        messages,
+        outbox,
+        &contacts,
+        events,
+        &entry.id,
+        OutboxState::Sending,
+    )
+    .await;
+    let result = send_entry(
+        &rust_push,
+        messages,
+        &attachments,
+        transport.as_ref(),
+        events,
+        &entry,
+    )
+    .await;
+    match result {
+        Ok(_) => {
+            set_state(
+                messages,
+                outbox,
+                &contacts,
+                events,
+                &entry.id,
+                OutboxState::Sent,
+            )
+            .await
+        }
+        Err(e) => {
+            log::warn!("Attempt to send {} failed: {:?}", entry.id, e);
+            let updated = outbox
+                .lock()
+                .await
+                .attempt_failed(&entry.id, format!("{:?}", e));
+            match updated {
+                Ok(Some(updated)) => {
+                    let status = match updated.state {
+                        OutboxState::Failed => MessageStatus::Failed,
+                        _ => MessageStatus::Queued,
+                    };
+                    if let Err(e) = messages
+                        .lock()
+                        .await
+                        .set_send_status(&entry.id, Some(status))
+                    {
+                        log::error!("Error saving message: {:?}", e);
+                    }
+                    events.publish(AppEvent::OutboxChanged { entry: updated });
+                }
+                Ok(None) => {}
+                Err(e) => log::error!("Error saving outbox: {:?}", e),
+            }
+        }
+    }
+}
+
+/**
+ * Queue a failed message again
+ *
+ * Returns whether there was a failed message to retry
+ */
+pub async fn do_retry_message(
+    messages: Arc<Mutex<MessageStore>>,
+    outbox: Arc<Mutex<OutboxStore>>,
+    events: EventBus,
+    id: &str,
+) -> Result<bool, InvokeError> {
+    let entry = outbox
+        .lock()
+        .await
+        .retry(id)
+        .map_err(|e| InvokeError::from(e.to_string()))?;
+    let Some(entry) = entry else {
+        return Ok(false);
+    };
+    if let Err(e) = messages
+        .lock()
+        .await
+        .set_send_status(id, Some(MessageStatus::Queued))
+    {
+        log::error!("Error saving message: {:?}", e);
+    }
+    events.publish(AppEvent::OutboxChanged { entry });
+    Ok(true)
+}
+
+/**
+ * Drop a message that has not been sent from the outbox and the history
+ *
+ * Returns whether there was such a message
+ */
+pub async fn do_cancel_message(
+    messages: Arc<Mutex<MessageStore>>,
+    outbox: Arc<Mutex<OutboxStore>>,
+    id: &str,
+) -> Result<bool, InvokeError> {
+    let removed = outbox
+        .lock()
+        .await
+        .cancel(id)
+        .map_err(|e| InvokeError::from(e.to_string()))?;
+    if removed.is_none() {
+        return Ok(false);
+    }
+    messages
+        .lock()
+        .await
+        .remove_unsent(id)
+        .map_err(|e| InvokeError::from(e.to_string()))?;
+    Ok(true)
+}
+
+/**
+ * Send queued messages in the background, one at a time in the order they
+ * are due, for as long as the connection is up
+ *
+ * Coming back online retries everything straight away. Receipts for sent
+ * messages mark them as delivered.
+ */
+pub async fn spawn_outbox_worker(state: TauriState) -> JoinHandle<()> {
+    let (messages, outbox, contacts, events) = {
+        let state = state.0.lock().await;
+        (
+            state.messages.clone(),
+            state.outbox.clone(),
+            state.contacts.clone(),
+            state.events.clone(),
+        )
+    };
+    // Subscribed before the receiver starts so we hear that we are online
+    let mut receiver = events.subscribe();
+    let wake = outbox.lock().await.waker();
+    tokio::spawn(async move {
+        let mut online = events.connected();
+        // When the keys of each recipient were last checked
+        let mut checked = HashMap::new();
+        loop {
+            let due = outbox.lock().await.next_due(now_millis());
+            if let (true, Some((entry, true))) = (online, &due) {
+                attempt(
+                    &state,
+                    &messages,
+                    &outbox,
+                    &events,
+                    &mut checked,
+                    entry.clone(),
+                )
+                .await;
+                continue;
+            }
+            let wait = match due {
+                Some((entry, false)) if online => {
+                    Duration::from_millis(entry.next_attempt.saturating_sub(now_millis()))
+                }
+                _ => IDLE_WAIT,
+            };
+            tokio::select! {
+                _ = wake.notified() => {}
+                _ = tokio::time::sleep(wait) => {
+                    if let Err(e) = outbox.lock().await.prune() {
+                        log::error!("Error saving outbox: {:?}", e);
+                    }
+                }
+                event = receiver.recv() => match event {
+                    Ok(AppEvent::ConnectionChanged { connected }) => {
+                        online = connected;
+                        if connected {
+                            if let Err(e) = outbox.lock().await.reset_backoff() {
+                                log::error!("Error saving outbox: {:?}", e);
+                            }
+                        }
+                    }
+                    Ok(AppEvent::MessageDelivered { id, .. })
+                    | Ok(AppEvent::MessageRead { id, .. }) => {
+                        let delivered = outbox
+                            .lock()
+                            .await
+                            .get(&id)
+                            .is_some_and(|entry| entry.state == OutboxState::Sent);
+                        if delivered {
+                            let state = OutboxState::Delivered;
+                            set_state(&messages, &outbox, &contacts, &events, &id, state).await;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::warn!("Outbox missed {} events", skipped);
+                        online = events.connected();
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+    })
+}
@@ -0,0 +1,145 @@
+use std::{collections::HashMap, future::Future, sync::Arc};
+
+use tauri::ipc::InvokeError;
+use tokio::sync::Mutex;
+
+use crate::state::{
+    messagestore::now_millis,
+    reachability::{Reachability, ReachabilityCache},
+    rustpushstate::RustPushState,
+};
+
+/// Handles asked about in one IDS query, which rejects large lookups
+const MAX_BATCH: usize = 20;
+
+/**
+ * Whether each handle can be messaged on iMessage, asking IDS about those
+ * that are not cached
+ *
+ * Handles must already be normalized. If a lookup fails the error is
+ * returned, and the handles it was for are asked about again next time.
+ */
+pub async fn do_check_reachability(
+    state: Arc<Mutex<RustPushState>>,
+    cache: Arc<Mutex<ReachabilityCache>>,
+    handles: &[String],
+) -> Result<HashMap<String, Reachability>, InvokeError> {
+    let now = now_millis();
+    let mut results = HashMap::new();
+    let mut unknown = Vec::new();
+    {
+        let cache = cache.lock().await;
+        for handle in handles {
+            match cache.get(handle, now) {
+                Some(reachability) => {
+                    results.insert(handle.clone(), reachability);
+                }
+                None if !unknown.contains(handle) => unknown.push(handle.clone()),
+                None => {}
+            }
+        }
+    }
+    if unknown.is_empty() {
+        return Ok(results);
+    }
+
+    let (client, handle) = {
+        let mut state = state.lock().await;
+        match state.get_active_user().await {
+            Some((_, handle)) => (state.client.clone(), handle),
+            None => return Err(InvokeError::from("No logged in users")),
+        }
+    };
+    let (looked_up, failed) = look_up(&unknown, |batch| {
+        let client = client.clone();
+        let handle = handle.clone();
+        let batch = batch.to_vec();
+        async move { client.validate_targets(&batch, &handle).await }
+    })
+    .await;
+    results.extend(looked_up.iter().cloned());
+    // Batches that did succeed are kept either way
+    if let Err(e) = cache.lock().await.set(looked_up, now_millis()) {
+        log::error!("Error saving reachability: {:?}", e);
+    }
+    match failed {
+        Some(e) => Err(InvokeError::from(e.to_string())),
+        None => Ok(results),
+    }
+}
+
+/**
+ * Ask about handles in batches with `validate`, which returns the ones that
+ * are reachable, stopping at the first batch that fails
+ *
+ * Returns the results of the batches before it along with the error
+ */
+async fn look_up<F, E>(
+    handles: &[String],
+    mut validate: impl FnMut(&[String]) -> F,
+) -> (Vec<(String, Reachability)>, Option<E>)
+where
+    F: Future<Output = Result<Vec<String>, E>>,
+{
+    let mut looked_up = Vec::new();
+    for batch in handles.chunks(MAX_BATCH) {
+        let valid = match validate(batch).await {
+            Ok(valid) => valid,
+            Err(e) => return (looked_up, Some(e)),
+        };
+        let checked_at = now_millis();
+        looked_up.extend(batch.iter().map(|target| {
+            let reachability = Reachability {
+                reachable: valid.contains(target),
+                checked_at,
+            };
+            (target.clone(), reachability)
+        }));
+    }
+    (looked_up, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn batches_before_a_failure_are_kept() {
+        let handles: Vec<String> = (0..MAX_BATCH * 2 + 1)
+            .map(|index| format!("tel:+1555000{:04}", index))
+            .collect();
+        let mut batches = Vec::new();
+        let (looked_up, failed) = look_up(&handles, |batch| {
+            batches.push(batch.len());
+            let result = match batches.len() {
+                1 => Ok(vec![batch[0].clone()]),
+                _ => Err("rate limited"),
+            };
+            async move { result }
+        })
+        .await;
+
+        assert_eq!(failed, Some("rate limited"));
+        // The third batch is never asked about
+        assert_eq!(batches, [MAX_BATCH, MAX_BATCH]);
+        assert_eq!(looked_up.len(), MAX_BATCH);
+        assert_eq!(looked_up[0].0, handles[0]);
+        assert!(looked_up[0].1.reachable);
+        assert!(looked_up[1..].iter().all(|(_, result)| !result.reachable));
+    }
+
+    #[tokio::test]
+    async fn every_batch_is_looked_up() {
+        let handles: Vec<String> = (0..MAX_BATCH + 1)
+            .map(|index| format!("tel:+1555000{:04}", index))
+            .collect();
+        let (looked_up, failed) = look_up(&handles, |batch| {
+            let valid = batch.to_vec();
+            async move { Ok::<_, ()>(valid) }
+        })
+        .await;
+        assert!(failed.is_none());
+        assert_eq!(looked_up.len(), MAX_BATCH + 1);
+        assert!(looked_up.iter().all(|(_, result)| result.reachable));
+    }
+}
@@ -0,0 +1,82 @@
+use std::sync::Arc;
+
+use tauri::ipc::InvokeError;
+use tokio::sync::Mutex;
+
+use crate::{
+    actions::send::do_send_read_receipt,
+    events::{AppEvent, EventBus},
+    imessage::messenger::conversation_data,
+    state::{
+        keytrust::KeyTrustStore,
+        messagestore::{now_millis, MessageStore},
+        rustpushstate::RustPushState,
+        settings::Settings,
+    },
+};
+
+/**
+ * Mark what we received in a conversation as read, up to and including
+ * `up_to` if given, because it is being viewed
+ *
+ * A read receipt for the newest of those messages is sent unless the
+ * conversation or the global setting says not to. Returns the IDs of the
+ * messages that were unread
+ */
+pub async fn do_mark_conversation_read(
+    state: Arc<Mutex<RustPushState>>,
+    keys: Arc<Mutex<KeyTrustStore>>,
+    messages: Arc<Mutex<MessageStore>>,
+    settings: Arc<Mutex<Settings>>,
+    events: EventBus,
+    conversation_id: &str,
+    up_to: Option<&str>,
+) -> Result<Vec<String>, InvokeError> {
+    let marked = {
+        let mut messages = messages.lock().await;
+        let up_to = match up_to {
+            Some(id) => Some(
+                messages
+                    .get(id)
+                    .ok_or_else(|| InvokeError::from("Message not found"))?
+                    .timestamp,
+            ),
+            None => None,
+        };
+        messages
+            .mark_conversation_read(conversation_id, up_to, now_millis())
+            .map_err(|e| InvokeError::from(e.to_string()))?
+    };
+    let Some(newest) = marked.last() else {
+        return Ok(Vec::new());
+    };
+
+    let send_receipt = settings
+        .lock()
+        .await
+        .receipts
+        .sends_read_receipts(conversation_id);
+    let mut receipt_sent = false;
+    if send_receipt {
+        // Other clients treat everything before the newest message as read too
+        match do_send_read_receipt(
+            state,
+            keys,
+            conversation_data(conversation_id, newest.participants.clone()),
+            &newest.id,
+        )
+        .await
+        {
+            Ok(_) => receipt_sent = true,
+            Err(e) => log::error!("Error sending read receipt: {:?}", e),
+        }
+    }
+
+    let message_ids: Vec<String> = marked.into_iter().map(|message| message.id).collect();
+    events.publish(AppEvent::ConversationRead {
+        conversation_id: conversation_id.to_owned(),
+        message_ids: message_ids.clone(),
+        receipt_sent,
+    });
+    Ok(message_ids)
+}
@@ -0,0 +1,389 @@
+use std::{fmt::Debug, future::Future, sync::Arc, time::Duration};
+
+use rustpush::{AttachmentType, IMessage, Message};
+use tokio::{sync::Mutex, task::JoinHandle};
+use uuid::Uuid;
+
+use crate::{
+    actions::{edit::apply_unsend, typing::INCOMING_TIMEOUT},
+    events::{AppEvent, EventBus},
+    imessage::{
+        attachments::{attachment_size, message_attachments, to_remote},
+        messenger::{conversation_id, from_reaction, receive_message, to_stored_message},
+    },
+    state::{
+        attachmentstore::{AttachmentStore, StoredAttachment},
+        contacts::ContactStore,
+        messagestore::{now_millis, MessageStatus, MessageStore, StoredReaction},
+        rustpushstate::RustPushState,
+        TauriState,
+    },
+};
+
+/**
+ * Record the attachments of an incoming message, returning their IDs
+ *
+ * Inline content is stored straight away, anything else is only
+ * downloaded when it is fetched
+ */
+fn store_attachments(
+    store: &mut AttachmentStore,
+    msg: &IMessage,
+    conversation_id: &str,
+    from_me: bool,
+    account: Option<String>,
+) -> Vec<String> {
+    let mut ids = Vec::new();
+    for attachment in message_attachments(msg) {
+        let stored = StoredAttachment {
+            id: Uuid::new_v4().to_string(),
+            message_id: Some(msg.id.clone()),
+            conversation_id: Some(conversation_id.to_owned()),
+            name: attachment.name.clone(),
+            mime: attachment.mime.clone(),
+            uti: attachment.uti_type.clone(),
+            size: attachment_size(&attachment),
+            sha256: None,
+            from_me,
+            created: now_millis(),
+            remote: to_remote(&attachment),
+            display: None,
+            thumbnail: None,
+            placeholder: None,
+            video: None,
+            derive_failed: false,
+            account: account.clone(),
+            last_accessed: now_millis(),
+        };
+        let id = stored.id.clone();
+        if let Err(e) = store.add_remote(stored) {
+            log::error!("Error saving attachment: {:?}", e);
+            continue;
+        }
+        if let AttachmentType::Inline(data) = &attachment.a_type {
+            if let Err(e) = store.set_content(&id, data) {
+                log::error!("Error saving attachment content: {:?}", e);
+            }
+        }
+        ids.push(id);
+    }
+    ids
+}
+
+/**
+ * Update the status of the message a receipt is for and of everything we
+ * sent before it in the conversation, returning the new status if we have
+ * the message
+ */
+async fn apply_receipt(
+    messages: &Mutex<MessageStore>,
+    id: &str,
+    at: u64,
+    read: bool,
+) -> Option<MessageStatus> {
+    let mut messages = messages.lock().await;
+    let result = if read {
+        messages.mark_read(id, at)
+    } else {
+        messages.mark_delivered(id, at)
+    };
+    if let Err(e) = result {
+        log::error!("Error saving receipt: {:?}", e);
+    }
+    messages.get(id).map(|message| message.status())
+}
+
+/**
+ * Wait for the next incoming message or receipt and record it in the history
+ *
+ * Returns the event to publish for it, or None once the connection has gone away
+ */
+pub async fn do_receive_event(
+    state: Arc<Mutex<RustPushState>>,
+    messages: Arc<Mutex<MessageStore>>,
+    attachments: Arc<Mutex<AttachmentStore>>,
+    contacts: Arc<Mutex<ContactStore>>,
+) -> Option<AppEvent> {
+    loop {
+        let client = state.lock().await.client.clone();
+        let msg = receive_message(client.clone()).await?;
+        let from_me = match &msg.sender {
+            Some(sender) => client.get_handles().contains(sender),
+            None => false,
+        };
+        let conversation_id = msg.conversation.as_ref().map(conversation_id);
+        match &msg.message {
+            Message::Delivered => {
+                let status = apply_receipt(&messages, &msg.id, msg.sent_timestamp, false).await;
+                return Some(AppEvent::MessageDelivered {
+                    id: msg.id.clone(),
+                    conversation_id,
+                    sender: msg.sender.clone(),
+                    timestamp: msg.sent_timestamp,
+                    status,
+                });
+            }
+            Message::Read => {
+                let status = apply_receipt(&messages, &msg.id, msg.sent_timestamp, true).await;
+                return Some(AppEvent::MessageRead {
+                    id: msg.id.clone(),
+                    conversation_id,
+                    sender: msg.sender.clone(),
+                    timestamp: msg.sent_timestamp,
+                    status,
+                });
+            }
+            // Tapbacks update the message they are on instead of being stored
+            Message::React(react) => {
+                let reaction = StoredReaction {
+                    sender: msg.sender.clone(),
+                    from_me,
+                    tapback: from_reaction(&react.reaction),
+                    part: react.to_part,
+                    timestamp: msg.sent_timestamp,
+                };
+                let updated = messages.lock().await.set_reaction(
+                    &react.to_uuid,
+                    reaction.clone(),
+                    react.enable,
+                );
+                match updated {
+                    Ok(Some(target)) => {
+                        return Some(AppEvent::ReactionChanged {
+                            message_id: target.id.clone(),
+                            conversation_id: target.conversation_id.clone(),
+                            reaction,
+                            enabled: react.enable,
+                            reactions: target.reaction_summary(),
+                        })
+                    }
+                    Ok(None) => {
+                        log::debug!("Tapback on unknown message {}", react.to_uuid);
+                        continue;
+                    }
+                    Err(e) => {
+                        log::error!("Error saving reaction: {:?}", e);
+                        continue;
+                    }
+                }
+            }
+            // Edits and unsends change the message they are for, and only its
+            // sender may make them
+            Message::Edit(edit) => {
+                let mut messages = messages.lock().await;
+                match messages.get(&edit.tuuid) {
+                    Some(target) if target.sent_by(msg.sender.as_deref(), from_me) => {}
+                    Some(_) => {
+                        log::warn!("Ignoring edit of {} by someone else", edit.tuuid);
+                        continue;
+                    }
+                    None => {
+                        log::debug!("Edit of unknown message {}", edit.tuuid);
+                        continue;
+                    }
+                }
+                match messages.edit(&edit.tuuid, edit.new_parts.raw_text(), msg.sent_timestamp) {
+                    Ok(Some(message)) => return Some(AppEvent::MessageEdited { message }),
+                    Ok(None) => continue,
+                    Err(e) => {
+                        log::error!("Error saving edit: {:?}", e);
+                        continue;
+                    }
+                }
+            }
+            Message::Unsend(unsend) => {
+                let allowed = messages
+                    .lock()
+                    .await
+                    .get(&unsend.tuuid)
+                    .map(|target| target.sent_by(msg.sender.as_deref(), from_me));
+                match allowed {
+                    // We show the parts of a message together, so any part
+                    // being unsent takes back all of it
+                    Some(true) => {
+                        match apply_unsend(&messages, &attachments, &unsend.tuuid).await {
+                            Some(message) => return Some(AppEvent::MessageUnsent { message }),
+                            None => continue,
+                        }
+                    }
+                    Some(false) => {
+                        log::warn!("Ignoring unsend of {} by someone else", unsend.tuuid);
+                        continue;
+                    }
+                    None => {
+                        log::debug!("Unsend of unknown message {}", unsend.tuuid);
+                        continue;
+                    }
+                }
+            }
+            Message::Typing(typing) => {
+                // Our other devices typing is nothing to show
+                if from_me {
+                    continue;
+                }
+                let Some(conversation_id) = conversation_id else {
+                    continue;
+                };
+                let expires_at = typing.then(|| now_millis() + INCOMING_TIMEOUT.as_millis() as u64);
+                return Some(AppEvent::TypingChanged {
+                    conversation_id,
+                    sender: msg.sender.clone(),
+                    typing: *typing,
+                    expires_at,
+                });
+            }
+            _ => {}
+        }
+        let Some(mut stored) = to_stored_message(&msg, from_me) else {
+            continue;
+        };
+        if messages.lock().await.get(&stored.id).is_some() {
+            continue;
+        }
+        let account = state
+            .lock()
+            .await
+            .get_active_user()
+            .await
+            .map(|(user, _)| user.user_id);
+        stored.attachments = store_attachments(
+            &mut *attachments.lock().await,
+            &msg,
+            &stored.conversation_id,
+            from_me,
+            account,
+        );
+        match messages.lock().await.add(stored.clone()) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => log::error!("Error saving message: {:?}", e),
+        }
+        let contact = contacts.lock().await.message_contact(&stored);
+        return Some(AppEvent::MessageReceived {
+            message: stored,
+            contact,
+        });
+    }
+}
+
+/**
+ * How long to wait before reconnecting after the given number of failed
+ * attempts, doubling from a second up to five minutes
+ */
+fn reconnect_delay(failures: u32) -> Duration {
+    Duration::from_secs(1u64 << failures.min(9)).min(Duration::from_secs(300))
+}
+
+/**
+ * Keep a connection up, running `receive` for as long as each one lasts
+ * and `connect` to replace it once it closes
+ *
+ * Whether we are connected is published as it changes, starting from a
+ * connection that is already up
+ */
+async fn supervise<R, C, E>(
+    events: &EventBus,
+    mut receive: impl FnMut() -> R,
+    mut connect: impl FnMut() -> C,
+) where
+    R: Future<Output = ()>,
+    C: Future<Output = Result<(), E>>,
+    E: Debug,
+{
+    loop {
+        events.publish(AppEvent::ConnectionChanged { connected: true });
+        receive().await;
+        log::error!("Connection closed, reconnecting");
+        events.publish(AppEvent::ConnectionChanged { connected: false });
+        let mut failures = 0;
+        loop {
+            tokio::time::sleep(reconnect_delay(failures)).await;
+            match connect().await {
+                Ok(()) => break,
+                Err(e) => log::warn!("Error reconnecting: {:?}", e),
+            }
+            failures += 1;
+        }
+    }
+}
+
+/**
+ * Receive messages in the background, publishing each one and every
+ * receipt on the event bus, and reconnect whenever the connection closes
+ */
+pub fn spawn_receiver(state: TauriState) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let (rust_push, messages, attachments, contacts, events) = {
+            let state = state.0.lock().await;
+            (
+                state.rust_push.clone(),
+                state.messages.clone(),
+                state.attachments.clone(),
+                state.contacts.clone(),
+                state.events.clone(),
+            )
+        };
+        let (rust_push, messages, attachments, contacts, events) =
+            (&rust_push, &messages, &attachments, &contacts, &events);
+        let receive = move || async move {
+            while let Some(event) = do_receive_event(
+                rust_push.clone(),
+                messages.clone(),
+                attachments.clone(),
+                contacts.clone(),
+            )
+            .await
+            {
+                events.publish(event);
+            }
+        };
+        // The first connection is made before the app starts
+        let connect = move || async move { rust_push.lock().await.reconnect().await };
+        supervise(events, receive, connect).await;
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Mutex as SyncMutex};
+
+    use super::*;
+
+    #[test]
+    fn reconnecting_backs_off_up_to_five_minutes() {
+        assert_eq!(reconnect_delay(0), Duration::from_secs(1));
+        assert_eq!(reconnect_delay(3), Duration::from_secs(8));
+        assert_eq!(reconnect_delay(9), Duration::from_secs(300));
+        assert_eq!(reconnect_delay(u32::MAX), Duration::from_secs(300));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connection_changes_follow_the_connection() {
+        let events = EventBus::new();
+        let mut receiver = events.subscribe();
+        // Each connection closes straight away, until the third
+        let connections = &SyncMutex::new(0);
+        let receive = move || async move {
+            let count = {
+                let mut connections = connections.lock().unwrap();
+                *connections += 1;
+                *connections
+            };
+            if count == 3 {
+                std::future::pending::<()>().await;
+            }
+        };
+        let attempts = &SyncMutex::new(VecDeque::from([Err("offline"), Ok(()), Ok(())]));
+        let connect = move || async move { attempts.lock().unwrap().pop_front().unwrap() };
+        let supervisor = supervise(&events, receive, connect);
+        let _ = tokio::time::timeout(Duration::from_secs(3600), supervisor).await;
+
+        let mut changes = Vec::new();
+        while let Ok(AppEvent::ConnectionChanged { connected }) = receiver.try_recv() {
+            changes.push(connected);
+        }
+        assert_eq!(changes, [true, false, true, false, true]);
+        assert!(events.connected());
+        assert!(attempts.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,232 @@
+use std::{sync::Arc, time::Duration};
+
+use tauri::ipc::InvokeError;
+use tokio::{sync::Mutex, task::JoinHandle};
+use uuid::Uuid;
+
+use crate::{
+    actions::outbox::{do_queue_message_with_id, OutgoingMessage},
+    events::{AppEvent, EventBus},
+    imessage::messenger::{conversation_data, conversation_id},
+    state::{
+        attachmentstore::AttachmentStore,
+        messagestore::now_millis,
+        schedule::{ScheduleStore, ScheduledMessage, SendTime},
+        TauriState,
+    },
+};
+
+/// The longest the scheduler sleeps, so a wall clock that jumps (after a
+/// suspend, or when it is set) is noticed
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/**
+ * Keep a message to be put in the outbox at a later time
+ *
+ * A time that has already passed sends the message straight away
+ */
+pub async fn do_schedule_message(
+    attachments: Arc<Mutex<AttachmentStore>>,
+    schedule: Arc<Mutex<ScheduleStore>>,
+    events: EventBus,
+    message: OutgoingMessage,
+    send_time: SendTime,
+) -> Result<ScheduledMessage, InvokeError> {
+    let send_at = send_time
+        .to_millis()
+        .map_err(|e| InvokeError::from(e.to_string()))?;
+    let id = Uuid::new_v4().to_string().to_uppercase();
+    let conversation_id = conversation_id(&message.conversation);
+    // Linked so the attachments are not cleaned up while we wait
+    let remote = vec![None; message.attachments.len()];
+    attachments
+        .lock()
+        .await
+        .set_message(&message.attachments, &id, &conversation_id, None, remote)
+        .map_err(|e| InvokeError::from(e.to_string()))?;
+    let now = now_millis();
+    let scheduled = ScheduledMessage {
+        id,
+        conversation_id,
+        participants: message.conversation.participants,
+        text: message.text,
+        attachments: message.attachments,
+        reply_to: message.reply_to,
+        send_time,
+        send_at,
+        created: now,
+        updated: now,
+    };
+    schedule
+        .lock()
+        .await
+        .add(scheduled.clone())
+        .map_err(|e| InvokeError::from(e.to_string()))?;
+    events.publish(AppEvent::ScheduleChanged {
+        scheduled: scheduled.clone(),
+    });
+    Ok(scheduled)
+}
+
+/**
+ * Change the text or the time of a message that has not been sent yet
+ *
+ * Returns None if there is no such message waiting
+ */
+pub async fn do_edit_scheduled(
+    schedule: Arc<Mutex<ScheduleStore>>,
+    events: EventBus,
+    id: &str,
+    text: Option<String>,
+    send_time: Option<SendTime>,
+) -> Result<Option<ScheduledMessage>, InvokeError> {
+    let send_at = match &send_time {
+        Some(send_time) => Some(
+            send_time
+                .to_millis()
+                .map_err(|e| InvokeError::from(e.to_string()))?,
+        ),
+        None => None,
+    };
+    let scheduled = schedule
+        .lock()
+        .await
+        .update(id, |entry| {
+            if let Some(text) = text {
+                entry.text = text;
+            }
+            if let (Some(send_time), Some(send_at)) = (send_time, send_at) {
+                entry.send_time = send_time;
+                entry.send_at = send_at;
+            }
+        })
+        .map_err(|e| InvokeError::from(e.to_string()))?;
+    if let Some(scheduled) = &scheduled {
+        events.publish(AppEvent::ScheduleChanged {
+            scheduled: scheduled.clone(),
+        });
+    }
+    Ok(scheduled)
+}
+
+/**
+ * Drop a message that has not been sent yet, its attachments are cleaned
+ * up with the other orphans
+ *
+ * Returns whether there was such a message
+ */
+pub async fn do_cancel_scheduled(
+    schedule: Arc<Mutex<ScheduleStore>>,
+    events: EventBus,
+    id: &str,
+) -> Result<bool, InvokeError> {
+    let removed = schedule
+        .lock()
+        .await
+        .remove(id)
+        .map_err(|e| InvokeError::from(e.to_string()))?;
+    let Some(scheduled) = removed else {
+        return Ok(false);
+    };
+    events.publish(AppEvent::ScheduleRemoved {
+        scheduled,
+        message_id: None,
+    });
+    Ok(true)
+}
+
+/**
+ * Hand a message whose time has come to the outbox, as a message with the
+ * same ID
+ *
+ * The schedule stays locked until the message is out of it, so it can't
+ * be edited or cancelled part way through. If we stop before it is
+ * removed, queueing it again on the next start does nothing.
+ */
+async fn send_due(state: &TauriState, schedule: &Mutex<ScheduleStore>, id: &str) {
+    let (rust_push, messages, attachments, outbox, events) = {
+        let state = state.0.lock().await;
+        (
+            state.rust_push.clone(),
+            state.messages.clone(),
+            state.attachments.clone(),
+            state.outbox.clone(),
+            state.events.clone(),
+        )
+    };
+    let mut schedule = schedule.lock().await;
+    let now = now_millis();
+    // It may have been cancelled or moved since it was found to be due
+    let Some(scheduled) = schedule
+        .get(id)
+        .filter(|scheduled| scheduled.send_at <= now)
+        .cloned()
+    else {
+        return;
+    };
+    let late = now.saturating_sub(scheduled.send_at);
+    if late > MAX_WAIT.as_millis() as u64 {
+        log::info!("Sending {} late by {} ms", scheduled.id, late);
+    }
+    let message = OutgoingMessage {
+        conversation: conversation_data(&scheduled.conversation_id, scheduled.participants.clone()),
+        text: scheduled.text.clone(),
+        attachments: scheduled.attachments.clone(),
+        reply_to: scheduled.reply_to.clone(),
+    };
+    let message_id = match do_queue_message_with_id(
+        rust_push,
+        messages,
+        attachments,
+        outbox,
+        events.clone(),
+        message,
+        scheduled.id.clone(),
+    )
+    .await
+    {
+        Ok(message_id) => message_id,
+        Err(e) => {
+            // Left in the schedule to be tried again
+            log::error!("Error queueing scheduled message {}: {:?}", scheduled.id, e);
+            return;
+        }
+    };
+    match schedule.remove(&scheduled.id) {
+        Ok(_) => events.publish(AppEvent::ScheduleRemoved {
+            scheduled,
+            message_id: Some(message_id),
+        }),
+        Err(e) => log::error!("Error saving schedule: {:?}", e),
+    }
+}
+
+/**
+ * Put scheduled messages in the outbox once their time comes
+ *
+ * Messages whose time passed while we were not running are sent as soon as
+ * this starts. One that can't be queued, because nobody is logged in, is
+ * tried again every `MAX_WAIT`.
+ */
+pub fn spawn_scheduler(state: TauriState) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let schedule = state.0.lock().await.schedule.clone();
+        let wake = schedule.lock().await.waker();
+        loop {
+            let due = schedule.lock().await.due(now_millis());
+            for scheduled in due {
+                send_due(&state, &schedule, &scheduled.id).await;
+            }
+            // Anything still due could not be queued and waits for the next round
+            let now = now_millis();
+            let wait = match schedule.lock().await.next_send_at(now) {
+                Some(send_at) => Duration::from_millis(send_at - now).min(MAX_WAIT),
+                None => MAX_WAIT,
+            };
+            tokio::select! {
+                _ = wake.notified() => {}
+                _ = tokio::time::sleep(wait) => {}
+            }
+        }
+    })
+}
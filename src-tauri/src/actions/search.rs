@@ -0,0 +1,456 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use tokio::{
+    sync::{broadcast::error::RecvError, Mutex},
+    task::JoinHandle,
+};
+
+use crate::{
+    actions::{conversations::stored_conversation_ids, send::resolve_handle},
+    events::AppEvent,
+    imessage::handle::HandleError,
+    state::{
+        attachmentstore::AttachmentStore,
+        contacts::ContactStore,
+        messagestore::{MessageStore, StoredMessage},
+        searchindex::{IndexedMessage, SearchFilter, SearchIndex, MATCH_END, MATCH_START},
+        TauriState,
+    },
+};
+
+/// Results in a page if none is asked for
+const DEFAULT_LIMIT: u32 = 20;
+const MAX_LIMIT: u32 = 100;
+/// How often to look for renamed contacts, since editing them does not
+/// publish an event
+const CONTACTS_CHECK: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum SearchError {
+    /// Nothing to search for once quotes and punctuation are left out
+    EmptyQuery,
+    InvalidHandle(HandleError),
+    IndexError(rusqlite::Error),
+}
+
+impl From<rusqlite::Error> for SearchError {
+    fn from(error: rusqlite::Error) -> Self {
+        SearchError::IndexError(error)
+    }
+}
+
+pub struct SearchQuery {
+    /// Words to find, or phrases in double quotes
+    pub text: String,
+    /// A stored conversation or a contact's merged one
+    pub conversation_id: Option<String>,
+    pub sender: Option<String>,
+    pub after: Option<u64>,
+    pub before: Option<u64>,
+    pub has_attachment: Option<bool>,
+    pub from_me: Option<bool>,
+    pub offset: u32,
+    pub limit: u32,
+}
+
+pub struct SnippetPart {
+    pub text: String,
+    /// Whether this part matched the query, for highlighting
+    pub matched: bool,
+}
+
+pub struct SearchResult {
+    pub message: StoredMessage,
+    /// The text around the best match, which may be a contact name or an
+    /// attachment's filename rather than the message
+    pub snippet: Vec<SnippetPart>,
+}
+
+/**
+ * Turn what the user typed into an FTS5 query, quoting every word so
+ * nothing is taken as syntax. Phrases in double quotes stay together and
+ * the word still being typed matches as a prefix.
+ */
+fn to_fts_query(text: &str) -> Option<String> {
+    let mut terms: Vec<(&str, bool)> = Vec::new();
+    let mut rest = text;
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let (phrase, after) = quoted.split_once('"').unwrap_or((quoted, ""));
+            terms.push((phrase, false));
+            rest = after;
+        } else {
+            let end = rest
+                .find(|c: char| c.is_whitespace() || c == '"')
+                .unwrap_or(rest.len());
+            terms.push((&rest[..end], true));
+            rest = &rest[end..];
+        }
+    }
+    let typing = !text.ends_with(|c: char| c.is_whitespace() || c == '"');
+    let count = terms.len();
+    let query: Vec<String> = terms
+        .into_iter()
+        .enumerate()
+        .filter(|(_, (term, _))| term.chars().any(char::is_alphanumeric))
+        .map(|(i, (term, word))| {
+            if word && typing && i + 1 == count {
+                format!("\"{}\"*", term)
+            } else {
+                format!("\"{}\"", term)
+            }
+        })
+        .collect();
+    (!query.is_empty()).then(|| query.join(" "))
+}
+
+fn split_snippet(snippet: &str) -> Vec<SnippetPart> {
+    let mut parts = Vec::new();
+    let mut rest = snippet;
+    while let Some(start) = rest.find(MATCH_START) {
+        if start > 0 {
+            parts.push(SnippetPart {
+                text: rest[..start].to_owned(),
+                matched: false,
+            });
+        }
+        let matched = &rest[start + MATCH_START.len_utf8()..];
+        let end = matched.find(MATCH_END).unwrap_or(matched.len());
+        parts.push(SnippetPart {
+            text: matched[..end].to_owned(),
+            matched: true,
+        });
+        rest = matched.get(end + MATCH_END.len_utf8()..).unwrap_or("");
+    }
+    if !rest.is_empty() {
+        parts.push(SnippetPart {
+            text: rest.to_owned(),
+            matched: false,
+        });
+    }
+    parts
+}
+
+/**
+ * The name of every handle that belongs to a contact
+ */
+fn contact_names(contacts: &ContactStore) -> HashMap<String, String> {
+    let mut names = HashMap::new();
+    for contact in contacts.entries() {
+        for handle in contact.handles.iter() {
+            names
+                .entry(handle.clone())
+                .or_insert_with(|| contact.name.clone());
+        }
+    }
+    names
+}
+
+fn to_indexed(
+    message: &StoredMessage,
+    attachments: &AttachmentStore,
+    names: &HashMap<String, String>,
+) -> IndexedMessage {
+    let mut handles = message.participants.clone();
+    if let Some(sender) = &message.sender {
+        if !handles.contains(sender) {
+            handles.push(sender.clone());
+        }
+    }
+    let contacts = handles
+        .iter()
+        .filter_map(|handle| names.get(handle).cloned())
+        .collect();
+    IndexedMessage {
+        id: message.id.clone(),
+        conversation_id: message.conversation_id.clone(),
+        sender: message.sender.clone(),
+        from_me: message.from_me,
+        timestamp: message.timestamp,
+        body: message.text.clone(),
+        filenames: message
+            .attachments
+            .iter()
+            .filter_map(|id| attachments.get(id))
+            .map(|attachment| attachment.name.clone())
+            .collect(),
+        handles,
+        contacts,
+        revision: message.edits.len() as u32,
+    }
+}
+
+/**
+ * Index what the index is missing or has out of date, and drop messages
+ * that are gone or were unsent
+ */
+async fn catch_up(
+    messages: &Mutex<MessageStore>,
+    attachments: &Mutex<AttachmentStore>,
+    index: &Mutex<SearchIndex>,
+    names: &HashMap<String, String>,
+) -> Result<(), rusqlite::Error> {
+    let mut revisions = index.lock().await.revisions()?;
+    let stale: Vec<IndexedMessage> = {
+        let messages = messages.lock().await;
+        let attachments = attachments.lock().await;
+        messages
+            .messages()
+            .iter()
+            .filter(|message| message.unsent_at.is_none())
+            .filter(|message| revisions.remove(&message.id) != Some(message.edits.len() as u32))
+            .map(|message| to_indexed(message, &attachments, names))
+            .collect()
+    };
+    // Whatever is left was not matched with a message that can be found
+    let mut index = index.lock().await;
+    for id in revisions.keys() {
+        index.remove(id)?;
+    }
+    for message in stale.iter() {
+        index.put(message)?;
+    }
+    if !stale.is_empty() || !revisions.is_empty() {
+        log::info!(
+            "Search index caught up: {} indexed, {} removed",
+            stale.len(),
+            revisions.len()
+        );
+    }
+    Ok(())
+}
+
+/**
+ * Keep the search index up to date with the history, catching up with
+ * whatever changed while it was not running
+ */
+pub fn spawn_search_indexer(state: TauriState) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let (messages, attachments, contacts, index, events) = {
+            let state = state.0.lock().await;
+            (
+                state.messages.clone(),
+                state.attachments.clone(),
+                state.contacts.clone(),
+                state.search.clone(),
+                state.events.clone(),
+            )
+        };
+        let mut receiver = events.subscribe();
+        let mut names = contact_names(&*contacts.lock().await);
+        let mut interval = tokio::time::interval(CONTACTS_CHECK);
+        let mut behind = true;
+        loop {
+            if behind {
+                match catch_up(&messages, &attachments, &index, &names).await {
+                    Ok(()) => behind = false,
+                    Err(e) => log::error!("Error catching up the search index: {:?}", e),
+                }
+            }
+            let event = tokio::select! {
+                _ = interval.tick() => None,
+                event = receiver.recv() => match event {
+                    Ok(event) => Some(event),
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::warn!("Search index missed {} events", skipped);
+                        behind = true;
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            };
+            let result = match event {
+                Some(AppEvent::MessageReceived { message, .. })
+                | Some(AppEvent::MessageSent { message, .. })
+                | Some(AppEvent::MessageEdited { message }) => {
+                    let indexed = to_indexed(&message, &*attachments.lock().await, &names);
+                    index.lock().await.put(&indexed)
+                }
+                Some(AppEvent::MessageUnsent { message }) => index.lock().await.remove(&message.id),
+                None | Some(AppEvent::ContactsSynced { .. }) => {
+                    let current = contact_names(&*contacts.lock().await);
+                    let changed: HashSet<String> = names
+                        .keys()
+                        .chain(current.keys())
+                        .filter(|handle| names.get(*handle) != current.get(*handle))
+                        .cloned()
+                        .collect();
+                    names = current;
+                    if changed.is_empty() {
+                        continue;
+                    }
+                    index.lock().await.rename(&names, &changed)
+                }
+                Some(_) => continue,
+            };
+            if let Err(e) = result {
+                log::error!("Error updating the search index: {:?}", e);
+                behind = true;
+            }
+        }
+    })
+}
+
+/**
+ * Find messages by their text, the filenames of their attachments or the
+ * names of the contacts in their conversation
+ *
+ * Returns a page of results, best matches first, and how many there are
+ */
+pub async fn do_search(
+    state: &TauriState,
+    query: SearchQuery,
+) -> Result<(Vec<SearchResult>, u32), SearchError> {
+    let fts_query = to_fts_query(&query.text).ok_or(SearchError::EmptyQuery)?;
+    let (messages, settings, index) = {
+        let state = state.0.lock().await;
+        (
+            state.messages.clone(),
+            state.settings.clone(),
+            state.search.clone(),
+        )
+    };
+    let sender = match &query.sender {
+        Some(sender) => Some(
+            resolve_handle(&settings, sender)
+                .await
+                .map_err(SearchError::InvalidHandle)?,
+        ),
+        None => None,
+    };
+    let conversation_ids = match &query.conversation_id {
+        Some(id) => {
+            let ids = stored_conversation_ids(state, id).await;
+            if ids.is_empty() {
+                return Ok((Vec::new(), 0));
+            }
+            ids
+        }
+        None => Vec::new(),
+    };
+    let filter = SearchFilter {
+        conversation_ids,
+        sender,
+        after: query.after,
+        before: query.before,
+        has_attachment: query.has_attachment,
+        from_me: query.from_me,
+    };
+    let limit = match query.limit {
+        0 => DEFAULT_LIMIT,
+        limit => limit.min(MAX_LIMIT),
+    };
+    let (hits, total) = index
+        .lock()
+        .await
+        .search(&fts_query, &filter, query.offset, limit)?;
+    let messages = messages.lock().await;
+    let results = hits
+        .into_iter()
+        .filter_map(|hit| {
+            Some(SearchResult {
+                message: messages.get(&hit.message_id)?.clone(),
+                snippet: split_snippet(&hit.snippet),
+            })
+        })
+        .collect();
+    Ok((results, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_are_quoted_and_the_last_one_is_a_prefix() {
+        let cases = [
+            ("hello", Some(r#""hello"*"#)),
+            ("hello wor", Some(r#""hello" "wor"*"#)),
+            ("hello ", Some(r#""hello""#)),
+            (r#""hello world""#, Some(r#""hello world""#)),
+            (r#""hello world" fo"#, Some(r#""hello world" "fo"*"#)),
+            // An unclosed phrase is still a phrase, not a prefix
+            (r#""hello wor"#, Some(r#""hello wor""#)),
+            (r#"say"hi"#, Some(r#""say" "hi""#)),
+        ];
+        for (text, query) in cases {
+            assert_eq!(to_fts_query(text).as_deref(), query, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn fts_syntax_is_searched_for_as_text() {
+        let cases = [
+            ("NEAR", Some(r#""NEAR"*"#)),
+            ("a NEAR b", Some(r#""a" "NEAR" "b"*"#)),
+            ("NEAR(a b)", Some(r#""NEAR(a" "b)"*"#)),
+            ("-spam", Some(r#""-spam"*"#)),
+            ("ham -spam", Some(r#""ham" "-spam"*"#)),
+            ("pre*", Some(r#""pre*"*"#)),
+            ("a OR b", Some(r#""a" "OR" "b"*"#)),
+            ("col:value", Some(r#""col:value"*"#)),
+        ];
+        for (text, query) in cases {
+            assert_eq!(to_fts_query(text).as_deref(), query, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn punctuation_alone_is_nothing_to_search_for() {
+        for text in ["", "   ", "\"", "\"\"", "*", "-", "- * \" ^", "\"*\""] {
+            assert_eq!(to_fts_query(text), None, "{:?}", text);
+        }
+        // Left out, without taking the prefix from the word before
+        assert_eq!(to_fts_query("hello -").as_deref(), Some(r#""hello""#));
+    }
+
+    fn parts(snippet: &str) -> Vec<(String, bool)> {
+        split_snippet(snippet)
+            .into_iter()
+            .map(|part| (part.text, part.matched))
+            .collect()
+    }
+
+    fn part(text: &str, matched: bool) -> (String, bool) {
+        (text.to_owned(), matched)
+    }
+
+    #[test]
+    fn snippets_are_split_at_the_matches() {
+        let snippet = format!(
+            "say {}hello{} to {}all{}",
+            MATCH_START, MATCH_END, MATCH_START, MATCH_END
+        );
+        assert_eq!(
+            parts(&snippet),
+            [
+                part("say ", false),
+                part("hello", true),
+                part(" to ", false),
+                part("all", true),
+            ]
+        );
+        let snippet = format!("{}\"quoted\"{} -dash* NEAR…", MATCH_START, MATCH_END);
+        assert_eq!(
+            parts(&snippet),
+            [part("\"quoted\"", true), part(" -dash* NEAR…", false)]
+        );
+        assert_eq!(parts("no match"), [part("no match", false)]);
+        assert!(parts("").is_empty());
+    }
+
+    #[test]
+    fn an_unclosed_match_runs_to_the_end() {
+        let snippet = format!("a {}b", MATCH_START);
+        assert_eq!(parts(&snippet), [part("a ", false), part("b", true)]);
+        let snippet = MATCH_START.to_string();
+        assert_eq!(parts(&snippet), [part("", true)]);
+    }
+}
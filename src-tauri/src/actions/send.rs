@@ -5,27 +5,194 @@ use tauri::ipc::InvokeError;
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
-use crate::{imessage::messenger::send_text_message, state::rustpushstate::RustPushState};
+use crate::{
+    actions::identities::check_not_held,
+    events::{AppEvent, EventBus},
+    imessage::{
+        handle::{normalize_handle, HandleError},
+        messenger::{
+            conversation_data, reaction_message, send_read_receipt, send_text_message,
+            to_stored_message,
+        },
+    },
+    state::{
+        contacts::ContactStore,
+        keytrust::KeyTrustStore,
+        messagestore::{now_millis, MessageStore, StoredReaction, Tapback},
+        rustpushstate::RustPushState,
+        settings::Settings,
+    },
+};
+
+/**
+ * Turn a recipient the user typed into the handle IDS expects, taking
+ * phone numbers without a country code to be in the configured region
+ */
+pub async fn resolve_handle(
+    settings: &Mutex<Settings>,
+    input: &str,
+) -> Result<String, HandleError> {
+    let default_region = settings.lock().await.handles.default_region.clone();
+    normalize_handle(input, &default_region)
+}
 
+/**
+ * Send a text message from the active handle to a new conversation and
+ * record it in the history
+ *
+ * This sends straight away instead of going through the outbox, for when
+ * nothing will be around to retry. `to` should already have gone through
+ * `resolve_handle`. This will return the message ID
+ */
 pub async fn do_send_message(
     state: Arc<Mutex<RustPushState>>,
+    keys: Arc<Mutex<KeyTrustStore>>,
+    messages: Arc<Mutex<MessageStore>>,
+    contacts: Arc<Mutex<ContactStore>>,
+    events: EventBus,
     message: String,
-    // conversation: ConversationData,
     to: String,
-) -> Result<bool, InvokeError> {
-    let client = state.lock().await.client.clone();
-    match send_text_message(
-        client,
+) -> Result<String, InvokeError> {
+    do_send_to_conversation(
+        state,
+        keys,
+        messages,
+        contacts,
+        events,
         ConversationData {
             participants: vec![to],
             cv_name: None,
             sender_guid: Some(Uuid::new_v4().to_string()),
         },
         Message::Message(NormalMessage::new(message)),
+        Vec::new(),
     )
     .await
-    {
-        Ok(_) => Ok(true),
+}
+
+/**
+ * Send any message from the active handle to an existing conversation,
+ * recording it in the history with the given attachment IDs if it has
+ * content
+ *
+ * This will return the message ID
+ */
+pub async fn do_send_to_conversation(
+    state: Arc<Mutex<RustPushState>>,
+    keys: Arc<Mutex<KeyTrustStore>>,
+    messages: Arc<Mutex<MessageStore>>,
+    contacts: Arc<Mutex<ContactStore>>,
+    events: EventBus,
+    conversation: ConversationData,
+    message: Message,
+    attachments: Vec<String>,
+) -> Result<String, InvokeError> {
+    check_not_held(&keys, &conversation.participants).await?;
+    let (client, handle) = {
+        let mut state = state.lock().await;
+        match state.get_active_user().await {
+            Some((_, handle)) => (state.client.clone(), handle),
+            None => return Err(InvokeError::from("No logged in users")),
+        }
+    };
+    match send_text_message(client, &handle, conversation, message).await {
+        Ok(msg) => {
+            if let Some(mut stored) = to_stored_message(&msg, true) {
+                stored.attachments = attachments;
+                if let Err(e) = messages.lock().await.add(stored.clone()) {
+                    log::error!("Error saving message: {:?}", e);
+                }
+                let contact = contacts.lock().await.message_contact(&stored);
+                events.publish(AppEvent::MessageSent {
+                    message: stored,
+                    contact,
+                });
+            }
+            Ok(msg.id)
+        }
         Err(e) => Err(InvokeError::from(e.to_string())),
     }
 }
+
+/**
+ * Mark a message in a conversation as read for the other participants
+ */
+pub async fn do_send_read_receipt(
+    state: Arc<Mutex<RustPushState>>,
+    keys: Arc<Mutex<KeyTrustStore>>,
+    conversation: ConversationData,
+    message_id: &str,
+) -> Result<(), InvokeError> {
+    check_not_held(&keys, &conversation.participants).await?;
+    let (client, handle) = {
+        let mut state = state.lock().await;
+        match state.get_active_user().await {
+            Some((_, handle)) => (state.client.clone(), handle),
+            None => return Err(InvokeError::from("No logged in users")),
+        }
+    };
+    send_read_receipt(client, &handle, conversation, message_id)
+        .await
+        .map_err(|e| InvokeError::from(e.to_string()))
+}
+
+/**
+ * Add or remove our tapback on a part of a stored message
+ */
+pub async fn do_send_tapback(
+    state: Arc<Mutex<RustPushState>>,
+    keys: Arc<Mutex<KeyTrustStore>>,
+    messages: Arc<Mutex<MessageStore>>,
+    events: EventBus,
+    message_id: &str,
+    part: u64,
+    tapback: Tapback,
+    enable: bool,
+) -> Result<(), InvokeError> {
+    let target = messages
+        .lock()
+        .await
+        .get(message_id)
+        .cloned()
+        .ok_or_else(|| InvokeError::from("Message not found"))?;
+    check_not_held(&keys, &target.participants).await?;
+    let (client, handle) = {
+        let mut state = state.lock().await;
+        match state.get_active_user().await {
+            Some((_, handle)) => (state.client.clone(), handle),
+            None => return Err(InvokeError::from("No logged in users")),
+        }
+    };
+    send_text_message(
+        client,
+        &handle,
+        conversation_data(&target.conversation_id, target.participants.clone()),
+        reaction_message(&target.id, part, &target.text, &tapback, enable),
+    )
+    .await
+    .map_err(|e| InvokeError::from(e.to_string()))?;
+
+    let reaction = StoredReaction {
+        sender: Some(handle),
+        from_me: true,
+        tapback,
+        part,
+        timestamp: now_millis(),
+    };
+    let updated = messages
+        .lock()
+        .await
+        .set_reaction(&target.id, reaction.clone(), enable);
+    match updated {
+        Ok(Some(updated)) => events.publish(AppEvent::ReactionChanged {
+            message_id: updated.id.clone(),
+            conversation_id: updated.conversation_id.clone(),
+            reaction,
+            enabled: enable,
+            reactions: updated.reaction_summary(),
+        }),
+        Ok(None) => {}
+        Err(e) => log::error!("Error saving reaction: {:?}", e),
+    }
+    Ok(())
+}
@@ -0,0 +1,358 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tauri::ipc::InvokeError;
+use tokio::{
+    sync::{broadcast::error::RecvError, Mutex},
+    task::JoinHandle,
+};
+
+use crate::{
+    actions::identities::check_not_held,
+    events::{AppEvent, EventBus},
+    imessage::messenger::{conversation_data, send_text_message, typing_message},
+    state::{
+        keytrust::KeyTrustStore, messagestore::MessageStore, rustpushstate::RustPushState,
+        TauriState,
+    },
+};
+
+/// How long after the last keystroke we tell the others we stopped
+const OUTGOING_IDLE: Duration = Duration::from_secs(10);
+/// How long an incoming indicator lasts unless it is repeated
+pub const INCOMING_TIMEOUT: Duration = Duration::from_secs(60);
+
+/**
+ * Send a typing indicator from the active handle to everyone in a
+ * conversation we have history for
+ */
+pub async fn do_send_typing(
+    state: Arc<Mutex<RustPushState>>,
+    keys: Arc<Mutex<KeyTrustStore>>,
+    messages: Arc<Mutex<MessageStore>>,
+    conversation_id: &str,
+    typing: bool,
+) -> Result<(), InvokeError> {
+    let participants = messages
+        .lock()
+        .await
+        .conversation(conversation_id)
+        .last()
+        .map(|message| message.participants.clone())
+        .ok_or_else(|| InvokeError::from("Conversation not found"))?;
+    check_not_held(&keys, &participants).await?;
+    let (client, handle) = {
+        let mut state = state.lock().await;
+        match state.get_active_user().await {
+            Some((_, handle)) => (state.client.clone(), handle),
+            None => return Err(InvokeError::from("No logged in users")),
+        }
+    };
+    send_text_message(
+        client,
+        &handle,
+        conversation_data(conversation_id, participants),
+        typing_message(typing),
+    )
+    .await
+    .map_err(|e| InvokeError::from(e.to_string()))?;
+    Ok(())
+}
+
+/**
+ * Automatic stops of the conversations we are typing in
+ */
+struct StopTimers {
+    /// Each conversation's pending stop, with the generation it was
+    /// started in
+    timers: Mutex<HashMap<String, (u64, JoinHandle<()>)>>,
+    /// Generation of the next automatic stop, so one that fires late does
+    /// not take the place of its replacement
+    generation: AtomicU64,
+}
+
+impl StopTimers {
+    fn new() -> Arc<StopTimers> {
+        Arc::new(StopTimers {
+            timers: Mutex::new(HashMap::new()),
+            generation: AtomicU64::new(0),
+        })
+    }
+
+    /**
+     * Run `stop` once the conversation has been idle for `OUTGOING_IDLE`,
+     * in place of any stop that was already waiting
+     *
+     * Returns whether one was waiting
+     */
+    async fn restart(
+        self: &Arc<Self>,
+        conversation_id: &str,
+        stop: impl Future<Output = ()> + Send + 'static,
+    ) -> bool {
+        let mut timers = self.timers.lock().await;
+        let replaced = match timers.remove(conversation_id) {
+            Some((_, timer)) => {
+                timer.abort();
+                true
+            }
+            None => false,
+        };
+        let this = self.clone();
+        let id = conversation_id.to_owned();
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+        let timer = tokio::spawn(async move {
+            tokio::time::sleep(OUTGOING_IDLE).await;
+            {
+                let mut timers = this.timers.lock().await;
+                match timers.get(&id) {
+                    Some((current, _)) if *current == generation => {
+                        timers.remove(&id);
+                    }
+                    // Typing was stopped or started again meanwhile
+                    _ => return,
+                }
+            }
+            stop.await;
+        });
+        timers.insert(conversation_id.to_owned(), (generation, timer));
+        replaced
+    }
+
+    /**
+     * Drop the stop waiting for a conversation, returning whether there was one
+     */
+    async fn cancel(&self, conversation_id: &str) -> bool {
+        match self.timers.lock().await.remove(conversation_id) {
+            Some((_, timer)) => {
+                timer.abort();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/**
+ * Our typing state in each conversation, so indicators are only sent when
+ * it changes and typing stops by itself once the user goes idle
+ */
+pub struct TypingTracker {
+    state: Arc<Mutex<RustPushState>>,
+    keys: Arc<Mutex<KeyTrustStore>>,
+    messages: Arc<Mutex<MessageStore>>,
+    timers: Arc<StopTimers>,
+}
+
+impl TypingTracker {
+    pub fn new(
+        state: Arc<Mutex<RustPushState>>,
+        keys: Arc<Mutex<KeyTrustStore>>,
+        messages: Arc<Mutex<MessageStore>>,
+    ) -> Arc<TypingTracker> {
+        Arc::new(TypingTracker {
+            state,
+            keys,
+            messages,
+            timers: StopTimers::new(),
+        })
+    }
+
+    /**
+     * Start or stop typing in a conversation
+     *
+     * Call this on every keystroke, each one pushes the automatic stop back
+     */
+    pub async fn set(
+        self: &Arc<Self>,
+        conversation_id: &str,
+        typing: bool,
+    ) -> Result<(), InvokeError> {
+        let was_typing = if typing {
+            let tracker = self.clone();
+            let id = conversation_id.to_owned();
+            let stop = async move {
+                if let Err(e) = do_send_typing(
+                    tracker.state.clone(),
+                    tracker.keys.clone(),
+                    tracker.messages.clone(),
+                    &id,
+                    false,
+                )
+                .await
+                {
+                    log::error!("Error stopping typing in {}: {:?}", id, e);
+                }
+            };
+            self.timers.restart(conversation_id, stop).await
+        } else {
+            self.timers.cancel(conversation_id).await
+        };
+        if typing == was_typing {
+            return Ok(());
+        }
+        do_send_typing(
+            self.state.clone(),
+            self.keys.clone(),
+            self.messages.clone(),
+            conversation_id,
+            typing,
+        )
+        .await
+    }
+
+    /**
+     * Forget that we are typing without telling anyone, because sending a
+     * message clears the indicator on the other side
+     */
+    pub async fn clear(&self, conversation_id: &str) {
+        self.timers.cancel(conversation_id).await;
+    }
+}
+
+/**
+ * Expire incoming typing indicators that are not repeated and clear them
+ * when the message arrives, and stop tracking our own typing when we send
+ */
+pub fn spawn_typing_expiry(state: TauriState) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let (events, typing) = {
+            let state = state.0.lock().await;
+            (state.events.clone(), state.typing.clone())
+        };
+        let mut receiver = events.subscribe();
+        // Indicators currently shown, by conversation and sender, with the
+        // expiry that will clear them
+        let mut shown: HashMap<(String, String), JoinHandle<()>> = HashMap::new();
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("Typing expiry missed {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            match event {
+                AppEvent::TypingChanged {
+                    conversation_id,
+                    sender: Some(sender),
+                    typing,
+                    ..
+                } => {
+                    let key = (conversation_id.clone(), sender.clone());
+                    if let Some(expiry) = shown.remove(&key) {
+                        expiry.abort();
+                    }
+                    if typing {
+                        shown.insert(key, spawn_expiry(events.clone(), conversation_id, sender));
+                    }
+                }
+                AppEvent::MessageReceived { message, .. } if !message.from_me => {
+                    let Some(sender) = message.sender else {
+                        continue;
+                    };
+                    let key = (message.conversation_id, sender);
+                    if let Some(expiry) = shown.remove(&key) {
+                        expiry.abort();
+                        events.publish(AppEvent::TypingChanged {
+                            conversation_id: key.0,
+                            sender: Some(key.1),
+                            typing: false,
+                            expires_at: None,
+                        });
+                    }
+                }
+                AppEvent::MessageSent { message, .. } => {
+                    typing.clear(&message.conversation_id).await
+                }
+                _ => {}
+            }
+        }
+    })
+}
+
+fn spawn_expiry(events: EventBus, conversation_id: String, sender: String) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        tokio::time::sleep(INCOMING_TIMEOUT).await;
+        events.publish(AppEvent::TypingChanged {
+            conversation_id,
+            sender: Some(sender),
+            typing: false,
+            expires_at: None,
+        });
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    fn count(stops: &Arc<AtomicUsize>) -> impl Future<Output = ()> + Send + 'static {
+        let stops = stops.clone();
+        async move {
+            stops.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn typing_again_pushes_the_stop_back() {
+        let timers = StopTimers::new();
+        let stops = Arc::new(AtomicUsize::new(0));
+        assert!(!timers.restart("c", count(&stops)).await);
+        tokio::time::sleep(OUTGOING_IDLE - Duration::from_secs(1)).await;
+        assert!(timers.restart("c", count(&stops)).await);
+
+        // Past when the first stop would have fired
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(stops.load(Ordering::SeqCst), 0);
+
+        tokio::time::sleep(OUTGOING_IDLE).await;
+        assert_eq!(stops.load(Ordering::SeqCst), 1);
+        // Fired stops are forgotten, so the next keystroke starts typing
+        assert!(!timers.cancel("c").await);
+        assert!(!timers.restart("c", count(&stops)).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_late_stop_leaves_its_replacement_alone() {
+        let timers = StopTimers::new();
+        let stops = Arc::new(AtomicUsize::new(0));
+        timers.restart("c", count(&stops)).await;
+        // The first stop wakes up and waits for the lock, then typing
+        // starts again before it gets it, too late to abort it
+        let mut held = timers.timers.lock().await;
+        tokio::time::sleep(OUTGOING_IDLE + Duration::from_secs(1)).await;
+        let replacement = tokio::spawn(std::future::pending());
+        held.insert("c".to_owned(), (u64::MAX, replacement));
+        drop(held);
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(stops.load(Ordering::SeqCst), 0);
+        assert!(timers.cancel("c").await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stopping_cancels_the_automatic_stop() {
+        let timers = StopTimers::new();
+        let stops = Arc::new(AtomicUsize::new(0));
+        timers.restart("a", count(&stops)).await;
+        timers.restart("b", count(&stops)).await;
+        assert!(timers.cancel("a").await);
+        assert!(!timers.cancel("a").await);
+
+        tokio::time::sleep(OUTGOING_IDLE * 2).await;
+        // Only the other conversation stopped by itself
+        assert_eq!(stops.load(Ordering::SeqCst), 1);
+        assert!(!timers.cancel("b").await);
+    }
+}
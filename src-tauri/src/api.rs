@@ -0,0 +1,173 @@
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
+use uuid::Uuid;
+
+use crate::state::TauriState;
+
+pub mod routes;
+pub mod websocket;
+
+/**
+ * The optional local HTTP API for bots and automations
+ *
+ * It only ever listens on a loopback address or a unix socket, and every
+ * request has to carry the token from the settings file
+ */
+#[derive(Clone)]
+pub struct ApiCtx {
+    pub tauri_state: TauriState,
+    pub token: Arc<String>,
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    InvalidAddress(std::net::AddrParseError),
+    NotLoopback(SocketAddr),
+    /// The token in the settings is empty, which would let anyone in
+    EmptyToken,
+    /// Something other than a socket is at the configured socket path,
+    /// which is not removed to make room
+    NotASocket(PathBuf),
+    IOError(std::io::Error),
+    HyperError(hyper::Error),
+}
+
+impl From<std::io::Error> for ApiError {
+    fn from(error: std::io::Error) -> Self {
+        ApiError::IOError(error)
+    }
+}
+
+impl From<hyper::Error> for ApiError {
+    fn from(error: hyper::Error) -> Self {
+        ApiError::HyperError(error)
+    }
+}
+
+/**
+ * Listen on a unix socket that only we can connect to, replacing one left
+ * by an earlier run
+ *
+ * It is bound in a directory only we can enter and then moved into place,
+ * so other users cannot connect in the moment before it is made private.
+ */
+#[cfg(unix)]
+fn bind_socket(path: &std::path::Path) -> Result<tokio::net::UnixListener, ApiError> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => return Err(ApiError::NotASocket(path.to_owned())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => std::path::Path::new("."),
+    };
+    // Kept short, as socket paths are limited to about 100 bytes
+    let private_dir = parent.join(format!(
+        ".api-{}",
+        &Uuid::new_v4().simple().to_string()[..8]
+    ));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)?;
+    let bound = private_dir.join("s");
+    let listener = tokio::net::UnixListener::bind(&bound).and_then(|listener| {
+        std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    // Also takes the socket with it if it could not be moved
+    let _ = std::fs::remove_dir_all(&private_dir);
+    Ok(listener?)
+}
+
+/**
+ * Start the API server if it is enabled in the settings
+ *
+ * This runs until the server fails, so it should be spawned
+ */
+pub async fn serve(tauri_state: TauriState) -> Result<(), ApiError> {
+    let settings = tauri_state.0.lock().await.settings.clone();
+    let api_settings = {
+        let mut settings = settings.lock().await;
+        if !settings.api.enabled {
+            return Ok(());
+        }
+        match &settings.api.token {
+            Some(token) if token.trim().is_empty() => return Err(ApiError::EmptyToken),
+            Some(_) => {}
+            None => {
+                settings.api.token = Some(Uuid::new_v4().simple().to_string());
+                settings.save()?;
+            }
+        }
+        settings.api.clone()
+    };
+
+    let ctx = ApiCtx {
+        tauri_state,
+        token: Arc::new(api_settings.token.unwrap_or_default()),
+    };
+    let app = routes::router(ctx);
+
+    #[cfg(unix)]
+    if let Some(path) = api_settings.unix_socket {
+        use hyper::server::accept;
+        use tokio_stream::wrappers::UnixListenerStream;
+
+        let listener = bind_socket(&path)?;
+        log::info!("API listening on {:?}", path);
+        axum::Server::builder(accept::from_stream(UnixListenerStream::new(listener)))
+            .serve(app.into_make_service())
+            .await?;
+        return Ok(());
+    }
+
+    let address: SocketAddr = api_settings
+        .address
+        .parse()
+        .map_err(ApiError::InvalidAddress)?;
+    if !address.ip().is_loopback() {
+        return Err(ApiError::NotLoopback(address));
+    }
+    log::info!("API listening on {}", address);
+    axum::Server::try_bind(&address)?
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    use super::*;
+    use crate::state::test_dir;
+
+    #[tokio::test]
+    async fn the_socket_is_private_and_replaces_only_a_socket() {
+        let dir = test_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("api.sock");
+
+        let listener = bind_socket(&path).unwrap();
+        let metadata = std::fs::symlink_metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+        drop(listener);
+
+        // Left by the last run
+        bind_socket(&path).unwrap();
+        // Only the socket is left in the directory
+        assert_eq!(std::fs::read_dir(&*dir).unwrap().count(), 1);
+
+        let file = dir.join("notes.txt");
+        std::fs::write(&file, "keep").unwrap();
+        assert!(matches!(bind_socket(&file), Err(ApiError::NotASocket(_))));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep");
+    }
+}
@@ -0,0 +1,216 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    actions::{
+        conversations::{list_conversations, stored_conversation_ids},
+        outbox::{do_queue_message, OutgoingMessage},
+        send::resolve_handle,
+    },
+    api::websocket::events,
+    api::ApiCtx,
+    imessage::messenger::new_conversation,
+};
+
+const OPENAPI: &str = include_str!("openapi.json");
+
+pub fn router(ctx: ApiCtx) -> Router {
+    Router::new()
+        .route("/v1/account", get(account))
+        .route("/v1/messages", post(send_message))
+        .route("/v1/conversations", get(conversations))
+        .route("/v1/conversations/:id/messages", get(conversation_messages))
+        .route("/v1/events", get(events))
+        .route_layer(middleware::from_fn_with_state(ctx.clone(), authenticate))
+        .route("/v1/openapi.json", get(openapi))
+        .with_state(ctx)
+}
+
+pub fn error_response(status: StatusCode, error: &str, message: String) -> Response {
+    (status, Json(json!({ "error": error, "message": message }))).into_response()
+}
+
+/**
+ * Compare tokens in constant time, so one cannot be guessed a byte at a
+ * time from how long rejections take
+ *
+ * An empty token, e.g. one that was never generated, matches nothing
+ */
+pub fn token_matches(given: &str, token: &str) -> bool {
+    // memcmp::eq panics on different lengths, and the length is no secret
+    !token.is_empty()
+        && given.len() == token.len()
+        && openssl::memcmp::eq(given.as_bytes(), token.as_bytes())
+}
+
+/**
+ * The token a request carries as a bearer token or, only for WebSocket
+ * upgrades since browsers cannot set headers on those, as a `token` query
+ * parameter. Anywhere else it would end up in logs and proxy histories.
+ */
+fn request_token<B>(request: &Request<B>) -> Option<&str> {
+    let header_token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if header_token.is_some() {
+        return header_token;
+    }
+    let upgrade = request
+        .headers()
+        .get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+    if !upgrade {
+        return None;
+    }
+    request.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+    })
+}
+
+async fn authenticate<B>(
+    State(ctx): State<ApiCtx>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    match request_token(&request) {
+        Some(token) if token_matches(token, &ctx.token) => next.run(request).await,
+        _ => error_response(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "Missing or invalid token".to_owned(),
+        ),
+    }
+}
+
+async fn openapi() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "application/json")], OPENAPI)
+}
+
+async fn account(State(ctx): State<ApiCtx>) -> Response {
+    let rust_push = ctx.tauri_state.0.lock().await.rust_push.clone();
+    let status = rust_push.lock().await.account_status().await;
+    Json(status).into_response()
+}
+
+#[derive(Deserialize)]
+struct SendRequest {
+    to: String,
+    text: String,
+}
+
+async fn send_message(State(ctx): State<ApiCtx>, Json(request): Json<SendRequest>) -> Response {
+    let (rust_push, messages, attachments, outbox, settings, events) = {
+        let state = ctx.tauri_state.0.lock().await;
+        (
+            state.rust_push.clone(),
+            state.messages.clone(),
+            state.attachments.clone(),
+            state.outbox.clone(),
+            state.settings.clone(),
+            state.events.clone(),
+        )
+    };
+    let to = match resolve_handle(&settings, &request.to).await {
+        Ok(to) => to,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, "invalidHandle", e.to_string()),
+    };
+    let message = OutgoingMessage::text(new_conversation(vec![to]), request.text);
+    match do_queue_message(rust_push, messages, attachments, outbox, events, message).await {
+        // Sending happens in the background, follow it with outboxChanged events
+        Ok(id) => (StatusCode::ACCEPTED, Json(json!({ "id": id }))).into_response(),
+        Err(e) => error_response(StatusCode::BAD_GATEWAY, "sendFailed", e.0.to_string()),
+    }
+}
+
+async fn conversations(State(ctx): State<ApiCtx>) -> Response {
+    let conversations = list_conversations(&ctx.tauri_state).await;
+    Json(conversations).into_response()
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    before: Option<u64>,
+    limit: Option<usize>,
+}
+
+async fn conversation_messages(
+    State(ctx): State<ApiCtx>,
+    Path(id): Path<String>,
+    Query(query): Query<PageQuery>,
+) -> Response {
+    let ids = stored_conversation_ids(&ctx.tauri_state, &id).await;
+    let messages = ctx.tauri_state.0.lock().await.messages.clone();
+    let page = messages
+        .lock()
+        .await
+        .page(&ids, query.before, query.limit.unwrap_or(50).min(500));
+    Json(page).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_match_only_exactly() {
+        assert!(token_matches("secret-token", "secret-token"));
+        assert!(!token_matches("secret-tokem", "secret-token"));
+        assert!(!token_matches("secret", "secret-token"));
+        assert!(!token_matches("", "secret-token"));
+        assert!(!token_matches("", ""));
+    }
+
+    fn request(uri: &str, headers: &[(header::HeaderName, &str)]) -> Request<()> {
+        let mut builder = Request::builder().uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn the_token_is_only_taken_from_the_query_for_websockets() {
+        let bearer = (header::AUTHORIZATION, "Bearer secret");
+        let upgrade = (header::UPGRADE, "WebSocket");
+        assert_eq!(
+            request_token(&request("/v1/conversations", std::slice::from_ref(&bearer))),
+            Some("secret")
+        );
+        assert_eq!(
+            request_token(&request("/v1/conversations?token=secret", &[])),
+            None
+        );
+        assert_eq!(
+            request_token(&request(
+                "/v1/events?a=1&token=secret",
+                std::slice::from_ref(&upgrade)
+            )),
+            Some("secret")
+        );
+        // The header wins over the query
+        assert_eq!(
+            request_token(&request("/v1/events?token=other", &[upgrade, bearer])),
+            Some("secret")
+        );
+        assert_eq!(
+            request_token(&request(
+                "/v1/conversations",
+                &[(header::AUTHORIZATION, "Basic secret")]
+            )),
+            None
+        );
+    }
+}
@@ -0,0 +1,47 @@
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        State, WebSocketUpgrade,
+    },
+    response::Response,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{api::ApiCtx, events::AppEvent};
+
+/**
+ * Stream every app event to the client as a JSON text frame
+ */
+pub async fn events(State(ctx): State<ApiCtx>, ws: WebSocketUpgrade) -> Response {
+    let receiver = ctx.tauri_state.0.lock().await.events.subscribe();
+    ws.on_upgrade(move |socket| stream_events(socket, receiver))
+}
+
+async fn stream_events(mut socket: WebSocket, mut receiver: broadcast::Receiver<AppEvent>) {
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(event) => {
+                    let payload = match serde_json::to_string(&event) {
+                        Ok(payload) => payload,
+                        Err(e) => {
+                            log::error!("Error serializing event: {:?}", e);
+                            continue;
+                        }
+                    };
+                    if socket.send(Message::Text(payload)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("WebSocket client missed {} events", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                _ => {}
+            },
+        }
+    }
+}
@@ -0,0 +1,1165 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    io::Write,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use serde_json::{json, Value};
+use tokio::sync::{broadcast::error::RecvError, Mutex};
+use uuid::Uuid;
+
+use crate::{
+    actions::{
+        attachments::{do_fetch_attachment, do_import_attachment, rename_for_mime},
+        edit::{do_edit_message, do_unsend_message},
+        outbox::{do_queue_message, OutgoingMessage},
+        receipts::do_mark_conversation_read,
+        send::do_send_tapback,
+        typing::INCOMING_TIMEOUT,
+    },
+    events::AppEvent,
+    imessage::messenger::conversation_data,
+    state::{
+        data_dir,
+        messagestore::{StoredMessage, StoredReaction, Tapback},
+        settings::MatrixSettings,
+        write_atomic, TauriState,
+    },
+};
+
+use self::{
+    client::{MatrixClient, MatrixError},
+    portals::{BridgedMessage, BridgedReaction, Portal, PortalStore},
+};
+
+pub mod appservice;
+pub mod client;
+pub mod portals;
+
+/// How many transaction IDs to remember so retried transactions are ignored
+const SEEN_TRANSACTIONS: usize = 1000;
+
+#[derive(Debug)]
+pub enum BridgeError {
+    InvalidAddress(std::net::AddrParseError),
+    IOError(std::io::Error),
+    MatrixError(MatrixError),
+    AttachmentError(String),
+}
+
+impl From<std::io::Error> for BridgeError {
+    fn from(error: std::io::Error) -> Self {
+        BridgeError::IOError(error)
+    }
+}
+
+impl From<MatrixError> for BridgeError {
+    fn from(error: MatrixError) -> Self {
+        BridgeError::MatrixError(error)
+    }
+}
+
+/**
+ * Escape a handle into something usable as a Matrix localpart
+ *
+ * Anything outside of the allowed characters becomes `=xx`, as in the
+ * spec's suggested mapping from other protocols
+ */
+pub fn escape_localpart(handle: &str) -> String {
+    handle
+        .chars()
+        .flat_map(|c| match c {
+            'a'..='z' | '0'..='9' | '.' | '_' | '-' | '/' => vec![c],
+            'A'..='Z' => vec![c.to_ascii_lowercase()],
+            _ => {
+                let mut buf = [0u8; 4];
+                c.encode_utf8(&mut buf)
+                    .bytes()
+                    .flat_map(|byte| format!("={:02x}", byte).chars().collect::<Vec<_>>())
+                    .collect()
+            }
+        })
+        .collect()
+}
+
+/**
+ * The Matrix message type for an attachment
+ */
+fn msgtype_for_mime(mime: &str) -> &'static str {
+    match mime.split('/').next() {
+        Some("image") => "m.image",
+        Some("video") => "m.video",
+        Some("audio") => "m.audio",
+        _ => "m.file",
+    }
+}
+
+/**
+ * Drop the quote of the replied to message that Matrix clients put at the
+ * start of a reply's body
+ */
+fn strip_reply_fallback(body: &str) -> &str {
+    if !body.starts_with("> ") {
+        return body;
+    }
+    match body.find("\n\n") {
+        Some(end) => &body[end + 2..],
+        None => body,
+    }
+}
+
+/**
+ * The application service registration the homeserver has to be configured with
+ */
+pub fn registration(settings: &MatrixSettings) -> String {
+    format!(
+        "id: cross-messenger
+url: http://{listen_address}
+as_token: {as_token}
+hs_token: {hs_token}
+sender_localpart: {bot_localpart}
+rate_limited: false
+namespaces:
+  users:
+    - exclusive: true
+      regex: '@{user_prefix}.*:{server_name}'
+  aliases: []
+  rooms: []
+receive_ephemeral: true
+de.sorunome.msc2409.push_ephemeral: true
+",
+        listen_address = settings.listen_address,
+        as_token = settings.as_token.as_deref().unwrap_or_default(),
+        hs_token = settings.hs_token.as_deref().unwrap_or_default(),
+        bot_localpart = settings.bot_localpart,
+        // The regex is in single quotes, where a quote is written twice
+        user_prefix = regex::escape(&settings.user_prefix).replace('\'', "''"),
+        server_name = regex::escape(&settings.server_name).replace('\'', "''"),
+    )
+}
+
+pub fn registration_path() -> PathBuf {
+    data_dir().join("matrix-registration.yaml")
+}
+
+/**
+ * A Matrix application service that mirrors every iMessage conversation into
+ * a room, with the other participants puppeted as ghost users
+ */
+pub struct MatrixBridge {
+    settings: MatrixSettings,
+    client: MatrixClient,
+    portals: Mutex<PortalStore>,
+    tauri_state: TauriState,
+    seen_transactions: Mutex<VecDeque<String>>,
+    /// Ghost users already registered since startup
+    ghosts: Mutex<HashSet<String>>,
+}
+
+impl MatrixBridge {
+    pub fn hs_token(&self) -> &str {
+        self.settings.hs_token.as_deref().unwrap_or_default()
+    }
+
+    pub fn bot_user_id(&self) -> String {
+        format!(
+            "@{}:{}",
+            self.settings.bot_localpart, self.settings.server_name
+        )
+    }
+
+    pub fn ghost_user_id(&self, handle: &str) -> String {
+        format!(
+            "@{}{}:{}",
+            self.settings.user_prefix,
+            escape_localpart(handle),
+            self.settings.server_name
+        )
+    }
+
+    /**
+     * Whether a user ID belongs to the users this bridge manages
+     */
+    pub fn is_bridge_user(&self, user_id: &str) -> bool {
+        user_id == self.bot_user_id()
+            || (user_id.starts_with(&format!("@{}", self.settings.user_prefix))
+                && user_id.ends_with(&format!(":{}", self.settings.server_name)))
+    }
+
+    /**
+     * Register one of our users because the homeserver asked about it
+     */
+    pub async fn provision_user(&self, user_id: &str) -> Result<(), MatrixError> {
+        let localpart = user_id
+            .trim_start_matches('@')
+            .trim_end_matches(&format!(":{}", self.settings.server_name));
+        self.client.register(localpart).await
+    }
+
+    async fn own_handles(&self) -> Vec<String> {
+        let rust_push = self.tauri_state.0.lock().await.rust_push.clone();
+        let client = rust_push.lock().await.client.clone();
+        client.get_handles()
+    }
+
+    async fn ensure_ghost(&self, handle: &str) -> Result<String, MatrixError> {
+        let user_id = self.ghost_user_id(handle);
+        if self.ghosts.lock().await.contains(&user_id) {
+            return Ok(user_id);
+        }
+        let localpart = format!("{}{}", self.settings.user_prefix, escape_localpart(handle));
+        self.client.register(&localpart).await?;
+        self.client.set_display_name(&user_id, handle).await?;
+        self.ghosts.lock().await.insert(user_id.clone());
+        Ok(user_id)
+    }
+
+    /**
+     * The room for a conversation, creating it and inviting everyone if
+     * this is the first message in it
+     */
+    async fn ensure_portal(
+        &self,
+        conversation_id: &str,
+        participants: &[String],
+    ) -> Result<String, MatrixError> {
+        if let Some(portal) = self.portals.lock().await.by_conversation(conversation_id) {
+            return Ok(portal.room_id.clone());
+        }
+
+        let own_handles = self.own_handles().await;
+        let others: Vec<&String> = participants
+            .iter()
+            .filter(|participant| !own_handles.contains(participant))
+            .collect();
+        let name = others
+            .iter()
+            .map(|participant| participant.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let room_id = self
+            .client
+            .create_room(&name, vec![self.settings.owner.clone()], others.len() == 1)
+            .await?;
+        for participant in others {
+            let ghost = self.ensure_ghost(participant).await?;
+            self.client.invite(&room_id, &ghost).await?;
+            self.client.join(&room_id, &ghost).await?;
+        }
+
+        if let Err(e) = self.portals.lock().await.add_portal(Portal {
+            conversation_id: conversation_id.to_owned(),
+            participants: participants.to_vec(),
+            room_id: room_id.clone(),
+        }) {
+            log::error!("Error saving Matrix portals: {:?}", e);
+        }
+        Ok(room_id)
+    }
+
+    async fn remember_message(&self, message_id: &str, event_id: &str, room_id: &str) {
+        if let Err(e) = self.portals.lock().await.add_message(BridgedMessage {
+            message_id: message_id.to_owned(),
+            event_id: event_id.to_owned(),
+            room_id: room_id.to_owned(),
+        }) {
+            log::error!("Error saving Matrix portals: {:?}", e);
+        }
+    }
+
+    /**
+     * Download an iMessage attachment and send it into a room as the ghost,
+     * returning the event ID
+     */
+    async fn relay_incoming_attachment(
+        &self,
+        room_id: &str,
+        ghost: &str,
+        attachment_id: &str,
+    ) -> Result<String, BridgeError> {
+        let (attachments, transport, events, settings) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.attachments.clone(),
+                state.transport.clone(),
+                state.events.clone(),
+                state.settings.clone(),
+            )
+        };
+        let media = settings.lock().await.media.clone();
+        let stored =
+            do_fetch_attachment(attachments.clone(), transport, events, media, attachment_id)
+                .await
+                .map_err(|e| BridgeError::AttachmentError(e.0.to_string()))?;
+        // Matrix clients cannot show HEIC either, so send the converted copy
+        let (path, mime, name) = {
+            let store = attachments.lock().await;
+            match &stored.display {
+                Some(display) => (
+                    store.derived_path(display),
+                    display.mime.clone(),
+                    rename_for_mime(&stored.name, &display.mime),
+                ),
+                None => (
+                    store.path(&stored),
+                    stored.mime.clone(),
+                    stored.name.clone(),
+                ),
+            }
+        };
+        let path = path
+            .ok_or_else(|| BridgeError::AttachmentError("Attachment is not on disk".to_owned()))?;
+        let data = std::fs::read(path)?;
+        let size = data.len();
+        let mxc = self.client.upload_media(ghost, data, &mime, &name).await?;
+        let mut info = json!({ "mimetype": mime, "size": size });
+        if let Some(video) = &stored.video {
+            info["w"] = json!(video.width);
+            info["h"] = json!(video.height);
+            if let Some(duration) = video.duration {
+                info["duration"] = json!(duration);
+            }
+        }
+        if let Some(thumbnail) = &stored.thumbnail {
+            let thumbnail_path = attachments.lock().await.derived_path(thumbnail);
+            if let Some(thumbnail_path) = thumbnail_path {
+                let thumbnail_data = std::fs::read(thumbnail_path)?;
+                let thumbnail_size = thumbnail_data.len();
+                let thumbnail_url = self
+                    .client
+                    .upload_media(ghost, thumbnail_data, &thumbnail.mime, "thumbnail")
+                    .await?;
+                info["thumbnail_url"] = json!(thumbnail_url);
+                info["thumbnail_info"] =
+                    json!({ "mimetype": thumbnail.mime, "size": thumbnail_size });
+            }
+        }
+        Ok(self
+            .client
+            .send_message(
+                room_id,
+                ghost,
+                json!({
+                    "msgtype": msgtype_for_mime(&mime),
+                    "body": name,
+                    "url": mxc,
+                    "info": info,
+                }),
+            )
+            .await?)
+    }
+
+    async fn relay_incoming_message(&self, message: &StoredMessage) -> Result<(), BridgeError> {
+        let Some(sender) = &message.sender else {
+            return Ok(());
+        };
+        let room_id = self
+            .ensure_portal(&message.conversation_id, &message.participants)
+            .await?;
+        let ghost = self.ensure_ghost(sender).await?;
+        for attachment_id in &message.attachments {
+            match self
+                .relay_incoming_attachment(&room_id, &ghost, attachment_id)
+                .await
+            {
+                Ok(event_id) => self.remember_message(&message.id, &event_id, &room_id).await,
+                Err(e) => log::error!("Error bridging attachment {}: {:?}", attachment_id, e),
+            }
+        }
+        if message.text.is_empty() {
+            return Ok(());
+        }
+        let mut content = json!({ "msgtype": "m.text", "body": message.text });
+        if let Some(reply_to) = &message.reply_to {
+            let replied = self
+                .portals
+                .lock()
+                .await
+                .by_message_id(&reply_to.message_id)
+                .cloned();
+            if let Some(replied) = replied {
+                content["m.relates_to"] = json!({
+                    "m.in_reply_to": { "event_id": replied.event_id }
+                });
+            }
+        }
+        let event_id = self
+            .client
+            .send_message(&room_id, &ghost, content)
+            .await?;
+        self.remember_message(&message.id, &event_id, &room_id).await;
+        Ok(())
+    }
+
+    async fn relay_incoming_read(
+        &self,
+        message_id: &str,
+        sender: Option<&str>,
+    ) -> Result<(), MatrixError> {
+        let Some(sender) = sender else {
+            return Ok(());
+        };
+        let Some(bridged) = self.portals.lock().await.by_message_id(message_id).cloned() else {
+            return Ok(());
+        };
+        let ghost = self.ghost_user_id(sender);
+        self.client
+            .send_read_receipt(&bridged.room_id, &ghost, &bridged.event_id)
+            .await
+    }
+
+    /**
+     * Mirror a tapback as an annotation from the ghost, replacing or
+     * redacting its earlier one
+     */
+    async fn relay_incoming_reaction(
+        &self,
+        message_id: &str,
+        reaction: &StoredReaction,
+        enabled: bool,
+    ) -> Result<(), BridgeError> {
+        let Some(sender) = &reaction.sender else {
+            return Ok(());
+        };
+        let Some(bridged) = self.portals.lock().await.by_message_id(message_id).cloned() else {
+            return Ok(());
+        };
+        let ghost = self.ensure_ghost(sender).await?;
+        let key = reaction.tapback.emoji().to_owned();
+        let stale = if enabled {
+            let event_id = self
+                .client
+                .send_event(
+                    &bridged.room_id,
+                    &ghost,
+                    "m.reaction",
+                    json!({
+                        "m.relates_to": {
+                            "rel_type": "m.annotation",
+                            "event_id": bridged.event_id,
+                            "key": key,
+                        }
+                    }),
+                )
+                .await?;
+            self.portals.lock().await.add_reaction(BridgedReaction {
+                message_id: message_id.to_owned(),
+                sender: sender.clone(),
+                key,
+                event_id,
+                room_id: bridged.room_id.clone(),
+            })?
+        } else {
+            self.portals.lock().await.remove_reaction(|existing| {
+                existing.message_id == message_id
+                    && &existing.sender == sender
+                    && existing.key == key
+            })?
+        };
+        if let Some(stale) = stale {
+            self.client
+                .redact(&stale.room_id, &ghost, &stale.event_id)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Replace the bridged event of an edited message with its new text
+     */
+    async fn relay_incoming_edit(&self, message: &StoredMessage) -> Result<(), BridgeError> {
+        let Some(sender) = &message.sender else {
+            return Ok(());
+        };
+        let Some(bridged) = self.portals.lock().await.by_message_id(&message.id).cloned() else {
+            return Ok(());
+        };
+        let ghost = self.ensure_ghost(sender).await?;
+        self.client
+            .send_message(
+                &bridged.room_id,
+                &ghost,
+                json!({
+                    "msgtype": "m.text",
+                    "body": format!("* {}", message.text),
+                    "m.new_content": {
+                        "msgtype": "m.text",
+                        "body": message.text,
+                    },
+                    "m.relates_to": {
+                        "rel_type": "m.replace",
+                        "event_id": bridged.event_id,
+                    },
+                }),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn relay_incoming_unsend(&self, message: &StoredMessage) -> Result<(), BridgeError> {
+        let Some(sender) = &message.sender else {
+            return Ok(());
+        };
+        let Some(bridged) = self.portals.lock().await.by_message_id(&message.id).cloned() else {
+            return Ok(());
+        };
+        let ghost = self.ensure_ghost(sender).await?;
+        self.client
+            .redact(&bridged.room_id, &ghost, &bridged.event_id)
+            .await?;
+        Ok(())
+    }
+
+    async fn relay_incoming_typing(
+        &self,
+        conversation_id: &str,
+        sender: Option<&str>,
+        typing: bool,
+    ) -> Result<(), BridgeError> {
+        let Some(sender) = sender else {
+            return Ok(());
+        };
+        let room_id = self
+            .portals
+            .lock()
+            .await
+            .by_conversation(conversation_id)
+            .map(|portal| portal.room_id.clone());
+        let Some(room_id) = room_id else {
+            return Ok(());
+        };
+        let ghost = self.ensure_ghost(sender).await?;
+        self.client
+            .set_typing(
+                &room_id,
+                &ghost,
+                typing,
+                INCOMING_TIMEOUT.as_millis() as u64,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /**
+     * Mirror an event from the iMessage side into Matrix
+     */
+    pub async fn handle_imessage_event(&self, event: &AppEvent) -> Result<(), BridgeError> {
+        match event {
+            AppEvent::MessageReceived { message, .. } if !message.from_me => {
+                self.relay_incoming_message(message).await
+            }
+            AppEvent::MessageEdited { message } if !message.from_me => {
+                self.relay_incoming_edit(message).await
+            }
+            AppEvent::MessageUnsent { message } if !message.from_me => {
+                self.relay_incoming_unsend(message).await
+            }
+            AppEvent::MessageRead { id, sender, .. } => {
+                Ok(self.relay_incoming_read(id, sender.as_deref()).await?)
+            }
+            AppEvent::ReactionChanged {
+                message_id,
+                reaction,
+                enabled,
+                ..
+            } if !reaction.from_me => {
+                self.relay_incoming_reaction(message_id, reaction, *enabled)
+                    .await
+            }
+            AppEvent::TypingChanged {
+                conversation_id,
+                sender,
+                typing,
+                ..
+            } => {
+                self.relay_incoming_typing(conversation_id, sender.as_deref(), *typing)
+                    .await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /**
+     * Handle a transaction pushed by the homeserver
+     *
+     * Transactions are retried until acknowledged, so ones we have already
+     * handled are skipped
+     */
+    pub async fn handle_transaction(&self, txn_id: &str, body: &Value) {
+        {
+            let mut seen = self.seen_transactions.lock().await;
+            if seen.iter().any(|seen| seen == txn_id) {
+                return;
+            }
+            seen.push_back(txn_id.to_owned());
+            if seen.len() > SEEN_TRANSACTIONS {
+                seen.pop_front();
+            }
+        }
+
+        let events = body.get("events").and_then(Value::as_array);
+        for event in events.into_iter().flatten() {
+            if let Err(e) = self.handle_matrix_event(event).await {
+                log::error!("Error bridging Matrix event: {:?}", e);
+            }
+        }
+
+        let ephemeral = body
+            .get("ephemeral")
+            .or_else(|| body.get("de.sorunome.msc2409.ephemeral"))
+            .and_then(Value::as_array);
+        for event in ephemeral.into_iter().flatten() {
+            if let Err(e) = self.handle_matrix_ephemeral(event).await {
+                log::error!("Error bridging Matrix ephemeral event: {:?}", e);
+            }
+        }
+    }
+
+    async fn handle_matrix_event(&self, event: &Value) -> Result<(), BridgeError> {
+        let sender = event.get("sender").and_then(Value::as_str).unwrap_or("");
+        if sender != self.settings.owner {
+            return Ok(());
+        }
+        match event.get("type").and_then(Value::as_str) {
+            Some("m.room.message") => self.handle_owner_message(event).await,
+            Some("m.reaction") => self.handle_owner_reaction(event).await,
+            Some("m.room.redaction") => self.handle_owner_redaction(event).await,
+            _ => Ok(()),
+        }
+    }
+
+    /**
+     * Send the owner's tapback on a bridged message to iMessage
+     */
+    async fn handle_owner_reaction(&self, event: &Value) -> Result<(), BridgeError> {
+        let relation = event
+            .get("content")
+            .and_then(|content| content.get("m.relates_to"));
+        let Some(relation) = relation else {
+            return Ok(());
+        };
+        if relation.get("rel_type") != Some(&json!("m.annotation")) {
+            return Ok(());
+        }
+        let target = relation.get("event_id").and_then(Value::as_str).unwrap_or("");
+        let key = relation.get("key").and_then(Value::as_str).unwrap_or("");
+        let Some(bridged) = self.portals.lock().await.by_event_id(target).cloned() else {
+            return Ok(());
+        };
+        let (rust_push, keys, messages, events) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.rust_push.clone(),
+                state.keys.clone(),
+                state.messages.clone(),
+                state.events.clone(),
+            )
+        };
+        if let Err(e) = do_send_tapback(
+            rust_push,
+            keys,
+            messages,
+            events,
+            &bridged.message_id,
+            0,
+            Tapback::from_emoji(key),
+            true,
+        )
+        .await
+        {
+            log::error!("Error sending bridged tapback: {:?}", e.0);
+            return Ok(());
+        }
+        let event_id = event.get("event_id").and_then(Value::as_str).unwrap_or("");
+        self.portals.lock().await.add_reaction(BridgedReaction {
+            message_id: bridged.message_id,
+            sender: self.settings.owner.clone(),
+            key: key.to_owned(),
+            event_id: event_id.to_owned(),
+            room_id: bridged.room_id,
+        })?;
+        Ok(())
+    }
+
+    /**
+     * Redacting one of the owner's reactions removes the tapback, and
+     * redacting one of their messages unsends it
+     */
+    async fn handle_owner_redaction(&self, event: &Value) -> Result<(), BridgeError> {
+        // Room version 11 moved `redacts` into the content
+        let redacts = event
+            .get("redacts")
+            .or_else(|| event.get("content").and_then(|content| content.get("redacts")))
+            .and_then(Value::as_str)
+            .unwrap_or("");
+        let owner = &self.settings.owner;
+        let removed = self
+            .portals
+            .lock()
+            .await
+            .remove_reaction(|reaction| reaction.event_id == redacts && &reaction.sender == owner)?;
+        let Some(removed) = removed else {
+            return self.handle_owner_unsend(event, redacts).await;
+        };
+        let (rust_push, keys, messages, events) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.rust_push.clone(),
+                state.keys.clone(),
+                state.messages.clone(),
+                state.events.clone(),
+            )
+        };
+        if let Err(e) = do_send_tapback(
+            rust_push,
+            keys,
+            messages,
+            events,
+            &removed.message_id,
+            0,
+            Tapback::from_emoji(&removed.key),
+            false,
+        )
+        .await
+        {
+            log::error!("Error removing bridged tapback: {:?}", e.0);
+        }
+        Ok(())
+    }
+
+    async fn handle_owner_unsend(&self, event: &Value, redacts: &str) -> Result<(), BridgeError> {
+        let Some(bridged) = self.portals.lock().await.by_event_id(redacts).cloned() else {
+            return Ok(());
+        };
+        let (rust_push, keys, messages, attachments, events) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.rust_push.clone(),
+                state.keys.clone(),
+                state.messages.clone(),
+                state.attachments.clone(),
+                state.events.clone(),
+            )
+        };
+        let from_me = messages
+            .lock()
+            .await
+            .get(&bridged.message_id)
+            .is_some_and(|message| message.from_me);
+        if !from_me {
+            return Ok(());
+        }
+        if let Err(e) = do_unsend_message(
+            rust_push,
+            keys,
+            messages,
+            attachments,
+            events,
+            &bridged.message_id,
+        )
+        .await
+        {
+            self.report_failure(event, "unsend", &e.0.to_string()).await?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Send an edit of one of the owner's bridged messages to iMessage
+     */
+    async fn handle_owner_edit(&self, event: &Value, content: &Value) -> Result<(), BridgeError> {
+        let target = content
+            .pointer("/m.relates_to/event_id")
+            .and_then(Value::as_str)
+            .unwrap_or("");
+        let Some(bridged) = self.portals.lock().await.by_event_id(target).cloned() else {
+            return Ok(());
+        };
+        let Some(text) = content
+            .pointer("/m.new_content/body")
+            .and_then(Value::as_str)
+        else {
+            return Ok(());
+        };
+        let (rust_push, keys, messages, events) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.rust_push.clone(),
+                state.keys.clone(),
+                state.messages.clone(),
+                state.events.clone(),
+            )
+        };
+        if let Err(e) = do_edit_message(
+            rust_push,
+            keys,
+            messages,
+            events,
+            &bridged.message_id,
+            text.to_owned(),
+        )
+        .await
+        {
+            self.report_failure(event, "edit", &e.0.to_string()).await?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Tell the owner in the room that something they did could not be sent
+     * to iMessage
+     */
+    async fn report_failure(
+        &self,
+        event: &Value,
+        what: &str,
+        error: &str,
+    ) -> Result<(), BridgeError> {
+        log::error!("Error bridging {}: {}", what, error);
+        let room_id = event.get("room_id").and_then(Value::as_str).unwrap_or("");
+        self.client
+            .send_message(
+                room_id,
+                &self.bot_user_id(),
+                json!({
+                    "msgtype": "m.notice",
+                    "body": format!("Failed to {} on iMessage: {}", what, error),
+                }),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_owner_message(&self, event: &Value) -> Result<(), BridgeError> {
+        let room_id = event.get("room_id").and_then(Value::as_str).unwrap_or("");
+        let Some(portal) = self.portals.lock().await.by_room(room_id).cloned() else {
+            return Ok(());
+        };
+        let content = event.get("content").cloned().unwrap_or(Value::Null);
+        if content.pointer("/m.relates_to/rel_type") == Some(&json!("m.replace")) {
+            return self.handle_owner_edit(event, &content).await;
+        }
+        let body = content.get("body").and_then(Value::as_str).unwrap_or("");
+        let (rust_push, messages, attachments, outbox, events) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.rust_push.clone(),
+                state.messages.clone(),
+                state.attachments.clone(),
+                state.outbox.clone(),
+                state.events.clone(),
+            )
+        };
+        let conversation = conversation_data(&portal.conversation_id, portal.participants.clone());
+        let message = match content.get("msgtype").and_then(Value::as_str) {
+            Some("m.text") | Some("m.notice") => {
+                let replied = match content
+                    .pointer("/m.relates_to/m.in_reply_to/event_id")
+                    .and_then(Value::as_str)
+                {
+                    Some(event_id) => self.portals.lock().await.by_event_id(event_id).cloned(),
+                    None => None,
+                };
+                match replied {
+                    Some(replied) => {
+                        OutgoingMessage::reply(
+                            &messages,
+                            &replied.message_id,
+                            0,
+                            strip_reply_fallback(body).to_owned(),
+                        )
+                        .await
+                    }
+                    None => Ok(OutgoingMessage::text(conversation, body.to_owned())),
+                }
+            }
+            Some("m.image") | Some("m.file") | Some("m.video") | Some("m.audio") => {
+                let Some(mxc) = content.get("url").and_then(Value::as_str) else {
+                    return Ok(());
+                };
+                let mime = content
+                    .get("info")
+                    .and_then(|info| info.get("mimetype"))
+                    .and_then(Value::as_str)
+                    .unwrap_or("application/octet-stream");
+                let data = self.client.download_media(mxc).await?;
+                let media = self.tauri_state.0.lock().await.settings.clone();
+                let media = media.lock().await.media.clone();
+                let stored = match do_import_attachment(
+                    attachments.clone(),
+                    media,
+                    data,
+                    body.to_owned(),
+                    mime.to_owned(),
+                )
+                .await
+                {
+                    Ok(stored) => stored,
+                    Err(e) => return Err(BridgeError::AttachmentError(e.0.to_string())),
+                };
+                Ok(OutgoingMessage {
+                    conversation,
+                    text: String::new(),
+                    attachments: vec![stored.id],
+                    reply_to: None,
+                })
+            }
+            _ => return Ok(()),
+        };
+        let result = match message {
+            Ok(message) => {
+                do_queue_message(rust_push, messages, attachments, outbox, events, message).await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(message_id) => {
+                let event_id = event.get("event_id").and_then(Value::as_str).unwrap_or("");
+                self.remember_message(&message_id, event_id, room_id).await;
+            }
+            Err(e) => {
+                log::error!("Error sending bridged message: {:?}", e.0);
+                self.client
+                    .send_message(
+                        room_id,
+                        &self.bot_user_id(),
+                        json!({
+                            "msgtype": "m.notice",
+                            "body": format!("Failed to send to iMessage: {}", e.0),
+                        }),
+                    )
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Pass on whether the owner is typing in a portal, the homeserver sends
+     * the full list of who is typing whenever it changes
+     */
+    async fn handle_owner_typing(&self, event: &Value) -> Result<(), BridgeError> {
+        let Some(room_id) = event.get("room_id").and_then(Value::as_str) else {
+            return Ok(());
+        };
+        let conversation_id = self
+            .portals
+            .lock()
+            .await
+            .by_room(room_id)
+            .map(|portal| portal.conversation_id.clone());
+        let Some(conversation_id) = conversation_id else {
+            return Ok(());
+        };
+        let typing = event
+            .pointer("/content/user_ids")
+            .and_then(Value::as_array)
+            .map(|user_ids| user_ids.contains(&json!(self.settings.owner)))
+            .unwrap_or(false);
+        let tracker = self.tauri_state.0.lock().await.typing.clone();
+        if let Err(e) = tracker.set(&conversation_id, typing).await {
+            log::error!("Error setting typing: {:?}", e.0);
+        }
+        Ok(())
+    }
+
+    /**
+     * Mark what the owner has read as read, which sends read receipts to
+     * iMessage if they are enabled for the conversation
+     */
+    async fn handle_matrix_ephemeral(&self, event: &Value) -> Result<(), BridgeError> {
+        match event.get("type").and_then(Value::as_str) {
+            Some("m.receipt") => {}
+            Some("m.typing") => return self.handle_owner_typing(event).await,
+            _ => return Ok(()),
+        }
+        let Some(content) = event.get("content").and_then(Value::as_object) else {
+            return Ok(());
+        };
+        for (event_id, receipts) in content {
+            let read_by_owner = receipts
+                .get("m.read")
+                .and_then(|read| read.get(&self.settings.owner))
+                .is_some();
+            if !read_by_owner {
+                continue;
+            }
+            let bridged = self.portals.lock().await.by_event_id(event_id).cloned();
+            let Some(bridged) = bridged else {
+                continue;
+            };
+            let Some(conversation_id) = self
+                .portals
+                .lock()
+                .await
+                .by_room(&bridged.room_id)
+                .map(|portal| portal.conversation_id.clone())
+            else {
+                continue;
+            };
+            let (rust_push, keys, messages, settings, events) = {
+                let state = self.tauri_state.0.lock().await;
+                (
+                    state.rust_push.clone(),
+                    state.keys.clone(),
+                    state.messages.clone(),
+                    state.settings.clone(),
+                    state.events.clone(),
+                )
+            };
+            if let Err(e) = do_mark_conversation_read(
+                rust_push,
+                keys,
+                messages,
+                settings,
+                events,
+                &conversation_id,
+                Some(&bridged.message_id),
+            )
+            .await
+            {
+                log::error!("Error marking conversation read: {:?}", e.0);
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Start the bridge if it is enabled in the settings
+ *
+ * Tokens are generated on first start and written into
+ * `matrix-registration.yaml`, which has to be added to the homeserver's
+ * application service config
+ */
+pub async fn spawn_matrix_bridge(tauri_state: TauriState) -> Result<(), BridgeError> {
+    let settings = tauri_state.0.lock().await.settings.clone();
+    let matrix_settings = {
+        let mut settings = settings.lock().await;
+        if !settings.matrix.enabled {
+            return Ok(());
+        }
+        // Only fill in what is missing, since the homeserver already has
+        // whichever token is set
+        if settings.matrix.as_token.is_none() || settings.matrix.hs_token.is_none() {
+            let matrix = &mut settings.matrix;
+            matrix
+                .as_token
+                .get_or_insert_with(|| Uuid::new_v4().simple().to_string());
+            matrix
+                .hs_token
+                .get_or_insert_with(|| Uuid::new_v4().simple().to_string());
+            settings.save()?;
+        }
+        settings.matrix.clone()
+    };
+    // Private, since it holds both tokens
+    let registration = registration(&matrix_settings);
+    write_atomic(&registration_path(), true, |writer| {
+        writer.write_all(registration.as_bytes())
+    })?;
+    log::info!(
+        "Matrix registration written to {:?}",
+        registration_path()
+    );
+
+    let address: SocketAddr = matrix_settings
+        .listen_address
+        .parse()
+        .map_err(BridgeError::InvalidAddress)?;
+    let events = tauri_state.0.lock().await.events.clone();
+    let bridge = Arc::new(MatrixBridge {
+        client: MatrixClient::new(
+            matrix_settings.homeserver_url.clone(),
+            matrix_settings.as_token.clone().unwrap_or_default(),
+        ),
+        settings: matrix_settings,
+        portals: Mutex::new(PortalStore::load()?),
+        tauri_state,
+        seen_transactions: Mutex::new(VecDeque::new()),
+        ghosts: Mutex::new(HashSet::new()),
+    });
+
+    let mut receiver = events.subscribe();
+    let relay_bridge = bridge.clone();
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    if let Err(e) = relay_bridge.handle_imessage_event(&event).await {
+                        log::error!("Error bridging to Matrix: {:?}", e);
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("Matrix bridge missed {} events", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let app = appservice::router(bridge);
+    tokio::spawn(async move {
+        log::info!("Matrix application service listening on {}", address);
+        let server = match axum::Server::try_bind(&address) {
+            Ok(server) => server,
+            Err(e) => {
+                log::error!("Error binding Matrix application service: {:?}", e);
+                return;
+            }
+        };
+        if let Err(e) = server.serve(app.into_make_service()).await {
+            log::error!("Error running Matrix application service: {:?}", e);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> MatrixSettings {
+        MatrixSettings {
+            server_name: "example.org".to_owned(),
+            listen_address: "127.0.0.1:29333".to_owned(),
+            as_token: Some("as-secret".to_owned()),
+            hs_token: Some("hs-secret".to_owned()),
+            bot_localpart: "crossmessenger".to_owned(),
+            user_prefix: "imessage_".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn localparts_are_lowercased_and_escaped() {
+        assert_eq!(escape_localpart("tel:+15551234567"), "tel=3a=2b15551234567");
+        assert_eq!(
+            escape_localpart("mailto:Jane.Doe@Example.com"),
+            "mailto=3ajane.doe=40example.com"
+        );
+        assert_eq!(escape_localpart("a_b-c/d"), "a_b-c/d");
+        assert_eq!(escape_localpart("é"), "=c3=a9");
+    }
+
+    #[test]
+    fn registration_has_tokens_and_namespace() {
+        let registration = registration(&settings());
+        assert!(registration.contains("url: http://127.0.0.1:29333\n"));
+        assert!(registration.contains("as_token: as-secret\n"));
+        assert!(registration.contains("hs_token: hs-secret\n"));
+        assert!(registration.contains("sender_localpart: crossmessenger\n"));
+        assert!(registration.contains("regex: '@imessage_.*:example\\.org'\n"));
+    }
+
+    #[test]
+    fn registration_escapes_user_prefix() {
+        let settings = MatrixSettings {
+            user_prefix: "im.msg+'".to_owned(),
+            ..settings()
+        };
+        assert!(registration(&settings).contains("regex: '@im\\.msg\\+''.*:example\\.org'\n"));
+    }
+}
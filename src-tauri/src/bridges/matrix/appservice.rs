@@ -0,0 +1,88 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::{header, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, put},
+    Json, Router,
+};
+use serde_json::{json, Value};
+
+use crate::{api::routes::token_matches, bridges::matrix::MatrixBridge};
+
+/**
+ * The endpoints the homeserver calls on an application service
+ */
+pub fn router(bridge: Arc<MatrixBridge>) -> Router {
+    Router::new()
+        .route("/_matrix/app/v1/transactions/:txn_id", put(transaction))
+        .route("/_matrix/app/v1/users/:user_id", get(query_user))
+        .route("/_matrix/app/v1/rooms/:alias", get(query_room))
+        // Homeservers that predate the v1 prefix
+        .route("/transactions/:txn_id", put(transaction))
+        .route("/users/:user_id", get(query_user))
+        .route("/rooms/:alias", get(query_room))
+        .route_layer(middleware::from_fn_with_state(bridge.clone(), authenticate))
+        .with_state(bridge)
+}
+
+fn matrix_error(status: StatusCode, errcode: &str, error: &str) -> Response {
+    (status, Json(json!({ "errcode": errcode, "error": error }))).into_response()
+}
+
+/**
+ * Only the homeserver knows the hs_token, either as a bearer token or the
+ * older `access_token` query parameter
+ */
+async fn authenticate<B>(
+    State(bridge): State<Arc<MatrixBridge>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let header_token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let query_token = request.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("access_token="))
+    });
+    match header_token.or(query_token) {
+        Some(token) if token_matches(token, bridge.hs_token()) => next.run(request).await,
+        Some(_) => matrix_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "Invalid token"),
+        None => matrix_error(StatusCode::UNAUTHORIZED, "M_UNAUTHORIZED", "Missing token"),
+    }
+}
+
+async fn transaction(
+    State(bridge): State<Arc<MatrixBridge>>,
+    Path(txn_id): Path<String>,
+    Json(body): Json<Value>,
+) -> Response {
+    bridge.handle_transaction(&txn_id, &body).await;
+    Json(json!({})).into_response()
+}
+
+async fn query_user(
+    State(bridge): State<Arc<MatrixBridge>>,
+    Path(user_id): Path<String>,
+) -> Response {
+    if !bridge.is_bridge_user(&user_id) {
+        return matrix_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "Not a bridged user");
+    }
+    match bridge.provision_user(&user_id).await {
+        Ok(_) => Json(json!({})).into_response(),
+        Err(e) => {
+            log::error!("Error registering {}: {:?}", user_id, e);
+            matrix_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "Could not register user")
+        }
+    }
+}
+
+async fn query_room(Path(_alias): Path<String>) -> Response {
+    matrix_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "No room aliases are bridged")
+}
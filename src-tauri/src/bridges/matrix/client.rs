@@ -0,0 +1,334 @@
+use reqwest::{Method, StatusCode, Url};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub enum MatrixError {
+    UrlError(url::ParseError),
+    InvalidHomeserver,
+    RequestError(reqwest::Error),
+    Status(StatusCode, Value),
+    MissingField(&'static str),
+}
+
+impl From<url::ParseError> for MatrixError {
+    fn from(error: url::ParseError) -> Self {
+        MatrixError::UrlError(error)
+    }
+}
+
+impl From<reqwest::Error> for MatrixError {
+    fn from(error: reqwest::Error) -> Self {
+        MatrixError::RequestError(error)
+    }
+}
+
+impl MatrixError {
+    pub fn errcode(&self) -> Option<&str> {
+        match self {
+            MatrixError::Status(_, body) => body.get("errcode").and_then(Value::as_str),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * The parts of the client-server API the bridge needs, authenticated as the
+ * application service
+ *
+ * Passing a user ID acts as that puppeted user instead of the bridge bot
+ */
+pub struct MatrixClient {
+    http: reqwest::Client,
+    homeserver_url: String,
+    as_token: String,
+}
+
+impl MatrixClient {
+    pub fn new(homeserver_url: String, as_token: String) -> MatrixClient {
+        MatrixClient {
+            http: reqwest::Client::new(),
+            homeserver_url,
+            as_token,
+        }
+    }
+
+    fn url(&self, segments: &[&str], user_id: Option<&str>) -> Result<Url, MatrixError> {
+        self.api_url("client", segments, user_id)
+    }
+
+    fn api_url(
+        &self,
+        api: &str,
+        segments: &[&str],
+        user_id: Option<&str>,
+    ) -> Result<Url, MatrixError> {
+        let mut url = Url::parse(&self.homeserver_url)?;
+        url.path_segments_mut()
+            .map_err(|_| MatrixError::InvalidHomeserver)?
+            .pop_if_empty()
+            .extend(["_matrix", api, "v3"])
+            .extend(segments);
+        if let Some(user_id) = user_id {
+            url.query_pairs_mut().append_pair("user_id", user_id);
+        }
+        Ok(url)
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        segments: &[&str],
+        user_id: Option<&str>,
+        body: Value,
+    ) -> Result<Value, MatrixError> {
+        let response = self
+            .http
+            .request(method, self.url(segments, user_id)?)
+            .bearer_auth(&self.as_token)
+            .json(&body)
+            .send()
+            .await?;
+        let status = response.status();
+        let body: Value = response.json().await.unwrap_or(Value::Null);
+        if status.is_success() {
+            Ok(body)
+        } else {
+            Err(MatrixError::Status(status, body))
+        }
+    }
+
+    /**
+     * Register a user in the bridge's namespace, doing nothing if it exists
+     */
+    pub async fn register(&self, localpart: &str) -> Result<(), MatrixError> {
+        match self
+            .request(
+                Method::POST,
+                &["register"],
+                None,
+                json!({ "type": "m.login.application_service", "username": localpart }),
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) if e.errcode() == Some("M_USER_IN_USE") => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn set_display_name(&self, user_id: &str, name: &str) -> Result<(), MatrixError> {
+        self.request(
+            Method::PUT,
+            &["profile", user_id, "displayname"],
+            Some(user_id),
+            json!({ "displayname": name }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /**
+     * Create a private room as the bridge bot, returning its ID
+     */
+    pub async fn create_room(
+        &self,
+        name: &str,
+        invite: Vec<String>,
+        is_direct: bool,
+    ) -> Result<String, MatrixError> {
+        let response = self
+            .request(
+                Method::POST,
+                &["createRoom"],
+                None,
+                json!({
+                    "name": name,
+                    "invite": invite,
+                    "is_direct": is_direct,
+                    "preset": "private_chat",
+                }),
+            )
+            .await?;
+        response
+            .get("room_id")
+            .and_then(Value::as_str)
+            .map(str::to_owned)
+            .ok_or(MatrixError::MissingField("room_id"))
+    }
+
+    pub async fn invite(&self, room_id: &str, user_id: &str) -> Result<(), MatrixError> {
+        match self
+            .request(
+                Method::POST,
+                &["rooms", room_id, "invite"],
+                None,
+                json!({ "user_id": user_id }),
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            // Already in the room
+            Err(MatrixError::Status(StatusCode::FORBIDDEN, _)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn join(&self, room_id: &str, user_id: &str) -> Result<(), MatrixError> {
+        self.request(Method::POST, &["join", room_id], Some(user_id), json!({}))
+            .await?;
+        Ok(())
+    }
+
+    /**
+     * Send a message event as the given user, returning its event ID
+     */
+    pub async fn send_message(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        content: Value,
+    ) -> Result<String, MatrixError> {
+        self.send_event(room_id, user_id, "m.room.message", content)
+            .await
+    }
+
+    /**
+     * Send any room event as the given user, returning its event ID
+     */
+    pub async fn send_event(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        event_type: &str,
+        content: Value,
+    ) -> Result<String, MatrixError> {
+        let txn_id = Uuid::new_v4().to_string();
+        let response = self
+            .request(
+                Method::PUT,
+                &["rooms", room_id, "send", event_type, &txn_id],
+                Some(user_id),
+                content,
+            )
+            .await?;
+        response
+            .get("event_id")
+            .and_then(Value::as_str)
+            .map(str::to_owned)
+            .ok_or(MatrixError::MissingField("event_id"))
+    }
+
+    pub async fn redact(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        event_id: &str,
+    ) -> Result<(), MatrixError> {
+        let txn_id = Uuid::new_v4().to_string();
+        self.request(
+            Method::PUT,
+            &["rooms", room_id, "redact", event_id, &txn_id],
+            Some(user_id),
+            json!({}),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn send_read_receipt(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        event_id: &str,
+    ) -> Result<(), MatrixError> {
+        self.request(
+            Method::POST,
+            &["rooms", room_id, "receipt", "m.read", event_id],
+            Some(user_id),
+            json!({}),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /**
+     * Show or clear the typing notice of the given user, which the
+     * homeserver drops by itself after the timeout
+     */
+    pub async fn set_typing(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        typing: bool,
+        timeout_ms: u64,
+    ) -> Result<(), MatrixError> {
+        let content = if typing {
+            json!({ "typing": true, "timeout": timeout_ms })
+        } else {
+            json!({ "typing": false })
+        };
+        self.request(
+            Method::PUT,
+            &["rooms", room_id, "typing", user_id],
+            Some(user_id),
+            content,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /**
+     * Upload content to the media repository as the given user, returning
+     * its `mxc://` URI
+     */
+    pub async fn upload_media(
+        &self,
+        user_id: &str,
+        data: Vec<u8>,
+        mime: &str,
+        name: &str,
+    ) -> Result<String, MatrixError> {
+        let mut url = self.api_url("media", &["upload"], Some(user_id))?;
+        url.query_pairs_mut().append_pair("filename", name);
+        let response = self
+            .http
+            .post(url)
+            .bearer_auth(&self.as_token)
+            .header(reqwest::header::CONTENT_TYPE, mime)
+            .body(data)
+            .send()
+            .await?;
+        let status = response.status();
+        let body: Value = response.json().await.unwrap_or(Value::Null);
+        if !status.is_success() {
+            return Err(MatrixError::Status(status, body));
+        }
+        body.get("content_uri")
+            .and_then(Value::as_str)
+            .map(str::to_owned)
+            .ok_or(MatrixError::MissingField("content_uri"))
+    }
+
+    /**
+     * Download content from the media repository by its `mxc://` URI
+     */
+    pub async fn download_media(&self, mxc: &str) -> Result<Vec<u8>, MatrixError> {
+        let (server, media_id) = mxc
+            .strip_prefix("mxc://")
+            .and_then(|rest| rest.split_once('/'))
+            .ok_or(MatrixError::MissingField("url"))?;
+        let response = self
+            .http
+            .get(self.api_url("media", &["download", server, media_id], None)?)
+            .bearer_auth(&self.as_token)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body: Value = response.json().await.unwrap_or(Value::Null);
+            return Err(MatrixError::Status(status, body));
+        }
+        Ok(response.bytes().await?.to_vec())
+    }
+}
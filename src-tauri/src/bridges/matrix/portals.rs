@@ -0,0 +1,165 @@
+use std::{fs::File, io::BufReader, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::state::{data_dir, write_json};
+
+/// How many message mappings to remember for receipts
+const MAX_BRIDGED_MESSAGES: usize = 10000;
+
+/**
+ * A Matrix room standing in for an iMessage conversation
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Portal {
+    pub conversation_id: String,
+    pub participants: Vec<String>,
+    pub room_id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BridgedMessage {
+    pub message_id: String,
+    pub event_id: String,
+    pub room_id: String,
+}
+
+/**
+ * An `m.reaction` event standing in for someone's tapback
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BridgedReaction {
+    pub message_id: String,
+    /// The iMessage handle, or the owner's Matrix ID for our own tapbacks
+    pub sender: String,
+    pub key: String,
+    pub event_id: String,
+    pub room_id: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct PortalData {
+    portals: Vec<Portal>,
+    messages: Vec<BridgedMessage>,
+    reactions: Vec<BridgedReaction>,
+}
+
+/**
+ * The mapping between conversations and rooms, and between messages and
+ * events, persisted so rooms are reused across restarts
+ */
+pub struct PortalStore {
+    path: PathBuf,
+    data: PortalData,
+}
+
+impl PortalStore {
+    pub fn load() -> Result<PortalStore, std::io::Error> {
+        let path = data_dir().join("matrix-portals.json");
+        if !path.exists() {
+            return Ok(PortalStore {
+                path,
+                data: PortalData::default(),
+            });
+        }
+        let file = File::open(&path)?;
+        let data: PortalData = serde_json::from_reader(BufReader::new(file))?;
+        Ok(PortalStore { path, data })
+    }
+
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        write_json(&self.path, &self.data)
+    }
+
+    pub fn by_conversation(&self, conversation_id: &str) -> Option<&Portal> {
+        self.data
+            .portals
+            .iter()
+            .find(|portal| portal.conversation_id == conversation_id)
+    }
+
+    pub fn by_room(&self, room_id: &str) -> Option<&Portal> {
+        self.data
+            .portals
+            .iter()
+            .find(|portal| portal.room_id == room_id)
+    }
+
+    pub fn add_portal(&mut self, portal: Portal) -> Result<(), std::io::Error> {
+        self.data.portals.push(portal);
+        self.save()
+    }
+
+    pub fn by_message_id(&self, message_id: &str) -> Option<&BridgedMessage> {
+        self.data
+            .messages
+            .iter()
+            .find(|message| message.message_id == message_id)
+    }
+
+    pub fn by_event_id(&self, event_id: &str) -> Option<&BridgedMessage> {
+        self.data
+            .messages
+            .iter()
+            .find(|message| message.event_id == event_id)
+    }
+
+    pub fn add_message(&mut self, message: BridgedMessage) -> Result<(), std::io::Error> {
+        self.data.messages.push(message);
+        let excess = self
+            .data
+            .messages
+            .len()
+            .saturating_sub(MAX_BRIDGED_MESSAGES);
+        self.data.messages.drain(..excess);
+        self.save()
+    }
+
+    /**
+     * Remember a reaction, returning the one it replaces since everyone
+     * only gets one tapback per message
+     */
+    pub fn add_reaction(
+        &mut self,
+        reaction: BridgedReaction,
+    ) -> Result<Option<BridgedReaction>, std::io::Error> {
+        let replaced = self.take_reaction(|existing| {
+            existing.message_id == reaction.message_id && existing.sender == reaction.sender
+        });
+        self.data.reactions.push(reaction);
+        let excess = self
+            .data
+            .reactions
+            .len()
+            .saturating_sub(MAX_BRIDGED_MESSAGES);
+        self.data.reactions.drain(..excess);
+        self.save()?;
+        Ok(replaced)
+    }
+
+    fn take_reaction(
+        &mut self,
+        predicate: impl Fn(&BridgedReaction) -> bool,
+    ) -> Option<BridgedReaction> {
+        let index = self.data.reactions.iter().position(predicate)?;
+        Some(self.data.reactions.remove(index))
+    }
+
+    /**
+     * Forget the first reaction matching a predicate, returning it
+     */
+    pub fn remove_reaction(
+        &mut self,
+        predicate: impl Fn(&BridgedReaction) -> bool,
+    ) -> Result<Option<BridgedReaction>, std::io::Error> {
+        let removed = self.take_reaction(predicate);
+        if removed.is_some() {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+}
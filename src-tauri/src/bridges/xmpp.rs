@@ -0,0 +1,785 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    sync::{broadcast::error::RecvError, mpsc},
+};
+
+use crate::{
+    actions::{
+        outbox::{do_queue_message, OutgoingMessage},
+        send::resolve_handle,
+    },
+    events::AppEvent,
+    imessage::messenger::{conversation_data, new_conversation},
+    state::{
+        messagestore::{now_millis, StoredMessage},
+        settings::XmppSettings,
+        TauriState,
+    },
+};
+
+use self::{
+    jid::{
+        bare, display_handle, handle_to_jid, localpart_to_handle, room_conversation_id, room_jid,
+        split,
+    },
+    xml::{Element, StanzaReader, XmlEvent},
+};
+
+pub mod jid;
+pub mod xml;
+
+const NS_COMPONENT: &str = "jabber:component:accept";
+const NS_RECEIPTS: &str = "urn:xmpp:receipts";
+const NS_CHATSTATES: &str = "http://jabber.org/protocol/chatstates";
+const NS_MUC: &str = "http://jabber.org/protocol/muc";
+const NS_MUC_USER: &str = "http://jabber.org/protocol/muc#user";
+const NS_CONFERENCE: &str = "jabber:x:conference";
+const NS_DISCO_INFO: &str = "http://jabber.org/protocol/disco#info";
+const NS_STANZAS: &str = "urn:ietf:params:xml:ns:xmpp-stanzas";
+
+/// How long to wait before reconnecting to the server
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+/// Receipt requests and messages sent from XMPP that are remembered, the
+/// oldest are forgotten first
+const MAX_TRACKED_MESSAGES: usize = 1000;
+/// Group messages kept for a room until the owner joins it
+const MAX_PENDING_ROOM_MESSAGES: usize = 100;
+
+#[derive(Debug)]
+pub enum XmppError {
+    IOError(std::io::Error),
+    XmlError(quick_xml::Error),
+    HandshakeFailed(String),
+    StreamClosed,
+}
+
+impl From<std::io::Error> for XmppError {
+    fn from(error: std::io::Error) -> Self {
+        XmppError::IOError(error)
+    }
+}
+
+impl From<quick_xml::Error> for XmppError {
+    fn from(error: quick_xml::Error) -> Self {
+        XmppError::XmlError(error)
+    }
+}
+
+/**
+ * An occupant's view of a room it has joined
+ */
+struct JoinedRoom {
+    full_jid: String,
+    nick: String,
+}
+
+/**
+ * An XEP-0114 external component presenting every iMessage handle as a JID
+ * on its domain, and every group chat as a MUC room
+ *
+ * Only the configured owner can use the gateway
+ */
+pub struct XmppGateway {
+    settings: XmppSettings,
+    tauri_state: TauriState,
+    /// Rooms the owner is in, by room JID
+    rooms: HashMap<String, JoinedRoom>,
+    /// Group messages that arrived before the owner joined, by room JID
+    pending_room_messages: HashMap<String, VecDeque<StoredMessage>>,
+    /// Receipt requests as the iMessage ID of the message they were for,
+    /// the stanza ID and the JID it was sent to
+    receipt_requests: VecDeque<(String, String, String)>,
+    /// iMessage IDs of messages the owner sent through the gateway, which
+    /// are not mirrored back to them
+    sent_from_xmpp: VecDeque<String>,
+}
+
+/**
+ * Remember something in a list that forgets the oldest entries past
+ * `MAX_TRACKED_MESSAGES`
+ */
+fn track<T>(list: &mut VecDeque<T>, item: T) {
+    list.push_back(item);
+    if list.len() > MAX_TRACKED_MESSAGES {
+        list.pop_front();
+    }
+}
+
+impl XmppGateway {
+    pub fn new(settings: XmppSettings, tauri_state: TauriState) -> XmppGateway {
+        XmppGateway {
+            settings,
+            tauri_state,
+            rooms: HashMap::new(),
+            pending_room_messages: HashMap::new(),
+            receipt_requests: VecDeque::new(),
+            sent_from_xmpp: VecDeque::new(),
+        }
+    }
+
+    async fn own_handles(&self) -> Vec<String> {
+        let rust_push = self.tauri_state.0.lock().await.rust_push.clone();
+        let client = rust_push.lock().await.client.clone();
+        client.get_handles()
+    }
+
+    async fn others(&self, participants: &[String]) -> Vec<String> {
+        let own_handles = self.own_handles().await;
+        participants
+            .iter()
+            .filter(|participant| !own_handles.contains(participant))
+            .cloned()
+            .collect()
+    }
+
+    fn message(&self, from: &str, to: &str, kind: &str) -> Element {
+        Element::new("message")
+            .attr("from", from)
+            .attr("to", to)
+            .attr("type", kind)
+    }
+
+    fn error_reply(&self, stanza: &Element, condition: &str) -> Element {
+        Element::new(&stanza.name)
+            .attr("from", stanza.get_attr("to").unwrap_or_default())
+            .attr("to", stanza.get_attr("from").unwrap_or_default())
+            .attr("id", stanza.get_attr("id").unwrap_or_default())
+            .attr("type", "error")
+            .child(
+                Element::new("error")
+                    .attr("type", "cancel")
+                    .child(Element::new(condition).ns(NS_STANZAS)),
+            )
+    }
+
+    /**
+     * Connect and authenticate to the server's component port
+     */
+    async fn connect(
+        &self,
+    ) -> Result<(mpsc::Receiver<Result<Element, XmppError>>, OwnedWriteHalf), XmppError> {
+        let stream = TcpStream::connect((self.settings.server.as_str(), self.settings.port)).await?;
+        let (read, mut write) = stream.into_split();
+        let mut reader = StanzaReader::new(BufReader::new(read));
+
+        let header = format!(
+            "<stream:stream xmlns='{}' xmlns:stream='http://etherx.jabber.org/streams' to='{}'>",
+            NS_COMPONENT,
+            quick_xml::escape::escape(self.settings.domain.as_str())
+        );
+        write.write_all(header.as_bytes()).await?;
+        let stream_id = match reader.next().await? {
+            XmlEvent::StreamStart(header) => header.get_attr("id").unwrap_or_default().to_owned(),
+            _ => return Err(XmppError::HandshakeFailed("No stream header".to_owned())),
+        };
+
+        let digest = openssl::sha::sha1(format!("{}{}", stream_id, self.settings.secret).as_bytes());
+        let digest: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+        write
+            .write_all(format!("<handshake>{}</handshake>", digest).as_bytes())
+            .await?;
+        match reader.next().await? {
+            XmlEvent::Stanza(element) if element.name == "handshake" => {}
+            XmlEvent::Stanza(element) => return Err(XmppError::HandshakeFailed(element.to_xml())),
+            _ => return Err(XmppError::StreamClosed),
+        }
+
+        // Reading is not cancel safe, so it gets its own task
+        let (sender, receiver) = mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                let result = match reader.next().await {
+                    Ok(XmlEvent::Stanza(element)) => Ok(element),
+                    Ok(XmlEvent::StreamStart(_)) => continue,
+                    Ok(XmlEvent::StreamEnd) => Err(XmppError::StreamClosed),
+                    Err(e) => Err(XmppError::XmlError(e)),
+                };
+                let failed = result.is_err();
+                if sender.send(result).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok((receiver, write))
+    }
+
+    /**
+     * Relay between the server and iMessage until the connection drops
+     */
+    pub async fn run(&mut self) -> Result<(), XmppError> {
+        let events = self.tauri_state.0.lock().await.events.clone();
+        let mut receiver = events.subscribe();
+        let (mut stanzas, mut writer) = self.connect().await?;
+        log::info!("XMPP component connected as {}", self.settings.domain);
+        // Joined rooms do not survive a reconnect
+        self.rooms.clear();
+
+        loop {
+            let outgoing = tokio::select! {
+                stanza = stanzas.recv() => match stanza {
+                    Some(Ok(stanza)) => self.handle_stanza(&stanza).await,
+                    Some(Err(e)) => return Err(e),
+                    None => return Err(XmppError::StreamClosed),
+                },
+                event = receiver.recv() => match event {
+                    Ok(event) => self.handle_app_event(&event).await,
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::warn!("XMPP gateway missed {} events", skipped);
+                        Vec::new()
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+            };
+            for stanza in outgoing {
+                writer.write_all(stanza.to_xml().as_bytes()).await?;
+            }
+        }
+    }
+
+    async fn handle_stanza(&mut self, stanza: &Element) -> Vec<Element> {
+        let from = stanza.get_attr("from").unwrap_or_default();
+        if bare(from) != self.settings.owner {
+            return match stanza.get_attr("type") {
+                Some("error") | Some("result") => Vec::new(),
+                _ => vec![self.error_reply(stanza, "forbidden")],
+            };
+        }
+        match stanza.name.as_str() {
+            "message" => self.handle_message(stanza).await,
+            "presence" => self.handle_presence(stanza).await,
+            "iq" => self.handle_iq(stanza),
+            _ => Vec::new(),
+        }
+    }
+
+    async fn handle_message(&mut self, stanza: &Element) -> Vec<Element> {
+        let from = stanza.get_attr("from").unwrap_or_default();
+        let to = stanza.get_attr("to").unwrap_or_default();
+        let Some(body) = stanza
+            .children
+            .iter()
+            .find(|child| child.name == "body")
+            .map(|body| body.text.clone())
+        else {
+            if let Some(received) = stanza.get_child("received", NS_RECEIPTS) {
+                return self.handle_receipt(received).await;
+            }
+            return self.handle_chat_state(stanza).await;
+        };
+        let (Some(localpart), _, _) = split(to) else {
+            return vec![self.error_reply(stanza, "item-not-found")];
+        };
+
+        let (rust_push, messages, attachments, outbox, events) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.rust_push.clone(),
+                state.messages.clone(),
+                state.attachments.clone(),
+                state.outbox.clone(),
+                state.events.clone(),
+            )
+        };
+
+        if let Some(conversation_id) = room_conversation_id(localpart) {
+            let room = bare(to).to_owned();
+            let Some(joined) = self.rooms.get(&room) else {
+                return vec![self.error_reply(stanza, "not-acceptable")];
+            };
+            let reflection = self
+                .message(&format!("{}/{}", room, joined.nick), from, "groupchat")
+                .attr("id", stanza.get_attr("id").unwrap_or_default())
+                .child(Element::new("body").text(&body));
+            let participants = messages
+                .lock()
+                .await
+                .conversation(&conversation_id)
+                .last()
+                .map(|message| message.participants.clone());
+            let Some(participants) = participants else {
+                return vec![self.error_reply(stanza, "item-not-found")];
+            };
+            let message =
+                OutgoingMessage::text(conversation_data(&conversation_id, participants), body);
+            return match do_queue_message(rust_push, messages, attachments, outbox, events, message)
+                .await
+            {
+                Ok(message_id) => {
+                    track(&mut self.sent_from_xmpp, message_id);
+                    vec![reflection]
+                }
+                Err(e) => {
+                    log::error!("Error sending from XMPP: {:?}", e.0);
+                    vec![self.error_reply(stanza, "recipient-unavailable")]
+                }
+            };
+        }
+
+        let app_settings = self.tauri_state.0.lock().await.settings.clone();
+        let handle = match resolve_handle(&app_settings, &localpart_to_handle(localpart)).await {
+            Ok(handle) => handle,
+            Err(e) => {
+                log::warn!("Not sending from XMPP to {}: {}", localpart, e);
+                return vec![self.error_reply(stanza, "jid-malformed")];
+            }
+        };
+        let own_handles = self.own_handles().await;
+        let existing = messages
+            .lock()
+            .await
+            .direct_conversation(&handle, &own_handles);
+        let conversation = match existing {
+            Some(conversation) => conversation_data(&conversation.id, conversation.participants),
+            None => new_conversation(vec![handle]),
+        };
+        let message = OutgoingMessage::text(conversation, body);
+        match do_queue_message(rust_push, messages, attachments, outbox, events, message).await {
+            Ok(message_id) => {
+                if let (Some(_), Some(id)) = (
+                    stanza.get_child("request", NS_RECEIPTS),
+                    stanza.get_attr("id"),
+                ) {
+                    track(
+                        &mut self.receipt_requests,
+                        (message_id.clone(), id.to_owned(), bare(to).to_owned()),
+                    );
+                }
+                track(&mut self.sent_from_xmpp, message_id);
+                Vec::new()
+            }
+            Err(e) => {
+                log::error!("Error sending from XMPP: {:?}", e.0);
+                vec![self.error_reply(stanza, "recipient-unavailable")]
+            }
+        }
+    }
+
+    /**
+     * Record that a message relayed to the owner reached their client, from
+     * its XEP-0184 receipt
+     */
+    async fn handle_receipt(&mut self, received: &Element) -> Vec<Element> {
+        let Some(id) = received.get_attr("id") else {
+            return Vec::new();
+        };
+        let messages = self.tauri_state.0.lock().await.messages.clone();
+        if let Err(e) = messages.lock().await.mark_relayed(id, now_millis()) {
+            log::error!("Error saving receipt from XMPP: {:?}", e);
+        }
+        Vec::new()
+    }
+
+    /**
+     * Pass on whether the owner is typing, following the XEP-0085 chat
+     * state of a message without a body
+     */
+    async fn handle_chat_state(&mut self, stanza: &Element) -> Vec<Element> {
+        let typing = stanza
+            .children
+            .iter()
+            .filter(|child| child.get_attr("xmlns") == Some(NS_CHATSTATES))
+            .find_map(|child| match child.local_name() {
+                "composing" => Some(true),
+                "paused" | "active" | "inactive" | "gone" => Some(false),
+                _ => None,
+            });
+        let Some(typing) = typing else {
+            return Vec::new();
+        };
+        let to = stanza.get_attr("to").unwrap_or_default();
+        let (Some(localpart), _, _) = split(to) else {
+            return Vec::new();
+        };
+        let (messages, tracker) = {
+            let state = self.tauri_state.0.lock().await;
+            (state.messages.clone(), state.typing.clone())
+        };
+        let conversation_id = match room_conversation_id(localpart) {
+            Some(conversation_id) => Some(conversation_id),
+            None => {
+                let own_handles = self.own_handles().await;
+                messages
+                    .lock()
+                    .await
+                    .direct_conversation(&localpart_to_handle(localpart), &own_handles)
+                    .map(|conversation| conversation.id)
+            }
+        };
+        // Typing before the first message has nowhere to go
+        let Some(conversation_id) = conversation_id else {
+            return Vec::new();
+        };
+        if let Err(e) = tracker.set(&conversation_id, typing).await {
+            log::error!("Error setting typing from XMPP: {:?}", e.0);
+        }
+        Vec::new()
+    }
+
+    async fn handle_presence(&mut self, stanza: &Element) -> Vec<Element> {
+        let from = stanza.get_attr("from").unwrap_or_default();
+        let to = stanza.get_attr("to").unwrap_or_default();
+        let (localpart, _, resource) = split(to);
+        let Some(localpart) = localpart else {
+            return Vec::new();
+        };
+        let kind = stanza.get_attr("type");
+
+        if let Some(conversation_id) = room_conversation_id(localpart) {
+            let room = bare(to).to_owned();
+            if kind == Some("unavailable") {
+                let Some(joined) = self.rooms.remove(&room) else {
+                    return Vec::new();
+                };
+                return vec![Element::new("presence")
+                    .attr("from", &format!("{}/{}", room, joined.nick))
+                    .attr("to", from)
+                    .attr("type", "unavailable")
+                    .child(
+                        Element::new("x")
+                            .ns(NS_MUC_USER)
+                            .child(Element::new("status").attr("code", "110")),
+                    )];
+            }
+            if stanza.get_child("x", NS_MUC).is_none() && !self.rooms.contains_key(&room) {
+                return Vec::new();
+            }
+            let Some(nick) = resource else {
+                return vec![self.error_reply(stanza, "jid-malformed")];
+            };
+            return self.join_room(&room, &conversation_id, from, nick).await;
+        }
+
+        let handle_jid = bare(to);
+        match kind {
+            Some("subscribe") => vec![
+                Element::new("presence")
+                    .attr("from", handle_jid)
+                    .attr("to", bare(from))
+                    .attr("type", "subscribed"),
+                Element::new("presence")
+                    .attr("from", handle_jid)
+                    .attr("to", bare(from)),
+            ],
+            Some("probe") | None => vec![Element::new("presence")
+                .attr("from", handle_jid)
+                .attr("to", from)],
+            _ => Vec::new(),
+        }
+    }
+
+    /**
+     * Join the owner to a room, sending the occupant list, their own
+     * presence, the subject and anything that arrived while they were away
+     */
+    async fn join_room(
+        &mut self,
+        room: &str,
+        conversation_id: &str,
+        full_jid: &str,
+        nick: &str,
+    ) -> Vec<Element> {
+        let messages = self.tauri_state.0.lock().await.messages.clone();
+        let participants = messages
+            .lock()
+            .await
+            .conversation(conversation_id)
+            .last()
+            .map(|message| message.participants.clone())
+            .unwrap_or_default();
+        let mut outgoing: Vec<Element> = Vec::new();
+        for participant in self.others(&participants).await {
+            outgoing.push(
+                Element::new("presence")
+                    .attr("from", &format!("{}/{}", room, display_handle(&participant)))
+                    .attr("to", full_jid)
+                    .child(
+                        Element::new("x").ns(NS_MUC_USER).child(
+                            Element::new("item")
+                                .attr("affiliation", "member")
+                                .attr("role", "participant")
+                                .attr("jid", &handle_to_jid(&participant, &self.settings.domain)),
+                        ),
+                    ),
+            );
+        }
+        outgoing.push(
+            Element::new("presence")
+                .attr("from", &format!("{}/{}", room, nick))
+                .attr("to", full_jid)
+                .child(
+                    Element::new("x")
+                        .ns(NS_MUC_USER)
+                        .child(
+                            Element::new("item")
+                                .attr("affiliation", "owner")
+                                .attr("role", "moderator"),
+                        )
+                        .child(Element::new("status").attr("code", "100"))
+                        .child(Element::new("status").attr("code", "110")),
+                ),
+        );
+        outgoing.push(self.message(room, full_jid, "groupchat").child(Element::new("subject")));
+
+        self.rooms.insert(
+            room.to_owned(),
+            JoinedRoom {
+                full_jid: full_jid.to_owned(),
+                nick: nick.to_owned(),
+            },
+        );
+        for message in self
+            .pending_room_messages
+            .remove(room)
+            .unwrap_or_default()
+        {
+            outgoing.extend(self.room_message(room, &message));
+        }
+        outgoing
+    }
+
+    fn handle_iq(&self, stanza: &Element) -> Vec<Element> {
+        let kind = stanza.get_attr("type").unwrap_or_default();
+        if kind == "result" || kind == "error" {
+            return Vec::new();
+        }
+        if kind != "get" || stanza.get_child("query", NS_DISCO_INFO).is_none() {
+            return vec![self.error_reply(stanza, "service-unavailable")];
+        }
+
+        let to = stanza.get_attr("to").unwrap_or_default();
+        let is_room = match split(to) {
+            (Some(localpart), _, _) => room_conversation_id(localpart).is_some(),
+            _ => false,
+        };
+        let mut query = Element::new("query").ns(NS_DISCO_INFO);
+        query = if is_room {
+            query.child(
+                Element::new("identity")
+                    .attr("category", "conference")
+                    .attr("type", "text"),
+            )
+        } else {
+            query.child(
+                Element::new("identity")
+                    .attr("category", "gateway")
+                    .attr("type", "imessage")
+                    .attr("name", "iMessage"),
+            )
+        };
+        for feature in [NS_DISCO_INFO, NS_MUC, NS_RECEIPTS, NS_CHATSTATES] {
+            query = query.child(Element::new("feature").attr("var", feature));
+        }
+        vec![Element::new("iq")
+            .attr("from", to)
+            .attr("to", stanza.get_attr("from").unwrap_or_default())
+            .attr("id", stanza.get_attr("id").unwrap_or_default())
+            .attr("type", "result")
+            .child(query)]
+    }
+
+    fn room_message(&self, room: &str, message: &StoredMessage) -> Vec<Element> {
+        let Some(joined) = self.rooms.get(room) else {
+            return Vec::new();
+        };
+        // What we sent from elsewhere shows as the owner's own message
+        let nick = if message.from_me {
+            joined.nick.as_str()
+        } else {
+            display_handle(message.sender.as_deref().unwrap_or("unknown"))
+        };
+        vec![self
+            .message(&format!("{}/{}", room, nick), &joined.full_jid, "groupchat")
+            .attr("id", &message.id)
+            .child(Element::new("body").text(&message.text))]
+    }
+
+    /**
+     * A chat state for someone typing, in the direct chat or the room the
+     * conversation is shown as
+     */
+    async fn typing_message(
+        &self,
+        conversation_id: &str,
+        sender: &str,
+        typing: bool,
+    ) -> Vec<Element> {
+        let messages = self.tauri_state.0.lock().await.messages.clone();
+        let participants = messages
+            .lock()
+            .await
+            .conversation(conversation_id)
+            .last()
+            .map(|message| message.participants.clone())
+            .unwrap_or_default();
+        let state = Element::new(if typing { "composing" } else { "paused" }).ns(NS_CHATSTATES);
+        if self.others(&participants).await.len() <= 1 {
+            return vec![self
+                .message(
+                    &handle_to_jid(sender, &self.settings.domain),
+                    &self.settings.owner,
+                    "chat",
+                )
+                .child(state)];
+        }
+        let room = room_jid(conversation_id, &self.settings.domain);
+        let Some(joined) = self.rooms.get(&room) else {
+            return Vec::new();
+        };
+        vec![self
+            .message(
+                &format!("{}/{}", room, display_handle(sender)),
+                &joined.full_jid,
+                "groupchat",
+            )
+            .child(state)]
+    }
+
+    /**
+     * Show a message the owner sent from this app or another of their
+     * devices, in the direct chat or the room the conversation is shown as
+     *
+     * A component cannot send messages as the owner, so in a direct chat it
+     * comes from the other side and is marked as the owner's
+     */
+    async fn mirror_own_message(&mut self, message: &StoredMessage) -> Vec<Element> {
+        if self.sent_from_xmpp.contains(&message.id) {
+            return Vec::new();
+        }
+        let others = self.others(&message.participants).await;
+        if let [handle] = others.as_slice() {
+            return vec![self
+                .message(
+                    &handle_to_jid(handle, &self.settings.domain),
+                    &self.settings.owner,
+                    "chat",
+                )
+                .attr("id", &message.id)
+                .child(Element::new("body").text(&format!("You: {}", message.text)))];
+        }
+        let room = room_jid(&message.conversation_id, &self.settings.domain);
+        if self.rooms.contains_key(&room) {
+            return self.room_message(&room, message);
+        }
+        self.queue_room_message(&room, message);
+        Vec::new()
+    }
+
+    /**
+     * Keep a group message until the owner joins its room
+     *
+     * Returns whether it is the first one waiting
+     */
+    fn queue_room_message(&mut self, room: &str, message: &StoredMessage) -> bool {
+        let pending = self
+            .pending_room_messages
+            .entry(room.to_owned())
+            .or_default();
+        pending.push_back(message.clone());
+        if pending.len() > MAX_PENDING_ROOM_MESSAGES {
+            pending.pop_front();
+        }
+        pending.len() == 1
+    }
+
+    async fn handle_app_event(&mut self, event: &AppEvent) -> Vec<Element> {
+        match event {
+            AppEvent::MessageReceived { message, .. } | AppEvent::MessageSent { message, .. }
+                if message.from_me =>
+            {
+                self.mirror_own_message(message).await
+            }
+            AppEvent::MessageReceived { message, .. } => {
+                let others = self.others(&message.participants).await;
+                if others.len() <= 1 {
+                    let Some(sender) = &message.sender else {
+                        return Vec::new();
+                    };
+                    return vec![self
+                        .message(
+                            &handle_to_jid(sender, &self.settings.domain),
+                            &self.settings.owner,
+                            "chat",
+                        )
+                        .attr("id", &message.id)
+                        .child(Element::new("body").text(&message.text))
+                        .child(Element::new("request").ns(NS_RECEIPTS))
+                        .child(Element::new("active").ns(NS_CHATSTATES))];
+                }
+
+                let room = room_jid(&message.conversation_id, &self.settings.domain);
+                if self.rooms.contains_key(&room) {
+                    return self.room_message(&room, message);
+                }
+                if !self.queue_room_message(&room, message) {
+                    return Vec::new();
+                }
+                // Invite the owner the first time a group they are not in gets a message
+                let reason = others
+                    .iter()
+                    .map(|participant| display_handle(participant))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                vec![Element::new("message")
+                    .attr("from", &self.settings.domain)
+                    .attr("to", &self.settings.owner)
+                    .child(
+                        Element::new("x")
+                            .ns(NS_CONFERENCE)
+                            .attr("jid", &room)
+                            .attr("reason", &reason),
+                    )]
+            }
+            AppEvent::MessageDelivered { id, .. } => {
+                let Some(index) = self
+                    .receipt_requests
+                    .iter()
+                    .position(|(message_id, _, _)| message_id == id)
+                else {
+                    return Vec::new();
+                };
+                let Some((_, stanza_id, handle_jid)) = self.receipt_requests.remove(index) else {
+                    return Vec::new();
+                };
+                vec![Element::new("message")
+                    .attr("from", &handle_jid)
+                    .attr("to", &self.settings.owner)
+                    .child(Element::new("received").ns(NS_RECEIPTS).attr("id", &stanza_id))]
+            }
+            AppEvent::TypingChanged {
+                conversation_id,
+                sender: Some(sender),
+                typing,
+                ..
+            } => self.typing_message(conversation_id, sender, *typing).await,
+            _ => Vec::new(),
+        }
+    }
+}
+
+/**
+ * Start the gateway if it is enabled in the settings, reconnecting whenever
+ * the connection to the server drops
+ */
+pub async fn spawn_xmpp_gateway(tauri_state: TauriState) {
+    let settings = tauri_state.0.lock().await.settings.clone();
+    let xmpp_settings = settings.lock().await.xmpp.clone();
+    if !xmpp_settings.enabled {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut gateway = XmppGateway::new(xmpp_settings, tauri_state);
+        loop {
+            match gateway.run().await {
+                Ok(_) => break,
+                Err(e) => log::error!("XMPP component disconnected: {:?}", e),
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    });
+}
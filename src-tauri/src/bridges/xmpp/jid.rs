@@ -0,0 +1,163 @@
+/// Characters XEP-0106 escapes in a localpart, and what they become
+const ESCAPES: [(char, &str); 10] = [
+    ('\\', "\\5c"),
+    (' ', "\\20"),
+    ('"', "\\22"),
+    ('&', "\\26"),
+    ('\'', "\\27"),
+    ('/', "\\2f"),
+    (':', "\\3a"),
+    ('<', "\\3c"),
+    ('>', "\\3e"),
+    ('@', "\\40"),
+];
+
+/// Prefix of the localparts of rooms standing in for group chats
+pub const ROOM_PREFIX: &str = "chat.";
+
+pub fn escape_localpart(raw: &str) -> String {
+    raw.chars()
+        .map(|c| match ESCAPES.iter().find(|(escaped, _)| *escaped == c) {
+            Some((_, replacement)) => replacement.to_string(),
+            None => c.to_string(),
+        })
+        .collect()
+}
+
+pub fn unescape_localpart(escaped: &str) -> String {
+    let mut out = escaped.to_owned();
+    // The backslash has to go last so it cannot form new sequences
+    for (c, sequence) in ESCAPES.iter().rev() {
+        out = out.replace(sequence, &c.to_string());
+    }
+    out
+}
+
+/**
+ * Split a JID into localpart, domain and resource
+ */
+pub fn split(jid: &str) -> (Option<&str>, &str, Option<&str>) {
+    let (bare, resource) = match jid.split_once('/') {
+        Some((bare, resource)) => (bare, Some(resource)),
+        None => (jid, None),
+    };
+    match bare.split_once('@') {
+        Some((local, domain)) => (Some(local), domain, resource),
+        None => (None, bare, resource),
+    }
+}
+
+pub fn bare(jid: &str) -> &str {
+    jid.split('/').next().unwrap_or(jid)
+}
+
+/**
+ * The part of a handle people recognise, e.g. `+15551234567` for
+ * `tel:+15551234567`
+ */
+pub fn display_handle(handle: &str) -> &str {
+    handle
+        .strip_prefix("tel:")
+        .or_else(|| handle.strip_prefix("mailto:"))
+        .unwrap_or(handle)
+}
+
+pub fn handle_to_jid(handle: &str, domain: &str) -> String {
+    format!("{}@{}", escape_localpart(display_handle(handle)), domain)
+}
+
+/**
+ * The iMessage handle a gateway localpart stands for
+ */
+pub fn localpart_to_handle(localpart: &str) -> String {
+    let raw = unescape_localpart(localpart);
+    if raw.contains('@') {
+        format!("mailto:{}", raw)
+    } else {
+        format!("tel:{}", raw)
+    }
+}
+
+pub fn room_jid(conversation_id: &str, domain: &str) -> String {
+    format!(
+        "{}{}@{}",
+        ROOM_PREFIX,
+        escape_localpart(conversation_id),
+        domain
+    )
+}
+
+/**
+ * The conversation a room localpart stands for, if it is a room
+ */
+pub fn room_conversation_id(localpart: &str) -> Option<String> {
+    localpart.strip_prefix(ROOM_PREFIX).map(unescape_localpart)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn localparts_are_escaped() {
+        assert_eq!(
+            escape_localpart("jane doe@example.com"),
+            "jane\\20doe\\40example.com"
+        );
+        assert_eq!(escape_localpart("+15551234567"), "+15551234567");
+        assert_eq!(escape_localpart("a\\20b"), "a\\5c20b");
+    }
+
+    #[test]
+    fn localparts_round_trip() {
+        for raw in [
+            "jane doe@example.com",
+            "o'brien@example.com",
+            "a\\20b",
+            "\\5c",
+            "<chat>/\"x\":&",
+            "+15551234567",
+        ] {
+            assert_eq!(unescape_localpart(&escape_localpart(raw)), raw);
+        }
+    }
+
+    #[test]
+    fn handles_map_to_jids_and_back() {
+        let jid = handle_to_jid("mailto:jane@example.com", "imessage.example.org");
+        assert_eq!(jid, "jane\\40example.com@imessage.example.org");
+        let (local, domain, resource) = split(&jid);
+        assert_eq!(domain, "imessage.example.org");
+        assert_eq!(resource, None);
+        assert_eq!(
+            localpart_to_handle(local.unwrap()),
+            "mailto:jane@example.com"
+        );
+        assert_eq!(
+            localpart_to_handle(&escape_localpart("+15551234567")),
+            "tel:+15551234567"
+        );
+    }
+
+    #[test]
+    fn rooms_map_to_conversations() {
+        let jid = room_jid("chat123@group", "imessage.example.org");
+        assert_eq!(jid, "chat.chat123\\40group@imessage.example.org");
+        let (local, _, _) = split(&jid);
+        assert_eq!(
+            room_conversation_id(local.unwrap()).as_deref(),
+            Some("chat123@group")
+        );
+        assert_eq!(room_conversation_id("jane\\40example.com"), None);
+    }
+
+    #[test]
+    fn splits_full_jids() {
+        assert_eq!(
+            split("jane@example.org/phone"),
+            (Some("jane"), "example.org", Some("phone"))
+        );
+        assert_eq!(split("example.org"), (None, "example.org", None));
+        assert_eq!(bare("jane@example.org/phone/2"), "jane@example.org");
+    }
+}
@@ -0,0 +1,259 @@
+use quick_xml::{
+    escape::escape,
+    events::{BytesStart, Event},
+    Reader,
+};
+use tokio::io::AsyncBufRead;
+
+/**
+ * Just enough of an XML element tree for XMPP stanzas
+ *
+ * Namespaces are kept as plain `xmlns` attributes, and mixed content is
+ * flattened into `text`
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Element {
+    pub name: String,
+    pub attrs: Vec<(String, String)>,
+    pub children: Vec<Element>,
+    pub text: String,
+}
+
+impl Element {
+    pub fn new(name: &str) -> Element {
+        Element {
+            name: name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    pub fn attr(mut self, name: &str, value: &str) -> Element {
+        self.attrs.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn ns(self, ns: &str) -> Element {
+        self.attr("xmlns", ns)
+    }
+
+    pub fn child(mut self, child: Element) -> Element {
+        self.children.push(child);
+        self
+    }
+
+    pub fn text(mut self, text: &str) -> Element {
+        self.text.push_str(text);
+        self
+    }
+
+    pub fn get_attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn local_name(&self) -> &str {
+        self.name.rsplit(':').next().unwrap_or(&self.name)
+    }
+
+    pub fn get_child(&self, name: &str, ns: &str) -> Option<&Element> {
+        self.children
+            .iter()
+            .find(|child| child.local_name() == name && child.get_attr("xmlns") == Some(ns))
+    }
+
+    pub fn has_child_ns(&self, ns: &str) -> bool {
+        self.children
+            .iter()
+            .any(|child| child.get_attr("xmlns") == Some(ns))
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut out = format!("<{}", self.name);
+        for (key, value) in self.attrs.iter() {
+            out.push_str(&format!(" {}='{}'", key, escape(value.as_str())));
+        }
+        if self.children.is_empty() && self.text.is_empty() {
+            out.push_str("/>");
+            return out;
+        }
+        out.push('>');
+        out.push_str(&escape(self.text.as_str()));
+        for child in self.children.iter() {
+            out.push_str(&child.to_xml());
+        }
+        out.push_str(&format!("</{}>", self.name));
+        out
+    }
+}
+
+pub enum XmlEvent {
+    StreamStart(Element),
+    Stanza(Element),
+    StreamEnd,
+}
+
+fn element_from_start(start: &BytesStart) -> Result<Element, quick_xml::Error> {
+    let mut element = Element::new(&String::from_utf8_lossy(start.name().as_ref()));
+    for attr in start.attributes() {
+        let attr = attr?;
+        element.attrs.push((
+            String::from_utf8_lossy(attr.key.as_ref()).into_owned(),
+            attr.unescape_value()?.into_owned(),
+        ));
+    }
+    Ok(element)
+}
+
+/**
+ * Splits the never-ending XMPP stream into its header and top level stanzas
+ */
+pub struct StanzaReader<R> {
+    reader: Reader<R>,
+    buf: Vec<u8>,
+    stack: Vec<Element>,
+}
+
+impl<R: AsyncBufRead + Unpin> StanzaReader<R> {
+    pub fn new(inner: R) -> StanzaReader<R> {
+        // Text is not trimmed, since it would take the spaces off the ends
+        // of message bodies
+        StanzaReader {
+            reader: Reader::from_reader(inner),
+            buf: Vec::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    pub async fn next(&mut self) -> Result<XmlEvent, quick_xml::Error> {
+        loop {
+            self.buf.clear();
+            let completed = match self.reader.read_event_into_async(&mut self.buf).await? {
+                Event::Start(start) => {
+                    let element = element_from_start(&start)?;
+                    if self.stack.is_empty() && element.name == "stream:stream" {
+                        return Ok(XmlEvent::StreamStart(element));
+                    }
+                    self.stack.push(element);
+                    None
+                }
+                Event::Empty(start) => Some(element_from_start(&start)?),
+                Event::End(_) => match self.stack.pop() {
+                    Some(mut element) => {
+                        // Whitespace only between children is indentation
+                        if !element.children.is_empty() && element.text.trim().is_empty() {
+                            element.text.clear();
+                        }
+                        Some(element)
+                    }
+                    None => return Ok(XmlEvent::StreamEnd),
+                },
+                Event::Text(text) => {
+                    if let Some(top) = self.stack.last_mut() {
+                        top.text.push_str(&text.unescape()?);
+                    }
+                    None
+                }
+                Event::CData(data) => {
+                    if let Some(top) = self.stack.last_mut() {
+                        top.text.push_str(&String::from_utf8_lossy(&data));
+                    }
+                    None
+                }
+                Event::Eof => return Ok(XmlEvent::StreamEnd),
+                _ => None,
+            };
+            if let Some(element) = completed {
+                match self.stack.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => return Ok(XmlEvent::Stanza(element)),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn stanzas(xml: &str) -> Vec<Element> {
+        let mut reader = StanzaReader::new(xml.as_bytes());
+        let mut stanzas = Vec::new();
+        loop {
+            match reader.next().await.unwrap() {
+                XmlEvent::StreamStart(_) => {}
+                XmlEvent::Stanza(stanza) => stanzas.push(stanza),
+                XmlEvent::StreamEnd => return stanzas,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_stream_header_and_stanzas() {
+        let mut reader = StanzaReader::new(
+            "<?xml version='1.0'?><stream:stream xmlns='jabber:client' to='example.org'>\
+             <presence/> <iq type='get' id='1'><ping xmlns='urn:xmpp:ping'/></iq></stream:stream>"
+                .as_bytes(),
+        );
+        match reader.next().await.unwrap() {
+            XmlEvent::StreamStart(header) => {
+                assert_eq!(header.get_attr("to"), Some("example.org"));
+            }
+            _ => panic!("expected the stream header"),
+        }
+        match reader.next().await.unwrap() {
+            XmlEvent::Stanza(presence) => assert_eq!(presence.name, "presence"),
+            _ => panic!("expected a presence"),
+        }
+        match reader.next().await.unwrap() {
+            XmlEvent::Stanza(iq) => {
+                assert_eq!(iq.get_attr("id"), Some("1"));
+                assert!(iq.get_child("ping", "urn:xmpp:ping").is_some());
+            }
+            _ => panic!("expected an iq"),
+        }
+        assert!(matches!(reader.next().await.unwrap(), XmlEvent::StreamEnd));
+    }
+
+    #[tokio::test]
+    async fn keeps_whitespace_in_text() {
+        let stanzas = stanzas(
+            "<message to='a@example.org'>
+               <body>  two spaces either side  </body>
+               <subject> </subject>
+             </message>",
+        )
+        .await;
+        let message = &stanzas[0];
+        assert_eq!(message.text, "");
+        assert_eq!(message.children.len(), 2);
+        assert_eq!(message.children[0].text, "  two spaces either side  ");
+        assert_eq!(message.children[1].text, " ");
+    }
+
+    #[tokio::test]
+    async fn unescapes_text_and_attributes() {
+        let stanzas = stanzas(
+            "<message to='a&amp;b@example.org'><body>1 &lt; 2 <![CDATA[& <3]]></body></message>",
+        )
+        .await;
+        assert_eq!(stanzas[0].get_attr("to"), Some("a&b@example.org"));
+        assert_eq!(stanzas[0].children[0].text, "1 < 2 & <3");
+    }
+
+    #[test]
+    fn writes_escaped_xml() {
+        let message = Element::new("message")
+            .ns("jabber:client")
+            .attr("to", "o'brien@example.org")
+            .child(Element::new("body").text("1 < 2"))
+            .child(Element::new("active").ns("http://jabber.org/protocol/chatstates"));
+        assert_eq!(
+            message.to_xml(),
+            "<message xmlns='jabber:client' to='o&apos;brien@example.org'>\
+             <body>1 &lt; 2</body><active xmlns='http://jabber.org/protocol/chatstates'/></message>"
+        );
+    }
+}
@@ -0,0 +1,360 @@
+use std::{collections::HashMap, time::Duration};
+
+use reqwest::{StatusCode, Url};
+use serde::Serialize;
+use tokio::{sync::Mutex, task::JoinHandle};
+
+use crate::{
+    actions::contacts::{contact_from_card, contact_to_card},
+    events::AppEvent,
+    state::{
+        carddavsync::{CardDavSyncState, SyncedCard},
+        contacts::{Contact, ContactStore},
+        messagestore::now_millis,
+        settings::ConflictPolicy,
+        TauriState,
+    },
+    vcard::{self, Version},
+};
+
+use self::client::{CardDavClient, CardDavError, DavResponse};
+
+pub mod client;
+
+/// How many cards are fetched in one REPORT
+const MULTIGET_BATCH: usize = 50;
+/// How often to check whether sync was turned on
+const DISABLED_POLL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncReport {
+    /// Contacts added or changed here from the server
+    pub pulled: u32,
+    /// Contacts uploaded to the server
+    pub pushed: u32,
+    /// Contacts removed here because they were deleted on the server
+    pub removed: u32,
+    /// Cards deleted on the server because the contact was deleted here
+    pub deleted: u32,
+    /// Contacts that changed on both sides since the last sync
+    pub conflicts: u32,
+}
+
+/**
+ * The server's version of a contact that was also changed here, resolved
+ * by the configured policy. None keeps the local one.
+ */
+fn resolve_conflict(policy: ConflictPolicy, local: &Contact, remote: Contact) -> Option<Contact> {
+    match policy {
+        ConflictPolicy::Server => Some(remote),
+        ConflictPolicy::Local => None,
+        ConflictPolicy::Merge => {
+            let mut merged = remote;
+            for handle in local.handles.iter() {
+                if !merged.handles.contains(handle) {
+                    merged.handles.push(handle.clone());
+                }
+            }
+            if merged.photo.is_none() {
+                merged.photo = local.photo.clone();
+            }
+            Some(merged)
+        }
+    }
+}
+
+struct SyncRun<'a> {
+    client: &'a CardDavClient,
+    address_book: &'a Url,
+    contacts: &'a Mutex<ContactStore>,
+    state: &'a mut CardDavSyncState,
+    default_region: String,
+    policy: ConflictPolicy,
+    report: SyncReport,
+}
+
+impl SyncRun<'_> {
+    /**
+     * Take a card from the server that is new or changed since the last sync
+     */
+    async fn pull(&mut self, response: DavResponse) -> Result<(), std::io::Error> {
+        let Some(data) = response.address_data else {
+            return Ok(());
+        };
+        let cards = match vcard::parse(&data) {
+            Ok(cards) => cards,
+            Err(e) => {
+                log::warn!("Skipping card {} on the server: {}", response.href, e);
+                return Ok(());
+            }
+        };
+        let Some(remote) = cards
+            .first()
+            .and_then(|card| contact_from_card(card, &self.default_region))
+        else {
+            log::warn!("Skipping card {} without a name or handle", response.href);
+            return Ok(());
+        };
+
+        let mut contacts = self.contacts.lock().await;
+        let known = self.state.by_href(&response.href).cloned();
+        let local = known
+            .as_ref()
+            .and_then(|card| contacts.get(&card.contact_id))
+            .or_else(|| contacts.get_by_uid(&remote.uid))
+            .cloned();
+        let Some(local) = local else {
+            // New on the server, or deleted here after it changed there, in
+            // which case the change wins
+            contacts.import(vec![remote.clone()])?;
+            let Some(contact) = contacts.get_by_uid(&remote.uid) else {
+                return Ok(());
+            };
+            self.state.set(SyncedCard {
+                href: response.href,
+                etag: response.etag,
+                contact_id: contact.id.clone(),
+                synced_updated: contact.updated,
+            });
+            self.report.pulled += 1;
+            return Ok(());
+        };
+
+        // A contact we have but never synced counts as changed here, so
+        // its handles are not lost to the server's copy
+        let changed_here = known.map_or(true, |card| local.updated > card.synced_updated);
+        let resolved = if changed_here {
+            self.report.conflicts += 1;
+            resolve_conflict(self.policy, &local, remote.clone())
+        } else {
+            Some(remote.clone())
+        };
+        let Some(mut contact) = resolved else {
+            // Pushed over the server's copy, which is now the one we saw
+            self.state.set(SyncedCard {
+                href: response.href,
+                etag: response.etag,
+                contact_id: local.id,
+                synced_updated: 0,
+            });
+            return Ok(());
+        };
+        contact.id = local.id;
+        let matches_remote = contact.handles == remote.handles && contact.photo == remote.photo;
+        let saved = contacts.put(contact)?;
+        self.state.set(SyncedCard {
+            href: response.href,
+            etag: response.etag,
+            contact_id: saved.id,
+            // What was merged in still has to go to the server
+            synced_updated: if matches_remote { saved.updated } else { 0 },
+        });
+        self.report.pulled += 1;
+        Ok(())
+    }
+
+    /**
+     * Forget cards that were deleted on the server, and the contacts they
+     * were synced with unless those changed here since
+     */
+    async fn remove_deleted(
+        &mut self,
+        remote: &HashMap<String, Option<String>>,
+    ) -> Result<(), std::io::Error> {
+        let deleted = self.state.deleted(remote);
+        let mut contacts = self.contacts.lock().await;
+        for card in deleted {
+            self.state.remove(&card.href);
+            let changed_here = contacts
+                .get(&card.contact_id)
+                .is_some_and(|contact| contact.updated > card.synced_updated);
+            // Changed contacts are uploaded again as new cards
+            if !changed_here && contacts.remove(&card.contact_id)?.is_some() {
+                self.report.removed += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Upload contacts that are new or changed since the last sync
+     */
+    async fn push(&mut self) -> Result<(), CardDavError> {
+        let changed: Vec<Contact> = {
+            let contacts = self.contacts.lock().await;
+            contacts
+                .entries()
+                .into_iter()
+                .filter(|contact| {
+                    self.state
+                        .by_contact(&contact.id)
+                        .map_or(true, |card| contact.updated > card.synced_updated)
+                })
+                .cloned()
+                .collect()
+        };
+        for contact in changed {
+            let known = self.state.by_contact(&contact.id).cloned();
+            let (url, etag) = match &known {
+                Some(card) => (self.address_book.join(&card.href)?, card.etag.as_deref()),
+                // Named by ID, since UIDs of imported cards can be anything
+                None => (
+                    self.address_book.join(&format!("{}.vcf", contact.id))?,
+                    None,
+                ),
+            };
+            let card = contact_to_card(&contact, Version::V3);
+            match self.client.put(&url, card, etag).await {
+                Ok(etag) => {
+                    self.state.set(SyncedCard {
+                        href: known.map_or_else(|| url.path().to_owned(), |card| card.href),
+                        etag,
+                        contact_id: contact.id,
+                        synced_updated: contact.updated,
+                    });
+                    self.report.pushed += 1;
+                }
+                Err(CardDavError::PreconditionFailed) => {
+                    log::warn!("{} changed on the server, syncing it next time", url);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Delete the cards of contacts that were deleted here
+     */
+    async fn delete_removed(&mut self) -> Result<(), CardDavError> {
+        let removed: Vec<SyncedCard> = {
+            let contacts = self.contacts.lock().await;
+            self.state
+                .cards()
+                .iter()
+                .filter(|card| contacts.get(&card.contact_id).is_none())
+                .cloned()
+                .collect()
+        };
+        for card in removed {
+            let url = self.address_book.join(&card.href)?;
+            match self.client.delete(&url, card.etag.as_deref()).await {
+                Ok(()) => {
+                    self.state.remove(&card.href);
+                    self.report.deleted += 1;
+                }
+                // Pulled again next time, since it changed after we deleted it
+                Err(CardDavError::PreconditionFailed) => {
+                    log::warn!("{} changed on the server, not deleting it", url);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn run(&mut self) -> Result<(), CardDavError> {
+        let remote = self.client.list(self.address_book).await?;
+        let changed = self.state.changed(&remote);
+        for batch in changed.chunks(MULTIGET_BATCH) {
+            let responses = self.client.multiget(self.address_book, batch).await?;
+            for response in responses.into_iter().filter(|response| response.found) {
+                self.pull(response).await?;
+            }
+        }
+        self.remove_deleted(&remote).await?;
+        self.push().await?;
+        self.delete_removed().await
+    }
+}
+
+/**
+ * Sync contacts both ways with the configured CardDAV address book,
+ * transferring only the cards that changed on either side since last time
+ */
+pub async fn sync_contacts(state: &TauriState) -> Result<SyncReport, CardDavError> {
+    let (settings, contacts, sync_state, events) = {
+        let state = state.0.lock().await;
+        (
+            state.settings.clone(),
+            state.contacts.clone(),
+            state.carddav.clone(),
+            state.events.clone(),
+        )
+    };
+    let (config, default_region) = {
+        let settings = settings.lock().await;
+        (
+            settings.carddav.clone(),
+            settings.handles.default_region.clone(),
+        )
+    };
+    if config.url.is_empty() {
+        return Err(CardDavError::NotConfigured);
+    }
+    let client = CardDavClient::new(&config.url, &config.username, &config.password)?;
+
+    // Held for the whole sync, so two never run at once
+    let mut sync_state = sync_state.lock().await;
+    let address_book = match sync_state.address_book(&config.url) {
+        Some(address_book) => Url::parse(address_book)?,
+        None => {
+            let address_book = client.discover().await?;
+            log::info!("Syncing contacts with {}", address_book);
+            sync_state.set_address_book(&config.url, Some(address_book.to_string()));
+            address_book
+        }
+    };
+    let mut sync = SyncRun {
+        client: &client,
+        address_book: &address_book,
+        contacts: &contacts,
+        state: &mut *sync_state,
+        default_region,
+        policy: config.conflicts,
+        report: SyncReport::default(),
+    };
+    let result = sync.run().await;
+    let report = sync.report;
+    if matches!(result, Err(CardDavError::Status(StatusCode::NOT_FOUND))) {
+        // Discover it again next time, in case it moved
+        sync_state.set_address_book(&config.url, None);
+    }
+    if result.is_ok() {
+        sync_state.set_last_sync(now_millis());
+    }
+    sync_state.save()?;
+    result?;
+    log::info!(
+        "Synced contacts: {} pulled, {} pushed, {} removed, {} deleted, {} conflicts",
+        report.pulled,
+        report.pushed,
+        report.removed,
+        report.deleted,
+        report.conflicts
+    );
+    events.publish(AppEvent::ContactsSynced { report });
+    Ok(report)
+}
+
+/**
+ * Sync contacts on the configured interval while CardDAV sync is enabled
+ */
+pub fn spawn_carddav_sync(state: TauriState) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let settings = state.0.lock().await.settings.clone();
+        loop {
+            let config = settings.lock().await.carddav.clone();
+            if !config.enabled {
+                tokio::time::sleep(DISABLED_POLL).await;
+                continue;
+            }
+            if let Err(e) = sync_contacts(&state).await {
+                log::error!("Error syncing contacts: {:?}", e);
+            }
+            let interval = Duration::from_secs(config.interval_minutes.max(1) * 60);
+            tokio::time::sleep(interval).await;
+        }
+    })
+}
@@ -0,0 +1,564 @@
+use std::{collections::HashMap, time::Duration};
+
+use quick_xml::{escape::escape, events::Event, Reader};
+use reqwest::{
+    header::{CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH, LOCATION},
+    Method, RequestBuilder, StatusCode, Url,
+};
+
+/// Servers like Nextcloud redirect from `/.well-known/carddav`
+const MAX_REDIRECTS: usize = 5;
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum CardDavError {
+    NotConfigured,
+    UrlError(url::ParseError),
+    RequestError(reqwest::Error),
+    Status(StatusCode),
+    XmlError(quick_xml::Error),
+    TooManyRedirects,
+    /// Redirected to another server, which our credentials are not sent to
+    CrossOriginRedirect(Url),
+    /// Nothing under the configured URL is an address book
+    NoAddressBook,
+    /// A successful response that is not a WebDAV multistatus, such as a
+    /// login page from a proxy
+    NotMultistatus,
+    /// The card changed on the server since we last saw it
+    PreconditionFailed,
+    IOError(std::io::Error),
+}
+
+impl From<url::ParseError> for CardDavError {
+    fn from(error: url::ParseError) -> Self {
+        CardDavError::UrlError(error)
+    }
+}
+
+impl From<reqwest::Error> for CardDavError {
+    fn from(error: reqwest::Error) -> Self {
+        CardDavError::RequestError(error)
+    }
+}
+
+impl From<std::io::Error> for CardDavError {
+    fn from(error: std::io::Error) -> Self {
+        CardDavError::IOError(error)
+    }
+}
+
+impl From<quick_xml::Error> for CardDavError {
+    fn from(error: quick_xml::Error) -> Self {
+        CardDavError::XmlError(error)
+    }
+}
+
+/**
+ * An XML element by its local name, since servers pick their own prefixes
+ * for the DAV: and CardDAV namespaces
+ */
+#[derive(Debug, Default)]
+struct Node {
+    name: String,
+    text: String,
+    children: Vec<Node>,
+}
+
+impl Node {
+    fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Node> {
+        self.children.iter().filter(move |child| child.name == name)
+    }
+}
+
+fn parse_xml(text: &str) -> Result<Node, quick_xml::Error> {
+    let mut reader = Reader::from_str(text);
+    let mut stack = vec![Node::default()];
+    loop {
+        match reader.read_event()? {
+            Event::Start(start) => stack.push(Node {
+                name: String::from_utf8_lossy(start.local_name().as_ref()).into_owned(),
+                ..Default::default()
+            }),
+            Event::Empty(start) => {
+                let node = Node {
+                    name: String::from_utf8_lossy(start.local_name().as_ref()).into_owned(),
+                    ..Default::default()
+                };
+                stack.last_mut().unwrap().children.push(node);
+            }
+            Event::End(_) if stack.len() > 1 => {
+                let node = stack.pop().unwrap();
+                stack.last_mut().unwrap().children.push(node);
+            }
+            Event::Text(text) => stack.last_mut().unwrap().text.push_str(&text.unescape()?),
+            Event::CData(data) => stack
+                .last_mut()
+                .unwrap()
+                .text
+                .push_str(&String::from_utf8_lossy(&data.into_inner())),
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(stack.swap_remove(0))
+}
+
+/**
+ * One `response` of a multistatus, with the properties that were found
+ */
+#[derive(Debug, Default)]
+pub struct DavResponse {
+    pub href: String,
+    /// False for a card that no longer exists
+    pub found: bool,
+    pub etag: Option<String>,
+    pub address_data: Option<String>,
+    pub collection: bool,
+    pub address_book: bool,
+    /// The href in `current-user-principal`
+    pub principal: Option<String>,
+    /// The href in `addressbook-home-set`
+    pub home_set: Option<String>,
+}
+
+/**
+ * Whether a propstat has the properties that were found, which it says in
+ * its status
+ */
+fn is_found(propstat: &Node) -> bool {
+    propstat
+        .child("status")
+        .is_some_and(|status| status.text.contains(" 200 "))
+}
+
+fn parse_multistatus(text: &str) -> Result<Vec<DavResponse>, CardDavError> {
+    let root = parse_xml(text)?;
+    let multistatus = root
+        .child("multistatus")
+        .ok_or(CardDavError::NotMultistatus)?;
+    let responses = multistatus.children("response").map(|response| {
+        let mut parsed = DavResponse {
+            href: response
+                .child("href")
+                .map(|href| href.text.trim().to_owned())
+                .unwrap_or_default(),
+            found: response
+                .child("status")
+                .is_some_and(|status| status.text.contains(" 200 ")),
+            ..Default::default()
+        };
+        let props = response
+            .children("propstat")
+            .filter(|propstat| is_found(propstat))
+            .filter_map(|propstat| propstat.child("prop"));
+        for prop in props {
+            parsed.found = true;
+            for property in prop.children.iter() {
+                let href = property
+                    .child("href")
+                    .map(|href| href.text.trim().to_owned());
+                match property.name.as_str() {
+                    "getetag" => parsed.etag = Some(property.text.trim().to_owned()),
+                    "address-data" => parsed.address_data = Some(property.text.clone()),
+                    "resourcetype" => {
+                        parsed.collection = property.child("collection").is_some();
+                        parsed.address_book = property.child("addressbook").is_some();
+                    }
+                    "current-user-principal" => parsed.principal = href,
+                    "addressbook-home-set" => parsed.home_set = href,
+                    _ => {}
+                }
+            }
+        }
+        parsed
+    });
+    Ok(responses.collect())
+}
+
+/**
+ * Whether a redirect stays on the same server, allowing an upgrade from
+ * http to https
+ */
+fn same_server(from: &Url, to: &Url) -> bool {
+    from.origin() == to.origin()
+        || (from.scheme() == "http"
+            && to.scheme() == "https"
+            && from.host_str() == to.host_str()
+            && from.port().is_none()
+            && to.port().is_none())
+}
+
+/**
+ * The cards in the responses to a listing, leaving out the address book
+ * itself
+ */
+fn cards(responses: Vec<DavResponse>) -> HashMap<String, Option<String>> {
+    responses
+        .into_iter()
+        .filter(|response| response.found && !response.collection)
+        .map(|response| (response.href, response.etag))
+        .collect()
+}
+
+fn dav_method(name: &'static str) -> Method {
+    Method::from_bytes(name.as_bytes()).expect("WebDAV methods are valid tokens")
+}
+
+/**
+ * The parts of CardDAV (RFC 6352) and WebDAV a contact sync needs
+ */
+pub struct CardDavClient {
+    http: reqwest::Client,
+    url: Url,
+    username: String,
+    password: String,
+}
+
+impl CardDavClient {
+    pub fn new(url: &str, username: &str, password: &str) -> Result<CardDavClient, CardDavError> {
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(TIMEOUT)
+            .build()?;
+        Ok(CardDavClient {
+            http,
+            url: Url::parse(url)?,
+            username: username.to_owned(),
+            password: password.to_owned(),
+        })
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        if self.username.is_empty() {
+            request
+        } else {
+            request.basic_auth(&self.username, Some(&self.password))
+        }
+    }
+
+    /**
+     * Send a PROPFIND or REPORT, following redirects without turning it
+     * into a GET the way reqwest would
+     *
+     * Returns the URL that answered, which relative hrefs are resolved
+     * against, and its responses
+     */
+    async fn dav(
+        &self,
+        method: &'static str,
+        url: &Url,
+        depth: &str,
+        body: String,
+    ) -> Result<(Url, Vec<DavResponse>), CardDavError> {
+        let mut url = url.clone();
+        for _ in 0..MAX_REDIRECTS {
+            let request = self
+                .http
+                .request(dav_method(method), url.clone())
+                .header("Depth", depth)
+                .header(CONTENT_TYPE, "application/xml; charset=utf-8")
+                .body(body.clone());
+            let response = self.authorize(request).send().await?;
+            let status = response.status();
+            if status.is_redirection() {
+                let location = response
+                    .headers()
+                    .get(LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .ok_or(CardDavError::Status(status))?;
+                let next = url.join(location)?;
+                if !same_server(&url, &next) {
+                    return Err(CardDavError::CrossOriginRedirect(next));
+                }
+                url = next;
+                continue;
+            }
+            if !status.is_success() {
+                return Err(CardDavError::Status(status));
+            }
+            let text = response.text().await?;
+            return Ok((url, parse_multistatus(&text)?));
+        }
+        Err(CardDavError::TooManyRedirects)
+    }
+
+    async fn propfind(
+        &self,
+        url: &Url,
+        depth: &str,
+        props: &str,
+    ) -> Result<(Url, Vec<DavResponse>), CardDavError> {
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+             <d:propfind xmlns:d=\"DAV:\" xmlns:card=\"urn:ietf:params:xml:ns:carddav\">\
+             <d:prop>{}</d:prop></d:propfind>",
+            props
+        );
+        self.dav("PROPFIND", url, depth, body).await
+    }
+
+    /**
+     * Find the address book to sync: the configured URL if it is one,
+     * otherwise the first one in the home set of the current user, found
+     * from the configured URL or from `/.well-known/carddav`
+     */
+    pub async fn discover(&self) -> Result<Url, CardDavError> {
+        let props = "<d:resourcetype/><d:current-user-principal/><card:addressbook-home-set/>";
+        let (base, responses) = match self.propfind(&self.url, "0", props).await {
+            Ok((base, responses))
+                if responses
+                    .iter()
+                    .any(|r| r.address_book || r.home_set.is_some() || r.principal.is_some()) =>
+            {
+                (base, responses)
+            }
+            _ => {
+                let well_known = self.url.join("/.well-known/carddav")?;
+                self.propfind(&well_known, "0", props).await?
+            }
+        };
+        if responses.iter().any(|response| response.address_book) {
+            return Ok(base);
+        }
+        let mut home_set = responses.iter().find_map(|r| r.home_set.clone());
+        if home_set.is_none() {
+            let Some(principal) = responses.iter().find_map(|r| r.principal.clone()) else {
+                return Err(CardDavError::NoAddressBook);
+            };
+            let principal = base.join(&principal)?;
+            let props = "<card:addressbook-home-set/>";
+            let (_, responses) = self.propfind(&principal, "0", props).await?;
+            home_set = responses.into_iter().find_map(|r| r.home_set);
+        }
+        let home_set = base.join(&home_set.ok_or(CardDavError::NoAddressBook)?)?;
+        let (home_set, responses) = self.propfind(&home_set, "1", "<d:resourcetype/>").await?;
+        let address_book = responses
+            .into_iter()
+            .find(|response| response.address_book)
+            .ok_or(CardDavError::NoAddressBook)?;
+        Ok(home_set.join(&address_book.href)?)
+    }
+
+    /**
+     * The href and ETag of every card in an address book, without an ETag if
+     * the server did not give one
+     */
+    pub async fn list(
+        &self,
+        address_book: &Url,
+    ) -> Result<HashMap<String, Option<String>>, CardDavError> {
+        let props = "<d:resourcetype/><d:getetag/>";
+        let (_, responses) = self.propfind(address_book, "1", props).await?;
+        Ok(cards(responses))
+    }
+
+    /**
+     * Fetch cards by href, with their ETags
+     */
+    pub async fn multiget(
+        &self,
+        address_book: &Url,
+        hrefs: &[String],
+    ) -> Result<Vec<DavResponse>, CardDavError> {
+        let hrefs: String = hrefs
+            .iter()
+            .map(|href| format!("<d:href>{}</d:href>", escape(href.as_str())))
+            .collect();
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+             <card:addressbook-multiget xmlns:d=\"DAV:\" \
+             xmlns:card=\"urn:ietf:params:xml:ns:carddav\">\
+             <d:prop><d:getetag/><card:address-data/></d:prop>{}\
+             </card:addressbook-multiget>",
+            hrefs
+        );
+        let (_, responses) = self.dav("REPORT", address_book, "1", body).await?;
+        Ok(responses)
+    }
+
+    /**
+     * Create a card, or replace it if it still has the given ETag
+     *
+     * Returns its new ETag, if the server says
+     */
+    pub async fn put(
+        &self,
+        url: &Url,
+        card: String,
+        etag: Option<&str>,
+    ) -> Result<Option<String>, CardDavError> {
+        let request = self
+            .http
+            .put(url.clone())
+            .header(CONTENT_TYPE, "text/vcard; charset=utf-8")
+            .body(card);
+        let request = match etag {
+            Some(etag) => request.header(IF_MATCH, etag),
+            None => request.header(IF_NONE_MATCH, "*"),
+        };
+        let response = self.authorize(request).send().await?;
+        match response.status() {
+            StatusCode::PRECONDITION_FAILED => Err(CardDavError::PreconditionFailed),
+            status if status.is_success() => Ok(response
+                .headers()
+                .get(ETAG)
+                .and_then(|etag| etag.to_str().ok())
+                .map(str::to_owned)),
+            status => Err(CardDavError::Status(status)),
+        }
+    }
+
+    /**
+     * Delete a card if it still has the given ETag
+     */
+    pub async fn delete(&self, url: &Url, etag: Option<&str>) -> Result<(), CardDavError> {
+        let mut request = self.http.delete(url.clone());
+        if let Some(etag) = etag {
+            request = request.header(IF_MATCH, etag);
+        }
+        let response = self.authorize(request).send().await?;
+        match response.status() {
+            StatusCode::PRECONDITION_FAILED => Err(CardDavError::PreconditionFailed),
+            StatusCode::NOT_FOUND => Ok(()),
+            status if status.is_success() => Ok(()),
+            status => Err(CardDavError::Status(status)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LISTING: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:multistatus xmlns:d="DAV:" xmlns:card="urn:ietf:params:xml:ns:carddav">
+  <d:response>
+    <d:href>/dav/contacts/</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype><d:collection/><card:addressbook/></d:resourcetype>
+        <d:getetag>"book"</d:getetag>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/dav/contacts/a.vcf</d:href>
+    <d:propstat>
+      <d:prop><d:resourcetype/><d:getetag>"1"</d:getetag></d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/dav/contacts/b.vcf</d:href>
+    <d:propstat>
+      <d:prop><d:resourcetype/></d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+    <d:propstat>
+      <d:prop><d:getetag/></d:prop>
+      <d:status>HTTP/1.1 404 Not Found</d:status>
+    </d:propstat>
+  </d:response>
+</d:multistatus>"#;
+
+    #[test]
+    fn a_listing_has_the_cards_with_their_etags() {
+        let responses = parse_multistatus(LISTING).unwrap();
+        assert_eq!(responses.len(), 3);
+        assert!(responses[0].collection && responses[0].address_book);
+        assert_eq!(responses[1].etag.as_deref(), Some("\"1\""));
+
+        let cards = cards(responses);
+        assert_eq!(cards.len(), 2);
+        assert_eq!(cards["/dav/contacts/a.vcf"].as_deref(), Some("\"1\""));
+        // Still a card, so it is not taken for deleted
+        assert_eq!(cards["/dav/contacts/b.vcf"], None);
+    }
+
+    #[test]
+    fn prefixes_and_missing_cards_are_understood() {
+        let text = r#"<multistatus xmlns="DAV:" xmlns:C="urn:ietf:params:xml:ns:carddav">
+  <response>
+    <href>/a.vcf</href>
+    <propstat>
+      <prop>
+        <getetag>"2"</getetag>
+        <C:address-data><![CDATA[BEGIN:VCARD
+FN:A &amp; B
+END:VCARD
+]]></C:address-data>
+      </prop>
+      <status>HTTP/1.1 200 OK</status>
+    </propstat>
+  </response>
+  <response>
+    <href>/gone.vcf</href>
+    <status>HTTP/1.1 404 Not Found</status>
+  </response>
+</multistatus>"#;
+        let responses = parse_multistatus(text).unwrap();
+        assert!(responses[0].found);
+        assert_eq!(
+            responses[0].address_data.as_deref(),
+            Some("BEGIN:VCARD\nFN:A &amp; B\nEND:VCARD\n")
+        );
+        assert_eq!(responses[1].href, "/gone.vcf");
+        assert!(!responses[1].found);
+        assert_eq!(responses[1].etag, None);
+    }
+
+    #[test]
+    fn discovery_properties_are_read_from_their_hrefs() {
+        let text = r#"<d:multistatus xmlns:d="DAV:" xmlns:cr="urn:ietf:params:xml:ns:carddav">
+  <d:response>
+    <d:href>/</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:current-user-principal><d:href> /principals/me/ </d:href></d:current-user-principal>
+        <cr:addressbook-home-set><d:href>/dav/me/</d:href></cr:addressbook-home-set>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+</d:multistatus>"#;
+        let responses = parse_multistatus(text).unwrap();
+        assert_eq!(responses[0].principal.as_deref(), Some("/principals/me/"));
+        assert_eq!(responses[0].home_set.as_deref(), Some("/dav/me/"));
+        assert!(!responses[0].address_book);
+    }
+
+    #[test]
+    fn anything_but_a_multistatus_is_an_error() {
+        for text in ["<html><body>Sign in</body></html>", ""] {
+            assert!(matches!(
+                parse_multistatus(text),
+                Err(CardDavError::NotMultistatus)
+            ));
+        }
+        let empty = r#"<d:multistatus xmlns:d="DAV:"></d:multistatus>"#;
+        assert!(parse_multistatus(empty).unwrap().is_empty());
+    }
+
+    #[test]
+    fn redirects_must_stay_on_the_server() {
+        let from = Url::parse("http://example.com/.well-known/carddav").unwrap();
+        let same = |to: &str| same_server(&from, &Url::parse(to).unwrap());
+        assert!(same("http://example.com/remote.php/dav/"));
+        assert!(same("https://example.com/remote.php/dav/"));
+        assert!(!same("https://example.com:8443/dav/"));
+        assert!(!same("http://evil.example/dav/"));
+        assert!(!same("http://sub.example.com/dav/"));
+
+        let from = Url::parse("https://example.com/dav/").unwrap();
+        assert!(!same_server(
+            &from,
+            &Url::parse("http://example.com/dav/").unwrap()
+        ));
+    }
+}
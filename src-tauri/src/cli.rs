@@ -0,0 +1,350 @@
+use std::{
+    io::{BufRead, Write},
+    process::ExitCode,
+};
+
+use clap::{Parser, Subcommand};
+use serde_json::{json, Value};
+
+use crate::{
+    actions::{
+        identities::save_identities,
+        init::do_login,
+        receive::do_receive_event,
+        send::{do_send_message, resolve_handle},
+    },
+    carddav::sync_contacts,
+    events::AppEvent,
+    imessage::handle::HandleError,
+    state::{rustpushstate::IMClientError, TauriState},
+};
+
+/**
+ * Running the app with a subcommand uses it as a command-line client
+ * instead of opening the GUI
+ *
+ * Results are printed to stdout as JSON, errors to stderr as JSON, and the
+ * exit code says what went wrong
+ */
+#[derive(Parser)]
+#[command(name = "cross-messenger", about = "A Tauri GUI for iMessage")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Log in to an Apple ID, prompting for the password and 2FA code
+    Login {
+        #[arg(long)]
+        username: Option<String>,
+    },
+    /// List logged in accounts and their handles
+    Accounts,
+    /// Manage the handle messages are sent from
+    Handles {
+        #[command(subcommand)]
+        command: HandlesCommand,
+    },
+    /// Send a text message to a handle
+    Send { handle: String, text: String },
+    /// Print incoming messages as they arrive
+    Listen {
+        /// Print every event, including receipts, as one JSON object per line
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the stored message history as JSON
+    Export {
+        #[arg(long)]
+        conversation: Option<String>,
+    },
+    /// Manage contacts
+    Contacts {
+        #[command(subcommand)]
+        command: ContactsCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum HandlesCommand {
+    /// Send messages from this handle from now on
+    Select { handle: String },
+}
+
+#[derive(Subcommand)]
+pub enum ContactsCommand {
+    /// Sync contacts with the CardDAV server in the settings
+    Sync,
+}
+
+#[derive(Debug)]
+pub enum CliError {
+    InitFailed(IMClientError),
+    NotLoggedIn,
+    LoginFailed(String),
+    TwoFactorFailed,
+    HandleNotFound,
+    SendFailed(String),
+    ConnectionClosed,
+    InvalidHandle(HandleError),
+    SyncFailed(String),
+    IOError(std::io::Error),
+}
+
+impl From<std::io::Error> for CliError {
+    fn from(error: std::io::Error) -> Self {
+        CliError::IOError(error)
+    }
+}
+
+impl CliError {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            CliError::IOError(_) => 1,
+            // 2 is used by clap for usage errors
+            CliError::InitFailed(_) => 3,
+            CliError::NotLoggedIn => 4,
+            CliError::LoginFailed(_) => 5,
+            CliError::TwoFactorFailed => 6,
+            CliError::HandleNotFound => 7,
+            CliError::SendFailed(_) => 8,
+            CliError::ConnectionClosed => 9,
+            CliError::InvalidHandle(_) => 10,
+            CliError::SyncFailed(_) => 11,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            CliError::InitFailed(_) => "initFailed",
+            CliError::NotLoggedIn => "notLoggedIn",
+            CliError::LoginFailed(_) => "loginFailed",
+            CliError::TwoFactorFailed => "twoFactorFailed",
+            CliError::HandleNotFound => "handleNotFound",
+            CliError::SendFailed(_) => "sendFailed",
+            CliError::ConnectionClosed => "connectionClosed",
+            CliError::InvalidHandle(_) => "invalidHandle",
+            CliError::SyncFailed(_) => "syncFailed",
+            CliError::IOError(_) => "ioError",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            CliError::InitFailed(error) => format!("{:?}", error),
+            CliError::NotLoggedIn => "No logged in users".to_owned(),
+            CliError::LoginFailed(error) => error.to_owned(),
+            CliError::TwoFactorFailed => "Two-factor code was not accepted".to_owned(),
+            CliError::HandleNotFound => "Handle not found".to_owned(),
+            CliError::SendFailed(error) => error.to_owned(),
+            CliError::ConnectionClosed => "Connection closed".to_owned(),
+            CliError::InvalidHandle(error) => error.to_string(),
+            CliError::SyncFailed(error) => error.to_owned(),
+            CliError::IOError(error) => error.to_string(),
+        }
+    }
+}
+
+pub async fn run(command: Command) -> ExitCode {
+    let result = match TauriState::new().await {
+        Ok(state) => run_command(&state, command).await,
+        Err(e) => Err(CliError::InitFailed(e)),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!(
+                "{}",
+                json!({ "error": e.code(), "message": e.message() })
+            );
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+async fn run_command(state: &TauriState, command: Command) -> Result<(), CliError> {
+    match command {
+        Command::Login { username } => login(state, username).await,
+        Command::Accounts => accounts(state).await,
+        Command::Handles {
+            command: HandlesCommand::Select { handle },
+        } => select_handle(state, handle).await,
+        Command::Send { handle, text } => send(state, handle, text).await,
+        Command::Listen { json } => listen(state, json).await,
+        Command::Export { conversation } => export(state, conversation).await,
+        Command::Contacts {
+            command: ContactsCommand::Sync,
+        } => sync(state).await,
+    }
+}
+
+fn print_json(value: &Value) -> Result<(), CliError> {
+    let mut stdout = std::io::stdout().lock();
+    writeln!(stdout, "{}", value)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+fn prompt(label: &str) -> Result<String, CliError> {
+    eprint!("{}", label);
+    std::io::stderr().flush()?;
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim().to_owned())
+}
+
+async fn login(state: &TauriState, username: Option<String>) -> Result<(), CliError> {
+    let username = match username {
+        Some(username) => username,
+        None => prompt("Apple ID: ")?,
+    };
+    let password = rpassword::prompt_password("Password: ")?;
+    let rust_push = state.0.lock().await.rust_push.clone();
+
+    // The first attempt without a code is what makes Apple send one
+    let mut code: Option<String> = None;
+    loop {
+        match do_login(
+            rust_push.clone(),
+            username.clone(),
+            password.clone(),
+            code.clone(),
+        )
+        .await
+        {
+            Ok(true) => break,
+            Ok(false) if code.is_none() => code = Some(prompt("Two-factor code: ")?),
+            Ok(false) => return Err(CliError::TwoFactorFailed),
+            Err(e) => return Err(CliError::LoginFailed(format!("{:?}", e))),
+        }
+    }
+    accounts(state).await
+}
+
+async fn accounts(state: &TauriState) -> Result<(), CliError> {
+    let rust_push = state.0.lock().await.rust_push.clone();
+    let mut rust_push = rust_push.lock().await;
+    let selected_handle = rust_push.get_active_user().await.map(|(_, handle)| handle);
+    let accounts: Vec<Value> = rust_push
+        .client
+        .users
+        .iter()
+        .map(|user| {
+            json!({
+                "userId": user.user_id,
+                "handles": user.handles,
+            })
+        })
+        .collect();
+    print_json(&json!({
+        "accounts": accounts,
+        "selectedHandle": selected_handle,
+    }))
+}
+
+async fn select_handle(state: &TauriState, handle: String) -> Result<(), CliError> {
+    let rust_push = state.0.lock().await.rust_push.clone();
+    let mut rust_push = rust_push.lock().await;
+    match rust_push.select_handle(&handle).await {
+        Ok(_) => print_json(&json!({ "selectedHandle": handle })),
+        Err(IMClientError::HandleNotFound) => Err(CliError::HandleNotFound),
+        Err(IMClientError::IOError(e)) => Err(CliError::IOError(e)),
+        Err(e) => Err(CliError::InitFailed(e)),
+    }
+}
+
+async fn send(state: &TauriState, handle: String, text: String) -> Result<(), CliError> {
+    let (rust_push, keys, messages, contacts, settings, events) = {
+        let state = state.0.lock().await;
+        (
+            state.rust_push.clone(),
+            state.keys.clone(),
+            state.messages.clone(),
+            state.contacts.clone(),
+            state.settings.clone(),
+            state.events.clone(),
+        )
+    };
+    let handle = resolve_handle(&settings, &handle)
+        .await
+        .map_err(CliError::InvalidHandle)?;
+    if rust_push.lock().await.client.users.is_empty() {
+        return Err(CliError::NotLoggedIn);
+    }
+    let result = do_send_message(
+        rust_push.clone(),
+        keys,
+        messages,
+        contacts,
+        events,
+        text,
+        handle,
+    )
+    .await;
+    // Nothing else is running to save what was looked up
+    if let Err(e) = save_identities(&rust_push).await {
+        log::error!("Error saving identities: {:?}", e);
+    }
+    match result {
+        Ok(id) => print_json(&json!({ "id": id })),
+        Err(e) => Err(CliError::SendFailed(e.0.to_string())),
+    }
+}
+
+async fn listen(state: &TauriState, as_json: bool) -> Result<(), CliError> {
+    let (rust_push, messages, attachments, contacts) = {
+        let state = state.0.lock().await;
+        (
+            state.rust_push.clone(),
+            state.messages.clone(),
+            state.attachments.clone(),
+            state.contacts.clone(),
+        )
+    };
+    if rust_push.lock().await.client.users.is_empty() {
+        return Err(CliError::NotLoggedIn);
+    }
+    loop {
+        let Some(event) = do_receive_event(
+            rust_push.clone(),
+            messages.clone(),
+            attachments.clone(),
+            contacts.clone(),
+        )
+        .await
+        else {
+            return Err(CliError::ConnectionClosed);
+        };
+        if as_json {
+            print_json(&json!(event))?;
+        } else if let AppEvent::MessageReceived { message, contact } = event {
+            let sender = match (&contact, &message.sender) {
+                (Some(contact), _) if !message.from_me => contact.name.as_str(),
+                (_, Some(sender)) => sender.as_str(),
+                (_, None) => "unknown",
+            };
+            let mut stdout = std::io::stdout().lock();
+            writeln!(stdout, "{}: {}", sender, message.text)?;
+            stdout.flush()?;
+        }
+    }
+}
+
+async fn export(state: &TauriState, conversation: Option<String>) -> Result<(), CliError> {
+    let messages = state.0.lock().await.messages.clone();
+    let messages = messages.lock().await;
+    let exported = match conversation {
+        Some(conversation) => json!(messages.conversation(&conversation)),
+        None => json!(messages.messages()),
+    };
+    print_json(&exported)
+}
+
+async fn sync(state: &TauriState) -> Result<(), CliError> {
+    let report = sync_contacts(state)
+        .await
+        .map_err(|e| CliError::SyncFailed(format!("{:?}", e)))?;
+    print_json(&json!(report))
+}
@@ -2,7 +2,9 @@ use tauri::ipc::InvokeError;
 
 use crate::{
     actions::init::do_login,
-    actions::send::do_send_message,
+    actions::outbox::{do_queue_message, OutgoingMessage},
+    actions::send::resolve_handle,
+    imessage::messenger::new_conversation,
     state::{rustpushstate::IMClientError, TauriState},
 };
 
@@ -13,7 +15,7 @@ pub async fn authenticate(
     password: String,
     code: Option<String>,
 ) -> Result<bool, IMClientError> {
-    println!("authenticate: {:?} {:?} {:?}", username, password, code);
+    log::debug!("authenticate: {:?}", username);
     let retval = do_login(
         state.0.lock().await.rust_push.clone(),
         username,
@@ -21,7 +23,7 @@ pub async fn authenticate(
         code,
     )
     .await;
-    println!("authenticate: {:?}", retval);
+    log::debug!("authenticate: {:?}", retval);
     retval
 }
 
@@ -30,9 +32,31 @@ pub async fn send_message(
     state: tauri::State<'_, TauriState>,
     message: String,
     to: String,
-) -> Result<bool, InvokeError> {
-    println!("send_message: {:?} {:?}", message, to);
-    let retval = do_send_message(state.0.lock().await.rust_push.clone(), message, to).await;
-    println!("send_message: {:?}", retval);
+) -> Result<String, InvokeError> {
+    log::debug!("send_message: {:?} {:?}", message, to);
+    let (rust_push, messages, attachments, outbox, settings, events) = {
+        let state = state.0.lock().await;
+        (
+            state.rust_push.clone(),
+            state.messages.clone(),
+            state.attachments.clone(),
+            state.outbox.clone(),
+            state.settings.clone(),
+            state.events.clone(),
+        )
+    };
+    let to = resolve_handle(&settings, &to)
+        .await
+        .map_err(|e| InvokeError::from(e.to_string()))?;
+    let retval = do_queue_message(
+        rust_push,
+        messages,
+        attachments,
+        outbox,
+        events,
+        OutgoingMessage::text(new_conversation(vec![to]), message),
+    )
+    .await;
+    log::debug!("send_message: {:?}", retval);
     retval
 }
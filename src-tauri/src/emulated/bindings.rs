@@ -52,10 +52,10 @@ pub fn generate_validation_data() -> Result<String, ValidationDataError> {
         let sys_module = PyModule::import(py, "sys")?;
         let version_info = sys_module.getattr("version_info")?;
         let version_info_str = version_info.str()?;
-        println!("Python version: {}", version_info_str);
+        log::debug!("Python version: {}", version_info_str);
         let path = sys_module.getattr("path")?;
         let path_str = path.str()?;
-        println!("Python path: {}", path_str);
+        log::debug!("Python path: {}", path_str);
 
         let py_validation_data = generate_validation_data_py(py)?;
         let validation_data = py_validation_data.as_bytes(py).to_vec();
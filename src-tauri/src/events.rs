@@ -0,0 +1,286 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::{
+    carddav::SyncReport,
+    state::{
+        contacts::ResolvedContact,
+        conversationflags::ConversationFlags,
+        messagestore::{MessageStatus, ReactionSummary, StoredMessage, StoredReaction},
+        outbox::OutboxEntry,
+        rustpushstate::AccountStatus,
+        schedule::ScheduledMessage,
+    },
+};
+
+/**
+ * Something that happened which the frontend, the local API or any other
+ * listener may want to react to
+ */
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum AppEvent {
+    /// With the contact of the sender, if they are one
+    MessageReceived {
+        message: StoredMessage,
+        contact: Option<ResolvedContact>,
+    },
+    /// With the contact of the recipient, if there is one and they are one
+    MessageSent {
+        message: StoredMessage,
+        contact: Option<ResolvedContact>,
+    },
+    /// The text of a message changed, its earlier versions are in `edits`
+    MessageEdited { message: StoredMessage },
+    /// A message was taken back and no longer has any content
+    MessageUnsent { message: StoredMessage },
+    /// A receipt for one of our messages, with its status once applied,
+    /// which is None if the message is not in the history
+    #[serde(rename_all = "camelCase")]
+    MessageDelivered {
+        id: String,
+        conversation_id: Option<String>,
+        sender: Option<String>,
+        timestamp: u64,
+        status: Option<MessageStatus>,
+    },
+    #[serde(rename_all = "camelCase")]
+    MessageRead {
+        id: String,
+        conversation_id: Option<String>,
+        sender: Option<String>,
+        timestamp: u64,
+        status: Option<MessageStatus>,
+    },
+    /// We read messages we received in a conversation
+    #[serde(rename_all = "camelCase")]
+    ConversationRead {
+        conversation_id: String,
+        message_ids: Vec<String>,
+        /// Whether a read receipt was sent for them
+        receipt_sent: bool,
+    },
+    /// A tapback was added or removed, with every reaction now on the message
+    #[serde(rename_all = "camelCase")]
+    ReactionChanged {
+        message_id: String,
+        conversation_id: String,
+        reaction: StoredReaction,
+        enabled: bool,
+        reactions: Vec<ReactionSummary>,
+    },
+    /// Someone started or stopped typing, an indicator that is not
+    /// repeated is stopped for them once it expires
+    #[serde(rename_all = "camelCase")]
+    TypingChanged {
+        conversation_id: String,
+        sender: Option<String>,
+        typing: bool,
+        expires_at: Option<u64>,
+    },
+    /// The keys of a verified handle changed, so messages to it may be
+    /// read by a device the user did not check
+    KeysChanged {
+        handle: String,
+        added: Vec<String>,
+        removed: Vec<String>,
+        /// Whether messages to the handle are held until the user
+        /// confirms the new keys
+        held: bool,
+    },
+    /// A conversation was pinned, muted or archived, or no longer is
+    #[serde(rename_all = "camelCase")]
+    ConversationFlagsChanged {
+        conversation_id: String,
+        flags: ConversationFlags,
+    },
+    /// Messages we received and have not read, outside muted conversations,
+    /// for the window badge and tray
+    UnreadCountChanged { count: u32 },
+    /// Contacts were synced with the CardDAV server
+    ContactsSynced { report: SyncReport },
+    /// A message moved along in the outbox
+    OutboxChanged { entry: OutboxEntry },
+    /// A message was scheduled, or its text or time changed
+    ScheduleChanged { scheduled: ScheduledMessage },
+    /// A scheduled message was cancelled, or put in the outbox as the
+    /// message `message_id`
+    #[serde(rename_all = "camelCase")]
+    ScheduleRemoved {
+        scheduled: ScheduledMessage,
+        message_id: Option<String>,
+    },
+    AccountChanged { account: AccountStatus },
+    ConnectionChanged { connected: bool },
+    /// Only reported for large attachments
+    AttachmentProgress {
+        id: String,
+        transferred: u64,
+        total: u64,
+        upload: bool,
+    },
+}
+
+impl AppEvent {
+    /**
+     * The name the event is serialized with
+     */
+    pub fn name(&self) -> &'static str {
+        match self {
+            AppEvent::MessageReceived { .. } => "messageReceived",
+            AppEvent::MessageSent { .. } => "messageSent",
+            AppEvent::MessageEdited { .. } => "messageEdited",
+            AppEvent::MessageUnsent { .. } => "messageUnsent",
+            AppEvent::MessageDelivered { .. } => "messageDelivered",
+            AppEvent::MessageRead { .. } => "messageRead",
+            AppEvent::ConversationRead { .. } => "conversationRead",
+            AppEvent::ReactionChanged { .. } => "reactionChanged",
+            AppEvent::TypingChanged { .. } => "typingChanged",
+            AppEvent::KeysChanged { .. } => "keysChanged",
+            AppEvent::ConversationFlagsChanged { .. } => "conversationFlagsChanged",
+            AppEvent::UnreadCountChanged { .. } => "unreadCountChanged",
+            AppEvent::ContactsSynced { .. } => "contactsSynced",
+            AppEvent::OutboxChanged { .. } => "outboxChanged",
+            AppEvent::ScheduleChanged { .. } => "scheduleChanged",
+            AppEvent::ScheduleRemoved { .. } => "scheduleRemoved",
+            AppEvent::AccountChanged { .. } => "accountChanged",
+            AppEvent::ConnectionChanged { .. } => "connectionChanged",
+            AppEvent::AttachmentProgress { .. } => "attachmentProgress",
+        }
+    }
+
+    /**
+     * Whether the event is only worth anything right away, so it is not
+     * kept to be delivered later
+     */
+    pub fn is_ephemeral(&self) -> bool {
+        matches!(
+            self,
+            AppEvent::TypingChanged { .. } | AppEvent::AttachmentProgress { .. }
+        )
+    }
+
+    /**
+     * The conversation the event belongs to, if it is about a message
+     */
+    pub fn conversation_id(&self) -> Option<&str> {
+        match self {
+            AppEvent::MessageReceived { message, .. }
+            | AppEvent::MessageSent { message, .. }
+            | AppEvent::MessageEdited { message }
+            | AppEvent::MessageUnsent { message } => Some(&message.conversation_id),
+            AppEvent::MessageDelivered {
+                conversation_id, ..
+            }
+            | AppEvent::MessageRead {
+                conversation_id, ..
+            } => conversation_id.as_deref(),
+            AppEvent::ReactionChanged {
+                conversation_id, ..
+            }
+            | AppEvent::ConversationRead {
+                conversation_id, ..
+            }
+            | AppEvent::TypingChanged {
+                conversation_id, ..
+            }
+            | AppEvent::ConversationFlagsChanged {
+                conversation_id, ..
+            } => Some(conversation_id),
+            AppEvent::OutboxChanged { entry } => Some(&entry.conversation_id),
+            AppEvent::ScheduleChanged { scheduled }
+            | AppEvent::ScheduleRemoved { scheduled, .. } => Some(&scheduled.conversation_id),
+            _ => None,
+        }
+    }
+
+    /**
+     * Every handle involved in the event, if it is about a message
+     */
+    pub fn handles(&self) -> Vec<&str> {
+        match self {
+            AppEvent::MessageReceived { message, .. }
+            | AppEvent::MessageSent { message, .. }
+            | AppEvent::MessageEdited { message }
+            | AppEvent::MessageUnsent { message } => message
+                .participants
+                .iter()
+                .map(|participant| participant.as_str())
+                .chain(message.sender.as_deref())
+                .collect(),
+            AppEvent::KeysChanged { handle, .. } => vec![handle.as_str()],
+            AppEvent::MessageDelivered { sender, .. }
+            | AppEvent::MessageRead { sender, .. }
+            | AppEvent::TypingChanged { sender, .. } => sender.as_deref().into_iter().collect(),
+            AppEvent::OutboxChanged { entry } => entry
+                .participants
+                .iter()
+                .map(|participant| participant.as_str())
+                .collect(),
+            AppEvent::ScheduleChanged { scheduled }
+            | AppEvent::ScheduleRemoved { scheduled, .. } => scheduled
+                .participants
+                .iter()
+                .map(|participant| participant.as_str())
+                .collect(),
+            AppEvent::ReactionChanged { reaction, .. } => {
+                reaction.sender.as_deref().into_iter().collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/**
+ * Fan-out of app events to every subscriber
+ *
+ * Subscribers that fall too far behind miss events rather than blocking
+ * the publisher
+ */
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<AppEvent>,
+    /// What the last `ConnectionChanged` said, for subscribers that start
+    /// after it or miss it
+    connected: Arc<AtomicBool>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        let (sender, _) = broadcast::channel(256);
+        EventBus {
+            sender,
+            connected: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn publish(&self, event: AppEvent) {
+        if let AppEvent::ConnectionChanged { connected } = event {
+            self.connected.store(connected, Ordering::SeqCst);
+        }
+        // An error here only means nobody is listening
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<AppEvent> {
+        self.sender.subscribe()
+    }
+
+    /**
+     * Whether we are connected to APNs
+     */
+    pub fn connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus::new()
+    }
+}
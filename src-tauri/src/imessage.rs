@@ -1,2 +1,5 @@
+pub mod attachments;
+pub mod handle;
+pub mod identities;
 pub mod messenger;
 pub mod user;
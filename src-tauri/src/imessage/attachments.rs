@@ -0,0 +1,202 @@
+use std::{io::Cursor, sync::Arc};
+
+use async_trait::async_trait;
+use rustpush::{
+    Attachment, AttachmentType, IMessage, IndexedMessagePart, Message, MessagePart, MessageParts,
+    NormalMessage, PushError,
+};
+use tokio::sync::Mutex;
+
+use crate::state::rustpushstate::RustPushState;
+
+/// Called with the bytes transferred so far and the total
+pub type Progress<'a> = &'a mut (dyn FnMut(usize, usize) + Send);
+
+#[derive(Debug)]
+pub enum TransferError {
+    PushError(PushError),
+    IOError(std::io::Error),
+    InvalidAttachment(serde_json::Error),
+    NotAvailable,
+}
+
+impl From<PushError> for TransferError {
+    fn from(error: PushError) -> Self {
+        TransferError::PushError(error)
+    }
+}
+
+impl From<std::io::Error> for TransferError {
+    fn from(error: std::io::Error) -> Self {
+        TransferError::IOError(error)
+    }
+}
+
+impl From<serde_json::Error> for TransferError {
+    fn from(error: serde_json::Error) -> Self {
+        TransferError::InvalidAttachment(error)
+    }
+}
+
+/**
+ * Moves attachment content to and from wherever messages point at
+ */
+#[async_trait]
+pub trait AttachmentTransport: Send + Sync {
+    /**
+     * Upload content, returning the attachment to put in a message
+     */
+    async fn upload(
+        &self,
+        data: Vec<u8>,
+        mime: &str,
+        uti: &str,
+        name: &str,
+        progress: Progress<'_>,
+    ) -> Result<Attachment, TransferError>;
+
+    async fn download(
+        &self,
+        attachment: &Attachment,
+        progress: Progress<'_>,
+    ) -> Result<Vec<u8>, TransferError>;
+}
+
+/**
+ * Apple's MMCS, which is what real iMessage clients use
+ */
+pub struct MmcsTransport {
+    pub state: Arc<Mutex<RustPushState>>,
+}
+
+#[async_trait]
+impl AttachmentTransport for MmcsTransport {
+    async fn upload(
+        &self,
+        data: Vec<u8>,
+        mime: &str,
+        uti: &str,
+        name: &str,
+        progress: Progress<'_>,
+    ) -> Result<Attachment, TransferError> {
+        let apns = self.state.lock().await.apns_connection.clone();
+        let mut reader = Cursor::new(data);
+        Ok(Attachment::new_mmcs(&apns, &mut reader, mime, uti, name, progress).await?)
+    }
+
+    async fn download(
+        &self,
+        attachment: &Attachment,
+        progress: Progress<'_>,
+    ) -> Result<Vec<u8>, TransferError> {
+        let apns = self.state.lock().await.apns_connection.clone();
+        let mut data: Vec<u8> = Vec::new();
+        attachment
+            .get_attachment(&apns, &mut data, progress)
+            .await?;
+        Ok(data)
+    }
+}
+
+/**
+ * A stand-in that never talks to Apple, sending content inline in the
+ * message instead
+ *
+ * Only useful for testing, real clients will not accept large inline
+ * attachments
+ */
+pub struct LocalTransport;
+
+#[async_trait]
+impl AttachmentTransport for LocalTransport {
+    async fn upload(
+        &self,
+        data: Vec<u8>,
+        mime: &str,
+        uti: &str,
+        name: &str,
+        progress: Progress<'_>,
+    ) -> Result<Attachment, TransferError> {
+        progress(data.len(), data.len());
+        Ok(Attachment {
+            a_type: AttachmentType::Inline(data),
+            part: 0,
+            uti_type: uti.to_owned(),
+            mime: mime.to_owned(),
+            name: name.to_owned(),
+            iris: false,
+        })
+    }
+
+    async fn download(
+        &self,
+        attachment: &Attachment,
+        progress: Progress<'_>,
+    ) -> Result<Vec<u8>, TransferError> {
+        match &attachment.a_type {
+            AttachmentType::Inline(data) => {
+                progress(data.len(), data.len());
+                Ok(data.clone())
+            }
+            _ => Err(TransferError::NotAvailable),
+        }
+    }
+}
+
+pub fn attachment_size(attachment: &Attachment) -> u64 {
+    match &attachment.a_type {
+        AttachmentType::Inline(data) => data.len() as u64,
+        AttachmentType::MMCS(mmcs) => mmcs.size as u64,
+    }
+}
+
+/**
+ * The attachments in a message, in order
+ */
+pub fn message_attachments(msg: &IMessage) -> Vec<Attachment> {
+    match &msg.message {
+        Message::Message(normal) => normal
+            .parts
+            .0
+            .iter()
+            .filter_map(|part| match &part.0 {
+                MessagePart::Attachment(attachment) => Some(attachment.clone()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/**
+ * A message with the attachments first, then the text if there is any
+ */
+pub fn message_with_attachments(text: String, attachments: Vec<Attachment>) -> NormalMessage {
+    let mut parts: Vec<IndexedMessagePart> = attachments
+        .into_iter()
+        .map(|attachment| IndexedMessagePart(MessagePart::Attachment(attachment), None))
+        .collect();
+    if !text.is_empty() {
+        parts.push(IndexedMessagePart(MessagePart::Text(text), None));
+    }
+    let mut message = NormalMessage::new(String::new());
+    message.parts = MessageParts(parts);
+    message
+}
+
+/**
+ * What to keep of an attachment to download it again
+ *
+ * Inline attachments carry their content, which goes in the attachment
+ * store instead, so there is nothing to keep
+ */
+pub fn to_remote(attachment: &Attachment) -> Option<serde_json::Value> {
+    match attachment.a_type {
+        AttachmentType::Inline(_) => None,
+        AttachmentType::MMCS(_) => serde_json::to_value(attachment).ok(),
+    }
+}
+
+pub fn from_remote(remote: &serde_json::Value) -> Result<Attachment, TransferError> {
+    Ok(serde_json::from_value(remote.clone())?)
+}
@@ -0,0 +1,243 @@
+use phonenumber::{country, Mode};
+
+/// Longest local part RFC 5321 allows
+const MAX_LOCAL_PART: usize = 64;
+/// Longest address RFC 5321 allows
+const MAX_EMAIL: usize = 254;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandleError {
+    Empty,
+    InvalidPhoneNumber(String),
+    InvalidEmail(String),
+    /// The default region is not an ISO 3166-1 alpha-2 code like "US"
+    UnknownRegion(String),
+}
+
+impl std::fmt::Display for HandleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandleError::Empty => write!(f, "No handle given"),
+            HandleError::InvalidPhoneNumber(input) => {
+                write!(f, "{} is not a valid phone number", input)
+            }
+            HandleError::InvalidEmail(input) => write!(f, "{} is not a valid email address", input),
+            HandleError::UnknownRegion(region) => write!(f, "Unknown region {}", region),
+        }
+    }
+}
+
+/**
+ * A phone number or email address someone can be messaged at
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Handle {
+    /// In E.164 form, e.g. `+15551234567`
+    Phone(String),
+    /// Lower-cased
+    Email(String),
+}
+
+impl Handle {
+    /**
+     * Parse what the user typed, with or without a `tel:` or `mailto:`
+     * prefix. Phone numbers without a country code are taken to be in
+     * `default_region`.
+     */
+    pub fn parse(input: &str, default_region: &str) -> Result<Handle, HandleError> {
+        let input = input.trim();
+        if input.is_empty() {
+            return Err(HandleError::Empty);
+        }
+        if let Some(email) = strip_prefix_ignore_case(input, "mailto:") {
+            return parse_email(email).map(Handle::Email);
+        }
+        if let Some(phone) = strip_prefix_ignore_case(input, "tel:") {
+            return parse_phone(phone, default_region).map(Handle::Phone);
+        }
+        if input.contains('@') {
+            parse_email(input).map(Handle::Email)
+        } else {
+            parse_phone(input, default_region).map(Handle::Phone)
+        }
+    }
+
+    /**
+     * The URI form IDS expects, `tel:+15551234567` or `mailto:a@b.com`
+     */
+    pub fn uri(&self) -> String {
+        match self {
+            Handle::Phone(number) => format!("tel:{}", number),
+            Handle::Email(address) => format!("mailto:{}", address),
+        }
+    }
+}
+
+/**
+ * Turn what the user typed into the URI form IDS expects
+ */
+pub fn normalize_handle(input: &str, default_region: &str) -> Result<String, HandleError> {
+    Handle::parse(input, default_region).map(|handle| handle.uri())
+}
+
+fn strip_prefix_ignore_case<'a>(input: &'a str, prefix: &str) -> Option<&'a str> {
+    match input.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => Some(&input[prefix.len()..]),
+        _ => None,
+    }
+}
+
+fn parse_phone(input: &str, default_region: &str) -> Result<String, HandleError> {
+    let region: country::Id = default_region
+        .to_uppercase()
+        .parse()
+        .map_err(|_| HandleError::UnknownRegion(default_region.to_owned()))?;
+    let invalid = || HandleError::InvalidPhoneNumber(input.to_owned());
+    // The parser skips over letters, which would hide typos
+    if input.chars().any(|c| c.is_alphabetic()) {
+        return Err(invalid());
+    }
+    let number = phonenumber::parse(Some(region), input).map_err(|_| invalid())?;
+    if !phonenumber::is_valid(&number) {
+        return Err(invalid());
+    }
+    Ok(number.format().mode(Mode::E164).to_string())
+}
+
+fn parse_email(input: &str) -> Result<String, HandleError> {
+    let address = input.trim().to_lowercase();
+    let invalid = || HandleError::InvalidEmail(input.to_owned());
+    if address.len() > MAX_EMAIL {
+        return Err(invalid());
+    }
+    let Some((local, domain)) = address.split_once('@') else {
+        return Err(invalid());
+    };
+    let local_valid = !local.is_empty()
+        && local.len() <= MAX_LOCAL_PART
+        && !local.starts_with('.')
+        && !local.ends_with('.')
+        && !local.contains("..")
+        && local
+            .chars()
+            .all(|c| c.is_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.".contains(c));
+    let labels: Vec<&str> = domain.split('.').collect();
+    let domain_valid = labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+        })
+        && labels
+            .last()
+            .is_some_and(|tld| tld.len() >= 2 && !tld.chars().all(|c| c.is_ascii_digit()));
+    if !local_valid || !domain_valid {
+        return Err(invalid());
+    }
+    Ok(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phone_numbers_are_normalized() {
+        for (input, region, uri) in [
+            ("+1 (415) 555-2671", "US", "tel:+14155552671"),
+            ("(415) 555-2671", "US", "tel:+14155552671"),
+            ("415.555.2671", "us", "tel:+14155552671"),
+            ("tel:+14155552671", "US", "tel:+14155552671"),
+            ("TEL:415-555-2671", "US", "tel:+14155552671"),
+            ("  +44 20 7946 0958 ", "US", "tel:+442079460958"),
+            ("020 7946 0958", "GB", "tel:+442079460958"),
+            ("030 123456", "DE", "tel:+4930123456"),
+        ] {
+            assert_eq!(
+                normalize_handle(input, region).as_deref(),
+                Ok(uri),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn email_addresses_are_normalized() {
+        for (input, uri) in [
+            ("jane@example.com", "mailto:jane@example.com"),
+            ("Jane.Doe@Example.COM", "mailto:jane.doe@example.com"),
+            ("mailto:jane@example.com", "mailto:jane@example.com"),
+            (
+                "MAILTO:jane+tag@mail.example.co.uk",
+                "mailto:jane+tag@mail.example.co.uk",
+            ),
+            (" o'brien@example.com ", "mailto:o'brien@example.com"),
+        ] {
+            assert_eq!(
+                normalize_handle(input, "US").as_deref(),
+                Ok(uri),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_handles_are_rejected() {
+        for input in [
+            "555-CALL-NOW",
+            "123",
+            "+1 415 555 26719",
+            "tel:",
+            "tel:jane@example.com",
+        ] {
+            assert!(
+                matches!(
+                    normalize_handle(input, "US"),
+                    Err(HandleError::InvalidPhoneNumber(_))
+                ),
+                "{}",
+                input
+            );
+        }
+        for input in [
+            "jane@",
+            "@example.com",
+            "jane@example",
+            "jane@example.c",
+            "jane@192.168.0.1",
+            ".jane@example.com",
+            "jane..doe@example.com",
+            "jane doe@example.com",
+            "jane@-example.com",
+            "jane@example..com",
+            "mailto:+15551234567",
+        ] {
+            assert!(
+                matches!(
+                    normalize_handle(input, "US"),
+                    Err(HandleError::InvalidEmail(_))
+                ),
+                "{}",
+                input
+            );
+        }
+        assert_eq!(normalize_handle("  ", "US"), Err(HandleError::Empty));
+        assert_eq!(
+            normalize_handle("4155552671", "XX"),
+            Err(HandleError::UnknownRegion("XX".to_owned()))
+        );
+    }
+
+    #[test]
+    fn overlong_email_addresses_are_rejected() {
+        let local = "a".repeat(MAX_LOCAL_PART + 1);
+        assert!(normalize_handle(&format!("{}@example.com", local), "US").is_err());
+        let domain = format!("{}.com", "a.".repeat(MAX_EMAIL / 2));
+        assert!(normalize_handle(&format!("jane@{}", domain), "US").is_err());
+        let local = "a".repeat(MAX_LOCAL_PART);
+        assert!(normalize_handle(&format!("{}@example.com", local), "US").is_ok());
+    }
+}
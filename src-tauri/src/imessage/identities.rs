@@ -0,0 +1,118 @@
+use base64::{engine::general_purpose, Engine};
+use rustpush::{IDSIdentityResult, IDSPublicIdentity, IMClient, PushError};
+
+use crate::state::identitycache::{CachedIdentity, IdentityCache};
+
+fn to_cached(result: &IDSIdentityResult) -> Option<CachedIdentity> {
+    let identity = match result.identity.encode() {
+        Ok(identity) => identity,
+        Err(e) => {
+            log::warn!("Could not encode an identity: {:?}", e);
+            return None;
+        }
+    };
+    Some(CachedIdentity {
+        identity: general_purpose::STANDARD.encode(identity),
+        push_token: general_purpose::STANDARD.encode(&result.push_token),
+        session_token: general_purpose::STANDARD.encode(&result.session_token),
+    })
+}
+
+fn from_cached(cached: &CachedIdentity) -> Option<IDSIdentityResult> {
+    let identity = general_purpose::STANDARD.decode(&cached.identity).ok()?;
+    Some(IDSIdentityResult {
+        identity: IDSPublicIdentity::decode(&identity).ok()?,
+        push_token: general_purpose::STANDARD.decode(&cached.push_token).ok()?,
+        session_token: general_purpose::STANDARD
+            .decode(&cached.session_token)
+            .ok()?,
+    })
+}
+
+/**
+ * Whether a send failed because a recipient's keys changed, e.g. they
+ * signed in on another device, so the identities we have are stale
+ */
+pub fn is_key_change(error: &PushError) -> bool {
+    matches!(
+        error,
+        PushError::KeyNotFound(_) | PushError::VerificationFailed
+    )
+}
+
+/**
+ * Give a new client the identities we looked up before, so it does not
+ * ask IDS for them again
+ */
+pub async fn restore_identities(client: &IMClient, cache: &IdentityCache, now: u64) {
+    let mut keys = client.key_cache.lock().await;
+    for (handle, cached) in cache.fresh(now) {
+        let identities: Option<Vec<IDSIdentityResult>> =
+            cached.identities.iter().map(from_cached).collect();
+        match identities {
+            Some(identities) => {
+                keys.insert(handle.clone(), identities);
+            }
+            None => log::warn!("Ignoring unreadable identities of {}", handle),
+        }
+    }
+}
+
+/**
+ * Bring the cache in line with what the client knows: record what it has
+ * looked up since, drop what it has forgotten, and make it forget what has
+ * expired so it is looked up again
+ *
+ * Returns whether the cache changed, and the handles that were looked up
+ */
+pub async fn sync_identities(
+    client: &IMClient,
+    cache: &mut IdentityCache,
+    now: u64,
+) -> (bool, Vec<String>) {
+    let mut keys = client.key_cache.lock().await;
+    let mut changed = false;
+    for handle in cache.prune(now) {
+        keys.remove(&handle);
+        changed = true;
+    }
+    let forgotten: Vec<String> = cache
+        .fresh(now)
+        .map(|(handle, _)| handle.clone())
+        .filter(|handle| !keys.contains_key(handle))
+        .collect();
+    changed |= cache.remove(&forgotten);
+    let mut looked_up = Vec::new();
+    for (handle, results) in keys.iter() {
+        let identities: Option<Vec<CachedIdentity>> = results.iter().map(to_cached).collect();
+        if let Some(identities) = identities {
+            if cache.set(handle, identities, now) {
+                looked_up.push(handle.clone());
+            }
+        }
+    }
+    changed |= !looked_up.is_empty();
+    (changed, looked_up)
+}
+
+/**
+ * Have the client look up any of these handles it does not have
+ * identities for
+ */
+pub async fn resolve_identities(
+    client: &IMClient,
+    handles: &[String],
+    from: &str,
+) -> Result<(), PushError> {
+    client.cache_keys(handles, from).await
+}
+
+/**
+ * Make the client look these handles up again before its next send to them
+ */
+pub async fn forget_identities(client: &IMClient, handles: &[String]) {
+    let mut keys = client.key_cache.lock().await;
+    for handle in handles {
+        keys.remove(handle);
+    }
+}
@@ -1,27 +1,297 @@
 use std::sync::Arc;
 
-use rustpush::{ConversationData, IMClient, Message, PushError};
+use rustpush::{
+    ConversationData, EditMessage, IMClient, IMessage, IndexedMessagePart, Message, MessagePart,
+    MessageParts, NormalMessage, PushError, ReactMessage, Reaction, RecievedMessage,
+    UnsendMessage,
+};
+
+use uuid::Uuid;
+
+use crate::{
+    imessage::identities::{forget_identities, is_key_change},
+    state::messagestore::{ReplyTo, StoredMessage, Tapback},
+};
 
 /**
- * Send a plain text message to a user
+ * Send a message, forgetting the recipients' identities if their keys
+ * changed so the next attempt looks them up again
+ */
+async fn send(client: &IMClient, msg: &mut IMessage) -> Result<(), PushError> {
+    let result = client.send(msg).await;
+    if let Err(e) = &result {
+        if is_key_change(e) {
+            let participants = msg
+                .conversation
+                .as_ref()
+                .map(|conversation| conversation.participants.clone())
+                .unwrap_or_default();
+            log::warn!("Keys changed for {:?}: {:?}", participants, e);
+            forget_identities(client, &participants).await;
+        }
+    }
+    result
+}
+
+/**
+ * Send a plain text message to a user from the given handle
  *
- * This will return the message ID
+ * This will return the sent message
  */
 pub async fn send_text_message(
     client: Arc<IMClient>,
+    handle: &str,
     conversation: ConversationData,
     message: Message,
-) -> Result<String, PushError> {
-    let handles = client.get_handles();
-    if let Some(handle) = handles.first() {
-        let mut msg = client.new_msg(conversation, &handle, message).await;
-        println!("Sending message: {:?}", msg.to_string());
-        client.send(&mut msg).await?;
-        println!("Sent message: {:?}", msg.to_string());
-        Ok(msg.id)
+) -> Result<IMessage, PushError> {
+    let mut msg = client.new_msg(conversation, handle, message).await;
+    log::debug!("Sending message: {:?}", msg.to_string());
+    send(&client, &mut msg).await?;
+    log::info!("Sent message: {:?}", msg.to_string());
+    Ok(msg)
+}
+
+/**
+ * Send a message with an ID we chose, so sending it again after a failed
+ * attempt is recognised as the same message
+ */
+pub async fn send_with_id(
+    client: Arc<IMClient>,
+    handle: &str,
+    conversation: ConversationData,
+    message: Message,
+    id: &str,
+) -> Result<IMessage, PushError> {
+    let mut msg = client.new_msg(conversation, handle, message).await;
+    msg.id = id.to_owned();
+    log::debug!("Sending message: {:?}", msg.to_string());
+    send(&client, &mut msg).await?;
+    log::info!("Sent message: {:?}", msg.to_string());
+    Ok(msg)
+}
+
+/**
+ * Tell the other participants that we have read one of their messages
+ */
+pub async fn send_read_receipt(
+    client: Arc<IMClient>,
+    handle: &str,
+    conversation: ConversationData,
+    message_id: &str,
+) -> Result<(), PushError> {
+    let mut msg = client.new_msg(conversation, handle, Message::Read).await;
+    // Receipts are matched up by the ID of the message they are for
+    msg.id = message_id.to_owned();
+    send(&client, &mut msg).await?;
+    Ok(())
+}
+
+/**
+ * Wait for the next message from APNs
+ *
+ * Returns None once the connection has gone away
+ */
+pub async fn receive_message(client: Arc<IMClient>) -> Option<IMessage> {
+    match client.recieve_wait().await {
+        Some(RecievedMessage::Message { msg }) => Some(msg),
+        None => None,
+    }
+}
+
+/**
+ * A stable identifier for a conversation
+ *
+ * iMessage identifies conversations by their sender guid, but that is not
+ * always present, so fall back to the sorted participant list
+ */
+pub fn conversation_id(conversation: &ConversationData) -> String {
+    match &conversation.sender_guid {
+        Some(guid) => guid.to_owned(),
+        None => {
+            let mut participants = conversation.participants.clone();
+            participants.sort();
+            participants.join(",")
+        }
+    }
+}
+
+/**
+ * A conversation we have not messaged in before
+ */
+pub fn new_conversation(participants: Vec<String>) -> ConversationData {
+    ConversationData {
+        participants,
+        cv_name: None,
+        sender_guid: Some(Uuid::new_v4().to_string()),
+    }
+}
+
+/**
+ * Rebuild the conversation for a stored conversation ID
+ *
+ * This is the inverse of `conversation_id`, so IDs that were derived from
+ * the participants do not become a sender guid
+ */
+pub fn conversation_data(conversation_id: &str, participants: Vec<String>) -> ConversationData {
+    let mut sorted = participants.clone();
+    sorted.sort();
+    let sender_guid = if conversation_id == sorted.join(",") {
+        None
     } else {
-        println!("No handles found");
-        // TODO: Return a proper error
-        Err(PushError::TwoFaError)
+        Some(conversation_id.to_owned())
+    };
+    ConversationData {
+        participants,
+        cv_name: None,
+        sender_guid,
     }
 }
+
+/**
+ * Convert a message into the form we keep in the message store
+ *
+ * Only messages with text content are stored, everything else returns None
+ */
+pub fn to_stored_message(msg: &IMessage, from_me: bool) -> Option<StoredMessage> {
+    let (text, reply_to) = match &msg.message {
+        Message::Message(normal) => (normal.parts.raw_text(), reply_to(normal)),
+        _ => return None,
+    };
+    let (conversation_id, participants) = match &msg.conversation {
+        Some(conversation) => (
+            conversation_id(conversation),
+            conversation.participants.clone(),
+        ),
+        None => (String::new(), Vec::new()),
+    };
+    Some(StoredMessage {
+        id: msg.id.clone(),
+        conversation_id,
+        participants,
+        sender: msg.sender.clone(),
+        from_me,
+        text,
+        timestamp: msg.sent_timestamp,
+        attachments: Vec::new(),
+        reactions: Vec::new(),
+        reply_to,
+        delivered_at: None,
+        read_at: None,
+        edits: Vec::new(),
+        unsent_at: None,
+        send_status: None,
+    })
+}
+
+/**
+ * The thread a message was sent in, if it is a reply
+ *
+ * The part is sent as `part:start:length`, we only keep the part index
+ */
+pub fn reply_to(normal: &NormalMessage) -> Option<ReplyTo> {
+    let message_id = normal.reply_guid.as_ref()?;
+    let part = normal
+        .reply_part
+        .as_deref()
+        .and_then(|part| part.split(':').next())
+        .and_then(|part| part.parse().ok())
+        .unwrap_or(0);
+    Some(ReplyTo {
+        message_id: message_id.clone(),
+        part,
+    })
+}
+
+/**
+ * Make a message a reply to a part of the thread originator
+ */
+pub fn set_reply_to(normal: &mut NormalMessage, reply_to: &ReplyTo, originator_text: &str) {
+    normal.reply_guid = Some(reply_to.message_id.clone());
+    normal.reply_part = Some(format!(
+        "{}:0:{}",
+        reply_to.part,
+        originator_text.chars().count()
+    ));
+}
+
+pub fn to_reaction(tapback: &Tapback) -> Reaction {
+    match tapback {
+        Tapback::Love => Reaction::Heart,
+        Tapback::Like => Reaction::Like,
+        Tapback::Dislike => Reaction::Dislike,
+        Tapback::Laugh => Reaction::Laugh,
+        Tapback::Emphasize => Reaction::Emphsize,
+        Tapback::Question => Reaction::Question,
+        Tapback::Emoji(emoji) => Reaction::Emoji(emoji.clone()),
+    }
+}
+
+pub fn from_reaction(reaction: &Reaction) -> Tapback {
+    match reaction {
+        Reaction::Heart => Tapback::Love,
+        Reaction::Like => Tapback::Like,
+        Reaction::Dislike => Tapback::Dislike,
+        Reaction::Laugh => Tapback::Laugh,
+        Reaction::Emphsize => Tapback::Emphasize,
+        Reaction::Question => Tapback::Question,
+        Reaction::Emoji(emoji) => Tapback::Emoji(emoji.clone()),
+    }
+}
+
+/**
+ * A tapback on a part of a message, or its removal
+ *
+ * The target's text is only used by other clients for notifications
+ */
+pub fn reaction_message(
+    target_id: &str,
+    part: u64,
+    target_text: &str,
+    tapback: &Tapback,
+    enable: bool,
+) -> Message {
+    Message::React(ReactMessage {
+        to_uuid: target_id.to_owned(),
+        to_part: part,
+        reaction: to_reaction(tapback),
+        enable,
+        to_text: target_text.to_owned(),
+    })
+}
+
+/**
+ * A typing indicator, which has no content of its own
+ */
+pub fn typing_message(typing: bool) -> Message {
+    Message::Typing(typing)
+}
+
+/**
+ * The index of the text part of a message we stored
+ *
+ * Attachments are sent before the text, so it comes after all of them
+ */
+pub fn text_part(message: &StoredMessage) -> u64 {
+    message.attachments.len() as u64
+}
+
+/**
+ * A new version of the text in a part of one of our messages
+ */
+pub fn edit_message(target_id: &str, part: u64, text: String) -> Message {
+    Message::Edit(EditMessage {
+        tuuid: target_id.to_owned(),
+        edit_part: part,
+        new_parts: MessageParts(vec![IndexedMessagePart(MessagePart::Text(text), None)]),
+    })
+}
+
+/**
+ * Take back a part of one of our messages
+ */
+pub fn unsend_message(target_id: &str, part: u64) -> Message {
+    Message::Unsend(UnsendMessage {
+        tuuid: target_id.to_owned(),
+        edit_part: part,
+    })
+}
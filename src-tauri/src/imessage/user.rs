@@ -74,7 +74,7 @@ pub async fn register_users(
 ) -> Result<(), RegisterError> {
     let validation = generate_validation_data()?;
     for user in users.to_vec().iter_mut() {
-        println!("Registering user {:#?}", user.handles);
+        log::debug!("Registering user {:#?}", user.handles);
     }
     match register(validation.as_str(), users, connection).await {
         Ok(_) => Ok(()),
@@ -1,12 +1,65 @@
+use std::path::Path;
+
 use async_trait::async_trait;
+use reqwest::StatusCode;
 use rustpush::PushError;
 
 use crate::{
-    imessage::user::{login, LoginError},
-    state::TauriState,
+    actions::{
+        attachments::{do_attach_file, do_fetch_attachment},
+        autocomplete::do_suggest_recipients,
+        cache::do_free_conversation,
+        contacts::{
+            do_export_vcards, do_import_vcards, do_save_contact, do_set_contact_photo, ContactError,
+        },
+        conversations::{
+            conversation_list, do_set_contact_merged, do_update_flags, reply_conversation,
+            stored_conversation_ids, unread_count, ConversationError,
+        },
+        edit::{do_edit_message, do_unsend_message},
+        identities::{do_lookup_keys, do_set_key_verified},
+        outbox::{do_cancel_message, do_queue_message, do_retry_message, OutgoingMessage},
+        reachability::do_check_reachability,
+        receipts::do_mark_conversation_read,
+        schedule::{do_cancel_scheduled, do_edit_scheduled, do_schedule_message},
+        search::{do_search, SearchError, SearchQuery as StoredSearchQuery},
+        send::{do_send_tapback, resolve_handle},
+    },
+    carddav::{client::CardDavError, sync_contacts},
+    events::AppEvent,
+    imessage::{
+        handle::HandleError,
+        messenger::new_conversation,
+        user::{login, LoginError},
+    },
+    state::{
+        attachmentstore::StoredAttachment,
+        contacts::Contact as StoredContact,
+        keytrust::HandleKeys,
+        messagestore::{
+            now_millis, ChangeRejected, MessageStatus as StoredStatus,
+            ReactionSummary as StoredReactionSummary, StoredMessage, Tapback, EDIT_WINDOW,
+            UNSEND_WINDOW,
+        },
+        outbox::{OutboxEntry as StoredOutboxEntry, OutboxState as StoredOutboxState},
+        rustpushstate::IMClientError,
+        schedule::{ScheduledMessage as StoredScheduledMessage, SendTime, SendTimeError},
+        TauriState,
+    },
+    vcard::Version,
 };
 
-use self::ipc::{GetUserErrorCode, LoginErrorCode, LogoutErrorCode, SelectHandleErrorCode, User};
+use self::ipc::{
+    Attachment, AttachmentErrorCode, Contact, ContactErrorCode, ContactImport, ContactKeys,
+    ContactSync, ContactSyncErrorCode, Conversation, ConversationErrorCode, ConversationStorage,
+    EditErrorCode, GetUserErrorCode, HandleErrorCode, HandleReachability, KeyErrorCode, KeyRecord,
+    LoginErrorCode, LogoutErrorCode, Message, MessageEdit, MessageStatus, OutboxEntry,
+    OutboxErrorCode, OutboxState, ReachabilityErrorCode, ReachabilityStatus, ReactionSummary,
+    ReceiptErrorCode, RecipientSuggestion, ScheduleErrorCode, ScheduledMessage, SearchErrorCode,
+    SearchPage, SearchQuery, SearchResult, SelectHandleErrorCode, SendErrorCode, SnippetPart,
+    StorageErrorCode, TapbackErrorCode, TapbackKind, ThreadErrorCode, TypingErrorCode, User,
+    VcardVersion, VideoInfo,
+};
 
 tauri_bindgen_host::generate!({
     path: "ipc.wit",
@@ -19,6 +72,275 @@ pub struct IpcCtx {
     pub tauri_state: TauriState,
 }
 
+impl IpcCtx {
+    async fn to_attachment(&self, stored: StoredAttachment) -> Attachment {
+        let attachments = self.tauri_state.0.lock().await.attachments.clone();
+        let store = attachments.lock().await;
+        let path = store.path(&stored);
+        let display_path = stored
+            .display
+            .as_ref()
+            .and_then(|display| store.derived_path(display));
+        let thumbnail_path = stored
+            .thumbnail
+            .as_ref()
+            .and_then(|thumbnail| store.derived_path(thumbnail));
+        let to_string = |path: std::path::PathBuf| path.to_string_lossy().into_owned();
+        Attachment {
+            id: stored.id,
+            name: stored.name,
+            mime: stored.mime,
+            size: stored.size,
+            path: path.map(to_string),
+            sha256: stored.sha256,
+            display_path: display_path.map(to_string),
+            thumbnail_path: thumbnail_path.map(to_string),
+            placeholder: stored.placeholder,
+            video: stored.video.map(|video| VideoInfo {
+                duration: video.duration,
+                width: video.width,
+                height: video.height,
+                codec: video.codec,
+            }),
+        }
+    }
+
+    /**
+     * Check a message can still be changed before sending anything, so the
+     * reason it cannot is reported precisely
+     */
+    async fn check_change(&self, message_id: &str, window: u64) -> Result<(), EditErrorCode> {
+        let (rust_push, messages) = {
+            let state = self.tauri_state.0.lock().await;
+            (state.rust_push.clone(), state.messages.clone())
+        };
+        if rust_push.lock().await.client.users.is_empty() {
+            return Err(EditErrorCode::NotLoggedIn);
+        }
+        let messages = messages.lock().await;
+        let message = messages
+            .get(message_id)
+            .ok_or(EditErrorCode::MessageNotFound)?;
+        message
+            .check_change(window, now_millis())
+            .map_err(|rejected| match rejected {
+                ChangeRejected::NotFromMe => EditErrorCode::NotFromMe,
+                ChangeRejected::NotSent => EditErrorCode::NotSent,
+                ChangeRejected::Unsent => EditErrorCode::AlreadyUnsent,
+                ChangeRejected::WindowExpired => EditErrorCode::WindowExpired,
+            })
+    }
+}
+
+/// Emoji tapbacks longer than this cannot be a single emoji
+const MAX_EMOJI_BYTES: usize = 32;
+
+fn to_tapback(kind: TapbackKind, emoji: Option<String>) -> Option<Tapback> {
+    Some(match kind {
+        TapbackKind::Love => Tapback::Love,
+        TapbackKind::Like => Tapback::Like,
+        TapbackKind::Dislike => Tapback::Dislike,
+        TapbackKind::Laugh => Tapback::Laugh,
+        TapbackKind::Emphasize => Tapback::Emphasize,
+        TapbackKind::Question => Tapback::Question,
+        TapbackKind::Emoji => {
+            let emoji = emoji?;
+            let valid = !emoji.is_empty()
+                && emoji.len() <= MAX_EMOJI_BYTES
+                && !emoji.chars().any(|c| c.is_ascii_alphanumeric() || c.is_whitespace());
+            if !valid {
+                return None;
+            }
+            Tapback::Emoji(emoji)
+        }
+    })
+}
+
+fn to_reaction_summary(summary: StoredReactionSummary) -> ReactionSummary {
+    let (tapback, emoji) = match summary.tapback {
+        Tapback::Love => (TapbackKind::Love, None),
+        Tapback::Like => (TapbackKind::Like, None),
+        Tapback::Dislike => (TapbackKind::Dislike, None),
+        Tapback::Laugh => (TapbackKind::Laugh, None),
+        Tapback::Emphasize => (TapbackKind::Emphasize, None),
+        Tapback::Question => (TapbackKind::Question, None),
+        Tapback::Emoji(emoji) => (TapbackKind::Emoji, Some(emoji)),
+    };
+    ReactionSummary {
+        tapback,
+        emoji,
+        count: summary.count as u32,
+        from_me: summary.from_me,
+        senders: summary.senders,
+    }
+}
+
+fn to_message(message: StoredMessage) -> Message {
+    let status = match message.status() {
+        StoredStatus::Queued => MessageStatus::Queued,
+        StoredStatus::Sending => MessageStatus::Sending,
+        StoredStatus::Failed => MessageStatus::Failed,
+        StoredStatus::Sent => MessageStatus::Sent,
+        StoredStatus::Delivered => MessageStatus::Delivered,
+        StoredStatus::Unread => MessageStatus::Unread,
+        StoredStatus::Read => MessageStatus::Read,
+    };
+    Message {
+        status,
+        id: message.id,
+        conversation_id: message.conversation_id,
+        sender: message.sender,
+        from_me: message.from_me,
+        text: message.text,
+        timestamp: message.timestamp,
+        attachments: message.attachments,
+        reply_part: message.reply_to.as_ref().map(|reply_to| reply_to.part),
+        edited_at: message.edits.last().map(|edit| edit.replaced_at),
+        unsent: message.unsent_at.is_some(),
+        reply_to: message.reply_to.map(|reply_to| reply_to.message_id),
+    }
+}
+
+fn to_outbox_entry(entry: StoredOutboxEntry) -> OutboxEntry {
+    let state = match entry.state {
+        StoredOutboxState::Queued => OutboxState::Queued,
+        StoredOutboxState::Sending => OutboxState::Sending,
+        StoredOutboxState::Sent => OutboxState::Sent,
+        StoredOutboxState::Delivered => OutboxState::Delivered,
+        StoredOutboxState::Failed => OutboxState::Failed,
+        StoredOutboxState::Held => OutboxState::Held,
+    };
+    OutboxEntry {
+        state,
+        id: entry.id,
+        conversation_id: entry.conversation_id,
+        text: entry.text,
+        attachments: entry.attachments,
+        attempts: entry.attempts,
+        next_attempt: entry.next_attempt,
+        last_error: entry.last_error,
+        created: entry.created,
+    }
+}
+
+fn to_scheduled_message(scheduled: StoredScheduledMessage) -> ScheduledMessage {
+    ScheduledMessage {
+        id: scheduled.id,
+        conversation_id: scheduled.conversation_id,
+        text: scheduled.text,
+        attachments: scheduled.attachments,
+        local_time: scheduled.send_time.local_time,
+        time_zone: scheduled.send_time.time_zone,
+        send_at: scheduled.send_at,
+        created: scheduled.created,
+    }
+}
+
+fn to_handle_error_code(error: HandleError) -> HandleErrorCode {
+    match error {
+        HandleError::Empty => HandleErrorCode::Empty,
+        HandleError::InvalidPhoneNumber(_) => HandleErrorCode::InvalidPhoneNumber,
+        HandleError::InvalidEmail(_) => HandleErrorCode::InvalidEmail,
+        HandleError::UnknownRegion(_) => HandleErrorCode::UnknownRegion,
+    }
+}
+
+fn to_contact_keys(handle: String, keys: &HandleKeys) -> ContactKeys {
+    ContactKeys {
+        handle,
+        verified: keys.verified,
+        held: keys.held,
+        fingerprints: keys.current(),
+        verified_fingerprints: keys.verified_fingerprints.clone(),
+        history: keys
+            .history
+            .iter()
+            .map(|record| KeyRecord {
+                fingerprint: record.fingerprint.clone(),
+                first_seen: record.first_seen,
+                last_seen: record.last_seen,
+                removed_at: record.removed_at,
+            })
+            .collect(),
+    }
+}
+
+fn to_contact(stored: &StoredContact) -> Contact {
+    Contact {
+        id: stored.id.clone(),
+        name: stored.name.clone(),
+        handles: stored.handles.clone(),
+        photo: stored.photo.as_ref().map(|photo| photo.to_data_url()),
+    }
+}
+
+fn to_contact_error_code(error: ContactError) -> ContactErrorCode {
+    match error {
+        ContactError::NotFound => ContactErrorCode::NotFound,
+        ContactError::NoName => ContactErrorCode::NoName,
+        ContactError::InvalidHandle(_) => ContactErrorCode::InvalidHandle,
+        ContactError::NotAnImage => ContactErrorCode::NotAnImage,
+        ContactError::InvalidVCard(e) => {
+            log::warn!("Error reading vCards: {}", e);
+            ContactErrorCode::InvalidVcard
+        }
+        ContactError::IOError(e) => {
+            log::error!("Error with contacts: {:?}", e);
+            ContactErrorCode::IoError
+        }
+    }
+}
+
+fn to_contact_sync_error_code(error: CardDavError) -> ContactSyncErrorCode {
+    match error {
+        CardDavError::NotConfigured => ContactSyncErrorCode::NotConfigured,
+        CardDavError::NoAddressBook => ContactSyncErrorCode::NoAddressBook,
+        CardDavError::Status(status)
+            if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN =>
+        {
+            ContactSyncErrorCode::Unauthorized
+        }
+        e => {
+            log::error!("Error syncing contacts: {:?}", e);
+            ContactSyncErrorCode::RequestFailed
+        }
+    }
+}
+
+fn to_conversation_error_code(error: ConversationError) -> ConversationErrorCode {
+    match error {
+        ConversationError::NotFound => ConversationErrorCode::NotFound,
+        ConversationError::IOError(e) => {
+            log::error!("Error saving conversation flags: {:?}", e);
+            ConversationErrorCode::IoError
+        }
+    }
+}
+
+fn to_search_error_code(error: SearchError) -> SearchErrorCode {
+    match error {
+        SearchError::EmptyQuery => SearchErrorCode::EmptyQuery,
+        SearchError::InvalidHandle(e) => {
+            log::warn!("Invalid sender to search for: {:?}", e);
+            SearchErrorCode::InvalidHandle
+        }
+        SearchError::IndexError(e) => {
+            log::error!("Error searching messages: {:?}", e);
+            SearchErrorCode::Unknown
+        }
+    }
+}
+
+fn check_send_time(send_time: &SendTime) -> Result<(), ScheduleErrorCode> {
+    match send_time.to_millis() {
+        Ok(_) => Ok(()),
+        Err(SendTimeError::UnknownTimeZone(_)) => Err(ScheduleErrorCode::UnknownTimeZone),
+        Err(SendTimeError::InvalidTime(_)) | Err(SendTimeError::SkippedTime(_)) => {
+            Err(ScheduleErrorCode::InvalidTime)
+        }
+    }
+}
+
 /*
  enum loginErrorCode {
    twoFactorRequired,
@@ -38,6 +360,107 @@ pub struct IpcCtx {
    handleNotFound,
    unknown,
  }
+ enum attachmentErrorCode {
+   notFound,
+   unreadable,
+   processingFailed,
+   downloadFailed,
+   unknown,
+ }
+ enum storageErrorCode {
+   ioError,
+   unknown,
+ }
+ enum tapbackErrorCode {
+   notLoggedIn,
+   messageNotFound,
+   invalidEmoji,
+   sendFailed,
+   unknown,
+ }
+ enum receiptErrorCode {
+   notLoggedIn,
+   invalidSetting,
+   unknown,
+ }
+ enum threadErrorCode {
+   messageNotFound,
+   unknown,
+ }
+ enum sendErrorCode {
+   notLoggedIn,
+   messageNotFound,
+   attachmentNotFound,
+   invalidHandle,
+   sendFailed,
+   unknown,
+ }
+ enum typingErrorCode {
+   notLoggedIn,
+   conversationNotFound,
+   sendFailed,
+ }
+ enum editErrorCode {
+   notLoggedIn,
+   messageNotFound,
+   notFromMe,
+   notSent,
+   alreadyUnsent,
+   windowExpired,
+   sendFailed,
+ }
+ enum outboxErrorCode {
+   notFound,
+   unknown,
+ }
+ enum scheduleErrorCode {
+   conversationNotFound,
+   attachmentNotFound,
+   notFound,
+   unknownTimeZone,
+   invalidTime,
+   unknown,
+ }
+ enum handleErrorCode {
+   empty,
+   invalidPhoneNumber,
+   invalidEmail,
+   unknownRegion,
+ }
+ enum reachabilityErrorCode {
+   notLoggedIn,
+   lookupFailed,
+ }
+ enum keyErrorCode {
+   notLoggedIn,
+   invalidHandle,
+   notFound,
+   lookupFailed,
+   unknown,
+ }
+ enum contactErrorCode {
+   notFound,
+   noName,
+   invalidHandle,
+   notAnImage,
+   invalidVcard,
+   ioError,
+ }
+ enum contactSyncErrorCode {
+   notConfigured,
+   noAddressBook,
+   unauthorized,
+   requestFailed,
+ }
+ enum conversationErrorCode {
+   notFound,
+   ioError,
+ }
+ enum searchErrorCode {
+   emptyQuery,
+   invalidHandle,
+   unknown,
+ }
 */
 
 #[async_trait]
@@ -59,9 +482,14 @@ impl ipc::Ipc for IpcCtx {
         .await
         {
             Ok(user) => {
-                println!("Logged in as {:?}", user.user_id);
+                log::info!("Logged in as {:?}", user.user_id);
                 match state.add_user(user).await {
-                    Ok(_) => None,
+                    Ok(_) => {
+                        app_state.events.publish(AppEvent::AccountChanged {
+                            account: state.account_status().await,
+                        });
+                        None
+                    }
                     Err(_) => Some(LoginErrorCode::Unknown),
                 }
             }
@@ -69,7 +497,7 @@ impl ipc::Ipc for IpcCtx {
                 Some(LoginErrorCode::TwoFactorRequired)
             }
             Err(LoginError::PushError(error)) => {
-                println!("Error logging in: {:?}", error);
+                log::error!("Error logging in: {:?}", error);
                 Some(LoginErrorCode::LoginFailed)
             }
         }
@@ -99,12 +527,1062 @@ impl ipc::Ipc for IpcCtx {
     async fn select_handle(&self, handle: String) -> Option<SelectHandleErrorCode> {
         let app_state = self.tauri_state.0.lock().await;
         let mut state = app_state.rust_push.lock().await;
-        match state.get_user_by_handle(&handle).await {
-            Some(_) => {
-                state.active_handle = Some(handle);
+        match state.select_handle(&handle).await {
+            Ok(_) => {
+                app_state.events.publish(AppEvent::AccountChanged {
+                    account: state.account_status().await,
+                });
                 None
             }
-            None => Some(SelectHandleErrorCode::HandleNotFound),
+            Err(IMClientError::HandleNotFound) => Some(SelectHandleErrorCode::HandleNotFound),
+            Err(_) => Some(SelectHandleErrorCode::Unknown),
+        }
+    }
+
+    async fn attach_file(&self, path: String) -> Result<Attachment, AttachmentErrorCode> {
+        let (attachments, settings) = {
+            let state = self.tauri_state.0.lock().await;
+            (state.attachments.clone(), state.settings.clone())
+        };
+        let media = settings.lock().await.media.clone();
+        match do_attach_file(attachments, media, Path::new(&path)).await {
+            Ok(stored) => Ok(self.to_attachment(stored).await),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(AttachmentErrorCode::NotFound)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                log::error!("Error processing {}: {:?}", path, e);
+                Err(AttachmentErrorCode::ProcessingFailed)
+            }
+            Err(e) => {
+                log::error!("Error attaching {}: {:?}", path, e);
+                Err(AttachmentErrorCode::Unreadable)
+            }
+        }
+    }
+
+    async fn fetch_attachment(&self, id: String) -> Result<Attachment, AttachmentErrorCode> {
+        let (attachments, transport, events, settings) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.attachments.clone(),
+                state.transport.clone(),
+                state.events.clone(),
+                state.settings.clone(),
+            )
+        };
+        if attachments.lock().await.get(&id).is_none() {
+            return Err(AttachmentErrorCode::NotFound);
+        }
+        let media = settings.lock().await.media.clone();
+        match do_fetch_attachment(attachments, transport, events, media, &id).await {
+            Ok(stored) => Ok(self.to_attachment(stored).await),
+            Err(e) => {
+                log::error!("Error fetching attachment {}: {:?}", id, e);
+                Err(AttachmentErrorCode::DownloadFailed)
+            }
+        }
+    }
+
+    async fn get_storage_usage(&self) -> Result<Vec<ConversationStorage>, StorageErrorCode> {
+        let attachments = self.tauri_state.0.lock().await.attachments.clone();
+        let usage = attachments.lock().await.usage_by_conversation();
+        Ok(usage
+            .into_iter()
+            .map(|usage| ConversationStorage {
+                conversation_id: usage.conversation_id,
+                attachment_count: usage.attachment_count,
+                original_bytes: usage.original_bytes,
+                thumbnail_bytes: usage.thumbnail_bytes,
+                remote_bytes: usage.remote_bytes,
+            })
+            .collect())
+    }
+
+    async fn free_conversation_storage(
+        &self,
+        conversation_id: String,
+    ) -> Result<u64, StorageErrorCode> {
+        let attachments = self.tauri_state.0.lock().await.attachments.clone();
+        do_free_conversation(attachments, &conversation_id)
+            .await
+            .map_err(|e| {
+                log::error!("Error freeing {}: {:?}", conversation_id, e);
+                StorageErrorCode::IoError
+            })
+    }
+
+    async fn send_tapback(
+        &self,
+        message_id: String,
+        part: u64,
+        tapback: TapbackKind,
+        emoji: Option<String>,
+        remove: bool,
+    ) -> Option<TapbackErrorCode> {
+        let Some(tapback) = to_tapback(tapback, emoji) else {
+            return Some(TapbackErrorCode::InvalidEmoji);
+        };
+        let (rust_push, keys, messages, events) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.rust_push.clone(),
+                state.keys.clone(),
+                state.messages.clone(),
+                state.events.clone(),
+            )
+        };
+        if rust_push.lock().await.client.users.is_empty() {
+            return Some(TapbackErrorCode::NotLoggedIn);
+        }
+        if messages.lock().await.get(&message_id).is_none() {
+            return Some(TapbackErrorCode::MessageNotFound);
+        }
+        match do_send_tapback(
+            rust_push,
+            keys,
+            messages,
+            events,
+            &message_id,
+            part,
+            tapback,
+            !remove,
+        )
+        .await
+        {
+            Ok(_) => None,
+            Err(e) => {
+                log::error!("Error sending tapback: {:?}", e);
+                Some(TapbackErrorCode::SendFailed)
+            }
+        }
+    }
+
+    async fn get_reactions(
+        &self,
+        message_id: String,
+    ) -> Result<Vec<ReactionSummary>, TapbackErrorCode> {
+        let messages = self.tauri_state.0.lock().await.messages.clone();
+        let messages = messages.lock().await;
+        let message = messages
+            .get(&message_id)
+            .ok_or(TapbackErrorCode::MessageNotFound)?;
+        Ok(message
+            .reaction_summary()
+            .into_iter()
+            .map(to_reaction_summary)
+            .collect())
+    }
+
+    async fn send_reply(
+        &self,
+        message_id: String,
+        part: u64,
+        text: String,
+    ) -> Result<String, SendErrorCode> {
+        let (rust_push, messages, attachments, outbox, events) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.rust_push.clone(),
+                state.messages.clone(),
+                state.attachments.clone(),
+                state.outbox.clone(),
+                state.events.clone(),
+            )
+        };
+        if rust_push.lock().await.client.users.is_empty() {
+            return Err(SendErrorCode::NotLoggedIn);
+        }
+        let reply = OutgoingMessage::reply(&messages, &message_id, part, text)
+            .await
+            .map_err(|_| SendErrorCode::MessageNotFound)?;
+        do_queue_message(rust_push, messages, attachments, outbox, events, reply)
+            .await
+            .map_err(|e| {
+                log::error!("Error queueing reply: {:?}", e);
+                SendErrorCode::SendFailed
+            })
+    }
+
+    async fn get_thread(&self, message_id: String) -> Result<Vec<Message>, ThreadErrorCode> {
+        let messages = self.tauri_state.0.lock().await.messages.clone();
+        let thread = messages.lock().await.thread(&message_id);
+        if thread.is_empty() {
+            return Err(ThreadErrorCode::MessageNotFound);
+        }
+        Ok(thread.into_iter().map(to_message).collect())
+    }
+
+    async fn mark_conversation_read(
+        &self,
+        conversation_id: String,
+    ) -> Result<Vec<String>, ReceiptErrorCode> {
+        let (rust_push, keys, messages, settings, events) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.rust_push.clone(),
+                state.keys.clone(),
+                state.messages.clone(),
+                state.settings.clone(),
+                state.events.clone(),
+            )
+        };
+        if rust_push.lock().await.client.users.is_empty() {
+            return Err(ReceiptErrorCode::NotLoggedIn);
+        }
+        // Every conversation in a contact's merged one is read at once
+        let mut marked = Vec::new();
+        for id in stored_conversation_ids(&self.tauri_state, &conversation_id).await {
+            let ids = do_mark_conversation_read(
+                rust_push.clone(),
+                keys.clone(),
+                messages.clone(),
+                settings.clone(),
+                events.clone(),
+                &id,
+                None,
+            )
+            .await
+            .map_err(|e| {
+                log::error!("Error marking {} read: {:?}", id, e);
+                ReceiptErrorCode::Unknown
+            })?;
+            marked.extend(ids);
+        }
+        Ok(marked)
+    }
+
+    /**
+     * Without a conversation this sets the global toggle, with one it sets
+     * or, given no value, clears that conversation's override
+     */
+    async fn set_read_receipts(
+        &self,
+        conversation_id: Option<String>,
+        enabled: Option<bool>,
+    ) -> Option<ReceiptErrorCode> {
+        let settings = self.tauri_state.0.lock().await.settings.clone();
+        let mut settings = settings.lock().await;
+        match (conversation_id, enabled) {
+            (None, Some(enabled)) => settings.receipts.send_read_receipts = enabled,
+            (None, None) => return Some(ReceiptErrorCode::InvalidSetting),
+            (Some(conversation_id), Some(enabled)) => {
+                settings
+                    .receipts
+                    .conversations
+                    .insert(conversation_id, enabled);
+            }
+            (Some(conversation_id), None) => {
+                settings.receipts.conversations.remove(&conversation_id);
+            }
+        }
+        match settings.save() {
+            Ok(_) => None,
+            Err(e) => {
+                log::error!("Error saving settings: {:?}", e);
+                Some(ReceiptErrorCode::Unknown)
+            }
+        }
+    }
+
+    async fn get_read_receipts(&self, conversation_id: String) -> bool {
+        let settings = self.tauri_state.0.lock().await.settings.clone();
+        let settings = settings.lock().await;
+        settings.receipts.sends_read_receipts(&conversation_id)
+    }
+
+    async fn send_attachments(
+        &self,
+        to: String,
+        text: String,
+        attachments: Vec<String>,
+    ) -> Result<String, SendErrorCode> {
+        let (rust_push, messages, store, outbox, settings, events) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.rust_push.clone(),
+                state.messages.clone(),
+                state.attachments.clone(),
+                state.outbox.clone(),
+                state.settings.clone(),
+                state.events.clone(),
+            )
+        };
+        if rust_push.lock().await.client.users.is_empty() {
+            return Err(SendErrorCode::NotLoggedIn);
+        }
+        let to = resolve_handle(&settings, &to)
+            .await
+            .map_err(|_| SendErrorCode::InvalidHandle)?;
+        {
+            let store = store.lock().await;
+            let missing = attachments.iter().any(|id| match store.get(id) {
+                Some(stored) => store.path(stored).is_none(),
+                None => true,
+            });
+            if missing {
+                return Err(SendErrorCode::AttachmentNotFound);
+            }
         }
+        let message = OutgoingMessage {
+            conversation: new_conversation(vec![to]),
+            text,
+            attachments,
+            reply_to: None,
+        };
+        do_queue_message(rust_push, messages, store, outbox, events, message)
+            .await
+            .map_err(|e| {
+                log::error!("Error queueing attachments: {:?}", e);
+                SendErrorCode::SendFailed
+            })
+    }
+
+    /**
+     * Call this on every keystroke while typing, it stops by itself once
+     * the user goes idle
+     */
+    async fn set_typing(&self, conversation_id: String, typing: bool) -> Option<TypingErrorCode> {
+        let (rust_push, tracker) = {
+            let state = self.tauri_state.0.lock().await;
+            (state.rust_push.clone(), state.typing.clone())
+        };
+        if rust_push.lock().await.client.users.is_empty() {
+            return Some(TypingErrorCode::NotLoggedIn);
+        }
+        // In a merged conversation, type where a reply would go
+        let stored = stored_conversation_ids(&self.tauri_state, &conversation_id).await;
+        let Some(conversation_id) = stored.into_iter().next() else {
+            return Some(TypingErrorCode::ConversationNotFound);
+        };
+        match tracker.set(&conversation_id, typing).await {
+            Ok(_) => None,
+            Err(e) => {
+                log::error!("Error setting typing in {}: {:?}", conversation_id, e);
+                Some(TypingErrorCode::SendFailed)
+            }
+        }
+    }
+
+    async fn edit_message(
+        &self,
+        message_id: String,
+        text: String,
+    ) -> Result<Message, EditErrorCode> {
+        self.check_change(&message_id, EDIT_WINDOW).await?;
+        let (rust_push, keys, messages, events) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.rust_push.clone(),
+                state.keys.clone(),
+                state.messages.clone(),
+                state.events.clone(),
+            )
+        };
+        do_edit_message(rust_push, keys, messages, events, &message_id, text)
+            .await
+            .map(to_message)
+            .map_err(|e| {
+                log::error!("Error editing {}: {:?}", message_id, e);
+                EditErrorCode::SendFailed
+            })
+    }
+
+    async fn unsend_message(&self, message_id: String) -> Option<EditErrorCode> {
+        if let Err(e) = self.check_change(&message_id, UNSEND_WINDOW).await {
+            return Some(e);
+        }
+        let (rust_push, keys, messages, attachments, events) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.rust_push.clone(),
+                state.keys.clone(),
+                state.messages.clone(),
+                state.attachments.clone(),
+                state.events.clone(),
+            )
+        };
+        match do_unsend_message(rust_push, keys, messages, attachments, events, &message_id).await {
+            Ok(_) => None,
+            Err(e) => {
+                log::error!("Error unsending {}: {:?}", message_id, e);
+                Some(EditErrorCode::SendFailed)
+            }
+        }
+    }
+
+    /**
+     * Earlier versions of a message's text, oldest first
+     */
+    async fn get_edit_history(
+        &self,
+        message_id: String,
+    ) -> Result<Vec<MessageEdit>, EditErrorCode> {
+        let messages = self.tauri_state.0.lock().await.messages.clone();
+        let messages = messages.lock().await;
+        let message = messages
+            .get(&message_id)
+            .ok_or(EditErrorCode::MessageNotFound)?;
+        Ok(message
+            .edits
+            .iter()
+            .map(|edit| MessageEdit {
+                text: edit.text.clone(),
+                replaced_at: edit.replaced_at,
+            })
+            .collect())
+    }
+
+    /**
+     * Messages waiting to be sent, and ones sent in the last day
+     */
+    async fn get_outbox(&self) -> Vec<OutboxEntry> {
+        let outbox = self.tauri_state.0.lock().await.outbox.clone();
+        let outbox = outbox.lock().await;
+        outbox
+            .entries()
+            .iter()
+            .cloned()
+            .map(to_outbox_entry)
+            .collect()
+    }
+
+    async fn retry_message(&self, message_id: String) -> Option<OutboxErrorCode> {
+        let (messages, outbox, events) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.messages.clone(),
+                state.outbox.clone(),
+                state.events.clone(),
+            )
+        };
+        match do_retry_message(messages, outbox, events, &message_id).await {
+            Ok(true) => None,
+            Ok(false) => Some(OutboxErrorCode::NotFound),
+            Err(e) => {
+                log::error!("Error retrying {}: {:?}", message_id, e);
+                Some(OutboxErrorCode::Unknown)
+            }
+        }
+    }
+
+    /**
+     * Drop a queued or failed message before it is sent
+     */
+    async fn cancel_message(&self, message_id: String) -> Option<OutboxErrorCode> {
+        let (messages, outbox) = {
+            let state = self.tauri_state.0.lock().await;
+            (state.messages.clone(), state.outbox.clone())
+        };
+        match do_cancel_message(messages, outbox, &message_id).await {
+            Ok(true) => None,
+            Ok(false) => Some(OutboxErrorCode::NotFound),
+            Err(e) => {
+                log::error!("Error cancelling {}: {:?}", message_id, e);
+                Some(OutboxErrorCode::Unknown)
+            }
+        }
+    }
+
+    /**
+     * Send a message at a wall clock time like "2024-03-01T09:30" in an
+     * IANA time zone, or in the time zone of this machine
+     */
+    async fn schedule_message(
+        &self,
+        conversation_id: String,
+        text: String,
+        attachments: Vec<String>,
+        local_time: String,
+        time_zone: Option<String>,
+    ) -> Result<ScheduledMessage, ScheduleErrorCode> {
+        let send_time = SendTime {
+            local_time,
+            time_zone: time_zone.unwrap_or_else(SendTime::system_time_zone),
+        };
+        check_send_time(&send_time)?;
+        let (store, schedule, events) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.attachments.clone(),
+                state.schedule.clone(),
+                state.events.clone(),
+            )
+        };
+        let conversation = reply_conversation(&self.tauri_state, &conversation_id)
+            .await
+            .ok_or(ScheduleErrorCode::ConversationNotFound)?;
+        {
+            let store = store.lock().await;
+            let missing = attachments.iter().any(|id| match store.get(id) {
+                Some(stored) => store.path(stored).is_none(),
+                None => true,
+            });
+            if missing {
+                return Err(ScheduleErrorCode::AttachmentNotFound);
+            }
+        }
+        let message = OutgoingMessage {
+            conversation,
+            text,
+            attachments,
+            reply_to: None,
+        };
+        do_schedule_message(store, schedule, events, message, send_time)
+            .await
+            .map(to_scheduled_message)
+            .map_err(|e| {
+                log::error!("Error scheduling message: {:?}", e);
+                ScheduleErrorCode::Unknown
+            })
+    }
+
+    /**
+     * Messages waiting to be sent, the next one first
+     */
+    async fn get_scheduled(&self, conversation_id: Option<String>) -> Vec<ScheduledMessage> {
+        let schedule = self.tauri_state.0.lock().await.schedule.clone();
+        let schedule = schedule.lock().await;
+        schedule
+            .entries()
+            .into_iter()
+            .filter(|scheduled| {
+                conversation_id
+                    .as_ref()
+                    .map_or(true, |id| &scheduled.conversation_id == id)
+            })
+            .cloned()
+            .map(to_scheduled_message)
+            .collect()
+    }
+
+    /**
+     * Changing only the time zone keeps the wall clock time, so the
+     * message is sent at that time in the new zone
+     */
+    async fn edit_scheduled(
+        &self,
+        id: String,
+        text: Option<String>,
+        local_time: Option<String>,
+        time_zone: Option<String>,
+    ) -> Result<ScheduledMessage, ScheduleErrorCode> {
+        let (schedule, events) = {
+            let state = self.tauri_state.0.lock().await;
+            (state.schedule.clone(), state.events.clone())
+        };
+        let current = schedule
+            .lock()
+            .await
+            .get(&id)
+            .map(|scheduled| scheduled.send_time.clone())
+            .ok_or(ScheduleErrorCode::NotFound)?;
+        let send_time = match (local_time, time_zone) {
+            (None, None) => None,
+            (local_time, time_zone) => Some(SendTime {
+                local_time: local_time.unwrap_or(current.local_time),
+                time_zone: time_zone.unwrap_or(current.time_zone),
+            }),
+        };
+        if let Some(send_time) = &send_time {
+            check_send_time(send_time)?;
+        }
+        match do_edit_scheduled(schedule, events, &id, text, send_time).await {
+            Ok(Some(scheduled)) => Ok(to_scheduled_message(scheduled)),
+            // Already on its way
+            Ok(None) => Err(ScheduleErrorCode::NotFound),
+            Err(e) => {
+                log::error!("Error editing scheduled message {}: {:?}", id, e);
+                Err(ScheduleErrorCode::Unknown)
+            }
+        }
+    }
+
+    async fn cancel_scheduled(&self, id: String) -> Option<ScheduleErrorCode> {
+        let (schedule, events) = {
+            let state = self.tauri_state.0.lock().await;
+            (state.schedule.clone(), state.events.clone())
+        };
+        match do_cancel_scheduled(schedule, events, &id).await {
+            Ok(true) => None,
+            Ok(false) => Some(ScheduleErrorCode::NotFound),
+            Err(e) => {
+                log::error!("Error cancelling scheduled message {}: {:?}", id, e);
+                Some(ScheduleErrorCode::Unknown)
+            }
+        }
+    }
+
+    /**
+     * The form a recipient is sent to, for checking what the user typed
+     * as they type it
+     */
+    async fn normalize_handle(&self, handle: String) -> Result<String, HandleErrorCode> {
+        let settings = self.tauri_state.0.lock().await.settings.clone();
+        resolve_handle(&settings, &handle)
+            .await
+            .map_err(to_handle_error_code)
+    }
+
+    /**
+     * Whether each handle is on iMessage, in the order they were given.
+     * Handles that are not valid phone numbers or email addresses are
+     * reported as such without asking IDS.
+     */
+    async fn check_reachability(
+        &self,
+        handles: Vec<String>,
+    ) -> Result<Vec<HandleReachability>, ReachabilityErrorCode> {
+        let (rust_push, settings, cache) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.rust_push.clone(),
+                state.settings.clone(),
+                state.reachability.clone(),
+            )
+        };
+        if rust_push.lock().await.client.users.is_empty() {
+            return Err(ReachabilityErrorCode::NotLoggedIn);
+        }
+        let mut normalized = Vec::new();
+        for handle in handles.iter() {
+            normalized.push(resolve_handle(&settings, handle).await.ok());
+        }
+        let valid: Vec<String> = normalized.iter().flatten().cloned().collect();
+        let results = do_check_reachability(rust_push, cache, &valid)
+            .await
+            .map_err(|e| {
+                log::error!("Error checking reachability: {:?}", e);
+                ReachabilityErrorCode::LookupFailed
+            })?;
+        Ok(handles
+            .into_iter()
+            .zip(normalized)
+            .map(|(handle, normalized)| {
+                let reachability = normalized
+                    .as_ref()
+                    .and_then(|normalized| results.get(normalized));
+                let status = match reachability {
+                    None => ReachabilityStatus::Invalid,
+                    Some(reachability) if reachability.reachable => ReachabilityStatus::Reachable,
+                    Some(_) => ReachabilityStatus::Unreachable,
+                };
+                HandleReachability {
+                    handle,
+                    normalized,
+                    status,
+                    checked_at: reachability.map(|reachability| reachability.checked_at),
+                }
+            })
+            .collect())
+    }
+
+    /**
+     * The fingerprints of the devices a handle uses, looking them up if we
+     * have not yet, and every key it has used before
+     */
+    async fn get_contact_keys(&self, handle: String) -> Result<ContactKeys, KeyErrorCode> {
+        let (rust_push, keys, settings, events) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.rust_push.clone(),
+                state.keys.clone(),
+                state.settings.clone(),
+                state.events.clone(),
+            )
+        };
+        if rust_push.lock().await.client.users.is_empty() {
+            return Err(KeyErrorCode::NotLoggedIn);
+        }
+        let handle = resolve_handle(&settings, &handle)
+            .await
+            .map_err(|_| KeyErrorCode::InvalidHandle)?;
+        do_lookup_keys(&rust_push, &keys, &settings, &events, &[handle.clone()])
+            .await
+            .map_err(|e| {
+                log::error!("Error looking up keys: {:?}", e);
+                KeyErrorCode::LookupFailed
+            })?;
+        let keys = keys.lock().await;
+        match keys.get(&handle) {
+            Some(handle_keys) => Ok(to_contact_keys(handle, handle_keys)),
+            None => Err(KeyErrorCode::NotFound),
+        }
+    }
+
+    /**
+     * Mark a handle as verified with the keys it has now, or not verified.
+     * Verifying again after its keys changed confirms the new keys and
+     * sends any messages held for it.
+     */
+    async fn set_key_verified(&self, handle: String, verified: bool) -> Option<KeyErrorCode> {
+        let (keys, outbox, settings, events) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.keys.clone(),
+                state.outbox.clone(),
+                state.settings.clone(),
+                state.events.clone(),
+            )
+        };
+        let handle = match resolve_handle(&settings, &handle).await {
+            Ok(handle) => handle,
+            Err(_) => return Some(KeyErrorCode::InvalidHandle),
+        };
+        match do_set_key_verified(&keys, &outbox, &events, &handle, verified).await {
+            Ok(true) => None,
+            Ok(false) => Some(KeyErrorCode::NotFound),
+            Err(e) => {
+                log::error!("Error verifying keys: {:?}", e);
+                Some(KeyErrorCode::Unknown)
+            }
+        }
+    }
+
+    /**
+     * Whether messages to a verified handle whose keys changed are held
+     * until the user confirms the new keys
+     */
+    async fn set_hold_on_key_change(&self, enabled: bool) -> Option<KeyErrorCode> {
+        let settings = self.tauri_state.0.lock().await.settings.clone();
+        let mut settings = settings.lock().await;
+        settings.keys.hold_on_change = enabled;
+        match settings.save() {
+            Ok(_) => None,
+            Err(e) => {
+                log::error!("Error saving settings: {:?}", e);
+                Some(KeyErrorCode::Unknown)
+            }
+        }
+    }
+
+    async fn get_contacts(&self) -> Vec<Contact> {
+        let contacts = self.tauri_state.0.lock().await.contacts.clone();
+        let contacts = contacts.lock().await;
+        contacts.entries().into_iter().map(to_contact).collect()
+    }
+
+    async fn get_contact(&self, id: String) -> Result<Contact, ContactErrorCode> {
+        let contacts = self.tauri_state.0.lock().await.contacts.clone();
+        let contacts = contacts.lock().await;
+        contacts
+            .get(&id)
+            .map(to_contact)
+            .ok_or(ContactErrorCode::NotFound)
+    }
+
+    /**
+     * The name of the contact a handle belongs to, for showing in place of
+     * the handle
+     */
+    async fn get_display_name(&self, handle: String) -> Option<String> {
+        let (contacts, settings) = {
+            let state = self.tauri_state.0.lock().await;
+            (state.contacts.clone(), state.settings.clone())
+        };
+        let handle = resolve_handle(&settings, &handle).await.ok()?;
+        let contacts = contacts.lock().await;
+        contacts
+            .find_by_handle(&handle)
+            .map(|contact| contact.name.clone())
+    }
+
+    /**
+     * Add a contact, or rename an existing one and replace its handles
+     */
+    async fn save_contact(
+        &self,
+        id: Option<String>,
+        name: String,
+        handles: Vec<String>,
+    ) -> Result<Contact, ContactErrorCode> {
+        let (contacts, settings) = {
+            let state = self.tauri_state.0.lock().await;
+            (state.contacts.clone(), state.settings.clone())
+        };
+        do_save_contact(&contacts, &settings, id, name, handles)
+            .await
+            .map(|contact| to_contact(&contact))
+            .map_err(to_contact_error_code)
+    }
+
+    /**
+     * Set the photo of a contact from an image file, or remove it if no
+     * path is given
+     */
+    async fn set_contact_photo(
+        &self,
+        id: String,
+        path: Option<String>,
+    ) -> Result<Contact, ContactErrorCode> {
+        let contacts = self.tauri_state.0.lock().await.contacts.clone();
+        do_set_contact_photo(&contacts, &id, path.as_deref().map(Path::new))
+            .await
+            .map(|contact| to_contact(&contact))
+            .map_err(to_contact_error_code)
+    }
+
+    async fn delete_contact(&self, id: String) -> Option<ContactErrorCode> {
+        let contacts = self.tauri_state.0.lock().await.contacts.clone();
+        let removed = contacts.lock().await.remove(&id);
+        match removed {
+            Ok(Some(_)) => None,
+            Ok(None) => Some(ContactErrorCode::NotFound),
+            Err(e) => Some(to_contact_error_code(ContactError::IOError(e))),
+        }
+    }
+
+    /**
+     * Import every card in a vCard 3 or 4 file. Cards that were imported
+     * before update their contact.
+     */
+    async fn import_vcards(&self, path: String) -> Result<ContactImport, ContactErrorCode> {
+        let (contacts, settings) = {
+            let state = self.tauri_state.0.lock().await;
+            (state.contacts.clone(), state.settings.clone())
+        };
+        let report = do_import_vcards(&contacts, &settings, Path::new(&path))
+            .await
+            .map_err(to_contact_error_code)?;
+        Ok(ContactImport {
+            added: report.added,
+            updated: report.updated,
+        })
+    }
+
+    /**
+     * Write contacts to a vCard file, all of them if no IDs are given.
+     * Returns how many were written.
+     */
+    async fn export_vcards(
+        &self,
+        path: String,
+        ids: Option<Vec<String>>,
+        version: VcardVersion,
+    ) -> Result<u32, ContactErrorCode> {
+        let contacts = self.tauri_state.0.lock().await.contacts.clone();
+        let version = match version {
+            VcardVersion::V3 => Version::V3,
+            VcardVersion::V4 => Version::V4,
+        };
+        do_export_vcards(&contacts, Path::new(&path), ids, version)
+            .await
+            .map_err(to_contact_error_code)
+    }
+
+    /**
+     * Sync contacts with the CardDAV server in the settings now, rather
+     * than waiting for the next scheduled sync
+     */
+    async fn sync_contacts(&self) -> Result<ContactSync, ContactSyncErrorCode> {
+        let report = sync_contacts(&self.tauri_state)
+            .await
+            .map_err(to_contact_sync_error_code)?;
+        Ok(ContactSync {
+            pulled: report.pulled,
+            pushed: report.pushed,
+            removed: report.removed,
+            deleted: report.deleted,
+            conflicts: report.conflicts,
+        })
+    }
+
+    /**
+     * Show the one-to-one conversations with each of a contact's handles
+     * as one, whose ID is `contact:` followed by the contact's ID
+     */
+    async fn set_merge_conversations(&self, enabled: bool) -> Option<ContactErrorCode> {
+        let settings = self.tauri_state.0.lock().await.settings.clone();
+        let mut settings = settings.lock().await;
+        settings.conversations.merge_contacts = enabled;
+        settings
+            .save()
+            .err()
+            .map(|e| to_contact_error_code(ContactError::IOError(e)))
+    }
+
+    /**
+     * Keep a contact's conversations apart while merging is turned on, or
+     * merge them again
+     */
+    async fn set_contact_merged(
+        &self,
+        contact_id: String,
+        merged: bool,
+    ) -> Option<ContactErrorCode> {
+        let (settings, contacts) = {
+            let state = self.tauri_state.0.lock().await;
+            (state.settings.clone(), state.contacts.clone())
+        };
+        do_set_contact_merged(&settings, &contacts, &contact_id, merged)
+            .await
+            .err()
+            .map(to_contact_error_code)
+    }
+
+    /**
+     * Send a text message in a conversation. In a contact's merged
+     * conversation it goes to the handle they last used.
+     */
+    async fn send_to_conversation(
+        &self,
+        conversation_id: String,
+        text: String,
+    ) -> Result<String, SendErrorCode> {
+        let (rust_push, messages, attachments, outbox, events) = {
+            let state = self.tauri_state.0.lock().await;
+            (
+                state.rust_push.clone(),
+                state.messages.clone(),
+                state.attachments.clone(),
+                state.outbox.clone(),
+                state.events.clone(),
+            )
+        };
+        if rust_push.lock().await.client.users.is_empty() {
+            return Err(SendErrorCode::NotLoggedIn);
+        }
+        let conversation = reply_conversation(&self.tauri_state, &conversation_id)
+            .await
+            .ok_or(SendErrorCode::ConversationNotFound)?;
+        let message = OutgoingMessage::text(conversation, text);
+        do_queue_message(rust_push, messages, attachments, outbox, events, message)
+            .await
+            .map_err(|e| {
+                log::error!("Error queueing message: {:?}", e);
+                SendErrorCode::SendFailed
+            })
+    }
+
+    /**
+     * Search message text, attachment filenames and contact names. Words
+     * must all match, phrases go in double quotes and the last word matches
+     * as a prefix while it is being typed.
+     */
+    async fn search_messages(&self, query: SearchQuery) -> Result<SearchPage, SearchErrorCode> {
+        let query = StoredSearchQuery {
+            text: query.text,
+            conversation_id: query.conversation_id,
+            sender: query.sender,
+            after: query.after,
+            before: query.before,
+            has_attachment: query.has_attachment,
+            from_me: query.from_me,
+            offset: query.offset,
+            limit: query.limit,
+        };
+        let (results, total) = do_search(&self.tauri_state, query)
+            .await
+            .map_err(to_search_error_code)?;
+        let results = results
+            .into_iter()
+            .map(|result| SearchResult {
+                message: to_message(result.message),
+                snippet: result
+                    .snippet
+                    .into_iter()
+                    .map(|part| SnippetPart {
+                        text: part.text,
+                        matched: part.matched,
+                    })
+                    .collect(),
+            })
+            .collect();
+        Ok(SearchPage { results, total })
+    }
+
+    /**
+     * Recipients for the compose field, best first, for what has been typed
+     * so far. With nothing typed, the people we talk with most.
+     */
+    async fn suggest_recipients(&self, query: String, limit: u32) -> Vec<RecipientSuggestion> {
+        do_suggest_recipients(&self.tauri_state, &query, limit as usize)
+            .await
+            .into_iter()
+            .map(|suggestion| RecipientSuggestion {
+                handle: suggestion.handle,
+                name: suggestion.name,
+                contact_id: suggestion.contact_id,
+                reachable: suggestion.reachable,
+                last_message: suggestion.last_message,
+            })
+            .collect()
+    }
+
+    /**
+     * Conversations with any history, pinned ones first and then by last
+     * activity. Archived ones are only included if asked for.
+     */
+    async fn list_conversations(&self, include_archived: bool) -> Vec<Conversation> {
+        conversation_list(&self.tauri_state, include_archived)
+            .await
+            .into_iter()
+            .map(|listing| Conversation {
+                id: listing.summary.id,
+                participants: listing.summary.participants,
+                last_message: to_message(listing.summary.last_message),
+                preview: listing.preview,
+                message_count: listing.summary.message_count as u32,
+                unread_count: listing.summary.unread_count as u32,
+                pinned: listing.flags.pinned,
+                muted: listing.muted,
+                muted_until: listing.flags.muted_until,
+                archived: listing.flags.archived,
+                merged: listing.summary.merged,
+            })
+            .collect()
+    }
+
+    /**
+     * Unread messages outside muted conversations, for the window badge and
+     * tray. Changes arrive as `unreadCountChanged` events.
+     */
+    async fn get_unread_count(&self) -> u32 {
+        unread_count(&self.tauri_state).await
+    }
+
+    async fn pin_conversation(
+        &self,
+        conversation_id: String,
+        pinned: bool,
+    ) -> Option<ConversationErrorCode> {
+        do_update_flags(&self.tauri_state, &conversation_id, |flags| {
+            flags.pinned = pinned
+        })
+        .await
+        .err()
+        .map(to_conversation_error_code)
+    }
+
+    /**
+     * Mute a conversation until a time in milliseconds since the unix epoch,
+     * or until it is unmuted if none is given
+     */
+    async fn mute_conversation(
+        &self,
+        conversation_id: String,
+        muted: bool,
+        until: Option<u64>,
+    ) -> Option<ConversationErrorCode> {
+        do_update_flags(&self.tauri_state, &conversation_id, |flags| {
+            flags.muted = muted;
+            flags.muted_until = until.filter(|_| muted);
+        })
+        .await
+        .err()
+        .map(to_conversation_error_code)
+    }
+
+    async fn archive_conversation(
+        &self,
+        conversation_id: String,
+        archived: bool,
+    ) -> Option<ConversationErrorCode> {
+        do_update_flags(&self.tauri_state, &conversation_id, |flags| {
+            flags.archived = archived
+        })
+        .await
+        .err()
+        .map(to_conversation_error_code)
     }
 }
@@ -1,20 +1,40 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+use std::process::ExitCode;
+
+use clap::Parser;
+use tauri::Manager;
 use tauri_bindgen_host::ipc_router_wip::{BuilderExt, Router};
+use tokio::sync::broadcast::error::RecvError;
 
 use state::TauriState;
 
 pub mod actions;
+pub mod api;
+pub mod bridges;
+pub mod carddav;
+pub mod cli;
 pub mod commands;
 pub mod dataplist;
 pub mod emulated;
+pub mod events;
 pub mod imessage;
 pub mod ipc;
+pub mod media;
 pub mod state;
+pub mod vcard;
+pub mod webhooks;
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
+    env_logger::init();
+
+    let cli = cli::Cli::parse();
+    if let Some(command) = cli.command {
+        return cli::run(command).await;
+    }
+
     let tauri_state = TauriState::new().await.unwrap();
 
     let mut router: Router<ipc::IpcCtx> = Router::new(ipc::IpcCtx {
@@ -22,9 +42,57 @@ async fn main() {
     });
     ipc::ipc::add_to_router(&mut router, |ctx| ctx).unwrap();
 
+    let api_state = tauri_state.clone();
+    tokio::spawn(async move {
+        if let Err(e) = api::serve(api_state).await {
+            log::error!("Error running API: {:?}", e);
+        }
+    });
+
+    if let Err(e) = webhooks::spawn_webhooks(tauri_state.clone()).await {
+        log::error!("Error starting webhooks: {:?}", e);
+    }
+
+    if let Err(e) = bridges::matrix::spawn_matrix_bridge(tauri_state.clone()).await {
+        log::error!("Error starting Matrix bridge: {:?}", e);
+    }
+
+    bridges::xmpp::spawn_xmpp_gateway(tauri_state.clone()).await;
+
+    actions::cache::spawn_cache_manager(tauri_state.clone());
+    actions::typing::spawn_typing_expiry(tauri_state.clone());
+    actions::schedule::spawn_scheduler(tauri_state.clone());
+    actions::identities::spawn_identity_saver(tauri_state.clone());
+    carddav::spawn_carddav_sync(tauri_state.clone());
+    actions::search::spawn_search_indexer(tauri_state.clone());
+    actions::conversations::spawn_unread_counter(tauri_state.clone());
+
+    actions::outbox::spawn_outbox_worker(tauri_state.clone()).await;
+
+    let mut events = tauri_state.0.lock().await.events.subscribe();
+    actions::receive::spawn_receiver(tauri_state.clone());
+
     tauri::Builder::default()
+        .setup(move |app| {
+            let handle = app.handle().clone();
+            tokio::spawn(async move {
+                loop {
+                    match events.recv().await {
+                        Ok(event) => {
+                            if let Err(e) = handle.emit_all("app-event", event) {
+                                log::error!("Error forwarding event: {:?}", e);
+                            }
+                        }
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+            });
+            Ok(())
+        })
         .manage(tauri_state)
         .ipc_router(router)
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
+    ExitCode::SUCCESS
 }
@@ -0,0 +1,607 @@
+use std::{io::Cursor, path::Path, process::Command};
+
+use base64::Engine;
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder},
+    imageops::FilterType,
+    DynamicImage, ImageEncoder,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::state::settings::{ImageFormat, MediaSettings};
+
+/// Longest side of the image a placeholder is made from
+const PLACEHOLDER_SIZE: u32 = 16;
+/// Lowest quality we will re-encode at before shrinking the image instead
+const MIN_QUALITY: u8 = 40;
+/// Latest point in a video the poster frame is taken from, in seconds
+const POSTER_OFFSET: f64 = 1.0;
+
+#[derive(Debug)]
+pub enum MediaError {
+    ImageError(image::ImageError),
+    #[cfg(feature = "heic")]
+    HeifError(libheif_rs::HeifError),
+    HeicUnsupported,
+    WebpError(String),
+    /// ffmpeg or ffprobe ran but failed
+    VideoError(String),
+    IOError(std::io::Error),
+    TooLarge,
+}
+
+impl From<std::io::Error> for MediaError {
+    fn from(error: std::io::Error) -> Self {
+        MediaError::IOError(error)
+    }
+}
+
+impl From<image::ImageError> for MediaError {
+    fn from(error: image::ImageError) -> Self {
+        MediaError::ImageError(error)
+    }
+}
+
+#[cfg(feature = "heic")]
+impl From<libheif_rs::HeifError> for MediaError {
+    fn from(error: libheif_rs::HeifError) -> Self {
+        MediaError::HeifError(error)
+    }
+}
+
+/**
+ * Content ready to be written to the attachment store
+ */
+pub struct Encoded {
+    pub data: Vec<u8>,
+    pub mime: &'static str,
+}
+
+/**
+ * What ffprobe found out about a video, which is passed through as it is
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct VideoInfo {
+    /// Milliseconds
+    pub duration: Option<u64>,
+    /// Size as displayed, after the rotation stored in the container
+    pub width: u32,
+    pub height: u32,
+    /// e.g. `hevc` or `h264`
+    pub codec: String,
+}
+
+/**
+ * Everything derived from a received image or video so the webview can
+ * show it
+ */
+pub struct Derived {
+    /// A version the webview can decode, if the original is not one
+    pub display: Option<Encoded>,
+    /// Made from the poster frame for a video, if it could be decoded
+    pub thumbnail: Option<Encoded>,
+    /// A tiny blurred JPEG as a data URI, shown while loading
+    pub placeholder: Option<String>,
+    pub video: Option<VideoInfo>,
+}
+
+pub fn is_heic(mime: &str) -> bool {
+    matches!(mime, "image/heic" | "image/heif")
+}
+
+/**
+ * Whether a MIME type is a video ffmpeg can make a poster frame of. iPhones
+ * send MOV files, usually HEVC.
+ */
+pub fn is_video(mime: &str) -> bool {
+    matches!(mime, "video/quicktime" | "video/mp4" | "video/x-m4v")
+}
+
+/**
+ * Whether we know how to decode a MIME type as a still image
+ *
+ * GIFs are left alone so they keep their animation, and HEIC photos unless
+ * we were built with libheif
+ */
+pub fn is_processable(mime: &str) -> bool {
+    cfg!(feature = "heic") && is_heic(mime)
+        || matches!(
+            mime,
+            "image/jpeg" | "image/png" | "image/webp" | "image/bmp"
+        )
+}
+
+#[cfg(feature = "heic")]
+fn decode_heic(data: &[u8]) -> Result<DynamicImage, MediaError> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let lib_heif = LibHeif::new();
+    let context = HeifContext::read_from_bytes(data)?;
+    let handle = context.primary_image_handle()?;
+    // libheif applies the rotation and mirroring stored in the container
+    let decoded = lib_heif.decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)?;
+    let plane = decoded
+        .planes()
+        .interleaved
+        .ok_or(MediaError::HeicUnsupported)?;
+    let (width, height) = (plane.width, plane.height);
+    let row_length = width as usize * 3;
+    let mut pixels = Vec::with_capacity(row_length * height as usize);
+    for row in plane.data.chunks(plane.stride).take(height as usize) {
+        pixels.extend_from_slice(&row[..row_length]);
+    }
+    image::RgbImage::from_raw(width, height, pixels)
+        .map(DynamicImage::ImageRgb8)
+        .ok_or(MediaError::HeicUnsupported)
+}
+
+#[cfg(not(feature = "heic"))]
+fn decode_heic(_data: &[u8]) -> Result<DynamicImage, MediaError> {
+    Err(MediaError::HeicUnsupported)
+}
+
+/**
+ * The EXIF orientation of a JPEG, 1 meaning upright
+ */
+fn exif_orientation(data: &[u8]) -> u32 {
+    exif::Reader::new()
+        .read_from_container(&mut Cursor::new(data))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+        })
+        .unwrap_or(1)
+}
+
+fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/**
+ * Decode an image upright, since the orientation is lost when re-encoding
+ */
+pub fn decode(data: &[u8], mime: &str) -> Result<DynamicImage, MediaError> {
+    if is_heic(mime) {
+        return decode_heic(data);
+    }
+    let image = image::load_from_memory(data)?;
+    Ok(apply_orientation(image, exif_orientation(data)))
+}
+
+/**
+ * Encode an image without any metadata
+ */
+pub fn encode(
+    image: &DynamicImage,
+    format: ImageFormat,
+    quality: u8,
+) -> Result<Encoded, MediaError> {
+    match format {
+        ImageFormat::Jpeg => {
+            let mut data = Vec::new();
+            JpegEncoder::new_with_quality(&mut data, quality)
+                .encode_image(&DynamicImage::ImageRgb8(image.to_rgb8()))?;
+            Ok(Encoded {
+                data,
+                mime: "image/jpeg",
+            })
+        }
+        ImageFormat::Webp => {
+            let rgba = DynamicImage::ImageRgba8(image.to_rgba8());
+            let encoder = webp::Encoder::from_image(&rgba)
+                .map_err(|e| MediaError::WebpError(e.to_owned()))?;
+            Ok(Encoded {
+                data: encoder.encode(quality as f32).to_vec(),
+                mime: "image/webp",
+            })
+        }
+    }
+}
+
+fn encode_png(image: &DynamicImage) -> Result<Encoded, MediaError> {
+    let rgba = image.to_rgba8();
+    let mut data = Vec::new();
+    PngEncoder::new(&mut data).write_image(
+        &rgba,
+        rgba.width(),
+        rgba.height(),
+        image::ColorType::Rgba8,
+    )?;
+    Ok(Encoded {
+        data,
+        mime: "image/png",
+    })
+}
+
+pub fn thumbnail(image: &DynamicImage, settings: &MediaSettings) -> Result<Encoded, MediaError> {
+    let size = settings.thumbnail_size;
+    encode(
+        &image.thumbnail(size, size),
+        settings.format,
+        settings.quality,
+    )
+}
+
+pub fn placeholder(image: &DynamicImage) -> Result<String, MediaError> {
+    let small = image
+        .thumbnail(PLACEHOLDER_SIZE, PLACEHOLDER_SIZE)
+        .blur(2.0);
+    let encoded = encode(&small, ImageFormat::Jpeg, 50)?;
+    Ok(format!(
+        "data:{};base64,{}",
+        encoded.mime,
+        base64::engine::general_purpose::STANDARD.encode(encoded.data)
+    ))
+}
+
+/**
+ * Make the thumbnail and placeholder for a received image, converting it
+ * if it is HEIC
+ *
+ * Returns None for anything that is not a still image we can decode
+ */
+pub fn derive(
+    data: &[u8],
+    mime: &str,
+    settings: &MediaSettings,
+) -> Result<Option<Derived>, MediaError> {
+    if !is_processable(mime) {
+        return Ok(None);
+    }
+    let image = decode(data, mime)?;
+    let display = if is_heic(mime) {
+        Some(encode(&image, settings.format, settings.quality)?)
+    } else {
+        None
+    };
+    Ok(Some(Derived {
+        display,
+        thumbnail: Some(thumbnail(&image, settings)?),
+        placeholder: Some(placeholder(&image)?),
+        video: None,
+    }))
+}
+
+/**
+ * Run ffmpeg or ffprobe, returning None if it is not installed
+ */
+fn run_tool(program: &str, args: &[&str]) -> Result<Option<Vec<u8>>, MediaError> {
+    let output = match Command::new(program).args(args).output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if !output.status.success() {
+        return Err(MediaError::VideoError(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+    Ok(Some(output.stdout))
+}
+
+/**
+ * Read the size, length and codec of a video from ffprobe's JSON output
+ */
+fn parse_probe(probe: &Value) -> Option<VideoInfo> {
+    let stream = probe.get("streams")?.as_array()?.first()?;
+    let mut width = stream.get("width")?.as_u64()? as u32;
+    let mut height = stream.get("height")?.as_u64()? as u32;
+    // Phones record upright video sideways with a rotation in the display
+    // matrix, or in a `rotate` tag for older files
+    let rotation = stream
+        .get("side_data_list")
+        .and_then(Value::as_array)
+        .and_then(|list| list.iter().find_map(|data| data.get("rotation")?.as_f64()))
+        .or_else(|| stream.get("tags")?.get("rotate")?.as_str()?.parse().ok())
+        .unwrap_or(0.0);
+    if (rotation.abs() as u32) % 180 == 90 {
+        std::mem::swap(&mut width, &mut height);
+    }
+    let duration = probe
+        .get("format")
+        .and_then(|format| format.get("duration"))
+        .or_else(|| stream.get("duration"))
+        .and_then(Value::as_str)
+        .and_then(|duration| duration.parse::<f64>().ok())
+        .map(|seconds| (seconds * 1000.0).round() as u64);
+    Some(VideoInfo {
+        duration,
+        width,
+        height,
+        codec: stream
+            .get("codec_name")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_owned(),
+    })
+}
+
+/**
+ * Make the thumbnail and placeholder of a video from a frame near its
+ * start, and read its metadata. The video itself is left as it is, since
+ * re-encoding would take far longer than the user waits for a preview.
+ *
+ * This needs ffmpeg and ffprobe on the PATH. Without them, returns None and
+ * the video is only stored.
+ */
+pub fn derive_video(path: &Path, settings: &MediaSettings) -> Result<Option<Derived>, MediaError> {
+    let path = path.to_string_lossy();
+    let Some(probe) = run_tool(
+        "ffprobe",
+        &[
+            "-v",
+            "error",
+            "-select_streams",
+            "v:0",
+            "-show_entries",
+            "stream=codec_name,width,height,duration:stream_tags=rotate:stream_side_data=rotation:format=duration",
+            "-of",
+            "json",
+            &path,
+        ],
+    )?
+    else {
+        log::debug!("ffprobe not found, videos are stored without previews");
+        return Ok(None);
+    };
+    let probe: Value =
+        serde_json::from_slice(&probe).map_err(|e| MediaError::VideoError(format!("{:?}", e)))?;
+    let info =
+        parse_probe(&probe).ok_or_else(|| MediaError::VideoError("no video stream".to_owned()))?;
+
+    // A frame a little way in is less likely to be black than the first
+    let offset = info.duration.map_or(0.0, |duration| {
+        (duration as f64 / 2000.0).min(POSTER_OFFSET)
+    });
+    let offset = format!("{:.3}", offset);
+    // ffmpeg applies the rotation itself, so the frame comes out upright
+    let frame = run_tool(
+        "ffmpeg",
+        &[
+            "-v",
+            "error",
+            "-ss",
+            &offset,
+            "-i",
+            &path,
+            "-frames:v",
+            "1",
+            "-f",
+            "image2pipe",
+            "-c:v",
+            "png",
+            "-",
+        ],
+    );
+    let poster = match frame {
+        Ok(Some(frame)) if !frame.is_empty() => Some(image::load_from_memory(&frame)?),
+        Ok(_) => None,
+        Err(e) => {
+            // Still worth keeping what ffprobe found, e.g. if this ffmpeg
+            // was built without an HEVC decoder
+            log::warn!("Could not get a poster frame: {:?}", e);
+            None
+        }
+    };
+    Ok(Some(Derived {
+        display: None,
+        thumbnail: poster
+            .as_ref()
+            .map(|poster| thumbnail(poster, settings))
+            .transpose()?,
+        placeholder: poster.as_ref().map(placeholder).transpose()?,
+        video: Some(info),
+    }))
+}
+
+/**
+ * Shrink an outgoing image to fit the configured limits
+ *
+ * Anything we can decode is re-encoded, which also drops EXIF data such as
+ * the GPS position. Images with transparency stay PNG, everything else
+ * becomes JPEG since that is what every iMessage client can show. Quality
+ * is lowered first and then the image is scaled down until it fits.
+ *
+ * Returns None if the content should be sent as it is
+ */
+pub fn prepare_outgoing(
+    data: &[u8],
+    mime: &str,
+    settings: &MediaSettings,
+) -> Result<Option<Encoded>, MediaError> {
+    if !settings.process_outgoing || !is_processable(mime) {
+        return Ok(None);
+    }
+    let mut image = decode(data, mime)?;
+    let max = settings.max_dimension;
+    if image.width() > max || image.height() > max {
+        image = image.resize(max, max, FilterType::Lanczos3);
+    }
+
+    if image.color().has_alpha() && mime == "image/png" {
+        let encoded = encode_png(&image)?;
+        if encoded.data.len() as u64 <= settings.max_image_bytes {
+            return Ok(Some(encoded));
+        }
+    }
+
+    let mut quality = settings.quality;
+    loop {
+        let encoded = encode(&image, ImageFormat::Jpeg, quality)?;
+        if encoded.data.len() as u64 <= settings.max_image_bytes {
+            return Ok(Some(encoded));
+        }
+        if quality > MIN_QUALITY {
+            quality = quality.saturating_sub(10).max(MIN_QUALITY);
+        } else if image.width() > PLACEHOLDER_SIZE && image.height() > PLACEHOLDER_SIZE {
+            image = image.resize(
+                image.width() * 3 / 4,
+                image.height() * 3 / 4,
+                FilterType::Lanczos3,
+            );
+        } else {
+            return Err(MediaError::TooLarge);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::state::test_dir;
+
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8])
+        }))
+    }
+
+    #[test]
+    fn reads_rotated_iphone_video() {
+        let probe = json!({
+            "programs": [],
+            "streams": [{
+                "codec_name": "hevc",
+                "width": 1920,
+                "height": 1080,
+                "duration": "3.003000",
+                "side_data_list": [{"side_data_type": "Display Matrix", "rotation": -90}]
+            }],
+            "format": {"duration": "3.016000"}
+        });
+        assert_eq!(
+            parse_probe(&probe),
+            Some(VideoInfo {
+                duration: Some(3016),
+                width: 1080,
+                height: 1920,
+                codec: "hevc".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn reads_rotate_tag_of_older_videos() {
+        let probe = json!({
+            "streams": [{
+                "codec_name": "h264",
+                "width": 640,
+                "height": 480,
+                "tags": {"rotate": "180"}
+            }],
+            "format": {}
+        });
+        assert_eq!(
+            parse_probe(&probe),
+            Some(VideoInfo {
+                duration: None,
+                width: 640,
+                height: 480,
+                codec: "h264".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn audio_only_has_no_video_info() {
+        assert_eq!(parse_probe(&json!({"streams": [], "format": {}})), None);
+    }
+
+    #[test]
+    fn derives_thumbnail_and_placeholder_of_images() {
+        let settings = MediaSettings::default();
+        let original = encode_png(&gradient(1000, 500)).unwrap();
+        let derived = derive(&original.data, "image/png", &settings)
+            .unwrap()
+            .unwrap();
+        assert!(derived.display.is_none());
+        assert!(derived.video.is_none());
+        let thumbnail = derived.thumbnail.unwrap();
+        assert_eq!(thumbnail.mime, "image/jpeg");
+        let thumbnail = image::load_from_memory(&thumbnail.data).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (320, 160));
+        assert!(derived
+            .placeholder
+            .unwrap()
+            .starts_with("data:image/jpeg;base64,"));
+        assert!(derive(b"GIF89a", "image/gif", &settings).unwrap().is_none());
+    }
+
+    #[cfg(not(feature = "heic"))]
+    #[test]
+    fn heic_is_left_alone_without_libheif() {
+        let settings = MediaSettings::default();
+        assert!(!is_processable("image/heic"));
+        assert!(prepare_outgoing(b"not decoded", "image/heic", &settings)
+            .unwrap()
+            .is_none());
+        assert!(derive(b"not decoded", "image/heif", &settings)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn outgoing_images_are_shrunk_to_fit() {
+        let settings = MediaSettings {
+            max_dimension: 800,
+            max_image_bytes: 20 * 1024,
+            ..Default::default()
+        };
+        let original = encode(&gradient(1600, 1200), ImageFormat::Jpeg, 95).unwrap();
+        let outgoing = prepare_outgoing(&original.data, "image/jpeg", &settings)
+            .unwrap()
+            .unwrap();
+        assert_eq!(outgoing.mime, "image/jpeg");
+        assert!(outgoing.data.len() as u64 <= settings.max_image_bytes);
+        let outgoing = image::load_from_memory(&outgoing.data).unwrap();
+        assert!(outgoing.width() <= 800 && outgoing.height() <= 600);
+        assert!(prepare_outgoing(b"", "video/quicktime", &settings)
+            .unwrap()
+            .is_none());
+    }
+
+    /// Needs ffmpeg and ffprobe on the PATH
+    #[test]
+    #[ignore]
+    fn derives_poster_frame_of_videos() {
+        let dir = test_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("video.mov");
+        let made = Command::new("ffmpeg")
+            .args([
+                "-v",
+                "error",
+                "-f",
+                "lavfi",
+                "-i",
+                "testsrc=duration=2:size=640x360",
+            ])
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(made.success());
+        let derived = derive_video(&path, &MediaSettings::default())
+            .unwrap()
+            .unwrap();
+        let video = derived.video.unwrap();
+        assert_eq!((video.width, video.height), (640, 360));
+        assert_eq!(video.duration, Some(2000));
+        let thumbnail = image::load_from_memory(&derived.thumbnail.unwrap().data).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (320, 180));
+        assert!(derived.placeholder.is_some());
+    }
+}
@@ -1,13 +1,146 @@
-use std::sync::Arc;
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
+use dirs::{data_local_dir, home_dir};
+use serde::Serialize;
 use tokio::sync::Mutex;
 
-use self::rustpushstate::IMClientError;
+use crate::{
+    actions::{autocomplete::RecipientHistory, typing::TypingTracker},
+    events::EventBus,
+    imessage::attachments::{AttachmentTransport, LocalTransport, MmcsTransport},
+};
 
+use self::{rustpushstate::IMClientError, settings::TransportKind};
+
+pub mod attachmentstore;
+pub mod carddavsync;
+pub mod contacts;
+pub mod conversationflags;
+pub mod identitycache;
+pub mod keytrust;
+pub mod messagestore;
+pub mod outbox;
+pub mod reachability;
 pub mod rustpushstate;
+pub mod schedule;
+pub mod searchindex;
+pub mod settings;
+
+/**
+ * The directory all of our persistent state lives in
+ */
+pub fn data_dir() -> PathBuf {
+    data_local_dir()
+        .unwrap_or(home_dir().unwrap().join(".crossmessenger"))
+        .join("crossmessenger")
+}
+
+/**
+ * Write a file through a temporary file next to it that is then renamed
+ * over it, so a crash part way through leaves the previous content in place
+ *
+ * Private files can only be read and written by the user, for those that
+ * hold secrets
+ */
+pub fn write_atomic(
+    path: &Path,
+    private: bool,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<(), std::io::Error>,
+) -> Result<(), std::io::Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        options.mode(0o600);
+        // The mode only applies to new files, not one left by a crash
+        if temp_path.exists() {
+            std::fs::set_permissions(&temp_path, std::fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    let mut writer = BufWriter::new(options.open(&temp_path)?);
+    write(&mut writer)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    std::fs::rename(&temp_path, path)
+}
+
+/**
+ * Write a value as JSON with `write_atomic`
+ */
+pub fn write_json<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<(), std::io::Error> {
+    write_atomic(path, false, |writer| Ok(serde_json::to_writer(writer, value)?))
+}
+
+/**
+ * A directory for a test's files, which is deleted when it is dropped so
+ * nothing is left behind even if the test fails
+ */
+#[cfg(test)]
+pub struct TestDir(PathBuf);
+
+#[cfg(test)]
+impl std::ops::Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/**
+ * A new directory under the system's temporary one, which need not exist yet
+ */
+#[cfg(test)]
+pub fn test_dir() -> TestDir {
+    TestDir(std::env::temp_dir().join(format!("crossmessenger-test-{}", uuid::Uuid::new_v4())))
+}
 
 pub struct ApplicationState {
     pub rust_push: Arc<Mutex<rustpushstate::RustPushState>>,
+    pub messages: Arc<Mutex<messagestore::MessageStore>>,
+    pub attachments: Arc<Mutex<attachmentstore::AttachmentStore>>,
+    pub outbox: Arc<Mutex<outbox::OutboxStore>>,
+    pub schedule: Arc<Mutex<schedule::ScheduleStore>>,
+    pub reachability: Arc<Mutex<reachability::ReachabilityCache>>,
+    pub keys: Arc<Mutex<keytrust::KeyTrustStore>>,
+    pub contacts: Arc<Mutex<contacts::ContactStore>>,
+    pub carddav: Arc<Mutex<carddavsync::CardDavSyncState>>,
+    pub flags: Arc<Mutex<conversationflags::ConversationFlagStore>>,
+    pub search: Arc<Mutex<searchindex::SearchIndex>>,
+    pub transport: Arc<dyn AttachmentTransport>,
+    pub settings: Arc<Mutex<settings::Settings>>,
+    pub events: EventBus,
+    pub typing: Arc<TypingTracker>,
+    pub recipients: Arc<Mutex<RecipientHistory>>,
 }
 
 #[derive(Clone)]
@@ -18,7 +151,62 @@ impl TauriState {
         let rust_push = Arc::new(Mutex::new(
             rustpushstate::RustPushState::new(rustpushstate::retrieve_saved_state()).await?,
         ));
-        let state = ApplicationState { rust_push };
+        let messages = Arc::new(Mutex::new(
+            messagestore::MessageStore::load().map_err(IMClientError::IOError)?,
+        ));
+        let attachments = Arc::new(Mutex::new(
+            attachmentstore::AttachmentStore::load().map_err(IMClientError::IOError)?,
+        ));
+        let outbox = Arc::new(Mutex::new(
+            outbox::OutboxStore::load().map_err(IMClientError::IOError)?,
+        ));
+        let schedule = Arc::new(Mutex::new(
+            schedule::ScheduleStore::load().map_err(IMClientError::IOError)?,
+        ));
+        let reachability = Arc::new(Mutex::new(
+            reachability::ReachabilityCache::load().map_err(IMClientError::IOError)?,
+        ));
+        let keys = Arc::new(Mutex::new(
+            keytrust::KeyTrustStore::load().map_err(IMClientError::IOError)?,
+        ));
+        let contacts = Arc::new(Mutex::new(
+            contacts::ContactStore::load().map_err(IMClientError::IOError)?,
+        ));
+        let carddav = Arc::new(Mutex::new(
+            carddavsync::CardDavSyncState::load().map_err(IMClientError::IOError)?,
+        ));
+        let flags = Arc::new(Mutex::new(
+            conversationflags::ConversationFlagStore::load().map_err(IMClientError::IOError)?,
+        ));
+        let search = Arc::new(Mutex::new(searchindex::SearchIndex::open().map_err(
+            |e| IMClientError::IOError(std::io::Error::new(std::io::ErrorKind::Other, e)),
+        )?));
+        let settings = settings::Settings::load().map_err(IMClientError::IOError)?;
+        let transport: Arc<dyn AttachmentTransport> = match settings.attachments.transport {
+            TransportKind::Mmcs => Arc::new(MmcsTransport {
+                state: rust_push.clone(),
+            }),
+            TransportKind::Local => Arc::new(LocalTransport),
+        };
+        let typing = TypingTracker::new(rust_push.clone(), keys.clone(), messages.clone());
+        let state = ApplicationState {
+            rust_push,
+            messages,
+            attachments,
+            outbox,
+            schedule,
+            reachability,
+            keys,
+            contacts,
+            carddav,
+            flags,
+            search,
+            transport,
+            settings: Arc::new(Mutex::new(settings)),
+            events: EventBus::new(),
+            typing,
+            recipients: Arc::new(Mutex::new(RecipientHistory::default())),
+        };
         Ok(Self(Arc::new(Mutex::new(state))))
     }
 }
@@ -0,0 +1,739 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    media::{Derived, Encoded, VideoInfo},
+    state::{data_dir, messagestore::now_millis, write_json},
+};
+
+/**
+ * A file made from an attachment, stored alongside it by hash
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DerivedFile {
+    pub sha256: String,
+    pub mime: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredAttachment {
+    pub id: String,
+    /// Set once the attachment has been sent or received in a message
+    pub message_id: Option<String>,
+    pub conversation_id: Option<String>,
+    pub name: String,
+    pub mime: String,
+    pub uti: String,
+    pub size: u64,
+    /// Hex encoded SHA-256 of the content, known once it is on disk
+    pub sha256: Option<String>,
+    pub from_me: bool,
+    /// Milliseconds since the unix epoch
+    pub created: u64,
+    /// What the transport needs to download the attachment again
+    pub remote: Option<serde_json::Value>,
+    /// A version the webview can show, if the original is not one
+    #[serde(default)]
+    pub display: Option<DerivedFile>,
+    #[serde(default)]
+    pub thumbnail: Option<DerivedFile>,
+    /// A tiny blurred image as a data URI
+    #[serde(default)]
+    pub placeholder: Option<String>,
+    /// Size, length and codec, for videos
+    #[serde(default)]
+    pub video: Option<VideoInfo>,
+    /// Set if the media pipeline could not process the content, so it is
+    /// not tried again on every fetch
+    #[serde(default)]
+    pub derive_failed: bool,
+    /// User ID of the account the attachment was sent or received with
+    #[serde(default)]
+    pub account: Option<String>,
+    /// Milliseconds since the unix epoch, for evicting the least recently
+    /// used originals first
+    #[serde(default)]
+    pub last_accessed: u64,
+}
+
+impl StoredAttachment {
+    /**
+     * Whether the original can be deleted because it can be downloaded again
+     */
+    pub fn is_evictable(&self) -> bool {
+        self.sha256.is_some() && self.remote.is_some() && self.message_id.is_some()
+    }
+}
+
+/**
+ * Disk space used by the attachments of one conversation
+ */
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversationUsage {
+    pub conversation_id: String,
+    pub attachment_count: u64,
+    /// Bytes of downloaded originals and converted copies
+    pub original_bytes: u64,
+    pub thumbnail_bytes: u64,
+    /// Bytes of originals that are not downloaded
+    pub remote_bytes: u64,
+}
+
+/**
+ * What an orphan scan found and removed
+ */
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct OrphanReport {
+    /// Files on disk no attachment refers to
+    pub files: u64,
+    /// Attachments whose message no longer exists, or that were never sent
+    pub attachments: u64,
+    pub bytes_freed: u64,
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    openssl::sha::sha256(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/**
+ * The Apple uniform type identifier for a MIME type
+ */
+pub fn uti_for_mime(mime: &str) -> &'static str {
+    match mime {
+        "image/jpeg" => "public.jpeg",
+        "image/png" => "public.png",
+        "image/gif" => "com.compuserve.gif",
+        "image/heic" => "public.heic",
+        "image/webp" => "org.webmproject.webp",
+        "video/quicktime" => "com.apple.quicktime-movie",
+        "video/mp4" => "public.mpeg-4",
+        "audio/mp4" | "audio/x-m4a" => "com.apple.m4a-audio",
+        "audio/mpeg" => "public.mp3",
+        "application/pdf" => "com.adobe.pdf",
+        "text/plain" => "public.plain-text",
+        "text/vcard" => "public.vcard",
+        _ => "public.data",
+    }
+}
+
+/**
+ * Attachment metadata, with the content kept on disk named by its hash so
+ * the same file sent twice is only stored once
+ */
+pub struct AttachmentStore {
+    dir: PathBuf,
+    index_path: PathBuf,
+    attachments: Vec<StoredAttachment>,
+    /// Whether an attachment was used since the index was last written
+    touched: bool,
+}
+
+impl AttachmentStore {
+    pub fn load() -> Result<AttachmentStore, std::io::Error> {
+        AttachmentStore::load_from(&data_dir())
+    }
+
+    pub(crate) fn load_from(data_dir: &Path) -> Result<AttachmentStore, std::io::Error> {
+        let dir = data_dir.join("attachments");
+        let index_path = data_dir.join("attachments.json");
+        std::fs::create_dir_all(&dir)?;
+        let attachments = if index_path.exists() {
+            let file = File::open(&index_path)?;
+            serde_json::from_reader(BufReader::new(file))?
+        } else {
+            Vec::new()
+        };
+        Ok(AttachmentStore {
+            dir,
+            index_path,
+            attachments,
+            touched: false,
+        })
+    }
+
+    pub fn save(&mut self) -> Result<(), std::io::Error> {
+        write_json(&self.index_path, &self.attachments)?;
+        self.touched = false;
+        Ok(())
+    }
+
+    /**
+     * Write when attachments were last used, if that changed since the
+     * index was last written
+     */
+    pub fn save_touched(&mut self) -> Result<(), std::io::Error> {
+        if !self.touched {
+            return Ok(());
+        }
+        self.save()
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /**
+     * Where the content of an attachment is, if it is on disk
+     */
+    pub fn path(&self, attachment: &StoredAttachment) -> Option<PathBuf> {
+        let path = self.dir.join(attachment.sha256.as_ref()?);
+        path.exists().then_some(path)
+    }
+
+    pub fn derived_path(&self, derived: &DerivedFile) -> Option<PathBuf> {
+        let path = self.dir.join(&derived.sha256);
+        path.exists().then_some(path)
+    }
+
+    fn write_content(&self, data: &[u8]) -> Result<String, std::io::Error> {
+        let sha256 = sha256_hex(data);
+        let path = self.dir.join(&sha256);
+        if !path.exists() {
+            std::fs::write(path, data)?;
+        }
+        Ok(sha256)
+    }
+
+    /**
+     * Add content to the store so it can be attached to a message
+     */
+    pub fn import_bytes(
+        &mut self,
+        data: &[u8],
+        name: &str,
+        mime: &str,
+    ) -> Result<StoredAttachment, std::io::Error> {
+        let sha256 = self.write_content(data)?;
+        let attachment = StoredAttachment {
+            id: Uuid::new_v4().to_string(),
+            message_id: None,
+            conversation_id: None,
+            name: name.to_owned(),
+            mime: mime.to_owned(),
+            uti: uti_for_mime(mime).to_owned(),
+            size: data.len() as u64,
+            sha256: Some(sha256),
+            from_me: true,
+            created: now_millis(),
+            remote: None,
+            display: None,
+            thumbnail: None,
+            placeholder: None,
+            video: None,
+            derive_failed: false,
+            account: None,
+            last_accessed: now_millis(),
+        };
+        self.attachments.push(attachment.clone());
+        self.save()?;
+        Ok(attachment)
+    }
+
+    /**
+     * Record an attachment we received but have not downloaded yet
+     */
+    pub fn add_remote(&mut self, attachment: StoredAttachment) -> Result<(), std::io::Error> {
+        self.attachments.push(attachment);
+        self.save()
+    }
+
+    /**
+     * Store the downloaded content of an attachment
+     */
+    pub fn set_content(
+        &mut self,
+        id: &str,
+        data: &[u8],
+    ) -> Result<StoredAttachment, std::io::Error> {
+        let sha256 = self.write_content(data)?;
+        let attachment = self
+            .attachments
+            .iter_mut()
+            .find(|attachment| attachment.id == id)
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))?;
+        attachment.sha256 = Some(sha256);
+        attachment.size = data.len() as u64;
+        let attachment = attachment.clone();
+        self.save()?;
+        Ok(attachment)
+    }
+
+    fn write_derived(&self, encoded: &Encoded) -> Result<DerivedFile, std::io::Error> {
+        Ok(DerivedFile {
+            sha256: self.write_content(&encoded.data)?,
+            mime: encoded.mime.to_owned(),
+        })
+    }
+
+    /**
+     * Store what the media pipeline made from an attachment
+     */
+    pub fn set_derived(
+        &mut self,
+        id: &str,
+        derived: &Derived,
+    ) -> Result<StoredAttachment, std::io::Error> {
+        let display = derived
+            .display
+            .as_ref()
+            .map(|display| self.write_derived(display))
+            .transpose()?;
+        let thumbnail = derived
+            .thumbnail
+            .as_ref()
+            .map(|thumbnail| self.write_derived(thumbnail))
+            .transpose()?;
+        let attachment = self
+            .attachments
+            .iter_mut()
+            .find(|attachment| attachment.id == id)
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))?;
+        attachment.display = display;
+        attachment.thumbnail = thumbnail;
+        attachment.placeholder = derived.placeholder.clone();
+        attachment.video = derived.video.clone();
+        let attachment = attachment.clone();
+        self.save()?;
+        Ok(attachment)
+    }
+
+    /**
+     * Record that the media pipeline could not process an attachment
+     */
+    pub fn set_derive_failed(&mut self, id: &str) -> Result<StoredAttachment, std::io::Error> {
+        let attachment = self
+            .attachments
+            .iter_mut()
+            .find(|attachment| attachment.id == id)
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))?;
+        attachment.derive_failed = true;
+        let attachment = attachment.clone();
+        self.save()?;
+        Ok(attachment)
+    }
+
+    /**
+     * Link attachments to the message they were sent in
+     */
+    pub fn set_message(
+        &mut self,
+        ids: &[String],
+        message_id: &str,
+        conversation_id: &str,
+        account: Option<String>,
+        remote: Vec<Option<serde_json::Value>>,
+    ) -> Result<(), std::io::Error> {
+        for (id, remote) in ids.iter().zip(remote) {
+            if let Some(attachment) = self
+                .attachments
+                .iter_mut()
+                .find(|attachment| &attachment.id == id)
+            {
+                attachment.message_id = Some(message_id.to_owned());
+                attachment.conversation_id = Some(conversation_id.to_owned());
+                attachment.account = account.clone();
+                attachment.remote = remote;
+            }
+        }
+        self.save()
+    }
+
+    /**
+     * Mark an attachment as used so it is evicted last
+     *
+     * This is only written with the next change, or by `save_touched`, so
+     * looking at an attachment does not rewrite the whole index
+     */
+    pub fn touch(&mut self, id: &str) {
+        if let Some(attachment) = self
+            .attachments
+            .iter_mut()
+            .find(|attachment| attachment.id == id)
+        {
+            attachment.last_accessed = now_millis();
+            self.touched = true;
+        }
+    }
+
+    fn file_size(&self, sha256: &str) -> u64 {
+        std::fs::metadata(self.dir.join(sha256))
+            .map(|metadata| metadata.len())
+            .unwrap_or(0)
+    }
+
+    /**
+     * Every content hash some attachment still refers to
+     */
+    fn referenced(&self) -> HashSet<&str> {
+        self.attachments
+            .iter()
+            .flat_map(|attachment| {
+                [
+                    attachment.sha256.as_deref(),
+                    attachment.display.as_ref().map(|d| d.sha256.as_str()),
+                    attachment.thumbnail.as_ref().map(|t| t.sha256.as_str()),
+                ]
+            })
+            .flatten()
+            .collect()
+    }
+
+    /**
+     * Delete content files nothing refers to any more, returning the bytes
+     * freed
+     */
+    fn remove_unreferenced(&self, candidates: Vec<String>) -> u64 {
+        let referenced = self.referenced();
+        let mut freed = 0;
+        for sha256 in candidates {
+            if referenced.contains(sha256.as_str()) {
+                continue;
+            }
+            let size = self.file_size(&sha256);
+            match std::fs::remove_file(self.dir.join(&sha256)) {
+                Ok(_) => freed += size,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => log::error!("Error deleting attachment content {}: {:?}", sha256, e),
+            }
+        }
+        freed
+    }
+
+    /**
+     * Forget the downloaded original of an attachment and its converted
+     * copy, returning their hashes
+     */
+    fn evict_one(&mut self, id: &str) -> Vec<String> {
+        let Some(attachment) = self
+            .attachments
+            .iter_mut()
+            .find(|attachment| attachment.id == id && attachment.is_evictable())
+        else {
+            return Vec::new();
+        };
+        let mut candidates: Vec<String> = attachment.sha256.take().into_iter().collect();
+        if let Some(display) = attachment.display.take() {
+            candidates.push(display.sha256);
+        }
+        candidates
+    }
+
+    /**
+     * Delete the downloaded originals of attachments and their converted
+     * copies, keeping thumbnails and placeholders
+     *
+     * Returns the bytes freed, which does not include content still shared
+     * with another attachment
+     */
+    pub fn evict(&mut self, ids: &[String]) -> Result<u64, std::io::Error> {
+        let candidates = ids.iter().flat_map(|id| self.evict_one(id)).collect();
+        self.save()?;
+        Ok(self.remove_unreferenced(candidates))
+    }
+
+    /**
+     * Forget attachments entirely, deleting every file of theirs that no
+     * other attachment shares
+     */
+    pub fn remove(&mut self, ids: &[String]) -> Result<(), std::io::Error> {
+        let mut candidates = Vec::new();
+        self.attachments.retain(|attachment| {
+            if !ids.contains(&attachment.id) {
+                return true;
+            }
+            candidates.extend(attachment.sha256.clone());
+            candidates.extend(attachment.display.as_ref().map(|d| d.sha256.clone()));
+            candidates.extend(attachment.thumbnail.as_ref().map(|t| t.sha256.clone()));
+            false
+        });
+        self.save()?;
+        self.remove_unreferenced(candidates);
+        Ok(())
+    }
+
+    /**
+     * Bytes of originals and converted copies on disk for an account,
+     * counting shared content once
+     */
+    pub fn account_usage(&self, account: Option<&str>) -> u64 {
+        let files: HashSet<&str> = self
+            .attachments
+            .iter()
+            .filter(|attachment| attachment.account.as_deref() == account)
+            .flat_map(|attachment| {
+                [
+                    attachment.sha256.as_deref(),
+                    attachment.display.as_ref().map(|d| d.sha256.as_str()),
+                ]
+            })
+            .flatten()
+            .collect();
+        files.into_iter().map(|sha256| self.file_size(sha256)).sum()
+    }
+
+    pub fn accounts(&self) -> Vec<Option<String>> {
+        let mut accounts: Vec<Option<String>> = self
+            .attachments
+            .iter()
+            .map(|attachment| attachment.account.clone())
+            .collect();
+        accounts.sort();
+        accounts.dedup();
+        accounts
+    }
+
+    /**
+     * Evictable attachments of an account, least recently used first
+     */
+    pub fn eviction_order(&self, account: Option<&str>) -> Vec<String> {
+        let mut candidates: Vec<&StoredAttachment> = self
+            .attachments
+            .iter()
+            .filter(|attachment| {
+                attachment.account.as_deref() == account && attachment.is_evictable()
+            })
+            .collect();
+        candidates.sort_by_key(|attachment| attachment.last_accessed);
+        candidates
+            .into_iter()
+            .map(|attachment| attachment.id.clone())
+            .collect()
+    }
+
+    pub fn for_conversation(&self, conversation_id: &str) -> Vec<&StoredAttachment> {
+        self.attachments
+            .iter()
+            .filter(|attachment| attachment.conversation_id.as_deref() == Some(conversation_id))
+            .collect()
+    }
+
+    pub fn usage_by_conversation(&self) -> Vec<ConversationUsage> {
+        let mut usage: HashMap<&str, ConversationUsage> = HashMap::new();
+        for attachment in &self.attachments {
+            let Some(conversation_id) = attachment.conversation_id.as_deref() else {
+                continue;
+            };
+            let entry = usage
+                .entry(conversation_id)
+                .or_insert_with(|| ConversationUsage {
+                    conversation_id: conversation_id.to_owned(),
+                    ..Default::default()
+                });
+            entry.attachment_count += 1;
+            match &attachment.sha256 {
+                Some(sha256) => entry.original_bytes += self.file_size(sha256),
+                None => entry.remote_bytes += attachment.size,
+            }
+            if let Some(display) = &attachment.display {
+                entry.original_bytes += self.file_size(&display.sha256);
+            }
+            if let Some(thumbnail) = &attachment.thumbnail {
+                entry.thumbnail_bytes += self.file_size(&thumbnail.sha256);
+            }
+        }
+        let mut usage: Vec<ConversationUsage> = usage.into_values().collect();
+        usage.sort_by_key(|usage| std::cmp::Reverse(usage.original_bytes));
+        usage
+    }
+
+    /**
+     * Drop attachments whose message is gone, attachments that were
+     * imported but never sent within `unsent_grace` milliseconds, and files
+     * that no attachment refers to
+     */
+    pub fn remove_orphans(
+        &mut self,
+        message_exists: impl Fn(&str) -> bool,
+        unsent_grace: u64,
+    ) -> Result<OrphanReport, std::io::Error> {
+        let now = now_millis();
+        let before = self.attachments.len();
+        self.attachments
+            .retain(|attachment| match &attachment.message_id {
+                Some(message_id) => message_exists(message_id),
+                None => now.saturating_sub(attachment.created) < unsent_grace,
+            });
+        let removed = (before - self.attachments.len()) as u64;
+        if removed > 0 {
+            self.save()?;
+        }
+
+        let files: Vec<String> = std::fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_file())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect();
+        let referenced = self.referenced();
+        let unreferenced: Vec<String> = files
+            .into_iter()
+            .filter(|file| !referenced.contains(file.as_str()))
+            .collect();
+        let count = unreferenced.len() as u64;
+        let bytes_freed = self.remove_unreferenced(unreferenced);
+        Ok(OrphanReport {
+            files: count,
+            attachments: removed,
+            bytes_freed,
+        })
+    }
+
+    pub fn get(&self, id: &str) -> Option<&StoredAttachment> {
+        self.attachments
+            .iter()
+            .find(|attachment| attachment.id == id)
+    }
+
+    pub fn for_message(&self, message_id: &str) -> Vec<&StoredAttachment> {
+        self.attachments
+            .iter()
+            .filter(|attachment| attachment.message_id.as_deref() == Some(message_id))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_dir;
+
+    fn saved_last_accessed(dir: &Path, id: &str) -> u64 {
+        AttachmentStore::load_from(dir)
+            .unwrap()
+            .get(id)
+            .unwrap()
+            .last_accessed
+    }
+
+    #[test]
+    fn the_same_content_is_stored_once() {
+        let dir = test_dir();
+        let mut store = AttachmentStore::load_from(&dir).unwrap();
+        let a = store.import_bytes(b"photo", "a.jpg", "image/jpeg").unwrap();
+        let b = store.import_bytes(b"photo", "b.jpg", "image/jpeg").unwrap();
+        assert_ne!(a.id, b.id);
+        assert_eq!(a.sha256, b.sha256);
+        assert_eq!(a.uti, "public.jpeg");
+        assert_eq!(
+            std::fs::read_dir(dir.join("attachments")).unwrap().count(),
+            1
+        );
+
+        // Removing one keeps the content the other still uses
+        store.remove(std::slice::from_ref(&a.id)).unwrap();
+        let store = AttachmentStore::load_from(&dir).unwrap();
+        assert!(store.get(&a.id).is_none());
+        assert!(store.path(store.get(&b.id).unwrap()).is_some());
+        assert!(!dir.join("attachments.json.tmp").exists());
+    }
+
+    #[test]
+    fn touching_is_only_written_with_the_next_save() {
+        let dir = test_dir();
+        let mut store = AttachmentStore::load_from(&dir).unwrap();
+        let stored = store.import_bytes(b"file", "a.txt", "text/plain").unwrap();
+        let before = saved_last_accessed(&dir, &stored.id);
+
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        store.touch(&stored.id);
+        assert_eq!(saved_last_accessed(&dir, &stored.id), before);
+        store.save_touched().unwrap();
+        assert!(saved_last_accessed(&dir, &stored.id) > before);
+    }
+
+    #[test]
+    fn eviction_keeps_thumbnails_and_what_cannot_be_downloaded_again() {
+        let dir = test_dir();
+        let mut store = AttachmentStore::load_from(&dir).unwrap();
+        let received = store
+            .import_bytes(b"original", "a.png", "image/png")
+            .unwrap();
+        let local = store.import_bytes(b"local", "b.png", "image/png").unwrap();
+        store
+            .set_message(
+                &[received.id.clone(), local.id.clone()],
+                "message",
+                "conversation",
+                None,
+                vec![Some(serde_json::json!({"mmcs": true})), None],
+            )
+            .unwrap();
+        let derived = Derived {
+            display: None,
+            thumbnail: Some(Encoded {
+                data: b"thumbnail".to_vec(),
+                mime: "image/jpeg",
+            }),
+            placeholder: None,
+            video: None,
+        };
+        store.set_derived(&received.id, &derived).unwrap();
+
+        assert_eq!(store.eviction_order(None), vec![received.id.clone()]);
+        let freed = store
+            .evict(&[received.id.clone(), local.id.clone()])
+            .unwrap();
+        assert_eq!(freed, b"original".len() as u64);
+        let evicted = store.get(&received.id).unwrap();
+        assert!(evicted.sha256.is_none());
+        assert!(store
+            .derived_path(evicted.thumbnail.as_ref().unwrap())
+            .is_some());
+        assert!(store.path(store.get(&local.id).unwrap()).is_some());
+    }
+
+    #[test]
+    fn orphans_are_removed() {
+        let dir = test_dir();
+        let mut store = AttachmentStore::load_from(&dir).unwrap();
+        let kept = store.import_bytes(b"kept", "a.txt", "text/plain").unwrap();
+        let gone = store.import_bytes(b"gone", "b.txt", "text/plain").unwrap();
+        let unsent = store
+            .import_bytes(b"unsent", "c.txt", "text/plain")
+            .unwrap();
+        store
+            .set_message(
+                std::slice::from_ref(&kept.id),
+                "kept",
+                "conversation",
+                None,
+                vec![None],
+            )
+            .unwrap();
+        store
+            .set_message(
+                std::slice::from_ref(&gone.id),
+                "gone",
+                "conversation",
+                None,
+                vec![None],
+            )
+            .unwrap();
+        std::fs::write(dir.join("attachments").join("stray"), b"stray").unwrap();
+
+        let report = store.remove_orphans(|id| id == "kept", 0).unwrap();
+        assert_eq!(report.attachments, 2);
+        // The content of both, and the stray file
+        assert_eq!(report.files, 3);
+        assert!(store.get(&kept.id).is_some());
+        assert!(store.get(&gone.id).is_none());
+        assert!(store.get(&unsent.id).is_none());
+        assert_eq!(
+            std::fs::read_dir(dir.join("attachments")).unwrap().count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::state::{data_dir, write_json};
+
+/**
+ * A card on the server and the contact it was synced with
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncedCard {
+    /// Path of the card on the server, as the server wrote it
+    pub href: String,
+    /// None if the server did not say after we uploaded it
+    pub etag: Option<String>,
+    pub contact_id: String,
+    /// `updated` of the contact when it last matched the card, so later
+    /// local changes can be told apart
+    pub synced_updated: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct SyncData {
+    /// The configured URL the address book was discovered from
+    source: Option<String>,
+    address_book: Option<String>,
+    cards: Vec<SyncedCard>,
+    /// Milliseconds since the unix epoch
+    last_sync: Option<u64>,
+}
+
+/**
+ * What the last CardDAV sync saw, so the next one only transfers what
+ * changed on either side
+ */
+pub struct CardDavSyncState {
+    path: PathBuf,
+    data: SyncData,
+}
+
+impl CardDavSyncState {
+    pub fn load() -> Result<CardDavSyncState, std::io::Error> {
+        CardDavSyncState::load_from(&data_dir())
+    }
+
+    fn load_from(dir: &Path) -> Result<CardDavSyncState, std::io::Error> {
+        let path = dir.join("carddav.json");
+        let data = if path.exists() {
+            let file = File::open(&path)?;
+            serde_json::from_reader(BufReader::new(file))?
+        } else {
+            SyncData::default()
+        };
+        Ok(CardDavSyncState { path, data })
+    }
+
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        write_json(&self.path, &self.data)
+    }
+
+    /**
+     * The address book discovered from `source`, unless the configured URL
+     * has changed since
+     */
+    pub fn address_book(&self, source: &str) -> Option<&str> {
+        if self.data.source.as_deref() != Some(source) {
+            return None;
+        }
+        self.data.address_book.as_deref()
+    }
+
+    /**
+     * Sync with another address book, or discover it again if None. Cards
+     * of a different address book are forgotten.
+     */
+    pub fn set_address_book(&mut self, source: &str, address_book: Option<String>) {
+        if self.data.address_book.is_some() && self.data.address_book != address_book {
+            self.data.cards.clear();
+        }
+        self.data.source = Some(source.to_owned());
+        self.data.address_book = address_book;
+    }
+
+    pub fn cards(&self) -> &[SyncedCard] {
+        &self.data.cards
+    }
+
+    pub fn by_href(&self, href: &str) -> Option<&SyncedCard> {
+        self.data.cards.iter().find(|card| card.href == href)
+    }
+
+    pub fn by_contact(&self, contact_id: &str) -> Option<&SyncedCard> {
+        self.data
+            .cards
+            .iter()
+            .find(|card| card.contact_id == contact_id)
+    }
+
+    pub fn set(&mut self, card: SyncedCard) {
+        match self.data.cards.iter_mut().find(|c| c.href == card.href) {
+            Some(existing) => *existing = card,
+            None => self.data.cards.push(card),
+        }
+    }
+
+    pub fn remove(&mut self, href: &str) {
+        self.data.cards.retain(|card| card.href != href);
+    }
+
+    /**
+     * The cards on the server that are new or changed since the last sync,
+     * from their hrefs and ETags. Cards without an ETag are always fetched,
+     * since there is no telling whether they changed.
+     */
+    pub fn changed(&self, remote: &HashMap<String, Option<String>>) -> Vec<String> {
+        remote
+            .iter()
+            .filter(|(href, etag)| {
+                etag.is_none() || !matches!(self.by_href(href), Some(card) if card.etag == **etag)
+            })
+            .map(|(href, _)| href.clone())
+            .collect()
+    }
+
+    /**
+     * The synced cards that are no longer on the server
+     *
+     * An empty listing when cards were synced before is more likely a
+     * server gone wrong than every card deleted, so it deletes nothing
+     */
+    pub fn deleted(&self, remote: &HashMap<String, Option<String>>) -> Vec<SyncedCard> {
+        if remote.is_empty() && !self.data.cards.is_empty() {
+            log::warn!(
+                "The address book lists no cards but {} were synced, not deleting any",
+                self.data.cards.len()
+            );
+            return Vec::new();
+        }
+        self.data
+            .cards
+            .iter()
+            .filter(|card| !remote.contains_key(&card.href))
+            .cloned()
+            .collect()
+    }
+
+    pub fn set_last_sync(&mut self, now: u64) {
+        self.data.last_sync = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_dir;
+
+    fn card(href: &str, etag: Option<&str>) -> SyncedCard {
+        SyncedCard {
+            href: href.to_owned(),
+            etag: etag.map(str::to_owned),
+            contact_id: format!("contact-{}", href),
+            synced_updated: 1,
+        }
+    }
+
+    fn remote(cards: &[(&str, Option<&str>)]) -> HashMap<String, Option<String>> {
+        cards
+            .iter()
+            .map(|(href, etag)| (href.to_string(), etag.map(str::to_owned)))
+            .collect()
+    }
+
+    #[test]
+    fn only_new_and_changed_cards_are_fetched() {
+        let dir = test_dir();
+        let mut state = CardDavSyncState::load_from(&dir).unwrap();
+        state.set(card("/same.vcf", Some("1")));
+        state.set(card("/changed.vcf", Some("1")));
+        state.set(card("/no-etag.vcf", None));
+        let remote = remote(&[
+            ("/same.vcf", Some("1")),
+            ("/changed.vcf", Some("2")),
+            ("/new.vcf", Some("1")),
+            ("/no-etag.vcf", None),
+            ("/new-no-etag.vcf", None),
+        ]);
+        let mut changed = state.changed(&remote);
+        changed.sort();
+        assert_eq!(
+            changed,
+            [
+                "/changed.vcf",
+                "/new-no-etag.vcf",
+                "/new.vcf",
+                "/no-etag.vcf"
+            ]
+        );
+    }
+
+    #[test]
+    fn cards_missing_from_the_listing_are_deleted() {
+        let dir = test_dir();
+        let mut state = CardDavSyncState::load_from(&dir).unwrap();
+        state.set(card("/kept.vcf", Some("1")));
+        state.set(card("/no-etag.vcf", Some("1")));
+        state.set(card("/gone.vcf", Some("1")));
+        let remote = remote(&[("/kept.vcf", Some("1")), ("/no-etag.vcf", None)]);
+        let deleted: Vec<String> = state
+            .deleted(&remote)
+            .into_iter()
+            .map(|card| card.href)
+            .collect();
+        assert_eq!(deleted, ["/gone.vcf"]);
+    }
+
+    #[test]
+    fn an_empty_listing_deletes_nothing() {
+        let dir = test_dir();
+        let mut state = CardDavSyncState::load_from(&dir).unwrap();
+        assert!(state.deleted(&remote(&[])).is_empty());
+        state.set(card("/a.vcf", Some("1")));
+        state.set(card("/b.vcf", Some("1")));
+        assert!(state.deleted(&remote(&[])).is_empty());
+        assert_eq!(state.deleted(&remote(&[("/a.vcf", Some("1"))])).len(), 1);
+    }
+
+    #[test]
+    fn another_address_book_forgets_the_cards() {
+        let dir = test_dir();
+        let mut state = CardDavSyncState::load_from(&dir).unwrap();
+        state.set_address_book(
+            "https://example.com",
+            Some("https://example.com/a/".to_owned()),
+        );
+        state.set(card("/a/1.vcf", Some("1")));
+        state.set_address_book(
+            "https://example.com",
+            Some("https://example.com/a/".to_owned()),
+        );
+        assert_eq!(state.cards().len(), 1);
+        assert_eq!(
+            state.address_book("https://example.com"),
+            Some("https://example.com/a/")
+        );
+        assert_eq!(state.address_book("https://other.example"), None);
+
+        state.set_address_book(
+            "https://example.com",
+            Some("https://example.com/b/".to_owned()),
+        );
+        assert!(state.cards().is_empty());
+    }
+
+    #[test]
+    fn the_state_survives_a_restart() {
+        let dir = test_dir();
+        let mut state = CardDavSyncState::load_from(&dir).unwrap();
+        state.set_address_book(
+            "https://example.com",
+            Some("https://example.com/a/".to_owned()),
+        );
+        state.set(card("/a/1.vcf", Some("1")));
+        state.set(card("/a/1.vcf", Some("2")));
+        state.save().unwrap();
+
+        let state = CardDavSyncState::load_from(&dir).unwrap();
+        assert_eq!(state.cards().len(), 1);
+        assert_eq!(
+            state.by_href("/a/1.vcf").unwrap().etag.as_deref(),
+            Some("2")
+        );
+        assert_eq!(
+            state.by_contact("contact-/a/1.vcf").unwrap().href,
+            "/a/1.vcf"
+        );
+    }
+}
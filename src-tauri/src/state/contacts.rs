@@ -0,0 +1,222 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::state::{
+    data_dir,
+    messagestore::{now_millis, StoredMessage},
+    write_json,
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ContactPhoto {
+    /// e.g. `image/jpeg`
+    pub media_type: String,
+    /// Base64
+    pub data: String,
+}
+
+impl ContactPhoto {
+    pub fn to_data_url(&self) -> String {
+        format!("data:{};base64,{}", self.media_type, self.data)
+    }
+}
+
+/**
+ * A person, and every handle they can be messaged at
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Contact {
+    pub id: String,
+    /// The UID of the vCard, kept so a card that is imported again updates
+    /// this contact instead of adding another
+    pub uid: String,
+    pub name: String,
+    /// Normalized, like `tel:+15551234567` or `mailto:a@b.com`
+    pub handles: Vec<String>,
+    pub photo: Option<ContactPhoto>,
+    /// Unfolded vCard lines we do not use, written back out on export so
+    /// nothing is lost
+    #[serde(default)]
+    pub extra: Vec<String>,
+    /// Milliseconds since the unix epoch
+    pub created: u64,
+    pub updated: u64,
+}
+
+/**
+ * Who a handle in an event belongs to
+ */
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolvedContact {
+    pub id: String,
+    pub name: String,
+    pub handle: String,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportReport {
+    pub added: u32,
+    pub updated: u32,
+}
+
+/**
+ * The user's contacts, so people show by name rather than by handle
+ */
+pub struct ContactStore {
+    path: PathBuf,
+    contacts: Vec<Contact>,
+    /// Handle to the ID of the first contact that has it
+    by_handle: HashMap<String, String>,
+}
+
+impl ContactStore {
+    pub fn load() -> Result<ContactStore, std::io::Error> {
+        ContactStore::load_from(&data_dir())
+    }
+
+    pub(crate) fn load_from(dir: &Path) -> Result<ContactStore, std::io::Error> {
+        let path = dir.join("contacts.json");
+        let contacts = if path.exists() {
+            let file = File::open(&path)?;
+            serde_json::from_reader(BufReader::new(file))?
+        } else {
+            Vec::new()
+        };
+        let mut store = ContactStore {
+            path,
+            contacts,
+            by_handle: HashMap::new(),
+        };
+        store.reindex();
+        Ok(store)
+    }
+
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        write_json(&self.path, &self.contacts)
+    }
+
+    fn reindex(&mut self) {
+        self.by_handle.clear();
+        for contact in self.contacts.iter() {
+            for handle in contact.handles.iter() {
+                self.by_handle
+                    .entry(handle.clone())
+                    .or_insert_with(|| contact.id.clone());
+            }
+        }
+    }
+
+    /**
+     * Every contact, by name
+     */
+    pub fn entries(&self) -> Vec<&Contact> {
+        let mut entries: Vec<&Contact> = self.contacts.iter().collect();
+        entries.sort_by_cached_key(|contact| contact.name.to_lowercase());
+        entries
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Contact> {
+        self.contacts.iter().find(|contact| contact.id == id)
+    }
+
+    pub fn get_by_uid(&self, uid: &str) -> Option<&Contact> {
+        self.contacts.iter().find(|contact| contact.uid == uid)
+    }
+
+    /**
+     * The contact a normalized handle belongs to
+     */
+    pub fn find_by_handle(&self, handle: &str) -> Option<&Contact> {
+        self.by_handle.get(handle).and_then(|id| self.get(id))
+    }
+
+    pub fn resolve(&self, handle: &str) -> Option<ResolvedContact> {
+        self.find_by_handle(handle).map(|contact| ResolvedContact {
+            id: contact.id.clone(),
+            name: contact.name.clone(),
+            handle: handle.to_owned(),
+        })
+    }
+
+    /**
+     * Who a message is from, or for a message we sent to one person, who it
+     * is to
+     */
+    pub fn message_contact(&self, message: &StoredMessage) -> Option<ResolvedContact> {
+        if !message.from_me {
+            return self.resolve(message.sender.as_deref()?);
+        }
+        let mut others = message
+            .participants
+            .iter()
+            .filter(|participant| Some(participant.as_str()) != message.sender.as_deref());
+        match (others.next(), others.next()) {
+            (Some(recipient), None) => self.resolve(recipient),
+            _ => None,
+        }
+    }
+
+    /**
+     * Add a contact, or replace the one with the same ID
+     */
+    pub fn put(&mut self, mut contact: Contact) -> Result<Contact, std::io::Error> {
+        contact.updated = now_millis();
+        match self.contacts.iter_mut().find(|c| c.id == contact.id) {
+            Some(existing) => {
+                contact.created = existing.created;
+                *existing = contact.clone();
+            }
+            None => self.contacts.push(contact.clone()),
+        }
+        self.reindex();
+        self.save()?;
+        Ok(contact)
+    }
+
+    pub fn remove(&mut self, id: &str) -> Result<Option<Contact>, std::io::Error> {
+        let Some(index) = self.contacts.iter().position(|contact| contact.id == id) else {
+            return Ok(None);
+        };
+        let contact = self.contacts.remove(index);
+        self.reindex();
+        self.save()?;
+        Ok(Some(contact))
+    }
+
+    /**
+     * Add imported contacts, updating those with the same UID instead of
+     * adding them twice
+     */
+    pub fn import(&mut self, contacts: Vec<Contact>) -> Result<ImportReport, std::io::Error> {
+        let mut report = ImportReport::default();
+        let now = now_millis();
+        for mut contact in contacts {
+            contact.updated = now;
+            match self.contacts.iter_mut().find(|c| c.uid == contact.uid) {
+                Some(existing) => {
+                    contact.id = existing.id.clone();
+                    contact.created = existing.created;
+                    *existing = contact;
+                    report.updated += 1;
+                }
+                None => {
+                    self.contacts.push(contact);
+                    report.added += 1;
+                }
+            }
+        }
+        self.reindex();
+        self.save()?;
+        Ok(report)
+    }
+}
@@ -0,0 +1,240 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::state::{data_dir, write_json};
+
+/**
+ * How the user filed a conversation
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ConversationFlags {
+    pub pinned: bool,
+    pub muted: bool,
+    /// Milliseconds since the unix epoch when muting ends, or None to stay
+    /// muted until it is turned off
+    pub muted_until: Option<u64>,
+    pub archived: bool,
+}
+
+impl ConversationFlags {
+    pub fn is_muted(&self, now: u64) -> bool {
+        self.muted && self.muted_until.map_or(true, |until| now < until)
+    }
+
+    /**
+     * Turn off a mute that ran out, which is the same as none
+     */
+    fn clear_expired_mute(&mut self, now: u64) {
+        if !self.is_muted(now) {
+            self.muted = false;
+            self.muted_until = None;
+        }
+    }
+}
+
+/**
+ * Flags of the conversations that have any, by the ID they are listed
+ * with, so a contact's merged conversation has its own
+ */
+pub struct ConversationFlagStore {
+    path: PathBuf,
+    flags: HashMap<String, ConversationFlags>,
+}
+
+impl ConversationFlagStore {
+    pub fn load() -> Result<ConversationFlagStore, std::io::Error> {
+        ConversationFlagStore::load_from(&data_dir())
+    }
+
+    fn load_from(dir: &Path) -> Result<ConversationFlagStore, std::io::Error> {
+        let path = dir.join("conversation-flags.json");
+        let flags = if path.exists() {
+            let file = File::open(&path)?;
+            serde_json::from_reader(BufReader::new(file))?
+        } else {
+            HashMap::new()
+        };
+        Ok(ConversationFlagStore { path, flags })
+    }
+
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        write_json(&self.path, &self.flags)
+    }
+
+    pub fn get(&self, conversation_id: &str) -> ConversationFlags {
+        self.flags.get(conversation_id).cloned().unwrap_or_default()
+    }
+
+    /**
+     * Change the flags of a conversation, returning them if they changed
+     */
+    pub fn update(
+        &mut self,
+        conversation_id: &str,
+        change: impl FnOnce(&mut ConversationFlags),
+        now: u64,
+    ) -> Result<Option<ConversationFlags>, std::io::Error> {
+        let before = self.get(conversation_id);
+        let mut flags = before.clone();
+        change(&mut flags);
+        flags.clear_expired_mute(now);
+        if flags == before {
+            return Ok(None);
+        }
+        if flags == ConversationFlags::default() {
+            self.flags.remove(conversation_id);
+        } else {
+            self.flags.insert(conversation_id.to_owned(), flags.clone());
+        }
+        self.save()?;
+        Ok(Some(flags))
+    }
+
+    /**
+     * Turn off the mutes that ran out, so they do not linger until the
+     * conversation's flags are next changed
+     *
+     * Returns the conversations whose flags changed, with their flags
+     */
+    pub fn clear_expired_mutes(
+        &mut self,
+        now: u64,
+    ) -> Result<Vec<(String, ConversationFlags)>, std::io::Error> {
+        let mut cleared = Vec::new();
+        for (conversation_id, flags) in self.flags.iter_mut() {
+            if flags.muted && !flags.is_muted(now) {
+                flags.clear_expired_mute(now);
+                cleared.push((conversation_id.clone(), flags.clone()));
+            }
+        }
+        if cleared.is_empty() {
+            return Ok(cleared);
+        }
+        self.flags
+            .retain(|_, flags| *flags != ConversationFlags::default());
+        self.save()?;
+        Ok(cleared)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_dir;
+
+    fn muted(until: Option<u64>) -> ConversationFlags {
+        ConversationFlags {
+            muted: true,
+            muted_until: until,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn a_mute_lasts_until_it_runs_out() {
+        assert!(!ConversationFlags::default().is_muted(0));
+        assert!(muted(None).is_muted(u64::MAX));
+        assert!(muted(Some(10)).is_muted(9));
+        assert!(!muted(Some(10)).is_muted(10));
+        let until_only = ConversationFlags {
+            muted_until: Some(10),
+            ..Default::default()
+        };
+        assert!(!until_only.is_muted(0));
+    }
+
+    #[test]
+    fn updates_return_the_flags_only_if_they_changed() {
+        let dir = test_dir();
+        let mut store = ConversationFlagStore::load_from(&dir).unwrap();
+        let pinned = store.update("a", |flags| flags.pinned = true, 0).unwrap();
+        assert_eq!(
+            pinned,
+            Some(ConversationFlags {
+                pinned: true,
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            store.update("a", |flags| flags.pinned = true, 0).unwrap(),
+            None
+        );
+
+        // Persisted, and forgotten once nothing is set
+        let mut store = ConversationFlagStore::load_from(&dir).unwrap();
+        assert!(store.get("a").pinned);
+        store.update("a", |flags| flags.pinned = false, 0).unwrap();
+        assert!(store.flags.is_empty());
+    }
+
+    #[test]
+    fn muting_until_a_time_already_past_does_nothing() {
+        let dir = test_dir();
+        let mut store = ConversationFlagStore::load_from(&dir).unwrap();
+        let change = |flags: &mut ConversationFlags| *flags = muted(Some(5));
+        assert_eq!(store.update("a", change, 10).unwrap(), None);
+        assert_eq!(store.update("a", change, 0).unwrap(), Some(muted(Some(5))));
+        // Clears the mute that ran out along with the change
+        let archived = store
+            .update("a", |flags| flags.archived = true, 10)
+            .unwrap();
+        assert_eq!(
+            archived,
+            Some(ConversationFlags {
+                archived: true,
+                ..Default::default()
+            })
+        );
+        assert!(!dir.join("conversation-flags.json.tmp").exists());
+    }
+
+    #[test]
+    fn expired_mutes_are_cleared_everywhere() {
+        let dir = test_dir();
+        let mut store = ConversationFlagStore::load_from(&dir).unwrap();
+        store.update("expired", |f| *f = muted(Some(5)), 0).unwrap();
+        store.update("later", |f| *f = muted(Some(50)), 0).unwrap();
+        store.update("forever", |f| *f = muted(None), 0).unwrap();
+        store
+            .update(
+                "pinned",
+                |f| {
+                    *f = ConversationFlags {
+                        pinned: true,
+                        ..muted(Some(5))
+                    }
+                },
+                0,
+            )
+            .unwrap();
+
+        let mut cleared = store.clear_expired_mutes(10).unwrap();
+        cleared.sort_by(|(a, _), (b, _)| a.cmp(b));
+        assert_eq!(
+            cleared,
+            [
+                ("expired".to_owned(), ConversationFlags::default()),
+                (
+                    "pinned".to_owned(),
+                    ConversationFlags {
+                        pinned: true,
+                        ..Default::default()
+                    }
+                ),
+            ]
+        );
+        assert!(store.clear_expired_mutes(10).unwrap().is_empty());
+
+        let store = ConversationFlagStore::load_from(&dir).unwrap();
+        assert!(!store.flags.contains_key("expired"));
+        assert!(store.get("later").is_muted(10));
+        assert!(store.get("forever").is_muted(10));
+    }
+}
@@ -0,0 +1,271 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::state::{data_dir, write_atomic};
+
+/// How long looked up identities are used before asking IDS again
+pub const IDENTITY_TTL: u64 = 24 * 60 * 60 * 1000;
+
+/**
+ * One device a handle can be reached on, as IDS returned it
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedIdentity {
+    /// The encoded public identity keys, base64
+    pub identity: String,
+    /// Base64
+    pub push_token: String,
+    /// Base64
+    pub session_token: String,
+}
+
+impl CachedIdentity {
+    /**
+     * SHA-256 of the public identity keys, in groups of four hex digits
+     * so people can compare them
+     */
+    pub fn fingerprint(&self) -> Option<String> {
+        let identity = general_purpose::STANDARD.decode(&self.identity).ok()?;
+        let hex: Vec<String> = openssl::sha::sha256(&identity)
+            .chunks(2)
+            .map(|pair| format!("{:02X}{:02X}", pair[0], pair[1]))
+            .collect();
+        Some(hex.join(" "))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedHandle {
+    pub identities: Vec<CachedIdentity>,
+    /// Milliseconds since the unix epoch
+    pub fetched_at: u64,
+    pub expires_at: u64,
+}
+
+/**
+ * Recipient identities looked up from IDS, kept next to the saved state so
+ * repeat sends skip the lookup, even after a restart
+ */
+pub struct IdentityCache {
+    path: PathBuf,
+    /// By handle
+    handles: HashMap<String, CachedHandle>,
+}
+
+/**
+ * The identity cache as it was when the snapshot was taken
+ */
+pub struct IdentitySnapshot {
+    path: PathBuf,
+    handles: HashMap<String, CachedHandle>,
+}
+
+impl IdentitySnapshot {
+    /**
+     * Private, since it holds the push and session tokens of everyone we
+     * have messaged
+     */
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        write_atomic(&self.path, true, |writer| {
+            Ok(serde_json::to_writer(writer, &self.handles)?)
+        })
+    }
+}
+
+impl IdentityCache {
+    pub fn load() -> Result<IdentityCache, std::io::Error> {
+        IdentityCache::load_from(&data_dir())
+    }
+
+    fn load_from(dir: &Path) -> Result<IdentityCache, std::io::Error> {
+        let path = dir.join("identities.json");
+        let handles = if path.exists() {
+            let file = File::open(&path)?;
+            serde_json::from_reader(BufReader::new(file))?
+        } else {
+            HashMap::new()
+        };
+        Ok(IdentityCache { path, handles })
+    }
+
+    /**
+     * A copy of the cache to save, so the file can be written once the
+     * cache is no longer locked
+     */
+    pub fn snapshot(&self) -> IdentitySnapshot {
+        IdentitySnapshot {
+            path: self.path.clone(),
+            handles: self.handles.clone(),
+        }
+    }
+
+    /**
+     * Every handle whose identities have not expired
+     */
+    pub fn fresh(&self, now: u64) -> impl Iterator<Item = (&String, &CachedHandle)> {
+        self.handles
+            .iter()
+            .filter(move |(_, cached)| cached.expires_at > now)
+    }
+
+    pub fn get(&self, handle: &str) -> Option<&CachedHandle> {
+        self.handles.get(handle)
+    }
+
+    /**
+     * Remember what a lookup returned, unless it is the lookup we already
+     * have, which is told by its tokens since IDS hands out new ones every
+     * time
+     *
+     * Returns whether anything changed
+     */
+    pub fn set(&mut self, handle: &str, identities: Vec<CachedIdentity>, now: u64) -> bool {
+        if let Some(cached) = self.handles.get(handle) {
+            let tokens = |identities: &[CachedIdentity]| -> Vec<(String, String)> {
+                identities
+                    .iter()
+                    .map(|identity| (identity.push_token.clone(), identity.session_token.clone()))
+                    .collect()
+            };
+            if cached.expires_at > now && tokens(&cached.identities) == tokens(&identities) {
+                return false;
+            }
+        }
+        self.handles.insert(
+            handle.to_owned(),
+            CachedHandle {
+                identities,
+                fetched_at: now,
+                expires_at: now + IDENTITY_TTL,
+            },
+        );
+        true
+    }
+
+    /**
+     * Forget handles so they are looked up again on the next send
+     *
+     * Returns whether any were cached
+     */
+    pub fn remove(&mut self, handles: &[String]) -> bool {
+        let before = self.handles.len();
+        self.handles.retain(|handle, _| !handles.contains(handle));
+        self.handles.len() != before
+    }
+
+    /**
+     * Forget everything that has expired
+     *
+     * Returns the handles that were dropped
+     */
+    pub fn prune(&mut self, now: u64) -> Vec<String> {
+        let expired: Vec<String> = self
+            .handles
+            .iter()
+            .filter(|(_, cached)| cached.expires_at <= now)
+            .map(|(handle, _)| handle.clone())
+            .collect();
+        self.remove(&expired);
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_dir;
+
+    fn identity(identity: &str, token: &str) -> CachedIdentity {
+        CachedIdentity {
+            identity: general_purpose::STANDARD.encode(identity),
+            push_token: general_purpose::STANDARD.encode(token),
+            session_token: general_purpose::STANDARD.encode(token),
+        }
+    }
+
+    #[test]
+    fn fingerprints_are_grouped_hex() {
+        let fingerprint = identity("keys", "token").fingerprint().unwrap();
+        let groups: Vec<&str> = fingerprint.split(' ').collect();
+        assert_eq!(groups.len(), 16);
+        assert!(groups
+            .iter()
+            .all(|group| group.len() == 4 && group.chars().all(|c| c.is_ascii_hexdigit())));
+        assert_eq!(
+            identity("keys", "other").fingerprint(),
+            Some(fingerprint.clone())
+        );
+        assert_ne!(identity("other", "token").fingerprint(), Some(fingerprint));
+        let unreadable = CachedIdentity {
+            identity: "not base64!".to_owned(),
+            ..identity("keys", "token")
+        };
+        assert_eq!(unreadable.fingerprint(), None);
+    }
+
+    #[test]
+    fn the_same_lookup_is_only_recorded_once() {
+        let dir = test_dir();
+        let mut cache = IdentityCache::load_from(&dir).unwrap();
+        let handle = "tel:+15551234567";
+        assert!(cache.set(handle, vec![identity("keys", "a")], 1));
+        assert!(!cache.set(handle, vec![identity("keys", "a")], 2));
+        assert_eq!(cache.get(handle).unwrap().fetched_at, 1);
+        assert!(cache.set(handle, vec![identity("keys", "b")], 3));
+        // Once expired, even the same lookup starts a fresh period
+        let expired = 3 + IDENTITY_TTL;
+        assert!(cache.set(handle, vec![identity("keys", "b")], expired));
+        assert_eq!(
+            cache.get(handle).unwrap().expires_at,
+            expired + IDENTITY_TTL
+        );
+
+        cache.snapshot().save().unwrap();
+        let cache = IdentityCache::load_from(&dir).unwrap();
+        assert_eq!(
+            cache.get(handle).unwrap().identities,
+            [identity("keys", "b")]
+        );
+    }
+
+    #[test]
+    fn expired_handles_are_pruned() {
+        let dir = test_dir();
+        let mut cache = IdentityCache::load_from(&dir).unwrap();
+        cache.set("old", vec![identity("keys", "a")], 0);
+        cache.set("new", vec![identity("keys", "b")], 10);
+        let fresh: Vec<&String> = cache
+            .fresh(IDENTITY_TTL)
+            .map(|(handle, _)| handle)
+            .collect();
+        assert_eq!(fresh, ["new"]);
+
+        assert_eq!(cache.prune(IDENTITY_TTL), ["old"]);
+        assert!(cache.get("old").is_none());
+        assert!(cache.prune(IDENTITY_TTL).is_empty());
+        assert!(cache.remove(&["new".to_owned()]));
+        assert!(!cache.remove(&["new".to_owned()]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn saved_identities_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = test_dir();
+        let mut cache = IdentityCache::load_from(&dir).unwrap();
+        cache.set("tel:+15551234567", vec![identity("keys", "a")], 0);
+        cache.snapshot().save().unwrap();
+        let metadata = std::fs::metadata(dir.join("identities.json")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::state::{data_dir, write_json};
+
+/**
+ * A device key a handle has used
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyRecord {
+    pub fingerprint: String,
+    /// Milliseconds since the unix epoch
+    pub first_seen: u64,
+    pub last_seen: u64,
+    /// When a lookup first no longer returned it
+    pub removed_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HandleKeys {
+    /// Whether the handle still has the keys the user verified
+    pub verified: bool,
+    /// The keys the user checked when they verified the handle, empty if
+    /// they never did
+    pub verified_fingerprints: Vec<String>,
+    /// Messages to the handle are held until the user confirms its new keys
+    pub held: bool,
+    /// Every key seen, oldest first
+    pub history: Vec<KeyRecord>,
+}
+
+impl HandleKeys {
+    pub fn current(&self) -> Vec<String> {
+        self.history
+            .iter()
+            .filter(|record| record.removed_at.is_none())
+            .map(|record| record.fingerprint.clone())
+            .collect()
+    }
+
+    /**
+     * Whether the current keys are exactly the ones the user verified
+     */
+    fn matches_verified(&self) -> bool {
+        let current = self.current();
+        current.len() == self.verified_fingerprints.len()
+            && current
+                .iter()
+                .all(|fingerprint| self.verified_fingerprints.contains(fingerprint))
+    }
+}
+
+/**
+ * How the keys of a verified handle changed
+ */
+#[derive(Clone, Debug)]
+pub struct KeyChange {
+    pub handle: String,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Whether messages to the handle are now held
+    pub held: bool,
+}
+
+/**
+ * Which keys each handle has used and whether the user has verified them
+ */
+pub struct KeyTrustStore {
+    path: PathBuf,
+    /// By handle
+    handles: HashMap<String, HandleKeys>,
+}
+
+impl KeyTrustStore {
+    pub fn load() -> Result<KeyTrustStore, std::io::Error> {
+        KeyTrustStore::load_from(&data_dir())
+    }
+
+    fn load_from(dir: &Path) -> Result<KeyTrustStore, std::io::Error> {
+        let path = dir.join("keys.json");
+        let handles = if path.exists() {
+            let file = File::open(&path)?;
+            serde_json::from_reader(BufReader::new(file))?
+        } else {
+            HashMap::new()
+        };
+        Ok(KeyTrustStore { path, handles })
+    }
+
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        write_json(&self.path, &self.handles)
+    }
+
+    pub fn get(&self, handle: &str) -> Option<&HandleKeys> {
+        self.handles.get(handle)
+    }
+
+    /**
+     * Record the keys a lookup returned for a handle
+     *
+     * A handle the user verified stays verified only while it has the keys
+     * they checked. Returns how the keys changed if they did and no longer
+     * match, holding its messages first if `hold` is set. Messages stay
+     * held until the user confirms, even if the verified keys come back.
+     */
+    pub fn observe(
+        &mut self,
+        handle: &str,
+        fingerprints: &[String],
+        now: u64,
+        hold: bool,
+    ) -> Result<Option<KeyChange>, std::io::Error> {
+        let keys = self.handles.entry(handle.to_owned()).or_default();
+        let current = keys.current();
+        let added: Vec<String> = fingerprints
+            .iter()
+            .filter(|fingerprint| !current.contains(fingerprint))
+            .cloned()
+            .collect();
+        let removed: Vec<String> = current
+            .iter()
+            .filter(|fingerprint| !fingerprints.contains(fingerprint))
+            .cloned()
+            .collect();
+        for record in keys.history.iter_mut() {
+            if record.removed_at.is_some() {
+                continue;
+            }
+            if removed.contains(&record.fingerprint) {
+                record.removed_at = Some(now);
+            } else {
+                record.last_seen = now;
+            }
+        }
+        keys.history
+            .extend(added.iter().map(|fingerprint| KeyRecord {
+                fingerprint: fingerprint.clone(),
+                first_seen: now,
+                last_seen: now,
+                removed_at: None,
+            }));
+        let tracked = !keys.verified_fingerprints.is_empty();
+        let changed = !(added.is_empty() && removed.is_empty());
+        if tracked {
+            keys.verified = keys.matches_verified();
+        }
+        let change = if tracked && changed && !keys.verified {
+            keys.held |= hold;
+            Some(KeyChange {
+                handle: handle.to_owned(),
+                added,
+                removed,
+                held: keys.held,
+            })
+        } else {
+            None
+        };
+        self.save()?;
+        Ok(change)
+    }
+
+    /**
+     * Mark a handle as verified with the keys it has now, or not verified
+     *
+     * Either way its messages are no longer held. Returns false if no keys
+     * are known for the handle.
+     */
+    pub fn set_verified(&mut self, handle: &str, verified: bool) -> Result<bool, std::io::Error> {
+        let Some(keys) = self.handles.get_mut(handle) else {
+            return Ok(false);
+        };
+        keys.verified = verified;
+        keys.verified_fingerprints = if verified { keys.current() } else { Vec::new() };
+        keys.held = false;
+        self.save()?;
+        Ok(true)
+    }
+
+    /**
+     * The handles among these whose messages are held
+     */
+    pub fn held_among(&self, handles: &[String]) -> Vec<String> {
+        handles
+            .iter()
+            .filter(|handle| self.handles.get(*handle).is_some_and(|keys| keys.held))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_dir;
+
+    const HANDLE: &str = "tel:+15551234567";
+
+    fn fingerprints(fingerprints: &[&str]) -> Vec<String> {
+        fingerprints.iter().map(|f| f.to_string()).collect()
+    }
+
+    #[test]
+    fn the_history_records_when_keys_come_and_go() {
+        let dir = test_dir();
+        let mut store = KeyTrustStore::load_from(&dir).unwrap();
+        let change = store.observe(HANDLE, &fingerprints(&["a", "b"]), 1, true);
+        assert!(change.unwrap().is_none());
+        let change = store.observe(HANDLE, &fingerprints(&["b", "c"]), 2, true);
+        // Nobody verified the handle, so nothing is held
+        assert!(change.unwrap().is_none());
+        assert!(store.held_among(&[HANDLE.to_owned()]).is_empty());
+
+        let store = KeyTrustStore::load_from(&dir).unwrap();
+        let keys = store.get(HANDLE).unwrap();
+        assert_eq!(keys.current(), ["b", "c"]);
+        let history: Vec<(&str, u64, u64, Option<u64>)> = keys
+            .history
+            .iter()
+            .map(|r| {
+                (
+                    r.fingerprint.as_str(),
+                    r.first_seen,
+                    r.last_seen,
+                    r.removed_at,
+                )
+            })
+            .collect();
+        assert_eq!(
+            history,
+            [("a", 1, 1, Some(2)), ("b", 1, 2, None), ("c", 2, 2, None)]
+        );
+    }
+
+    #[test]
+    fn a_verified_handle_with_new_keys_is_no_longer_verified() {
+        let dir = test_dir();
+        let mut store = KeyTrustStore::load_from(&dir).unwrap();
+        store
+            .observe(HANDLE, &fingerprints(&["a"]), 1, true)
+            .unwrap();
+        assert!(store.set_verified(HANDLE, true).unwrap());
+        assert!(store
+            .observe(HANDLE, &fingerprints(&["a"]), 2, true)
+            .unwrap()
+            .is_none());
+        assert!(store.get(HANDLE).unwrap().verified);
+
+        let change = store
+            .observe(HANDLE, &fingerprints(&["a", "b"]), 3, true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(change.added, ["b"]);
+        assert!(change.removed.is_empty());
+        assert!(change.held);
+        assert!(!store.get(HANDLE).unwrap().verified);
+        assert_eq!(store.held_among(&[HANDLE.to_owned()]), [HANDLE]);
+
+        // The same keys again are not another change, and still not verified
+        assert!(store
+            .observe(HANDLE, &fingerprints(&["a", "b"]), 4, true)
+            .unwrap()
+            .is_none());
+        assert!(!store.get(HANDLE).unwrap().verified);
+
+        // Going back to the verified keys restores it, but only the user
+        // releases the hold
+        assert!(store
+            .observe(HANDLE, &fingerprints(&["a"]), 5, true)
+            .unwrap()
+            .is_none());
+        assert!(store.get(HANDLE).unwrap().verified);
+        assert!(store.get(HANDLE).unwrap().held);
+    }
+
+    #[test]
+    fn every_change_from_the_verified_keys_is_reported() {
+        let dir = test_dir();
+        let mut store = KeyTrustStore::load_from(&dir).unwrap();
+        store
+            .observe(HANDLE, &fingerprints(&["a"]), 1, false)
+            .unwrap();
+        store.set_verified(HANDLE, true).unwrap();
+        let change = store.observe(HANDLE, &fingerprints(&["b"]), 2, false);
+        let change = change.unwrap().unwrap();
+        assert_eq!(
+            (change.added, change.removed),
+            (fingerprints(&["b"]), fingerprints(&["a"]))
+        );
+        assert!(!change.held);
+        let change = store.observe(HANDLE, &fingerprints(&["c"]), 3, false);
+        assert_eq!(change.unwrap().unwrap().added, ["c"]);
+        assert!(store.held_among(&[HANDLE.to_owned()]).is_empty());
+    }
+
+    #[test]
+    fn verifying_again_confirms_the_new_keys() {
+        let dir = test_dir();
+        let mut store = KeyTrustStore::load_from(&dir).unwrap();
+        assert!(!store.set_verified(HANDLE, true).unwrap());
+        store
+            .observe(HANDLE, &fingerprints(&["a"]), 1, true)
+            .unwrap();
+        store.set_verified(HANDLE, true).unwrap();
+        store
+            .observe(HANDLE, &fingerprints(&["b"]), 2, true)
+            .unwrap();
+
+        store.set_verified(HANDLE, true).unwrap();
+        let keys = store.get(HANDLE).unwrap();
+        assert!(keys.verified && !keys.held);
+        assert_eq!(keys.verified_fingerprints, ["b"]);
+
+        store.set_verified(HANDLE, false).unwrap();
+        assert!(store
+            .observe(HANDLE, &fingerprints(&["c"]), 3, true)
+            .unwrap()
+            .is_none());
+        assert!(!store.get(HANDLE).unwrap().verified);
+    }
+}
@@ -0,0 +1,1387 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::state::{data_dir, write_json};
+
+/// Journal lines after which the whole history is written out instead
+const JOURNAL_LIMIT: usize = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredMessage {
+    pub id: String,
+    pub conversation_id: String,
+    pub participants: Vec<String>,
+    pub sender: Option<String>,
+    pub from_me: bool,
+    pub text: String,
+    /// Milliseconds since the unix epoch
+    pub timestamp: u64,
+    /// IDs in the attachment store
+    #[serde(default)]
+    pub attachments: Vec<String>,
+    #[serde(default)]
+    pub reactions: Vec<StoredReaction>,
+    /// Set if this message is an inline reply
+    #[serde(default)]
+    pub reply_to: Option<ReplyTo>,
+    /// When a message we sent reached the recipient, or a message we
+    /// received reached the owner through a bridge, in milliseconds since
+    /// the unix epoch
+    #[serde(default)]
+    pub delivered_at: Option<u64>,
+    /// When the recipient read a message we sent, or when we read a message
+    /// we received
+    #[serde(default)]
+    pub read_at: Option<u64>,
+    /// Earlier versions of the text, oldest first
+    #[serde(default)]
+    pub edits: Vec<MessageEdit>,
+    /// When the sender took the message back, its content is gone after that
+    #[serde(default)]
+    pub unsent_at: Option<u64>,
+    /// Queued, sending or failed while the message is still in the outbox
+    #[serde(default)]
+    pub send_status: Option<MessageStatus>,
+}
+
+/// How long after sending a message it can still be edited, in milliseconds
+pub const EDIT_WINDOW: u64 = 15 * 60 * 1000;
+/// How long after sending a message it can still be unsent, in milliseconds
+pub const UNSEND_WINDOW: u64 = 2 * 60 * 1000;
+
+/**
+ * A version of an edited message's text that has been replaced
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageEdit {
+    pub text: String,
+    /// When the next version replaced it, in milliseconds since the unix epoch
+    pub replaced_at: u64,
+}
+
+/**
+ * Why we may not edit or unsend a message
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeRejected {
+    NotFromMe,
+    /// Still in the outbox
+    NotSent,
+    Unsent,
+    WindowExpired,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum MessageStatus {
+    /// Committed locally and waiting to be sent
+    Queued,
+    Sending,
+    /// Could not be sent, see the outbox for why
+    Failed,
+    Sent,
+    Delivered,
+    /// Received and not read by us yet
+    Unread,
+    Read,
+}
+
+/**
+ * The message a reply belongs to
+ *
+ * iMessage threads point every reply at the message that started the
+ * thread, not at the message directly above it
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplyTo {
+    /// The thread originator
+    pub message_id: String,
+    /// Index of the part of the originator that was replied to
+    pub part: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Tapback {
+    Love,
+    Like,
+    Dislike,
+    Laugh,
+    Emphasize,
+    Question,
+    Emoji(String),
+}
+
+impl Tapback {
+    /**
+     * The emoji other apps show this tapback as
+     */
+    pub fn emoji(&self) -> &str {
+        match self {
+            Tapback::Love => "\u{2764}\u{fe0f}",
+            Tapback::Like => "\u{1f44d}",
+            Tapback::Dislike => "\u{1f44e}",
+            Tapback::Laugh => "\u{1f602}",
+            Tapback::Emphasize => "\u{203c}\u{fe0f}",
+            Tapback::Question => "\u{2753}",
+            Tapback::Emoji(emoji) => emoji,
+        }
+    }
+
+    /**
+     * The classic tapback an emoji stands for, or an emoji tapback
+     */
+    pub fn from_emoji(emoji: &str) -> Tapback {
+        match emoji.trim_end_matches('\u{fe0f}') {
+            "\u{2764}" => Tapback::Love,
+            "\u{1f44d}" => Tapback::Like,
+            "\u{1f44e}" => Tapback::Dislike,
+            "\u{1f602}" => Tapback::Laugh,
+            "\u{203c}" => Tapback::Emphasize,
+            "\u{2753}" => Tapback::Question,
+            _ => Tapback::Emoji(emoji.to_owned()),
+        }
+    }
+}
+
+/**
+ * One participant's tapback on a message
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredReaction {
+    pub sender: Option<String>,
+    pub from_me: bool,
+    pub tapback: Tapback,
+    /// Index of the message part reacted to
+    pub part: u64,
+    /// Milliseconds since the unix epoch
+    pub timestamp: u64,
+}
+
+impl StoredReaction {
+    fn same_sender(&self, other: &StoredReaction) -> bool {
+        if self.from_me || other.from_me {
+            self.from_me == other.from_me
+        } else {
+            self.sender == other.sender
+        }
+    }
+}
+
+/**
+ * Everyone who reacted to a message with the same tapback
+ */
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReactionSummary {
+    pub tapback: Tapback,
+    pub count: usize,
+    /// Whether one of them is us
+    pub from_me: bool,
+    pub senders: Vec<String>,
+}
+
+impl StoredMessage {
+    /**
+     * Whether we may still edit or unsend this message at `now`, given how
+     * long after sending that is allowed
+     */
+    pub fn check_change(&self, window: u64, now: u64) -> Result<(), ChangeRejected> {
+        if !self.from_me {
+            return Err(ChangeRejected::NotFromMe);
+        }
+        if self.send_status.is_some() {
+            return Err(ChangeRejected::NotSent);
+        }
+        if self.unsent_at.is_some() {
+            return Err(ChangeRejected::Unsent);
+        }
+        if now.saturating_sub(self.timestamp) > window {
+            return Err(ChangeRejected::WindowExpired);
+        }
+        Ok(())
+    }
+
+    /**
+     * Whether a change to this message came from whoever sent it
+     */
+    pub fn sent_by(&self, sender: Option<&str>, from_me: bool) -> bool {
+        if self.from_me || from_me {
+            self.from_me == from_me
+        } else {
+            self.sender.as_deref() == sender
+        }
+    }
+
+    pub fn status(&self) -> MessageStatus {
+        if let Some(status) = self.send_status {
+            return status;
+        }
+        match (self.from_me, self.delivered_at, self.read_at) {
+            (_, _, Some(_)) => MessageStatus::Read,
+            (true, Some(_), None) => MessageStatus::Delivered,
+            (true, None, None) => MessageStatus::Sent,
+            (false, _, None) => MessageStatus::Unread,
+        }
+    }
+
+    /**
+     * The reactions on this message grouped by tapback, in the order they
+     * were first used
+     */
+    pub fn reaction_summary(&self) -> Vec<ReactionSummary> {
+        let mut summary: Vec<ReactionSummary> = Vec::new();
+        for reaction in &self.reactions {
+            let index = match summary
+                .iter()
+                .position(|entry| entry.tapback == reaction.tapback)
+            {
+                Some(index) => index,
+                None => {
+                    summary.push(ReactionSummary {
+                        tapback: reaction.tapback.clone(),
+                        count: 0,
+                        from_me: false,
+                        senders: Vec::new(),
+                    });
+                    summary.len() - 1
+                }
+            };
+            let entry = &mut summary[index];
+            entry.count += 1;
+            entry.from_me |= reaction.from_me;
+            entry.senders.extend(reaction.sender.clone());
+        }
+        summary
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversationSummary {
+    pub id: String,
+    pub participants: Vec<String>,
+    pub last_message: StoredMessage,
+    pub message_count: usize,
+    /// Messages we received and have not read
+    pub unread_count: usize,
+    /// For a contact's merged conversation, the IDs of the conversations
+    /// in it, most recently active first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub merged: Vec<String>,
+}
+
+/**
+ * A conversation with messages we received and have not read
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnreadConversation {
+    pub id: String,
+    pub participants: Vec<String>,
+    pub unread_count: usize,
+}
+
+/**
+ * Where a conversation's messages are in the history, so a summary clones
+ * only the last one
+ */
+struct ConversationTally {
+    first: usize,
+    last: usize,
+    message_count: usize,
+    unread_count: usize,
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/**
+ * The local message history, persisted as JSON next to the saved state
+ */
+pub struct MessageStore {
+    path: PathBuf,
+    /// Messages added or changed since `path` was last written, one per
+    /// line, so adding or changing a message does not rewrite the whole
+    /// history. A later line for a message replaces the earlier ones.
+    journal_path: PathBuf,
+    /// Lines in the journal
+    journaled: usize,
+    messages: Vec<StoredMessage>,
+    /// Position of each message in `messages` by ID
+    index: HashMap<String, usize>,
+    /// Changes whenever a message is added or removed
+    generation: u64,
+}
+
+impl MessageStore {
+    pub fn load() -> Result<MessageStore, std::io::Error> {
+        MessageStore::load_from(&data_dir())
+    }
+
+    pub(crate) fn load_from(dir: &Path) -> Result<MessageStore, std::io::Error> {
+        let path = dir.join("messages.json");
+        let journal_path = dir.join("messages.journal");
+        let messages = if path.exists() {
+            let file = File::open(&path)?;
+            serde_json::from_reader(BufReader::new(file))?
+        } else {
+            Vec::new()
+        };
+        let mut store = MessageStore {
+            path,
+            journal_path,
+            journaled: 0,
+            messages,
+            index: HashMap::new(),
+            generation: 0,
+        };
+        store.reindex();
+        if store.journal_path.exists() {
+            store.replay_journal()?;
+            store.save()?;
+        }
+        Ok(store)
+    }
+
+    fn replay_journal(&mut self) -> Result<(), std::io::Error> {
+        let file = File::open(&self.journal_path)?;
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<StoredMessage>(&line) {
+                Ok(message) => match self.get_mut(&message.id) {
+                    Some(existing) => *existing = message,
+                    None => self.push(message),
+                },
+                // Only a line cut short by a crash while appending
+                Err(e) => log::warn!("Skipping a damaged line in the message journal: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    fn reindex(&mut self) {
+        self.index = self
+            .messages
+            .iter()
+            .enumerate()
+            .map(|(position, message)| (message.id.clone(), position))
+            .collect();
+    }
+
+    fn push(&mut self, message: StoredMessage) {
+        self.index.insert(message.id.clone(), self.messages.len());
+        self.messages.push(message);
+        self.generation += 1;
+    }
+
+    /**
+     * Write the whole history, which also takes in everything in the journal
+     */
+    pub fn save(&mut self) -> Result<(), std::io::Error> {
+        write_json(&self.path, &self.messages)?;
+        self.journaled = 0;
+        match std::fs::remove_file(&self.journal_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn append(&mut self, messages: &[StoredMessage]) -> Result<(), std::io::Error> {
+        if let Some(parent) = self.journal_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut lines = Vec::new();
+        for message in messages {
+            serde_json::to_writer(&mut lines, message)?;
+            lines.push(b'\n');
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.journal_path)?
+            .write_all(&lines)?;
+        self.journaled += messages.len();
+        Ok(())
+    }
+
+    /**
+     * Keep messages that changed, in the journal until it gets long and then
+     * by writing the whole history
+     */
+    fn record(&mut self, changed: &[StoredMessage]) -> Result<(), std::io::Error> {
+        if self.journaled + changed.len() > JOURNAL_LIMIT {
+            return self.save();
+        }
+        self.append(changed)
+    }
+
+    /**
+     * Add a message to the history, ignoring messages we already have
+     *
+     * Returns whether the message was new
+     */
+    pub fn add(&mut self, message: StoredMessage) -> Result<bool, std::io::Error> {
+        if self.get(&message.id).is_some() {
+            return Ok(false);
+        }
+        if self.journaled < JOURNAL_LIMIT {
+            self.append(std::slice::from_ref(&message))?;
+            self.push(message);
+        } else {
+            self.push(message);
+            self.save()?;
+        }
+        Ok(true)
+    }
+
+    /**
+     * Apply a tapback to a message, replacing the sender's previous tapback
+     * on the same part since everyone only gets one
+     *
+     * Removing only takes effect if it matches the tapback the sender has.
+     * Returns the updated message, or None if we do not have it
+     */
+    pub fn set_reaction(
+        &mut self,
+        message_id: &str,
+        reaction: StoredReaction,
+        enable: bool,
+    ) -> Result<Option<StoredMessage>, std::io::Error> {
+        let Some(message) = self.get_mut(message_id) else {
+            return Ok(None);
+        };
+        if enable {
+            message.reactions.retain(|existing| {
+                !(existing.same_sender(&reaction) && existing.part == reaction.part)
+            });
+            message.reactions.push(reaction);
+        } else {
+            message.reactions.retain(|existing| {
+                !(existing.same_sender(&reaction)
+                    && existing.part == reaction.part
+                    && existing.tapback == reaction.tapback)
+            });
+        }
+        let message = message.clone();
+        self.record(std::slice::from_ref(&message))?;
+        Ok(Some(message))
+    }
+
+    fn update(
+        &mut self,
+        id: &str,
+        update: impl FnOnce(&mut StoredMessage) -> bool,
+    ) -> Result<Option<StoredMessage>, std::io::Error> {
+        let Some(message) = self.get_mut(id) else {
+            return Ok(None);
+        };
+        if !update(message) {
+            return Ok(None);
+        }
+        let message = message.clone();
+        self.record(std::slice::from_ref(&message))?;
+        Ok(Some(message))
+    }
+
+    /**
+     * Replace the text of a message, keeping the old one in its edit history
+     *
+     * Returns the updated message, or None if it is unknown, unsent or the
+     * text did not change
+     */
+    pub fn edit(
+        &mut self,
+        id: &str,
+        text: String,
+        at: u64,
+    ) -> Result<Option<StoredMessage>, std::io::Error> {
+        self.update(id, |message| {
+            if message.unsent_at.is_some() || message.text == text {
+                return false;
+            }
+            let previous = std::mem::replace(&mut message.text, text);
+            message.edits.push(MessageEdit {
+                text: previous,
+                replaced_at: at,
+            });
+            true
+        })
+    }
+
+    /**
+     * Take back a message, dropping its text, edit history and attachments
+     *
+     * Returns the message as it was before, or None if it is unknown or
+     * already unsent
+     */
+    pub fn unsend(&mut self, id: &str, at: u64) -> Result<Option<StoredMessage>, std::io::Error> {
+        let before = match self.get(id) {
+            Some(message) if message.unsent_at.is_none() => message.clone(),
+            _ => return Ok(None),
+        };
+        self.update(id, |message| {
+            message.text.clear();
+            message.edits.clear();
+            message.attachments.clear();
+            message.unsent_at = Some(at);
+            true
+        })?;
+        Ok(Some(before))
+    }
+
+    /**
+     * Set where a message is in the outbox, None once it has been sent
+     */
+    pub fn set_send_status(
+        &mut self,
+        id: &str,
+        status: Option<MessageStatus>,
+    ) -> Result<Option<StoredMessage>, std::io::Error> {
+        self.update(id, |message| {
+            if message.send_status == status {
+                return false;
+            }
+            message.send_status = status;
+            true
+        })
+    }
+
+    /**
+     * Drop a message that was never sent from the history
+     */
+    pub fn remove_unsent(&mut self, id: &str) -> Result<Option<StoredMessage>, std::io::Error> {
+        let Some(index) = self
+            .messages
+            .iter()
+            .position(|message| message.id == id && message.send_status.is_some())
+        else {
+            return Ok(None);
+        };
+        let message = self.messages.remove(index);
+        self.reindex();
+        self.generation += 1;
+        self.save()?;
+        Ok(Some(message))
+    }
+
+    /**
+     * Record that a message we sent was delivered, along with everything we
+     * sent before it in the same conversation, since receipts are only sent
+     * for the newest message
+     *
+     * Returns the messages that changed, or an empty list if the message is
+     * unknown
+     */
+    pub fn mark_delivered(
+        &mut self,
+        id: &str,
+        at: u64,
+    ) -> Result<Vec<StoredMessage>, std::io::Error> {
+        self.mark_sent_through(id, |message| {
+            if message.delivered_at.is_some() {
+                return false;
+            }
+            message.delivered_at = Some(at);
+            true
+        })
+    }
+
+    /**
+     * Record that a message we sent was read, along with everything we sent
+     * before it in the same conversation. Being read also means they were
+     * delivered, even if that receipt never arrived.
+     */
+    pub fn mark_read(&mut self, id: &str, at: u64) -> Result<Vec<StoredMessage>, std::io::Error> {
+        self.mark_sent_through(id, |message| {
+            if message.read_at.is_some() {
+                return false;
+            }
+            message.delivered_at.get_or_insert(at);
+            message.read_at = Some(at);
+            true
+        })
+    }
+
+    fn mark_sent_through(
+        &mut self,
+        id: &str,
+        mut update: impl FnMut(&mut StoredMessage) -> bool,
+    ) -> Result<Vec<StoredMessage>, std::io::Error> {
+        let Some((conversation_id, timestamp)) = self
+            .get(id)
+            .map(|message| (message.conversation_id.clone(), message.timestamp))
+        else {
+            return Ok(Vec::new());
+        };
+        let mut marked: Vec<StoredMessage> = Vec::new();
+        for message in self.messages.iter_mut() {
+            if message.conversation_id != conversation_id
+                || !message.from_me
+                || message.send_status.is_some()
+                || message.timestamp > timestamp
+            {
+                continue;
+            }
+            if update(message) {
+                marked.push(message.clone());
+            }
+        }
+        if !marked.is_empty() {
+            self.record(&marked)?;
+        }
+        marked.sort_by_key(|message| message.timestamp);
+        Ok(marked)
+    }
+
+    /**
+     * Record that a message we received was passed on to the owner's client
+     * by a bridge
+     *
+     * Returns the updated message, or None if it is unknown, ours or was
+     * already passed on
+     */
+    pub fn mark_relayed(
+        &mut self,
+        id: &str,
+        at: u64,
+    ) -> Result<Option<StoredMessage>, std::io::Error> {
+        self.update(id, |message| {
+            if message.from_me || message.delivered_at.is_some() {
+                return false;
+            }
+            message.delivered_at = Some(at);
+            true
+        })
+    }
+
+    /**
+     * Mark the messages we received in a conversation as read by us, up to
+     * and including `up_to` if it is given
+     *
+     * Returns the messages that were unread, oldest first
+     */
+    pub fn mark_conversation_read(
+        &mut self,
+        conversation_id: &str,
+        up_to: Option<u64>,
+        at: u64,
+    ) -> Result<Vec<StoredMessage>, std::io::Error> {
+        let mut marked: Vec<StoredMessage> = Vec::new();
+        for message in self.messages.iter_mut() {
+            if message.conversation_id != conversation_id
+                || message.from_me
+                || message.read_at.is_some()
+                || up_to.is_some_and(|up_to| message.timestamp > up_to)
+            {
+                continue;
+            }
+            message.read_at = Some(at);
+            marked.push(message.clone());
+        }
+        if !marked.is_empty() {
+            self.record(&marked)?;
+        }
+        marked.sort_by_key(|message| message.timestamp);
+        Ok(marked)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&StoredMessage> {
+        self.messages.get(*self.index.get(id)?)
+    }
+
+    fn get_mut(&mut self, id: &str) -> Option<&mut StoredMessage> {
+        self.messages.get_mut(*self.index.get(id)?)
+    }
+
+    /**
+     * A thread, starting with the message that started it and followed by
+     * every reply in the order they were sent
+     *
+     * Any message in the thread can be passed. Returns an empty list if we
+     * do not have the message
+     */
+    pub fn thread(&self, message_id: &str) -> Vec<StoredMessage> {
+        let Some(message) = self.get(message_id) else {
+            return Vec::new();
+        };
+        let originator_id = match &message.reply_to {
+            Some(reply_to) => reply_to.message_id.as_str(),
+            None => message.id.as_str(),
+        };
+        let mut replies: Vec<StoredMessage> = self
+            .messages
+            .iter()
+            .filter(|message| {
+                message
+                    .reply_to
+                    .as_ref()
+                    .is_some_and(|reply_to| reply_to.message_id == originator_id)
+            })
+            .cloned()
+            .collect();
+        replies.sort_by_key(|message| message.timestamp);
+        self.get(originator_id)
+            .cloned()
+            .into_iter()
+            .chain(replies)
+            .collect()
+    }
+
+    pub fn messages(&self) -> &[StoredMessage] {
+        &self.messages
+    }
+
+    /**
+     * Changes whenever a message is added or removed, so what is worked out
+     * from the conversations can be kept until then
+     */
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn conversation(&self, conversation_id: &str) -> Vec<&StoredMessage> {
+        self.messages
+            .iter()
+            .filter(|message| message.conversation_id == conversation_id)
+            .collect()
+    }
+
+    /**
+     * Every conversation we have history for, most recently active first
+     */
+    pub fn conversations(&self) -> Vec<ConversationSummary> {
+        let mut conversations: Vec<ConversationSummary> = self
+            .tally()
+            .into_iter()
+            .map(|tally| {
+                let first = &self.messages[tally.first];
+                ConversationSummary {
+                    id: first.conversation_id.clone(),
+                    participants: first.participants.clone(),
+                    last_message: self.messages[tally.last].clone(),
+                    message_count: tally.message_count,
+                    unread_count: tally.unread_count,
+                    merged: Vec::new(),
+                }
+            })
+            .collect();
+        conversations
+            .sort_by_key(|conversation| std::cmp::Reverse(conversation.last_message.timestamp));
+        conversations
+    }
+
+    /**
+     * The conversations with messages we received and have not read, without
+     * summarising the others
+     */
+    pub fn unread_conversations(&self) -> Vec<UnreadConversation> {
+        self.tally()
+            .into_iter()
+            .filter(|tally| tally.unread_count > 0)
+            .map(|tally| {
+                let first = &self.messages[tally.first];
+                UnreadConversation {
+                    id: first.conversation_id.clone(),
+                    participants: first.participants.clone(),
+                    unread_count: tally.unread_count,
+                }
+            })
+            .collect()
+    }
+
+    fn tally(&self) -> Vec<ConversationTally> {
+        let mut indices: HashMap<&str, usize> = HashMap::new();
+        let mut tallies: Vec<ConversationTally> = Vec::new();
+        for (index, message) in self.messages.iter().enumerate() {
+            let unread =
+                !message.from_me && message.read_at.is_none() && message.unsent_at.is_none();
+            match indices.get(message.conversation_id.as_str()) {
+                Some(&position) => {
+                    let tally = &mut tallies[position];
+                    tally.message_count += 1;
+                    tally.unread_count += unread as usize;
+                    if message.timestamp >= self.messages[tally.last].timestamp {
+                        tally.last = index;
+                    }
+                }
+                None => {
+                    indices.insert(&message.conversation_id, tallies.len());
+                    tallies.push(ConversationTally {
+                        first: index,
+                        last: index,
+                        message_count: 1,
+                        unread_count: unread as usize,
+                    });
+                }
+            }
+        }
+        tallies
+    }
+
+    /**
+     * The most recent one-to-one conversation with a handle
+     */
+    pub fn direct_conversation(
+        &self,
+        handle: &str,
+        own_handles: &[String],
+    ) -> Option<ConversationSummary> {
+        self.conversations().into_iter().find(|conversation| {
+            let others: Vec<&String> = conversation
+                .participants
+                .iter()
+                .filter(|participant| !own_handles.contains(participant))
+                .collect();
+            others.len() == 1 && others[0] == handle
+        })
+    }
+
+    /**
+     * Up to `limit` messages of some conversations sent before `before`,
+     * oldest first. Several are paged together for a merged conversation.
+     *
+     * Pass the timestamp of the oldest message of a page to get the next one
+     */
+    pub fn page(
+        &self,
+        conversation_ids: &[String],
+        before: Option<u64>,
+        limit: usize,
+    ) -> Vec<StoredMessage> {
+        let mut page: Vec<StoredMessage> = self
+            .messages
+            .iter()
+            .filter(|message| conversation_ids.contains(&message.conversation_id))
+            .filter(|message| before.is_none_or(|before| message.timestamp < before))
+            .cloned()
+            .collect();
+        page.sort_by_key(|message| message.timestamp);
+        let skip = page.len().saturating_sub(limit);
+        page.split_off(skip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_dir;
+
+    fn message(id: &str) -> StoredMessage {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "conversationId": "conversation",
+            "participants": ["tel:+15551234567"],
+            "sender": "tel:+15551234567",
+            "fromMe": false,
+            "text": "hello",
+            "timestamp": 1,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn added_messages_are_replayed_from_the_journal() {
+        let dir = test_dir();
+        let mut store = MessageStore::load_from(&dir).unwrap();
+        assert!(store.add(message("a")).unwrap());
+        assert!(store.add(message("b")).unwrap());
+        assert!(!store.add(message("a")).unwrap());
+        assert!(!dir.join("messages.json").exists());
+
+        let store = MessageStore::load_from(&dir).unwrap();
+        let ids: Vec<&str> = store.messages().iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, ["a", "b"]);
+        // Loading takes the journal into the history
+        assert!(dir.join("messages.json").exists());
+        assert!(!dir.join("messages.journal").exists());
+    }
+
+    #[test]
+    fn changes_are_journaled_instead_of_rewriting_the_history() {
+        let dir = test_dir();
+        let mut store = MessageStore::load_from(&dir).unwrap();
+        store.add(message("a")).unwrap();
+        store.add(sent("b", "conversation", 2)).unwrap();
+        store.save().unwrap();
+        let saved = std::fs::metadata(dir.join("messages.json"))
+            .unwrap()
+            .modified()
+            .unwrap();
+
+        store
+            .set_reaction("a", reaction("tel:+15551234567", Tapback::Love, 0), true)
+            .unwrap();
+        store.mark_delivered("b", 5).unwrap();
+        store.mark_read("b", 6).unwrap();
+        store
+            .mark_conversation_read("conversation", None, 7)
+            .unwrap();
+        store.edit("b", "edited".to_owned(), 8).unwrap();
+        assert_eq!(
+            std::fs::metadata(dir.join("messages.json"))
+                .unwrap()
+                .modified()
+                .unwrap(),
+            saved
+        );
+
+        let store = MessageStore::load_from(&dir).unwrap();
+        let a = store.get("a").unwrap();
+        assert_eq!(a.reactions.len(), 1);
+        assert_eq!(a.read_at, Some(7));
+        let b = store.get("b").unwrap();
+        assert_eq!((b.delivered_at, b.read_at), (Some(5), Some(6)));
+        assert_eq!(b.text, "edited");
+        assert_eq!(store.messages().len(), 2);
+    }
+
+    #[test]
+    fn a_long_journal_is_written_into_the_history() {
+        let dir = test_dir();
+        let mut store = MessageStore::load_from(&dir).unwrap();
+        store.add(message("a")).unwrap();
+        for at in 0..JOURNAL_LIMIT as u64 {
+            store.edit("a", at.to_string(), at).unwrap();
+        }
+        assert!(dir.join("messages.json").exists());
+        assert!(store.journaled <= JOURNAL_LIMIT);
+
+        let store = MessageStore::load_from(&dir).unwrap();
+        let last = (JOURNAL_LIMIT - 1).to_string();
+        assert_eq!(store.get("a").unwrap().text, last);
+    }
+
+    fn sent(id: &str, conversation_id: &str, timestamp: u64) -> StoredMessage {
+        StoredMessage {
+            conversation_id: conversation_id.to_owned(),
+            sender: None,
+            from_me: true,
+            timestamp,
+            ..message(id)
+        }
+    }
+
+    #[test]
+    fn a_receipt_covers_earlier_messages_in_the_conversation() {
+        let dir = test_dir();
+        let mut store = MessageStore::load_from(&dir).unwrap();
+        store.add(sent("a", "conversation", 1)).unwrap();
+        store.add(sent("b", "conversation", 2)).unwrap();
+        store.add(sent("c", "conversation", 3)).unwrap();
+        store.add(sent("other", "elsewhere", 1)).unwrap();
+        store.add(message("received")).unwrap();
+        store
+            .set_send_status("a", Some(MessageStatus::Failed))
+            .unwrap();
+
+        let read = store.mark_read("b", 10).unwrap();
+        let ids: Vec<&str> = read.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, ["b"]);
+        assert_eq!(store.get("b").unwrap().delivered_at, Some(10));
+        assert_eq!(store.get("c").unwrap().status(), MessageStatus::Sent);
+        assert_eq!(store.get("other").unwrap().status(), MessageStatus::Sent);
+        assert_eq!(
+            store.get("received").unwrap().status(),
+            MessageStatus::Unread
+        );
+
+        store.set_send_status("a", None).unwrap();
+        let delivered = store.mark_delivered("c", 20).unwrap();
+        let ids: Vec<&str> = delivered.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, ["a", "c"]);
+        assert_eq!(store.get("b").unwrap().delivered_at, Some(10));
+        assert!(store.mark_read("unknown", 30).unwrap().is_empty());
+    }
+
+    #[test]
+    fn only_received_messages_are_marked_relayed() {
+        let dir = test_dir();
+        let mut store = MessageStore::load_from(&dir).unwrap();
+        store.add(message("received")).unwrap();
+        store.add(sent("sent", "conversation", 2)).unwrap();
+        let relayed = store.mark_relayed("received", 5).unwrap().unwrap();
+        assert_eq!(relayed.delivered_at, Some(5));
+        assert_eq!(relayed.status(), MessageStatus::Unread);
+        assert!(store.mark_relayed("received", 6).unwrap().is_none());
+        assert!(store.mark_relayed("sent", 6).unwrap().is_none());
+    }
+
+    #[test]
+    fn messages_are_found_after_one_is_removed() {
+        let dir = test_dir();
+        let mut store = MessageStore::load_from(&dir).unwrap();
+        store.add(message("a")).unwrap();
+        store.add(message("b")).unwrap();
+        store.add(message("c")).unwrap();
+        store
+            .set_send_status("a", Some(MessageStatus::Queued))
+            .unwrap();
+        assert!(store.remove_unsent("a").unwrap().is_some());
+        assert!(store.get("a").is_none());
+        assert_eq!(store.get("b").unwrap().id, "b");
+        assert_eq!(store.get("c").unwrap().id, "c");
+
+        let store = MessageStore::load_from(&dir).unwrap();
+        assert_eq!(store.get("c").unwrap().id, "c");
+        // The history is written to a temporary file and renamed into place
+        assert!(!dir.join("messages.json.tmp").exists());
+    }
+
+    fn reaction(sender: &str, tapback: Tapback, part: u64) -> StoredReaction {
+        StoredReaction {
+            sender: Some(sender.to_owned()),
+            from_me: false,
+            tapback,
+            part,
+            timestamp: 2,
+        }
+    }
+
+    #[test]
+    fn a_tapback_replaces_the_senders_previous_one_on_the_part() {
+        let dir = test_dir();
+        let mut store = MessageStore::load_from(&dir).unwrap();
+        store.add(message("a")).unwrap();
+        store
+            .set_reaction("a", reaction("alice", Tapback::Love, 0), true)
+            .unwrap();
+        store
+            .set_reaction("a", reaction("alice", Tapback::Laugh, 0), true)
+            .unwrap();
+        store
+            .set_reaction("a", reaction("alice", Tapback::Like, 1), true)
+            .unwrap();
+        let updated = store
+            .set_reaction("a", reaction("bob", Tapback::Laugh, 0), true)
+            .unwrap()
+            .unwrap();
+        let tapbacks: Vec<(&str, &Tapback, u64)> = updated
+            .reactions
+            .iter()
+            .map(|r| (r.sender.as_deref().unwrap(), &r.tapback, r.part))
+            .collect();
+        assert_eq!(
+            tapbacks,
+            [
+                ("alice", &Tapback::Laugh, 0),
+                ("alice", &Tapback::Like, 1),
+                ("bob", &Tapback::Laugh, 0),
+            ]
+        );
+        assert!(store
+            .set_reaction("unknown", reaction("bob", Tapback::Like, 0), true)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn removing_a_tapback_needs_it_to_match() {
+        let dir = test_dir();
+        let mut store = MessageStore::load_from(&dir).unwrap();
+        store.add(message("a")).unwrap();
+        store
+            .set_reaction("a", reaction("alice", Tapback::Love, 0), true)
+            .unwrap();
+        let mine = StoredReaction {
+            sender: None,
+            from_me: true,
+            ..reaction("alice", Tapback::Love, 0)
+        };
+        store.set_reaction("a", mine.clone(), true).unwrap();
+
+        // A late removal of a tapback that was since replaced is ignored
+        store
+            .set_reaction("a", reaction("alice", Tapback::Like, 0), false)
+            .unwrap();
+        assert_eq!(store.get("a").unwrap().reactions.len(), 2);
+        let updated = store.set_reaction("a", mine, false).unwrap().unwrap();
+        assert_eq!(updated.reactions.len(), 1);
+        assert!(!updated.reactions[0].from_me);
+
+        let store = MessageStore::load_from(&dir).unwrap();
+        assert_eq!(store.get("a").unwrap().reactions.len(), 1);
+    }
+
+    #[test]
+    fn reactions_are_summarised_by_tapback_in_first_use_order() {
+        let mut message = message("a");
+        message.reactions = vec![
+            reaction("alice", Tapback::Laugh, 0),
+            reaction("bob", Tapback::Love, 0),
+            StoredReaction {
+                sender: None,
+                from_me: true,
+                ..reaction("me", Tapback::Laugh, 0)
+            },
+        ];
+        let summary = message.reaction_summary();
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary[0].tapback, Tapback::Laugh);
+        assert_eq!(summary[0].count, 2);
+        assert!(summary[0].from_me);
+        assert_eq!(summary[0].senders, ["alice"]);
+        assert_eq!(summary[1].tapback, Tapback::Love);
+        assert!(!summary[1].from_me);
+    }
+
+    #[test]
+    fn tapbacks_round_trip_through_their_emoji() {
+        for tapback in [
+            Tapback::Love,
+            Tapback::Like,
+            Tapback::Dislike,
+            Tapback::Laugh,
+            Tapback::Emphasize,
+            Tapback::Question,
+            Tapback::Emoji("\u{1f525}".to_owned()),
+        ] {
+            assert_eq!(Tapback::from_emoji(tapback.emoji()), tapback);
+        }
+        // Without the variation selector it is still the classic tapback
+        assert_eq!(Tapback::from_emoji("\u{2764}"), Tapback::Love);
+    }
+
+    fn reply(id: &str, to: &str, timestamp: u64) -> StoredMessage {
+        StoredMessage {
+            timestamp,
+            reply_to: Some(ReplyTo {
+                message_id: to.to_owned(),
+                part: 0,
+            }),
+            ..message(id)
+        }
+    }
+
+    #[test]
+    fn a_thread_is_the_originator_and_its_replies_in_order() {
+        let dir = test_dir();
+        let mut store = MessageStore::load_from(&dir).unwrap();
+        store.add(message("originator")).unwrap();
+        store.add(reply("late", "originator", 5)).unwrap();
+        store.add(reply("early", "originator", 3)).unwrap();
+        store.add(reply("elsewhere", "other", 4)).unwrap();
+        store.add(message("unrelated")).unwrap();
+
+        for id in ["originator", "late", "early"] {
+            let ids: Vec<String> = store.thread(id).into_iter().map(|m| m.id).collect();
+            assert_eq!(ids, ["originator", "early", "late"]);
+        }
+        assert!(store.thread("unknown").is_empty());
+    }
+
+    #[test]
+    fn a_thread_without_its_originator_has_only_the_replies() {
+        let dir = test_dir();
+        let mut store = MessageStore::load_from(&dir).unwrap();
+        store.add(reply("b", "missing", 2)).unwrap();
+        store.add(reply("a", "missing", 1)).unwrap();
+        let ids: Vec<String> = store.thread("b").into_iter().map(|m| m.id).collect();
+        assert_eq!(ids, ["a", "b"]);
+    }
+
+    #[test]
+    fn conversations_are_summarised_most_recently_active_first() {
+        let dir = test_dir();
+        let mut store = MessageStore::load_from(&dir).unwrap();
+        store.add(message("a")).unwrap();
+        store.add(sent("elsewhere", "elsewhere", 5)).unwrap();
+        // Out of order, so the last one added is not the last sent
+        store
+            .add(StoredMessage {
+                timestamp: 3,
+                ..message("c")
+            })
+            .unwrap();
+        store.add(sent("b", "conversation", 2)).unwrap();
+
+        let conversations = store.conversations();
+        let ids: Vec<&str> = conversations.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, ["elsewhere", "conversation"]);
+        let conversation = &conversations[1];
+        assert_eq!(conversation.last_message.id, "c");
+        assert_eq!(conversation.message_count, 3);
+        assert_eq!(conversation.unread_count, 2);
+        assert_eq!(conversation.participants, ["tel:+15551234567"]);
+
+        assert_eq!(
+            store.unread_conversations(),
+            [UnreadConversation {
+                id: "conversation".to_owned(),
+                participants: vec!["tel:+15551234567".to_owned()],
+                unread_count: 2,
+            }]
+        );
+        store
+            .mark_conversation_read("conversation", None, 10)
+            .unwrap();
+        assert!(store.unread_conversations().is_empty());
+    }
+
+    #[test]
+    fn viewing_a_conversation_marks_what_we_received_read() {
+        let dir = test_dir();
+        let mut store = MessageStore::load_from(&dir).unwrap();
+        for (id, timestamp) in [("c", 3), ("a", 1), ("b", 2)] {
+            store
+                .add(StoredMessage {
+                    timestamp,
+                    ..message(id)
+                })
+                .unwrap();
+        }
+        store.add(sent("mine", "conversation", 2)).unwrap();
+        store
+            .add(StoredMessage {
+                conversation_id: "elsewhere".to_owned(),
+                ..message("other")
+            })
+            .unwrap();
+
+        let read = store
+            .mark_conversation_read("conversation", Some(2), 10)
+            .unwrap();
+        let ids: Vec<&str> = read.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, ["a", "b"]);
+        assert_eq!(store.get("c").unwrap().status(), MessageStatus::Unread);
+        assert_eq!(store.get("mine").unwrap().status(), MessageStatus::Sent);
+        assert_eq!(store.get("other").unwrap().status(), MessageStatus::Unread);
+
+        let read = store
+            .mark_conversation_read("conversation", None, 20)
+            .unwrap();
+        let ids: Vec<&str> = read.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, ["c"]);
+        assert_eq!(store.get("a").unwrap().read_at, Some(10));
+        assert!(store
+            .mark_conversation_read("conversation", None, 30)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn the_status_follows_the_receipts() {
+        let mut message = sent("a", "conversation", 1);
+        assert_eq!(message.status(), MessageStatus::Sent);
+        message.delivered_at = Some(2);
+        assert_eq!(message.status(), MessageStatus::Delivered);
+        message.read_at = Some(3);
+        assert_eq!(message.status(), MessageStatus::Read);
+        // Where it is in the outbox wins until it has been sent
+        message.send_status = Some(MessageStatus::Failed);
+        assert_eq!(message.status(), MessageStatus::Failed);
+    }
+
+    #[test]
+    fn edits_keep_the_previous_text() {
+        let dir = test_dir();
+        let mut store = MessageStore::load_from(&dir).unwrap();
+        store.add(sent("a", "conversation", 1)).unwrap();
+        let edited = store.edit("a", "hi".to_owned(), 5).unwrap().unwrap();
+        assert_eq!(edited.text, "hi");
+        assert!(store.edit("a", "hi".to_owned(), 6).unwrap().is_none());
+        store.edit("a", "hey".to_owned(), 7).unwrap();
+        let history: Vec<(&str, u64)> = store
+            .get("a")
+            .unwrap()
+            .edits
+            .iter()
+            .map(|edit| (edit.text.as_str(), edit.replaced_at))
+            .collect();
+        assert_eq!(history, [("hello", 5), ("hi", 7)]);
+        assert!(store.edit("unknown", "hi".to_owned(), 8).unwrap().is_none());
+    }
+
+    #[test]
+    fn unsending_drops_the_content_once() {
+        let dir = test_dir();
+        let mut store = MessageStore::load_from(&dir).unwrap();
+        store.add(sent("a", "conversation", 1)).unwrap();
+        store.edit("a", "hi".to_owned(), 5).unwrap();
+        let before = store.unsend("a", 6).unwrap().unwrap();
+        assert_eq!(before.text, "hi");
+        assert_eq!(before.unsent_at, None);
+
+        let message = store.get("a").unwrap();
+        assert!(message.text.is_empty());
+        assert!(message.edits.is_empty());
+        assert_eq!(message.unsent_at, Some(6));
+        assert!(store.unsend("a", 7).unwrap().is_none());
+        assert!(store.edit("a", "again".to_owned(), 8).unwrap().is_none());
+    }
+
+    #[test]
+    fn only_our_recent_sent_messages_can_be_changed() {
+        let window = 15 * 60 * 1000;
+        let message = sent("a", "conversation", 1000);
+        assert_eq!(message.check_change(window, 1000 + window), Ok(()));
+        assert_eq!(
+            message.check_change(window, 1001 + window),
+            Err(ChangeRejected::WindowExpired)
+        );
+        assert_eq!(
+            StoredMessage {
+                send_status: Some(MessageStatus::Queued),
+                ..message.clone()
+            }
+            .check_change(window, 1000),
+            Err(ChangeRejected::NotSent)
+        );
+        assert_eq!(
+            StoredMessage {
+                unsent_at: Some(1000),
+                ..message.clone()
+            }
+            .check_change(window, 1000),
+            Err(ChangeRejected::Unsent)
+        );
+        assert_eq!(
+            self::message("b").check_change(window, 1),
+            Err(ChangeRejected::NotFromMe)
+        );
+    }
+
+    #[test]
+    fn changes_must_come_from_the_sender() {
+        let received = message("a");
+        assert!(received.sent_by(Some("tel:+15551234567"), false));
+        assert!(!received.sent_by(Some("tel:+15557654321"), false));
+        assert!(!received.sent_by(Some("tel:+15551234567"), true));
+        let mine = sent("b", "conversation", 1);
+        assert!(mine.sent_by(None, true));
+        assert!(!mine.sent_by(Some("tel:+15551234567"), false));
+    }
+
+    #[test]
+    fn a_damaged_journal_line_is_skipped() {
+        let dir = test_dir();
+        let mut store = MessageStore::load_from(&dir).unwrap();
+        store.add(message("a")).unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(dir.join("messages.journal"))
+            .unwrap()
+            .write_all(b"{\"id\": \"b\", \"conv")
+            .unwrap();
+
+        let store = MessageStore::load_from(&dir).unwrap();
+        assert_eq!(store.messages().len(), 1);
+    }
+}
@@ -0,0 +1,396 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::state::{
+    data_dir,
+    messagestore::{now_millis, ReplyTo},
+    write_json,
+};
+
+/// Attempts before a message is given up on
+pub const MAX_ATTEMPTS: u32 = 8;
+/// Delay before the first retry, doubled after every failed attempt
+const BASE_BACKOFF: u64 = 5 * 1000;
+const MAX_BACKOFF: u64 = 5 * 60 * 1000;
+
+/**
+ * How long to wait before the next attempt after `attempts` failed ones
+ */
+fn backoff(attempts: u32) -> u64 {
+    BASE_BACKOFF
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF)
+}
+/// How long sent messages stay in the outbox so their state can be seen
+const KEEP_SENT: u64 = 24 * 60 * 60 * 1000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum OutboxState {
+    Queued,
+    Sending,
+    Sent,
+    Delivered,
+    /// Gave up after too many attempts, until it is retried by hand
+    Failed,
+    /// A recipient's verified keys changed, until the user confirms them
+    Held,
+}
+
+/**
+ * A message waiting to be sent, or recently sent
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboxEntry {
+    /// The message guid, which stays the same across attempts so the
+    /// recipient only shows it once
+    pub id: String,
+    pub conversation_id: String,
+    pub participants: Vec<String>,
+    pub text: String,
+    /// IDs in the attachment store, uploaded when the message is sent
+    pub attachments: Vec<String>,
+    pub reply_to: Option<ReplyTo>,
+    pub state: OutboxState,
+    pub attempts: u32,
+    /// Milliseconds since the unix epoch
+    pub next_attempt: u64,
+    pub last_error: Option<String>,
+    pub created: u64,
+    pub updated: u64,
+}
+
+/**
+ * Outgoing messages, persisted so nothing typed while offline is lost
+ */
+pub struct OutboxStore {
+    path: PathBuf,
+    entries: Vec<OutboxEntry>,
+    /// Woken whenever there may be something new to send
+    wake: Arc<Notify>,
+}
+
+impl OutboxStore {
+    /**
+     * Load the outbox, queueing again anything that was being sent when we
+     * stopped since we cannot know whether it went out
+     */
+    pub fn load() -> Result<OutboxStore, std::io::Error> {
+        OutboxStore::load_from(&data_dir())
+    }
+
+    fn load_from(dir: &Path) -> Result<OutboxStore, std::io::Error> {
+        let path = dir.join("outbox.json");
+        let mut entries: Vec<OutboxEntry> = if path.exists() {
+            let file = File::open(&path)?;
+            serde_json::from_reader(BufReader::new(file))?
+        } else {
+            Vec::new()
+        };
+        for entry in entries.iter_mut() {
+            if entry.state == OutboxState::Sending {
+                entry.state = OutboxState::Queued;
+            }
+        }
+        Ok(OutboxStore {
+            path,
+            entries,
+            wake: Arc::new(Notify::new()),
+        })
+    }
+
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        write_json(&self.path, &self.entries)
+    }
+
+    pub fn waker(&self) -> Arc<Notify> {
+        self.wake.clone()
+    }
+
+    /**
+     * Queue a message, ignoring guids that are already in the outbox
+     *
+     * Returns whether the message was new
+     */
+    pub fn enqueue(&mut self, entry: OutboxEntry) -> Result<bool, std::io::Error> {
+        if self.get(&entry.id).is_some() {
+            return Ok(false);
+        }
+        self.entries.push(entry);
+        self.save()?;
+        self.wake.notify_one();
+        Ok(true)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&OutboxEntry> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    /**
+     * Every entry, oldest first
+     */
+    pub fn entries(&self) -> &[OutboxEntry] {
+        &self.entries
+    }
+
+    fn update(
+        &mut self,
+        id: &str,
+        update: impl FnOnce(&mut OutboxEntry) -> bool,
+    ) -> Result<Option<OutboxEntry>, std::io::Error> {
+        let Some(entry) = self.entries.iter_mut().find(|entry| entry.id == id) else {
+            return Ok(None);
+        };
+        if !update(entry) {
+            return Ok(None);
+        }
+        entry.updated = now_millis();
+        let entry = entry.clone();
+        self.save()?;
+        Ok(Some(entry))
+    }
+
+    /**
+     * The queued message that is due first, and whether it is due at `now`
+     */
+    pub fn next_due(&self, now: u64) -> Option<(OutboxEntry, bool)> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.state == OutboxState::Queued)
+            .min_by_key(|entry| entry.next_attempt)
+            .map(|entry| (entry.clone(), entry.next_attempt <= now))
+    }
+
+    pub fn set_state(
+        &mut self,
+        id: &str,
+        state: OutboxState,
+    ) -> Result<Option<OutboxEntry>, std::io::Error> {
+        self.update(id, |entry| {
+            if entry.state == state {
+                return false;
+            }
+            entry.state = state;
+            if state == OutboxState::Sent {
+                entry.last_error = None;
+            }
+            true
+        })
+    }
+
+    /**
+     * Record a failed attempt, backing off before the next one or giving up
+     * after `MAX_ATTEMPTS`
+     */
+    pub fn attempt_failed(
+        &mut self,
+        id: &str,
+        error: String,
+    ) -> Result<Option<OutboxEntry>, std::io::Error> {
+        self.update(id, |entry| {
+            entry.attempts += 1;
+            entry.last_error = Some(error);
+            if entry.attempts >= MAX_ATTEMPTS {
+                entry.state = OutboxState::Failed;
+            } else {
+                entry.state = OutboxState::Queued;
+                entry.next_attempt = now_millis() + backoff(entry.attempts);
+            }
+            true
+        })
+    }
+
+    /**
+     * Queue a failed message again with a fresh set of attempts
+     */
+    pub fn retry(&mut self, id: &str) -> Result<Option<OutboxEntry>, std::io::Error> {
+        let entry = self.update(id, |entry| {
+            if entry.state != OutboxState::Failed {
+                return false;
+            }
+            entry.state = OutboxState::Queued;
+            entry.attempts = 0;
+            entry.next_attempt = now_millis();
+            true
+        })?;
+        if entry.is_some() {
+            self.wake.notify_one();
+        }
+        Ok(entry)
+    }
+
+    /**
+     * Try every queued message again straight away, since backing off is
+     * pointless once we are back online
+     */
+    pub fn reset_backoff(&mut self) -> Result<(), std::io::Error> {
+        let now = now_millis();
+        for entry in self.entries.iter_mut() {
+            if entry.state == OutboxState::Queued {
+                entry.next_attempt = entry.next_attempt.min(now);
+            }
+        }
+        self.save()?;
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /**
+     * Queue held messages to any of these handles again
+     *
+     * Returns the entries that were released
+     */
+    pub fn release(&mut self, handles: &[String]) -> Result<Vec<OutboxEntry>, std::io::Error> {
+        let now = now_millis();
+        let mut released = Vec::new();
+        for entry in self.entries.iter_mut() {
+            let held = entry.state == OutboxState::Held
+                && entry
+                    .participants
+                    .iter()
+                    .any(|participant| handles.contains(participant));
+            if held {
+                entry.state = OutboxState::Queued;
+                entry.next_attempt = now;
+                entry.updated = now;
+                released.push(entry.clone());
+            }
+        }
+        if !released.is_empty() {
+            self.save()?;
+            self.wake.notify_one();
+        }
+        Ok(released)
+    }
+
+    /**
+     * Remove a message that has not been sent
+     *
+     * Returns the removed entry, or None if it is unknown or already sent
+     */
+    pub fn cancel(&mut self, id: &str) -> Result<Option<OutboxEntry>, std::io::Error> {
+        let Some(index) = self.entries.iter().position(|entry| {
+            entry.id == id
+                && matches!(
+                    entry.state,
+                    OutboxState::Queued | OutboxState::Failed | OutboxState::Held
+                )
+        }) else {
+            return Ok(None);
+        };
+        let entry = self.entries.remove(index);
+        self.save()?;
+        Ok(Some(entry))
+    }
+
+    /**
+     * Forget messages that were sent more than a day ago
+     */
+    pub fn prune(&mut self) -> Result<(), std::io::Error> {
+        let now = now_millis();
+        let before = self.entries.len();
+        self.entries.retain(|entry| {
+            !matches!(entry.state, OutboxState::Sent | OutboxState::Delivered)
+                || now.saturating_sub(entry.updated) < KEEP_SENT
+        });
+        if self.entries.len() != before {
+            self.save()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_dir;
+
+    fn entry(id: &str, next_attempt: u64) -> OutboxEntry {
+        OutboxEntry {
+            id: id.to_owned(),
+            conversation_id: "conversation".to_owned(),
+            participants: vec!["tel:+15551234567".to_owned()],
+            text: "hello".to_owned(),
+            attachments: Vec::new(),
+            reply_to: None,
+            state: OutboxState::Queued,
+            attempts: 0,
+            next_attempt,
+            last_error: None,
+            created: 0,
+            updated: 0,
+        }
+    }
+
+    #[test]
+    fn the_backoff_doubles_up_to_the_maximum() {
+        let delays: Vec<u64> = (1..=8).map(backoff).collect();
+        assert_eq!(
+            delays,
+            [5000, 10000, 20000, 40000, 80000, 160000, 300000, 300000]
+        );
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn failed_attempts_back_off_and_then_give_up() {
+        let dir = test_dir();
+        let mut store = OutboxStore::load_from(&dir).unwrap();
+        store.enqueue(entry("a", 0)).unwrap();
+
+        let before = now_millis();
+        let updated = store
+            .attempt_failed("a", "offline".to_owned())
+            .unwrap()
+            .unwrap();
+        assert_eq!(updated.state, OutboxState::Queued);
+        assert_eq!(updated.last_error.as_deref(), Some("offline"));
+        assert!(updated.next_attempt >= before + BASE_BACKOFF);
+        assert!(!store.next_due(before).unwrap().1);
+
+        for _ in 1..MAX_ATTEMPTS {
+            store.attempt_failed("a", "offline".to_owned()).unwrap();
+        }
+        assert_eq!(store.get("a").unwrap().state, OutboxState::Failed);
+        assert!(store.next_due(u64::MAX).is_none());
+
+        let retried = store.retry("a").unwrap().unwrap();
+        assert_eq!(retried.attempts, 0);
+        assert!(store.retry("a").unwrap().is_none());
+    }
+
+    #[test]
+    fn coming_online_makes_everything_due() {
+        let dir = test_dir();
+        let mut store = OutboxStore::load_from(&dir).unwrap();
+        store.enqueue(entry("later", u64::MAX)).unwrap();
+        store.enqueue(entry("soon", u64::MAX - 1)).unwrap();
+        let (due, ready) = store.next_due(now_millis()).unwrap();
+        assert_eq!(due.id, "soon");
+        assert!(!ready);
+
+        store.reset_backoff().unwrap();
+        assert!(store.next_due(now_millis()).unwrap().1);
+    }
+
+    #[test]
+    fn a_message_being_sent_is_queued_again_on_load() {
+        let dir = test_dir();
+        let mut store = OutboxStore::load_from(&dir).unwrap();
+        store.enqueue(entry("a", 0)).unwrap();
+        store.set_state("a", OutboxState::Sending).unwrap();
+        assert!(store.cancel("a").unwrap().is_none());
+
+        let mut store = OutboxStore::load_from(&dir).unwrap();
+        assert_eq!(store.get("a").unwrap().state, OutboxState::Queued);
+        assert!(store.cancel("a").unwrap().is_some());
+    }
+}
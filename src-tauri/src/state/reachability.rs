@@ -0,0 +1,159 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::state::{data_dir, write_json};
+
+/// How long a handle found on iMessage is trusted to stay there
+const REACHABLE_TTL: u64 = 24 * 60 * 60 * 1000;
+/// Shorter, so people who sign up are noticed soon after
+const UNREACHABLE_TTL: u64 = 60 * 60 * 1000;
+
+/**
+ * What IDS said about a handle, and when
+ */
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Reachability {
+    pub reachable: bool,
+    /// Milliseconds since the unix epoch
+    pub checked_at: u64,
+}
+
+impl Reachability {
+    pub fn is_fresh(&self, now: u64) -> bool {
+        let ttl = if self.reachable {
+            REACHABLE_TTL
+        } else {
+            UNREACHABLE_TTL
+        };
+        now.saturating_sub(self.checked_at) < ttl
+    }
+}
+
+/**
+ * Whether handles are on iMessage, so compose can warn without asking IDS
+ * on every keystroke
+ */
+pub struct ReachabilityCache {
+    path: PathBuf,
+    /// By normalized handle
+    entries: HashMap<String, Reachability>,
+}
+
+impl ReachabilityCache {
+    pub fn load() -> Result<ReachabilityCache, std::io::Error> {
+        ReachabilityCache::load_from(&data_dir())
+    }
+
+    /**
+     * A file that can't be read as a cache is ignored rather than failing,
+     * since everything in it can be asked for again
+     */
+    fn load_from(dir: &Path) -> Result<ReachabilityCache, std::io::Error> {
+        let path = dir.join("reachability.json");
+        let entries = if path.exists() {
+            let file = File::open(&path)?;
+            match serde_json::from_reader(BufReader::new(file)) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    log::warn!("Ignoring unreadable reachability cache: {:?}", e);
+                    HashMap::new()
+                }
+            }
+        } else {
+            HashMap::new()
+        };
+        Ok(ReachabilityCache { path, entries })
+    }
+
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        write_json(&self.path, &self.entries)
+    }
+
+    /**
+     * The cached result for a handle, unless it has expired
+     */
+    pub fn get(&self, handle: &str, now: u64) -> Option<Reachability> {
+        self.entries
+            .get(handle)
+            .filter(|reachability| reachability.is_fresh(now))
+            .copied()
+    }
+
+    /**
+     * Record fresh results, dropping any that have expired
+     */
+    pub fn set(
+        &mut self,
+        results: impl IntoIterator<Item = (String, Reachability)>,
+        now: u64,
+    ) -> Result<(), std::io::Error> {
+        self.entries
+            .retain(|_, reachability| reachability.is_fresh(now));
+        self.entries.extend(results);
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_dir;
+
+    fn checked(reachable: bool, checked_at: u64) -> Reachability {
+        Reachability {
+            reachable,
+            checked_at,
+        }
+    }
+
+    #[test]
+    fn unreachable_handles_are_checked_again_sooner() {
+        let reachable = checked(true, 1_000);
+        assert!(reachable.is_fresh(1_000 + UNREACHABLE_TTL));
+        assert!(reachable.is_fresh(1_000 + REACHABLE_TTL - 1));
+        assert!(!reachable.is_fresh(1_000 + REACHABLE_TTL));
+
+        let unreachable = checked(false, 1_000);
+        assert!(unreachable.is_fresh(1_000 + UNREACHABLE_TTL - 1));
+        assert!(!unreachable.is_fresh(1_000 + UNREACHABLE_TTL));
+        // A clock that went backwards does not expire anything
+        assert!(unreachable.is_fresh(0));
+    }
+
+    #[test]
+    fn expired_entries_are_dropped_when_results_are_recorded() {
+        let dir = test_dir();
+        let mut cache = ReachabilityCache::load_from(&dir).unwrap();
+        let results = [
+            ("mailto:old@example.com".to_owned(), checked(false, 0)),
+            ("tel:+15551234567".to_owned(), checked(true, 0)),
+        ];
+        cache.set(results, 0).unwrap();
+
+        let now = UNREACHABLE_TTL;
+        let fresh = [("mailto:new@example.com".to_owned(), checked(false, now))];
+        cache.set(fresh, now).unwrap();
+        let cache = ReachabilityCache::load_from(&dir).unwrap();
+        let mut handles: Vec<&str> = cache.entries.keys().map(String::as_str).collect();
+        handles.sort();
+        assert_eq!(handles, ["mailto:new@example.com", "tel:+15551234567"]);
+        assert!(cache.get("mailto:old@example.com", now).is_none());
+        assert!(cache.get("tel:+15551234567", now).unwrap().reachable);
+    }
+
+    #[test]
+    fn an_unreadable_cache_starts_empty() {
+        let dir = test_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("reachability.json"), "{\"tel:+1555").unwrap();
+        let cache = ReachabilityCache::load_from(&dir).unwrap();
+        assert!(cache.entries.is_empty());
+    }
+}
@@ -1,6 +1,5 @@
 use std::{path::Path, sync::Arc};
 
-use dirs::{data_local_dir, home_dir};
 use rustpush::{APNSConnection, APNSState, IDSUser, IMClient};
 use serde::{Deserialize, Serialize};
 use tauri::ipc::InvokeError;
@@ -8,20 +7,27 @@ use tauri::ipc::InvokeError;
 use crate::{
     dataplist::parse_plist,
     emulated::bindings::ValidationDataError,
-    imessage::user::{register_users, RegisterError},
+    imessage::{
+        identities::{restore_identities, sync_identities},
+        user::{register_users, RegisterError},
+    },
+    state::{
+        data_dir,
+        identitycache::{IdentityCache, IdentitySnapshot},
+        messagestore::now_millis,
+    },
 };
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct SavedState {
     pub push: APNSState,
     pub users: Vec<IDSUser>,
+    #[serde(default)]
+    pub active_handle: Option<String>,
 }
 
 pub fn retrieve_saved_state() -> Option<SavedState> {
-    let state_path = data_local_dir()
-        .unwrap_or(home_dir().unwrap().join(".crossmessenger"))
-        .join("crossmessenger")
-        .join("state.json");
+    let state_path = data_dir().join("state.json");
     if !state_path.exists() {
         return None;
     }
@@ -30,16 +36,28 @@ pub fn retrieve_saved_state() -> Option<SavedState> {
     Some(state)
 }
 
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountStatus {
+    pub logged_in: bool,
+    pub user_id: Option<String>,
+    pub handles: Vec<String>,
+    pub selected_handle: Option<String>,
+}
+
 pub struct RustPushState {
     pub apns_connection: Arc<APNSConnection>,
     pub client: Arc<IMClient>,
     pub active_handle: Option<String>,
+    /// Recipient identities the client has looked up, persisted
+    pub identities: IdentityCache,
 }
 
 #[derive(Debug)]
 pub enum IMClientError {
     NoUsers,
     NoClient,
+    HandleNotFound,
     PushError(rustpush::PushError),
     ValidationDataError(ValidationDataError),
     RegisterError(RegisterError),
@@ -62,6 +80,7 @@ impl Into<InvokeError> for IMClientError {
         match self {
             IMClientError::NoUsers => InvokeError::from("No logged in users"),
             IMClientError::NoClient => InvokeError::from("No client configured"),
+            IMClientError::HandleNotFound => InvokeError::from("Handle not found"),
             IMClientError::PushError(error) => InvokeError::from(error.to_string()),
             IMClientError::ValidationDataError(error) => error.into(),
             IMClientError::RegisterError(error) => match error {
@@ -74,35 +93,35 @@ impl Into<InvokeError> for IMClientError {
     }
 }
 
+/**
+ * The serial number of the Mac we connect to APNs as
+ */
+fn serial_number() -> Result<String, IMClientError> {
+    match parse_plist(&Path::new("src/emulated/pypush/data.plist")) {
+        Ok(plist) => Ok(plist.iokit.ioplatformserialnumber),
+        Err(crate::dataplist::PlistError::IOError(error)) => Err(IMClientError::IOError(error)),
+        Err(crate::dataplist::PlistError::PlistError(error)) => {
+            Err(IMClientError::PlistError(error))
+        }
+    }
+}
+
 impl RustPushState {
     pub async fn new(saved_state: Option<SavedState>) -> Result<RustPushState, IMClientError> {
-        let data_plist = parse_plist(&Path::new("src/emulated/pypush/data.plist"));
-        if let Err(e) = data_plist {
-            return match e {
-                crate::dataplist::PlistError::IOError(error) => Err(IMClientError::IOError(error)),
-                crate::dataplist::PlistError::PlistError(error) => {
-                    Err(IMClientError::PlistError(error))
-                }
-            };
-        }
+        let serial_number = serial_number()?;
 
+        let active_handle = saved_state
+            .as_ref()
+            .and_then(|saved_state| saved_state.active_handle.clone());
         let (apns_connection, mut users) = match saved_state {
             Some(saved_state) => {
-                let apns_connection = Arc::new(
-                    APNSConnection::new(
-                        &data_plist.unwrap().iokit.ioplatformserialnumber,
-                        Some(saved_state.push),
-                    )
-                    .await?,
-                );
+                let apns_connection =
+                    Arc::new(APNSConnection::new(&serial_number, Some(saved_state.push)).await?);
                 let users = saved_state.users;
                 (apns_connection, users)
             }
             None => {
-                let apns_connection = Arc::new(
-                    APNSConnection::new(&data_plist.unwrap().iokit.ioplatformserialnumber, None)
-                        .await?,
-                );
+                let apns_connection = Arc::new(APNSConnection::new(&serial_number, None).await?);
                 let users: Vec<IDSUser> = Vec::new();
                 (apns_connection, users)
             }
@@ -110,9 +129,9 @@ impl RustPushState {
 
         let mut needs_reregistration = false;
         for user in users.iter() {
-            println!("Checking user {:?}", user.user_id);
+            log::debug!("Checking user {:?}", user.user_id);
             if user.identity.is_none() {
-                println!("User {:?} has no identity", user.user_id);
+                log::info!("User {:?} has no identity", user.user_id);
                 needs_reregistration = true;
             }
         }
@@ -123,14 +142,17 @@ impl RustPushState {
         }
 
         let client = IMClient::new(apns_connection.clone(), Arc::new(users)).await;
+        let identities = IdentityCache::load().map_err(IMClientError::IOError)?;
+        restore_identities(&client, &identities, now_millis()).await;
 
         let application_state = RustPushState {
             apns_connection,
             client: Arc::new(client),
-            active_handle: None,
+            active_handle,
+            identities,
         };
         if let Err(e) = application_state.save_to_file().await {
-            println!("Error saving state: {:?}", e);
+            log::error!("Error saving state: {:?}", e);
         }
         Ok(application_state)
     }
@@ -139,13 +161,12 @@ impl RustPushState {
         SavedState {
             push: self.apns_connection.state.clone(),
             users: self.client.users.to_vec(),
+            active_handle: self.active_handle.clone(),
         }
     }
 
     pub async fn save_to_file(&self) -> Result<(), std::io::Error> {
-        let state_path = data_local_dir()
-            .unwrap_or(home_dir().unwrap().join(".crossmessenger"))
-            .join("crossmessenger");
+        let state_path = data_dir();
         std::fs::create_dir_all(&state_path)?;
         let state_path = state_path.join("state.json");
         let state_file = std::fs::File::create(state_path)?;
@@ -154,6 +175,22 @@ impl RustPushState {
         Ok(())
     }
 
+    /**
+     * Connect to APNs again with the same push state and users, after the
+     * last connection closed
+     */
+    pub async fn reconnect(&mut self) -> Result<(), IMClientError> {
+        let apns_connection = Arc::new(
+            APNSConnection::new(&serial_number()?, Some(self.apns_connection.state.clone()))
+                .await?,
+        );
+        let users = self.client.users.clone();
+        self.client = Arc::new(IMClient::new(apns_connection.clone(), users).await);
+        restore_identities(&self.client, &self.identities, now_millis()).await;
+        self.apns_connection = apns_connection;
+        Ok(())
+    }
+
     pub async fn get_user_by_handle(&self, handle: &str) -> Option<IDSUser> {
         let users = self.client.users.clone();
         users
@@ -197,12 +234,13 @@ impl RustPushState {
             Ok(_) => {
                 self.client =
                     Arc::new(IMClient::new(self.apns_connection.clone(), Arc::new(users)).await);
+                restore_identities(&self.client, &self.identities, now_millis()).await;
                 match self.save_to_file().await {
                     Ok(_) => Ok(()),
                     Err(e) => Err(IMClientError::IOError(e)),
                 }?;
                 if let Err(e) = self.save_to_file().await {
-                    println!("Error saving state: {:?}", e);
+                    log::error!("Error saving state: {:?}", e);
                 }
                 Ok(())
             }
@@ -210,6 +248,27 @@ impl RustPushState {
         }
     }
 
+    /**
+     * Take in the identities the client has looked up or forgotten since
+     * the last time
+     *
+     * Returns the handles that were looked up, and what to save if anything
+     * changed, to be written once the state is unlocked
+     */
+    pub async fn sync_identities(&mut self) -> (Vec<String>, Option<IdentitySnapshot>) {
+        let (changed, looked_up) =
+            sync_identities(&self.client, &mut self.identities, now_millis()).await;
+        (looked_up, changed.then(|| self.identities.snapshot()))
+    }
+
+    pub async fn select_handle(&mut self, handle: &str) -> Result<(), IMClientError> {
+        if self.get_user_by_handle(handle).await.is_none() {
+            return Err(IMClientError::HandleNotFound);
+        }
+        self.active_handle = Some(handle.to_owned());
+        self.save_to_file().await.map_err(IMClientError::IOError)
+    }
+
     pub async fn get_active_user(&mut self) -> Option<(IDSUser, String)> {
         match &self.active_handle {
             Some(handle) => match self.get_user_by_handle(handle).await {
@@ -228,4 +287,21 @@ impl RustPushState {
             },
         }
     }
+
+    pub async fn account_status(&mut self) -> AccountStatus {
+        match self.get_active_user().await {
+            Some((user, handle)) => AccountStatus {
+                logged_in: true,
+                user_id: Some(user.user_id),
+                handles: user.handles,
+                selected_handle: Some(handle),
+            },
+            None => AccountStatus {
+                logged_in: !self.client.users.is_empty(),
+                user_id: None,
+                handles: Vec::new(),
+                selected_handle: None,
+            },
+        }
+    }
 }
@@ -0,0 +1,328 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::{LocalResult, NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::state::{
+    data_dir,
+    messagestore::{now_millis, ReplyTo},
+    write_json,
+};
+
+#[derive(Debug)]
+pub enum SendTimeError {
+    /// Not an IANA time zone name like "Europe/Berlin"
+    UnknownTimeZone(String),
+    /// Not a local date and time like "2024-03-01T09:30"
+    InvalidTime(String),
+    /// The time is skipped when the clocks go forward in that zone
+    SkippedTime(String),
+}
+
+impl std::fmt::Display for SendTimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendTimeError::UnknownTimeZone(zone) => write!(f, "Unknown time zone {}", zone),
+            SendTimeError::InvalidTime(time) => write!(f, "Invalid local time {}", time),
+            SendTimeError::SkippedTime(time) => {
+                write!(f, "{} does not exist in that time zone", time)
+            }
+        }
+    }
+}
+
+/**
+ * A wall clock time in a time zone, as the user picked it
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SendTime {
+    /// Like "2024-03-01T09:30", seconds are optional
+    pub local_time: String,
+    /// IANA name like "America/New_York"
+    pub time_zone: String,
+}
+
+impl SendTime {
+    /**
+     * The time zone of this machine, falling back to UTC if it can't be
+     * determined
+     */
+    pub fn system_time_zone() -> String {
+        iana_time_zone::get_timezone().unwrap_or_else(|e| {
+            log::warn!("Could not determine the system time zone: {:?}", e);
+            "UTC".to_owned()
+        })
+    }
+
+    /**
+     * Milliseconds since the unix epoch at which it is this time in the
+     * zone. When the clocks go back the first of the two is used.
+     */
+    pub fn to_millis(&self) -> Result<u64, SendTimeError> {
+        let zone: Tz = self
+            .time_zone
+            .parse()
+            .map_err(|_| SendTimeError::UnknownTimeZone(self.time_zone.clone()))?;
+        let local = NaiveDateTime::parse_from_str(&self.local_time, "%Y-%m-%dT%H:%M:%S")
+            .or_else(|_| NaiveDateTime::parse_from_str(&self.local_time, "%Y-%m-%dT%H:%M"))
+            .map_err(|_| SendTimeError::InvalidTime(self.local_time.clone()))?;
+        let time = match zone.from_local_datetime(&local) {
+            LocalResult::Single(time) => time,
+            LocalResult::Ambiguous(earliest, _) => earliest,
+            LocalResult::None => return Err(SendTimeError::SkippedTime(self.local_time.clone())),
+        };
+        Ok(time.timestamp_millis().max(0) as u64)
+    }
+}
+
+/**
+ * A message waiting for its time to be put in the outbox
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduledMessage {
+    pub id: String,
+    pub conversation_id: String,
+    pub participants: Vec<String>,
+    pub text: String,
+    /// IDs in the attachment store
+    pub attachments: Vec<String>,
+    pub reply_to: Option<ReplyTo>,
+    pub send_time: SendTime,
+    /// `send_time` in milliseconds since the unix epoch, worked out when it
+    /// was scheduled
+    pub send_at: u64,
+    pub created: u64,
+    pub updated: u64,
+}
+
+/**
+ * Messages scheduled to be sent later, persisted so they are still sent
+ * after a restart
+ */
+pub struct ScheduleStore {
+    path: PathBuf,
+    entries: Vec<ScheduledMessage>,
+    /// Woken whenever the next send time may have changed
+    wake: Arc<Notify>,
+}
+
+impl ScheduleStore {
+    pub fn load() -> Result<ScheduleStore, std::io::Error> {
+        ScheduleStore::load_from(&data_dir())
+    }
+
+    pub(crate) fn load_from(dir: &Path) -> Result<ScheduleStore, std::io::Error> {
+        let path = dir.join("schedule.json");
+        let entries = if path.exists() {
+            let file = File::open(&path)?;
+            serde_json::from_reader(BufReader::new(file))?
+        } else {
+            Vec::new()
+        };
+        Ok(ScheduleStore {
+            path,
+            entries,
+            wake: Arc::new(Notify::new()),
+        })
+    }
+
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        write_json(&self.path, &self.entries)
+    }
+
+    pub fn waker(&self) -> Arc<Notify> {
+        self.wake.clone()
+    }
+
+    pub fn add(&mut self, entry: ScheduledMessage) -> Result<(), std::io::Error> {
+        self.entries.push(entry);
+        self.save()?;
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ScheduledMessage> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    /**
+     * Every scheduled message, the one to be sent first first
+     */
+    pub fn entries(&self) -> Vec<&ScheduledMessage> {
+        let mut entries: Vec<&ScheduledMessage> = self.entries.iter().collect();
+        entries.sort_by_key(|entry| entry.send_at);
+        entries
+    }
+
+    /**
+     * Change a message that is still waiting, returning it once changed
+     */
+    pub fn update(
+        &mut self,
+        id: &str,
+        update: impl FnOnce(&mut ScheduledMessage),
+    ) -> Result<Option<ScheduledMessage>, std::io::Error> {
+        let now = now_millis();
+        let Some(entry) = self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.id == id && entry.send_at > now)
+        else {
+            return Ok(None);
+        };
+        update(entry);
+        entry.updated = now;
+        let entry = entry.clone();
+        self.save()?;
+        self.wake.notify_one();
+        Ok(Some(entry))
+    }
+
+    pub fn remove(&mut self, id: &str) -> Result<Option<ScheduledMessage>, std::io::Error> {
+        let Some(index) = self.entries.iter().position(|entry| entry.id == id) else {
+            return Ok(None);
+        };
+        let entry = self.entries.remove(index);
+        self.save()?;
+        Ok(Some(entry))
+    }
+
+    /**
+     * Messages whose time has come, including any whose time passed while
+     * we were not running
+     */
+    pub fn due(&self, now: u64) -> Vec<ScheduledMessage> {
+        self.entries()
+            .into_iter()
+            .filter(|entry| entry.send_at <= now)
+            .cloned()
+            .collect()
+    }
+
+    /**
+     * When the next message that is not yet due is to be sent
+     */
+    pub fn next_send_at(&self, now: u64) -> Option<u64> {
+        self.entries
+            .iter()
+            .map(|entry| entry.send_at)
+            .filter(|send_at| *send_at > now)
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_dir;
+
+    fn send_time(local_time: &str, time_zone: &str) -> SendTime {
+        SendTime {
+            local_time: local_time.to_owned(),
+            time_zone: time_zone.to_owned(),
+        }
+    }
+
+    fn scheduled(id: &str, send_at: u64) -> ScheduledMessage {
+        ScheduledMessage {
+            id: id.to_owned(),
+            conversation_id: "conversation".to_owned(),
+            participants: vec!["tel:+15551234567".to_owned()],
+            text: "hello".to_owned(),
+            attachments: Vec::new(),
+            reply_to: None,
+            send_time: send_time("2024-03-01T09:00", "UTC"),
+            send_at,
+            created: 0,
+            updated: 0,
+        }
+    }
+
+    #[test]
+    fn a_local_time_is_an_instant_in_its_zone() {
+        let cases = [
+            ("2024-03-01T09:00", "UTC", 1709283600000),
+            ("2024-03-01T09:00:30", "UTC", 1709283630000),
+            ("2024-03-01T09:00", "America/New_York", 1709301600000),
+            ("2024-07-01T09:00", "America/New_York", 1719838800000),
+            ("2024-07-01T09:00", "Asia/Kolkata", 1719804600000),
+        ];
+        for (local_time, time_zone, millis) in cases {
+            let time = send_time(local_time, time_zone);
+            assert_eq!(time.to_millis().unwrap(), millis, "{:?}", time);
+        }
+    }
+
+    #[test]
+    fn a_time_the_clocks_skip_is_rejected() {
+        // New York went from 02:00 to 03:00 on 10 March 2024
+        let time = send_time("2024-03-10T02:30", "America/New_York");
+        assert!(matches!(
+            time.to_millis(),
+            Err(SendTimeError::SkippedTime(_))
+        ));
+        let time = send_time("2024-03-10T03:00", "America/New_York");
+        assert_eq!(time.to_millis().unwrap(), 1710054000000);
+    }
+
+    #[test]
+    fn a_time_that_happens_twice_is_the_first_one() {
+        // New York went from 02:00 back to 01:00 on 3 November 2024, so
+        // 01:30 is first in daylight time, at 05:30 UTC
+        let time = send_time("2024-11-03T01:30", "America/New_York");
+        assert_eq!(time.to_millis().unwrap(), 1730611800000);
+        // Berlin went from 03:00 back to 02:00 on 27 October 2024
+        let time = send_time("2024-10-27T02:30", "Europe/Berlin");
+        assert_eq!(time.to_millis().unwrap(), 1729989000000);
+    }
+
+    #[test]
+    fn unknown_zones_and_malformed_times_are_rejected() {
+        assert!(matches!(
+            send_time("2024-03-01T09:00", "Mars/Olympus").to_millis(),
+            Err(SendTimeError::UnknownTimeZone(_))
+        ));
+        for local_time in ["2024-03-01", "09:00", "2024-02-30T09:00", ""] {
+            assert!(matches!(
+                send_time(local_time, "UTC").to_millis(),
+                Err(SendTimeError::InvalidTime(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn only_messages_still_waiting_can_be_changed() {
+        let dir = test_dir();
+        let mut store = ScheduleStore::load_from(&dir).unwrap();
+        store.add(scheduled("later", u64::MAX)).unwrap();
+        store.add(scheduled("due", 1)).unwrap();
+
+        let due: Vec<String> = store.due(now_millis()).into_iter().map(|s| s.id).collect();
+        assert_eq!(due, ["due"]);
+        assert!(store
+            .update("due", |entry| entry.text = "changed".to_owned())
+            .unwrap()
+            .is_none());
+        let updated = store
+            .update("later", |entry| entry.text = "changed".to_owned())
+            .unwrap()
+            .unwrap();
+        assert_eq!(updated.text, "changed");
+        assert_eq!(store.next_send_at(now_millis()), Some(u64::MAX));
+
+        assert!(store.remove("due").unwrap().is_some());
+        let store = ScheduleStore::load_from(&dir).unwrap();
+        let ids: Vec<&str> = store.entries().iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(ids, ["later"]);
+        assert_eq!(store.get("later").unwrap().text, "changed");
+    }
+}
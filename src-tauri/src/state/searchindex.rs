@@ -0,0 +1,272 @@
+use std::collections::{HashMap, HashSet};
+
+use rusqlite::{params, params_from_iter, types::Value, Connection, OptionalExtension};
+
+use crate::state::data_dir;
+
+/// Around the matched terms in snippets, characters from the private use
+/// area so they cannot appear in messages
+pub const MATCH_START: char = '\u{E000}';
+pub const MATCH_END: char = '\u{E001}';
+
+/// Tokens of context in a snippet
+const SNIPPET_TOKENS: u32 = 12;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS indexed (
+        doc INTEGER PRIMARY KEY,
+        id TEXT NOT NULL UNIQUE,
+        conversation_id TEXT NOT NULL,
+        sender TEXT,
+        from_me INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        has_attachments INTEGER NOT NULL,
+        revision INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS indexed_conversation ON indexed (conversation_id);
+    CREATE INDEX IF NOT EXISTS indexed_timestamp ON indexed (timestamp);
+    CREATE TABLE IF NOT EXISTS participants (
+        doc INTEGER NOT NULL,
+        handle TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS participants_doc ON participants (doc);
+    CREATE INDEX IF NOT EXISTS participants_handle ON participants (handle);
+    CREATE VIRTUAL TABLE IF NOT EXISTS search USING fts5 (
+        body,
+        filenames,
+        contacts,
+        tokenize = 'unicode61 remove_diacritics 2'
+    );
+";
+
+/**
+ * What is indexed for a message
+ */
+pub struct IndexedMessage {
+    pub id: String,
+    pub conversation_id: String,
+    pub sender: Option<String>,
+    pub from_me: bool,
+    pub timestamp: u64,
+    pub body: String,
+    pub filenames: Vec<String>,
+    /// Everyone in the conversation, so their contact names can be updated
+    pub handles: Vec<String>,
+    /// Names of the contacts of `handles`
+    pub contacts: Vec<String>,
+    /// How many times the message was edited, to tell whether the indexed
+    /// text is current
+    pub revision: u32,
+}
+
+#[derive(Default)]
+pub struct SearchFilter {
+    /// Any of these, or every conversation if empty
+    pub conversation_ids: Vec<String>,
+    pub sender: Option<String>,
+    /// Milliseconds since the unix epoch, inclusive
+    pub after: Option<u64>,
+    /// Milliseconds since the unix epoch, exclusive
+    pub before: Option<u64>,
+    pub has_attachment: Option<bool>,
+    pub from_me: Option<bool>,
+}
+
+pub struct SearchHit {
+    pub message_id: String,
+    /// With the matches between `MATCH_START` and `MATCH_END`
+    pub snippet: String,
+}
+
+/**
+ * A full-text index of the message history in SQLite FTS5, kept apart from
+ * `messages.json` and rebuilt from it whenever they disagree
+ */
+pub struct SearchIndex {
+    connection: Connection,
+}
+
+impl SearchIndex {
+    pub fn open() -> Result<SearchIndex, rusqlite::Error> {
+        let dir = data_dir();
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            log::error!("Error creating {}: {:?}", dir.display(), e);
+        }
+        let connection = Connection::open(dir.join("search.db"))?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(SearchIndex { connection })
+    }
+
+    /**
+     * The revision of every indexed message, by ID
+     */
+    pub fn revisions(&self) -> Result<HashMap<String, u32>, rusqlite::Error> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT id, revision FROM indexed")?;
+        let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    /**
+     * Add a message, or replace what was indexed for it
+     */
+    pub fn put(&mut self, message: &IndexedMessage) -> Result<(), rusqlite::Error> {
+        let transaction = self.connection.transaction()?;
+        remove_doc(&transaction, &message.id)?;
+        transaction.execute(
+            "INSERT INTO indexed (id, conversation_id, sender, from_me, timestamp, \
+             has_attachments, revision) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                message.id,
+                message.conversation_id,
+                message.sender,
+                message.from_me,
+                message.timestamp as i64,
+                !message.filenames.is_empty(),
+                message.revision,
+            ],
+        )?;
+        let doc = transaction.last_insert_rowid();
+        transaction.execute(
+            "INSERT INTO search (rowid, body, filenames, contacts) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                doc,
+                message.body,
+                message.filenames.join("\n"),
+                message.contacts.join("\n"),
+            ],
+        )?;
+        for handle in message.handles.iter() {
+            transaction.execute(
+                "INSERT INTO participants (doc, handle) VALUES (?1, ?2)",
+                params![doc, handle],
+            )?;
+        }
+        transaction.commit()
+    }
+
+    pub fn remove(&mut self, id: &str) -> Result<(), rusqlite::Error> {
+        let transaction = self.connection.transaction()?;
+        remove_doc(&transaction, id)?;
+        transaction.commit()
+    }
+
+    /**
+     * Index the current contact names of messages with any of `handles`,
+     * given the name of every handle that has a contact
+     */
+    pub fn rename(
+        &mut self,
+        names: &HashMap<String, String>,
+        handles: &HashSet<String>,
+    ) -> Result<(), rusqlite::Error> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut docs_with =
+                transaction.prepare("SELECT doc FROM participants WHERE handle = ?1")?;
+            let mut handles_of =
+                transaction.prepare("SELECT handle FROM participants WHERE doc = ?1")?;
+            let mut update =
+                transaction.prepare("UPDATE search SET contacts = ?1 WHERE rowid = ?2")?;
+            let mut docs = HashSet::new();
+            for handle in handles {
+                let rows = docs_with.query_map([handle], |row| row.get::<_, i64>(0))?;
+                for doc in rows {
+                    docs.insert(doc?);
+                }
+            }
+            for doc in docs {
+                let doc_handles = handles_of.query_map([doc], |row| row.get::<_, String>(0))?;
+                let mut contacts = Vec::new();
+                for handle in doc_handles {
+                    if let Some(name) = names.get(&handle?) {
+                        contacts.push(name.as_str());
+                    }
+                }
+                update.execute(params![contacts.join("\n"), doc])?;
+            }
+        }
+        transaction.commit()
+    }
+
+    /**
+     * Messages matching an FTS5 query and the filter, best matches first
+     *
+     * Returns a page of them and how many there are in all
+     */
+    pub fn search(
+        &self,
+        query: &str,
+        filter: &SearchFilter,
+        offset: u32,
+        limit: u32,
+    ) -> Result<(Vec<SearchHit>, u32), rusqlite::Error> {
+        let mut conditions = vec!["search MATCH ?".to_owned()];
+        let mut values = vec![Value::Text(query.to_owned())];
+        if !filter.conversation_ids.is_empty() {
+            let placeholders = vec!["?"; filter.conversation_ids.len()].join(", ");
+            conditions.push(format!("indexed.conversation_id IN ({})", placeholders));
+            values.extend(filter.conversation_ids.iter().cloned().map(Value::Text));
+        }
+        if let Some(sender) = &filter.sender {
+            conditions.push("indexed.sender = ?".to_owned());
+            values.push(Value::Text(sender.clone()));
+        }
+        if let Some(after) = filter.after {
+            conditions.push("indexed.timestamp >= ?".to_owned());
+            values.push(Value::Integer(after as i64));
+        }
+        if let Some(before) = filter.before {
+            conditions.push("indexed.timestamp < ?".to_owned());
+            values.push(Value::Integer(before as i64));
+        }
+        if let Some(has_attachment) = filter.has_attachment {
+            conditions.push("indexed.has_attachments = ?".to_owned());
+            values.push(Value::Integer(has_attachment as i64));
+        }
+        if let Some(from_me) = filter.from_me {
+            conditions.push("indexed.from_me = ?".to_owned());
+            values.push(Value::Integer(from_me as i64));
+        }
+        let from = format!(
+            "FROM search JOIN indexed ON indexed.doc = search.rowid WHERE {}",
+            conditions.join(" AND ")
+        );
+
+        let total: u32 = self.connection.query_row(
+            &format!("SELECT count(*) {}", from),
+            params_from_iter(values.iter()),
+            |row| row.get(0),
+        )?;
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT indexed.id, snippet(search, -1, char({}), char({}), '…', {}) {} \
+             ORDER BY rank, indexed.timestamp DESC LIMIT {} OFFSET {}",
+            MATCH_START as u32, MATCH_END as u32, SNIPPET_TOKENS, from, limit, offset
+        ))?;
+        let hits = statement
+            .query_map(params_from_iter(values.iter()), |row| {
+                Ok(SearchHit {
+                    message_id: row.get(0)?,
+                    snippet: row.get(1)?,
+                })
+            })?
+            .collect::<Result<Vec<SearchHit>, _>>()?;
+        Ok((hits, total))
+    }
+}
+
+fn remove_doc(connection: &Connection, id: &str) -> Result<(), rusqlite::Error> {
+    let doc: Option<i64> = connection
+        .query_row("SELECT doc FROM indexed WHERE id = ?1", [id], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    let Some(doc) = doc else {
+        return Ok(());
+    };
+    connection.execute("DELETE FROM search WHERE rowid = ?1", [doc])?;
+    connection.execute("DELETE FROM participants WHERE doc = ?1", [doc])?;
+    connection.execute("DELETE FROM indexed WHERE doc = ?1", [doc])?;
+    Ok(())
+}
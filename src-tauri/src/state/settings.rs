@@ -0,0 +1,356 @@
+use std::{collections::HashMap, fs::File, io::BufReader, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::state::{data_dir, write_atomic};
+
+/**
+ * User configurable settings, persisted as JSON next to the saved state
+ *
+ * Missing keys fall back to their defaults so older files keep loading
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Settings {
+    pub api: ApiSettings,
+    pub webhooks: Vec<WebhookTarget>,
+    pub matrix: MatrixSettings,
+    pub xmpp: XmppSettings,
+    pub attachments: AttachmentSettings,
+    pub media: MediaSettings,
+    pub receipts: ReceiptSettings,
+    pub handles: HandleSettings,
+    pub keys: KeySettings,
+    pub carddav: CardDavSettings,
+    pub conversations: ConversationSettings,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ApiSettings {
+    pub enabled: bool,
+    /// Must be a loopback address
+    pub address: String,
+    /// Listen on this unix socket instead of `address`
+    pub unix_socket: Option<PathBuf>,
+    /// Bearer token clients must present, generated on first start
+    pub token: Option<String>,
+}
+
+impl Default for ApiSettings {
+    fn default() -> Self {
+        ApiSettings {
+            enabled: false,
+            address: "127.0.0.1:5795".to_owned(),
+            unix_socket: None,
+            token: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct WebhookTarget {
+    pub url: String,
+    /// Key for the HMAC-SHA256 signature of each payload
+    pub secret: String,
+    /// Event names to deliver, all events if empty
+    pub events: Vec<String>,
+    /// Only deliver message events for these conversations, all if empty
+    pub conversations: Vec<String>,
+    /// Only deliver message events involving these handles, all if empty
+    pub handles: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MatrixSettings {
+    pub enabled: bool,
+    /// Client-server API of the homeserver, e.g. http://localhost:8008
+    pub homeserver_url: String,
+    /// The homeserver's server name, the part after the colon in user IDs
+    pub server_name: String,
+    /// Where the homeserver pushes transactions to
+    pub listen_address: String,
+    /// Localpart of the bridge bot
+    pub bot_localpart: String,
+    /// Prefix of the localparts of puppeted iMessage handles
+    pub user_prefix: String,
+    /// The Matrix user who owns this bridge and is invited to every room
+    pub owner: String,
+    /// Generated into the registration file on first start
+    pub as_token: Option<String>,
+    pub hs_token: Option<String>,
+}
+
+impl Default for MatrixSettings {
+    fn default() -> Self {
+        MatrixSettings {
+            enabled: false,
+            homeserver_url: "http://localhost:8008".to_owned(),
+            server_name: "localhost".to_owned(),
+            listen_address: "127.0.0.1:29330".to_owned(),
+            bot_localpart: "imessagebot".to_owned(),
+            user_prefix: "imessage_".to_owned(),
+            owner: String::new(),
+            as_token: None,
+            hs_token: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct XmppSettings {
+    pub enabled: bool,
+    /// Host and port of the server's component listener
+    pub server: String,
+    pub port: u16,
+    /// The component's domain, handles appear as users on it
+    pub domain: String,
+    /// Shared secret configured for the component on the server
+    pub secret: String,
+    /// Bare JID of the user this gateway relays for
+    pub owner: String,
+}
+
+impl Default for XmppSettings {
+    fn default() -> Self {
+        XmppSettings {
+            enabled: false,
+            server: "localhost".to_owned(),
+            port: 5347,
+            domain: "imessage.localhost".to_owned(),
+            secret: String::new(),
+            owner: String::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum TransportKind {
+    /// Upload to and download from Apple's servers
+    #[default]
+    Mmcs,
+    /// Send content inline without touching Apple's servers, for testing
+    Local,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AttachmentSettings {
+    pub transport: TransportKind,
+    /// Bytes of downloaded originals to keep per account, 0 for no limit
+    pub quota_bytes: u64,
+}
+
+impl Default for AttachmentSettings {
+    fn default() -> Self {
+        AttachmentSettings {
+            transport: TransportKind::Mmcs,
+            quota_bytes: 5 * 1024 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ImageFormat {
+    #[default]
+    Jpeg,
+    Webp,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MediaSettings {
+    /// Format HEIC images and thumbnails are converted to
+    pub format: ImageFormat,
+    /// Encoder quality from 1 to 100
+    pub quality: u8,
+    /// Longest side of thumbnails in pixels
+    pub thumbnail_size: u32,
+    /// Downscale and re-encode outgoing images
+    pub process_outgoing: bool,
+    /// Longest side of outgoing images in pixels
+    pub max_dimension: u32,
+    /// Outgoing images are shrunk until they are at most this many bytes
+    pub max_image_bytes: u64,
+}
+
+impl Default for MediaSettings {
+    fn default() -> Self {
+        MediaSettings {
+            format: ImageFormat::Jpeg,
+            quality: 80,
+            thumbnail_size: 320,
+            process_outgoing: true,
+            max_dimension: 2048,
+            max_image_bytes: 2 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ReceiptSettings {
+    /// Tell senders when we have read their messages
+    pub send_read_receipts: bool,
+    /// Overrides of `send_read_receipts`, by conversation ID
+    pub conversations: HashMap<String, bool>,
+}
+
+impl Default for ReceiptSettings {
+    fn default() -> Self {
+        ReceiptSettings {
+            send_read_receipts: true,
+            conversations: HashMap::new(),
+        }
+    }
+}
+
+impl ReceiptSettings {
+    pub fn sends_read_receipts(&self, conversation_id: &str) -> bool {
+        self.conversations
+            .get(conversation_id)
+            .copied()
+            .unwrap_or(self.send_read_receipts)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HandleSettings {
+    /// ISO 3166-1 code of the region phone numbers without a country code
+    /// are in
+    pub default_region: String,
+}
+
+impl Default for HandleSettings {
+    fn default() -> Self {
+        HandleSettings {
+            default_region: "US".to_owned(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct KeySettings {
+    /// Hold messages to a verified handle whose keys changed until the
+    /// user confirms the new keys
+    pub hold_on_change: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ConversationSettings {
+    /// Show the one-to-one conversations with each of a contact's handles
+    /// as one conversation
+    pub merge_contacts: bool,
+    /// IDs of contacts whose conversations are kept apart anyway
+    pub unmerged: Vec<String>,
+}
+
+impl ConversationSettings {
+    pub fn merges(&self, contact_id: &str) -> bool {
+        self.merge_contacts && !self.unmerged.iter().any(|id| id == contact_id)
+    }
+}
+
+/**
+ * What to do with a contact that changed both here and on the server
+ * since the last sync
+ */
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ConflictPolicy {
+    /// Take the server's version, keeping the handles added here too
+    #[default]
+    Merge,
+    Server,
+    Local,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CardDavSettings {
+    pub enabled: bool,
+    /// The server, e.g. https://cloud.example.com, or an address book on it
+    pub url: String,
+    pub username: String,
+    pub password: String,
+    /// Minutes between syncs
+    pub interval_minutes: u64,
+    pub conflicts: ConflictPolicy,
+}
+
+impl Default for CardDavSettings {
+    fn default() -> Self {
+        CardDavSettings {
+            enabled: false,
+            url: String::new(),
+            username: String::new(),
+            password: String::new(),
+            interval_minutes: 15,
+            conflicts: ConflictPolicy::Merge,
+        }
+    }
+}
+
+fn settings_path() -> PathBuf {
+    data_dir().join("settings.json")
+}
+
+impl Settings {
+    pub fn load() -> Result<Settings, std::io::Error> {
+        let path = settings_path();
+        if !path.exists() {
+            return Ok(Settings::default());
+        }
+        // It is edited by hand, so it may have been created readable by
+        // others, which it must not be with the CardDAV password in it
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mut permissions = std::fs::metadata(&path)?.permissions();
+            if permissions.mode() & 0o077 != 0 {
+                permissions.set_mode(0o600);
+                std::fs::set_permissions(&path, permissions)?;
+            }
+        }
+        let file = File::open(path)?;
+        let settings: Settings = serde_json::from_reader(BufReader::new(file))?;
+        Ok(settings)
+    }
+
+    /**
+     * Only we may read the file, since it holds the API token and other
+     * secrets
+     */
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        write_atomic(&settings_path(), true, |writer| {
+            Ok(serde_json::to_writer_pretty(writer, self)?)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_conversation_can_override_read_receipts() {
+        let mut receipts = ReceiptSettings::default();
+        receipts.conversations.insert("quiet".to_owned(), false);
+        receipts.conversations.insert("open".to_owned(), true);
+        assert!(receipts.sends_read_receipts("other"));
+        assert!(!receipts.sends_read_receipts("quiet"));
+
+        receipts.send_read_receipts = false;
+        assert!(!receipts.sends_read_receipts("other"));
+        assert!(receipts.sends_read_receipts("open"));
+    }
+}
@@ -0,0 +1,411 @@
+/// Longest line allowed before it has to be folded, in octets
+const MAX_LINE: usize = 75;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VCardError {
+    /// A property outside of BEGIN:VCARD and END:VCARD, by line number
+    OutsideCard(usize),
+    /// A line that is not a property, by line number
+    InvalidLine(usize),
+    /// The file ended before END:VCARD
+    UnexpectedEnd,
+}
+
+impl std::fmt::Display for VCardError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VCardError::OutsideCard(line) => write!(f, "Line {} is outside of a vCard", line),
+            VCardError::InvalidLine(line) => write!(f, "Line {} is not a vCard property", line),
+            VCardError::UnexpectedEnd => write!(f, "The last vCard is not finished"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    /// RFC 2426
+    V3,
+    /// RFC 6350
+    V4,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::V3 => "3.0",
+            Version::V4 => "4.0",
+        }
+    }
+}
+
+/**
+ * One line of a vCard, e.g. `item1.TEL;TYPE=CELL:+1 555 123 4567`
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Property {
+    pub group: Option<String>,
+    /// Upper-cased
+    pub name: String,
+    /// With upper-cased names and without quotes around the values
+    pub params: Vec<(String, String)>,
+    /// As written, with text still escaped
+    pub value: String,
+}
+
+impl Property {
+    pub fn new(name: &str, value: String) -> Property {
+        Property {
+            group: None,
+            name: name.to_owned(),
+            params: Vec::new(),
+            value,
+        }
+    }
+
+    pub fn with_param(mut self, name: &str, value: &str) -> Property {
+        self.params.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /**
+     * Parse an unfolded line, or None if it is not a property
+     */
+    pub fn parse(line: &str) -> Option<Property> {
+        let colon = find_unquoted(line, ':')?;
+        let (head, value) = (&line[..colon], &line[colon + 1..]);
+        let mut parts = split_unquoted(head, ';').into_iter();
+        let full_name = parts.next()?;
+        let (group, name) = match full_name.rsplit_once('.') {
+            Some((group, name)) => (Some(group.to_owned()), name),
+            None => (None, full_name),
+        };
+        if name.is_empty() {
+            return None;
+        }
+        let params = parts
+            .filter(|param| !param.is_empty())
+            .map(|param| match param.split_once('=') {
+                Some((name, value)) => (name.to_uppercase(), value.trim_matches('"').to_owned()),
+                // vCard 2.1 leaves out the name of types, e.g. TEL;CELL
+                None => ("TYPE".to_owned(), param.to_owned()),
+            })
+            .collect();
+        Some(Property {
+            group,
+            name: name.to_uppercase(),
+            params,
+            value: value.to_owned(),
+        })
+    }
+
+    /**
+     * The first value of a parameter
+     */
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param, _)| param.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /**
+     * The value as text, unescaped
+     */
+    pub fn text(&self) -> String {
+        unescape(&self.value)
+    }
+
+    /**
+     * The unescaped parts of a structured value like N or ORG
+     */
+    pub fn components(&self) -> Vec<String> {
+        split_escaped(&self.value, ';')
+            .into_iter()
+            .map(|component| unescape(&component))
+            .collect()
+    }
+
+    /**
+     * The line this property is written as, before folding
+     */
+    pub fn to_line(&self) -> String {
+        let mut line = String::new();
+        if let Some(group) = &self.group {
+            line.push_str(group);
+            line.push('.');
+        }
+        line.push_str(&self.name);
+        for (name, value) in self.params.iter() {
+            line.push(';');
+            line.push_str(name);
+            line.push('=');
+            if value.contains([':', ';']) {
+                line.push('"');
+                line.push_str(value);
+                line.push('"');
+            } else {
+                line.push_str(value);
+            }
+        }
+        line.push(':');
+        line.push_str(&self.value);
+        line
+    }
+}
+
+/**
+ * Escape text for a property value
+ */
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            ',' => escaped.push_str("\\,"),
+            ';' => escaped.push_str("\\;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+fn find_unquoted(text: &str, separator: char) -> Option<usize> {
+    let mut quoted = false;
+    text.char_indices().find_map(|(i, c)| {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        (c == separator && !quoted).then_some(i)
+    })
+}
+
+fn split_unquoted(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = text;
+    while let Some(i) = find_unquoted(rest, separator) {
+        parts.push(&rest[..i]);
+        rest = &rest[i + separator.len_utf8()..];
+    }
+    parts.push(rest);
+    parts
+}
+
+fn split_escaped(text: &str, separator: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let part = parts.last_mut().unwrap();
+        if c == '\\' {
+            part.push(c);
+            if let Some(next) = chars.next() {
+                part.push(next);
+            }
+        } else if c == separator {
+            parts.push(String::new());
+        } else {
+            part.push(c);
+        }
+    }
+    parts
+}
+
+/**
+ * Every card in a file, as the properties between its BEGIN and END lines
+ */
+pub fn parse(text: &str) -> Result<Vec<Vec<Property>>, VCardError> {
+    // Folded lines continue with a space or tab, and keep the number of the
+    // line they started on for errors
+    let mut lines: Vec<(usize, String)> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let continued = line.strip_prefix([' ', '\t']);
+        match (continued, lines.last_mut()) {
+            (Some(continued), Some((_, last))) => last.push_str(continued),
+            _ => lines.push((i + 1, line.to_owned())),
+        }
+    }
+    let mut cards = Vec::new();
+    let mut card: Option<Vec<Property>> = None;
+    for (number, line) in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let property = Property::parse(&line).ok_or(VCardError::InvalidLine(number))?;
+        let is_vcard = property.value.trim().eq_ignore_ascii_case("VCARD");
+        let Some(properties) = card.as_mut() else {
+            if property.name == "BEGIN" && is_vcard {
+                card = Some(Vec::new());
+                continue;
+            }
+            return Err(VCardError::OutsideCard(number));
+        };
+        if property.name == "END" && is_vcard {
+            cards.extend(card.take());
+        } else {
+            properties.push(property);
+        }
+    }
+    match card {
+        Some(_) => Err(VCardError::UnexpectedEnd),
+        None => Ok(cards),
+    }
+}
+
+/**
+ * Split a line so no part is longer than `MAX_LINE` octets, without
+ * splitting a character
+ */
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + line.len() / MAX_LINE * 3);
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > MAX_LINE {
+            folded.push_str("\r\n ");
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+    folded
+}
+
+/**
+ * Write a card, adding its BEGIN and END lines
+ */
+pub fn write_card(properties: &[Property]) -> String {
+    let mut card = String::from("BEGIN:VCARD\r\n");
+    for property in properties {
+        card.push_str(&fold(&property.to_line()));
+        card.push_str("\r\n");
+    }
+    card.push_str("END:VCARD\r\n");
+    card
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn properties_are_parsed() {
+        let property = Property::parse("item1.tel;type=CELL;TYPE=\"voice,pref\":+1 555").unwrap();
+        assert_eq!(property.group.as_deref(), Some("item1"));
+        assert_eq!(property.name, "TEL");
+        assert_eq!(
+            property.params,
+            [
+                ("TYPE".to_owned(), "CELL".to_owned()),
+                ("TYPE".to_owned(), "voice,pref".to_owned())
+            ]
+        );
+        assert_eq!(property.param("type"), Some("CELL"));
+        assert_eq!(property.value, "+1 555");
+
+        // Colons and semicolons in quoted parameters are not separators
+        let property = Property::parse("PHOTO;X-NOTE=\"a:b;c\";CELL:data:x").unwrap();
+        assert_eq!(property.param("X-NOTE"), Some("a:b;c"));
+        assert_eq!(property.param("TYPE"), Some("CELL"));
+        assert_eq!(property.value, "data:x");
+
+        assert_eq!(Property::parse("no colon"), None);
+        assert_eq!(Property::parse(":value"), None);
+        assert_eq!(Property::parse("group.:value"), None);
+    }
+
+    #[test]
+    fn text_is_escaped() {
+        let text = "Line one\nback\\slash, comma; semicolon";
+        let escaped = escape(text);
+        assert_eq!(escaped, r"Line one\nback\\slash\, comma\; semicolon");
+        assert_eq!(unescape(&escaped), text);
+        assert_eq!(escape("a\r\nb"), "a\\nb");
+        assert_eq!(unescape("a\\Nb\\"), "a\nb\\");
+    }
+
+    #[test]
+    fn structured_values_split_on_unescaped_semicolons() {
+        let property = Property::parse(r"N:Doe\;Smith;Jane;;Dr.;").unwrap();
+        assert_eq!(property.components(), ["Doe;Smith", "Jane", "", "Dr.", ""]);
+    }
+
+    #[test]
+    fn folded_lines_are_joined() {
+        let text = "BEGIN:VCARD\r\nNOTE:a long\r\n  note\r\n\tcontinued\r\nEND:VCARD\r\n";
+        let cards = parse(text).unwrap();
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0][0].value, "a long notecontinued");
+    }
+
+    #[test]
+    fn long_lines_are_folded_between_characters() {
+        let line = format!("NOTE:{}", "\u{e9}".repeat(60));
+        let folded = fold(&line);
+        for part in folded.split("\r\n") {
+            assert!(part.len() <= MAX_LINE, "{:?}", part);
+        }
+        let unfolded: String = folded
+            .split("\r\n")
+            .enumerate()
+            .map(|(i, part)| if i == 0 { part } else { &part[1..] })
+            .collect();
+        assert_eq!(unfolded, line);
+        assert_eq!(fold("NOTE:short"), "NOTE:short");
+    }
+
+    #[test]
+    fn cards_round_trip() {
+        let properties = vec![
+            Property::new("VERSION", "3.0".to_owned()),
+            Property::new("FN", escape("Doe, Jane")),
+            Property::new("NOTE", escape(&"Some; long\nnote ".repeat(10))),
+            Property::new("TEL", "+15551234567".to_owned()).with_param("TYPE", "CELL"),
+            Property::new("X-PLACE", "here".to_owned()).with_param("X-GEO", "geo:1;2"),
+            Property {
+                group: Some("item1".to_owned()),
+                ..Property::new("EMAIL", "jane@example.com".to_owned())
+            },
+        ];
+        let text = write_card(&properties);
+        assert!(text.starts_with("BEGIN:VCARD\r\n"));
+        assert!(text.ends_with("END:VCARD\r\n"));
+        assert!(text.contains("X-PLACE;X-GEO=\"geo:1;2\":here\r\n"));
+        let cards = parse(&text).unwrap();
+        assert_eq!(cards, vec![properties]);
+        assert_eq!(cards[0][2].text(), "Some; long\nnote ".repeat(10));
+
+        let two = parse(&[text.as_str(), "\r\n", text.as_str()].concat()).unwrap();
+        assert_eq!(two.len(), 2);
+    }
+
+    #[test]
+    fn malformed_files_are_rejected() {
+        assert_eq!(parse("FN:Jane\r\n"), Err(VCardError::OutsideCard(1)));
+        assert_eq!(
+            parse("BEGIN:VCARD\r\nnot a property\r\nEND:VCARD"),
+            Err(VCardError::InvalidLine(2))
+        );
+        assert_eq!(
+            parse("BEGIN:VCARD\r\nFN:Jane\r\n"),
+            Err(VCardError::UnexpectedEnd)
+        );
+        assert_eq!(parse(""), Ok(Vec::new()));
+    }
+}
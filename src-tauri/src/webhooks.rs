@@ -0,0 +1,348 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use serde_json::json;
+use tokio::sync::{broadcast::error::RecvError, Mutex, Notify};
+use uuid::Uuid;
+
+use crate::{
+    events::AppEvent,
+    state::{messagestore::now_millis, settings::WebhookTarget, TauriState},
+};
+
+use self::{
+    delivery::{backoff, deliver, MAX_ATTEMPTS},
+    queue::{PendingDelivery, WebhookQueue},
+};
+
+pub mod delivery;
+pub mod queue;
+
+/**
+ * Whether an event should be sent to a webhook target
+ *
+ * Conversation and handle filters only apply to message events, status
+ * events go to every target that asks for them
+ */
+pub fn matches(target: &WebhookTarget, event: &AppEvent) -> bool {
+    if !target.events.is_empty() && !target.events.iter().any(|name| name == event.name()) {
+        return false;
+    }
+    let handles = event.handles();
+    let conversation_id = event.conversation_id();
+    if handles.is_empty() && conversation_id.is_none() {
+        return true;
+    }
+    if !target.conversations.is_empty()
+        && !conversation_id.map_or(false, |id| target.conversations.iter().any(|c| c == id))
+    {
+        return false;
+    }
+    if !target.handles.is_empty()
+        && !handles
+            .iter()
+            .any(|handle| target.handles.iter().any(|h| h == handle))
+    {
+        return false;
+    }
+    true
+}
+
+/**
+ * Queue every event for the webhook targets that want it, and deliver the
+ * queue in the background with retries
+ *
+ * Ephemeral events such as typing are sent once and never queued, since
+ * they are stale by the time a retry would go out
+ */
+pub async fn spawn_webhooks(state: TauriState) -> Result<(), std::io::Error> {
+    let queue = Arc::new(Mutex::new(WebhookQueue::load()?));
+    let notify = Arc::new(Notify::new());
+    let (settings, events) = {
+        let state = state.0.lock().await;
+        (state.settings.clone(), state.events.clone())
+    };
+
+    let client = reqwest::Client::new();
+    let enqueue_queue = queue.clone();
+    let enqueue_notify = notify.clone();
+    let enqueue_client = client.clone();
+    let mut receiver = events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("Webhooks missed {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let targets = settings.lock().await.webhooks.clone();
+            let mut queue = enqueue_queue.lock().await;
+            for target in targets.iter().filter(|target| matches(target, &event)) {
+                let id = Uuid::new_v4().to_string();
+                let body = json!({
+                    "id": id,
+                    "timestamp": now_millis(),
+                    "event": event,
+                })
+                .to_string();
+                let delivery = PendingDelivery {
+                    id,
+                    url: target.url.clone(),
+                    secret: target.secret.clone(),
+                    event: event.name().to_owned(),
+                    body,
+                    attempts: 0,
+                    next_attempt: now_millis(),
+                };
+                if event.is_ephemeral() {
+                    let client = enqueue_client.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = deliver(&client, &delivery).await {
+                            log::debug!("Webhook to {} failed: {:?}", delivery.url, e);
+                        }
+                    });
+                    continue;
+                }
+                match queue.push(delivery) {
+                    Ok(Some(dropped)) => log::warn!(
+                        "Too many webhooks waiting for {}, dropped {}",
+                        dropped.url,
+                        dropped.id
+                    ),
+                    Ok(None) => {}
+                    Err(e) => log::error!("Error saving webhook queue: {:?}", e),
+                }
+            }
+            enqueue_notify.notify_one();
+        }
+    });
+
+    tokio::spawn(deliver_queue(queue, client, notify));
+    Ok(())
+}
+
+/**
+ * Deliver the queue as it comes due, each URL in its own task
+ *
+ * Deliveries to one URL go out one at a time and in order, so a dead
+ * endpoint only holds up its own deliveries
+ */
+async fn deliver_queue(
+    queue: Arc<Mutex<WebhookQueue>>,
+    client: reqwest::Client,
+    notify: Arc<Notify>,
+) {
+    let busy = Arc::new(std::sync::Mutex::new(HashSet::new()));
+    loop {
+        let due = queue.lock().await.due_by_url(now_millis());
+        for (url, deliveries) in due {
+            if !busy.lock().unwrap().insert(url.clone()) {
+                continue;
+            }
+            let queue = queue.clone();
+            let client = client.clone();
+            let notify = notify.clone();
+            let busy = busy.clone();
+            tokio::spawn(async move {
+                deliver_url(&queue, &client, deliveries).await;
+                busy.lock().unwrap().remove(&url);
+                notify.notify_one();
+            });
+        }
+
+        let next = {
+            let queue = queue.lock().await;
+            let busy = busy.lock().unwrap();
+            queue.next_due(&busy)
+        };
+        let wait = match next {
+            Some(next) => Duration::from_millis(next.saturating_sub(now_millis())),
+            None => Duration::from_secs(3600),
+        };
+        tokio::select! {
+            _ = notify.notified() => {}
+            _ = tokio::time::sleep(wait) => {}
+        }
+    }
+}
+
+/**
+ * Deliver to one URL in order, stopping at the first failure
+ *
+ * The rest then wait out the same backoff instead of each timing out in turn
+ */
+async fn deliver_url(
+    queue: &Mutex<WebhookQueue>,
+    client: &reqwest::Client,
+    deliveries: Vec<PendingDelivery>,
+) {
+    for delivery in deliveries {
+        let result = deliver(client, &delivery).await;
+        let mut queue = queue.lock().await;
+        let Err(e) = result else {
+            if let Err(e) = queue.remove(&delivery.id) {
+                log::error!("Error saving webhook queue: {:?}", e);
+            }
+            continue;
+        };
+        let attempts = delivery.attempts + 1;
+        let retry = now_millis() + backoff(attempts).as_millis() as u64;
+        let saved = if attempts >= MAX_ATTEMPTS {
+            log::error!(
+                "Giving up on webhook {} to {}: {:?}",
+                delivery.id,
+                delivery.url,
+                e
+            );
+            queue.remove(&delivery.id)
+        } else {
+            log::warn!("Webhook to {} failed: {:?}", delivery.url, e);
+            queue.reschedule(&delivery.id, attempts, retry)
+        };
+        if let Err(e) = saved.and_then(|_| queue.postpone(&delivery.url, retry)) {
+            log::error!("Error saving webhook queue: {:?}", e);
+        }
+        return;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::state::test_dir;
+
+    fn received(conversation_id: &str, sender: &str) -> AppEvent {
+        AppEvent::MessageReceived {
+            message: serde_json::from_value(json!({
+                "id": "message",
+                "conversationId": conversation_id,
+                "participants": [sender, "tel:+15550000000"],
+                "sender": sender,
+                "fromMe": false,
+                "text": "hello",
+                "timestamp": 1,
+            }))
+            .unwrap(),
+            contact: None,
+        }
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    fn target(events: &[&str], conversations: &[&str], handles: &[&str]) -> WebhookTarget {
+        WebhookTarget {
+            events: strings(events),
+            conversations: strings(conversations),
+            handles: strings(handles),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn event_names_are_filtered() {
+        let event = received("c1", "tel:+15551234567");
+        assert!(matches(&target(&[], &[], &[]), &event));
+        assert!(matches(&target(&["messageReceived"], &[], &[]), &event));
+        assert!(!matches(&target(&["messageSent"], &[], &[]), &event));
+    }
+
+    #[test]
+    fn message_events_are_filtered_by_conversation_and_handle() {
+        let event = received("c1", "tel:+15551234567");
+        assert!(matches(&target(&[], &["c1"], &[]), &event));
+        assert!(!matches(&target(&[], &["c2"], &[]), &event));
+        assert!(matches(&target(&[], &[], &["tel:+15551234567"]), &event));
+        assert!(!matches(&target(&[], &[], &["tel:+15559999999"]), &event));
+        assert!(!matches(
+            &target(&[], &["c1"], &["tel:+15559999999"]),
+            &event
+        ));
+    }
+
+    #[test]
+    fn status_events_ignore_message_filters() {
+        let event = AppEvent::ConnectionChanged { connected: true };
+        assert!(matches(
+            &target(&[], &["c1"], &["tel:+15551234567"]),
+            &event
+        ));
+        assert!(!matches(&target(&["messageReceived"], &[], &[]), &event));
+    }
+
+    fn delivery(id: &str, url: &str) -> PendingDelivery {
+        PendingDelivery {
+            id: id.to_owned(),
+            url: url.to_owned(),
+            secret: "whsec_test".to_owned(),
+            event: "connectionChanged".to_owned(),
+            body: "{}".to_owned(),
+            attempts: 0,
+            next_attempt: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn a_dead_endpoint_does_not_hold_up_other_urls() {
+        // Never accepted, so requests to it hang until they time out
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead_url = format!("http://{}/hook", dead.local_addr().unwrap());
+        let healthy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let healthy_url = format!("http://{}/hook", healthy.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = healthy.accept().await {
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 4096];
+                while !request.ends_with(b"}") {
+                    let read = socket.read(&mut buffer).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                let response = "HTTP/1.1 204 No Content\r\nconnection: close\r\n\r\n";
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let dir = test_dir();
+        let mut queue = WebhookQueue::load_from(&dir).unwrap();
+        queue.push(delivery("dead-1", &dead_url)).unwrap();
+        queue.push(delivery("dead-2", &dead_url)).unwrap();
+        queue.push(delivery("healthy", &healthy_url)).unwrap();
+        let queue = Arc::new(Mutex::new(queue));
+        tokio::spawn(deliver_queue(
+            queue.clone(),
+            reqwest::Client::new(),
+            Arc::new(Notify::new()),
+        ));
+
+        // Well before the dead endpoint's first request times out
+        let waiting = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let waiting: Vec<String> = queue
+                    .lock()
+                    .await
+                    .due(u64::MAX)
+                    .into_iter()
+                    .map(|delivery| delivery.id)
+                    .collect();
+                if !waiting.iter().any(|id| id == "healthy") {
+                    return waiting;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(waiting, ["dead-1", "dead-2"]);
+    }
+}
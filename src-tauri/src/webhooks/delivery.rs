@@ -0,0 +1,195 @@
+use std::time::Duration;
+
+use openssl::{error::ErrorStack, hash::MessageDigest, pkey::PKey, sign::Signer};
+
+use crate::{state::messagestore::now_millis, webhooks::queue::PendingDelivery};
+
+pub const MAX_ATTEMPTS: u32 = 12;
+
+#[derive(Debug)]
+pub enum DeliveryError {
+    SignError(ErrorStack),
+    RequestError(reqwest::Error),
+    Status(reqwest::StatusCode),
+}
+
+impl From<ErrorStack> for DeliveryError {
+    fn from(error: ErrorStack) -> Self {
+        DeliveryError::SignError(error)
+    }
+}
+
+impl From<reqwest::Error> for DeliveryError {
+    fn from(error: reqwest::Error) -> Self {
+        DeliveryError::RequestError(error)
+    }
+}
+
+/**
+ * Hex encoded HMAC-SHA256 of `{timestamp}.{body}`
+ *
+ * Including the timestamp lets receivers reject replayed deliveries
+ */
+pub fn sign(secret: &str, timestamp: u64, body: &str) -> Result<String, ErrorStack> {
+    let key = PKey::hmac(secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(timestamp.to_string().as_bytes())?;
+    signer.update(b".")?;
+    signer.update(body.as_bytes())?;
+    Ok(signer
+        .sign_to_vec()?
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/**
+ * How long to wait before the given attempt, doubling from 5 seconds up to an hour
+ */
+pub fn backoff(attempts: u32) -> Duration {
+    Duration::from_secs(5u64.saturating_mul(1u64 << attempts.min(10)).min(3600))
+}
+
+pub async fn deliver(
+    client: &reqwest::Client,
+    delivery: &PendingDelivery,
+) -> Result<(), DeliveryError> {
+    let timestamp = now_millis();
+    let signature = sign(&delivery.secret, timestamp, &delivery.body)?;
+    let response = client
+        .post(&delivery.url)
+        .header("Content-Type", "application/json")
+        .header("X-CrossMessenger-Delivery", &delivery.id)
+        .header("X-CrossMessenger-Event", &delivery.event)
+        .header("X-CrossMessenger-Timestamp", timestamp.to_string())
+        .header(
+            "X-CrossMessenger-Signature",
+            format!("sha256={}", signature),
+        )
+        .body(delivery.body.clone())
+        .timeout(Duration::from_secs(15))
+        .send()
+        .await?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(DeliveryError::Status(response.status()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    const BODY: &str = r#"{"type":"connectionChanged","connected":true}"#;
+
+    #[test]
+    fn signature_matches_known_vector() {
+        // python3 -c "import hmac, hashlib; print(hmac.new(b'whsec_test',
+        //   b'1700000000000.' + BODY, hashlib.sha256).hexdigest())"
+        assert_eq!(
+            sign("whsec_test", 1_700_000_000_000, BODY).unwrap(),
+            "76d6f0a5fbd3eeb6083e129ad89b0e06e85ebc1e8075e12847349f1ef8823a47"
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_an_hour() {
+        assert_eq!(backoff(0), Duration::from_secs(5));
+        assert_eq!(backoff(1), Duration::from_secs(10));
+        assert_eq!(backoff(4), Duration::from_secs(80));
+        for attempts in 0..MAX_ATTEMPTS {
+            assert!(backoff(attempts + 1) >= backoff(attempts));
+        }
+        assert_eq!(backoff(10), Duration::from_secs(3600));
+        assert_eq!(backoff(u32::MAX), Duration::from_secs(3600));
+    }
+
+    /**
+     * Accept one request and answer it with the given status line,
+     * returning the request's headers and body
+     */
+    async fn receive_one(listener: TcpListener, status: &'static str) -> (String, String) {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            let read = socket.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            let Some((head, body)) = text.split_once("\r\n\r\n") else {
+                continue;
+            };
+            let length: usize = header(head, "content-length")
+                .and_then(|length| length.parse().ok())
+                .unwrap_or(0);
+            if body.len() >= length || read == 0 {
+                let response = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status);
+                socket.write_all(response.as_bytes()).await.unwrap();
+                return (head.to_owned(), body.to_owned());
+            }
+        }
+    }
+
+    fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+        head.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+
+    fn delivery(url: String) -> PendingDelivery {
+        PendingDelivery {
+            id: "delivery".to_owned(),
+            url,
+            secret: "whsec_test".to_owned(),
+            event: "connectionChanged".to_owned(),
+            body: BODY.to_owned(),
+            attempts: 0,
+            next_attempt: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_payload_to_local_receiver() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let receiver = tokio::spawn(receive_one(listener, "204 No Content"));
+        deliver(&reqwest::Client::new(), &delivery(url))
+            .await
+            .unwrap();
+
+        let (head, body) = receiver.await.unwrap();
+        assert_eq!(body, BODY);
+        assert_eq!(
+            header(&head, "X-CrossMessenger-Event"),
+            Some("connectionChanged")
+        );
+        let timestamp: u64 = header(&head, "X-CrossMessenger-Timestamp")
+            .unwrap()
+            .parse()
+            .unwrap();
+        let signature = format!("sha256={}", sign("whsec_test", timestamp, BODY).unwrap());
+        assert_eq!(
+            header(&head, "X-CrossMessenger-Signature"),
+            Some(signature.as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn error_status_fails_delivery() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(receive_one(listener, "500 Internal Server Error"));
+        let result = deliver(&reqwest::Client::new(), &delivery(url)).await;
+        assert!(matches!(
+            result,
+            Err(DeliveryError::Status(status)) if status.as_u16() == 500
+        ));
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::state::{data_dir, write_atomic};
+
+/// Most deliveries kept for one URL, so a dead endpoint cannot grow the
+/// queue without end. The oldest are dropped first.
+pub const MAX_PENDING_PER_URL: usize = 500;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingDelivery {
+    pub id: String,
+    pub url: String,
+    pub secret: String,
+    pub event: String,
+    pub body: String,
+    pub attempts: u32,
+    /// Milliseconds since the unix epoch
+    pub next_attempt: u64,
+}
+
+/**
+ * Webhook deliveries that have not succeeded yet, persisted so they survive
+ * restarts
+ */
+pub struct WebhookQueue {
+    path: PathBuf,
+    deliveries: Vec<PendingDelivery>,
+}
+
+impl WebhookQueue {
+    pub fn load() -> Result<WebhookQueue, std::io::Error> {
+        WebhookQueue::load_from(&data_dir())
+    }
+
+    pub(crate) fn load_from(dir: &Path) -> Result<WebhookQueue, std::io::Error> {
+        let path = dir.join("webhook-queue.json");
+        if !path.exists() {
+            return Ok(WebhookQueue {
+                path,
+                deliveries: Vec::new(),
+            });
+        }
+        let file = File::open(&path)?;
+        let deliveries: Vec<PendingDelivery> = serde_json::from_reader(BufReader::new(file))?;
+        Ok(WebhookQueue { path, deliveries })
+    }
+
+    /**
+     * Private, since the deliveries carry each target's signing secret
+     */
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        write_atomic(&self.path, true, |writer| {
+            Ok(serde_json::to_writer(writer, &self.deliveries)?)
+        })
+    }
+
+    /**
+     * Add a delivery, dropping the oldest one for the same URL if it already
+     * has `MAX_PENDING_PER_URL` waiting
+     *
+     * Returns the delivery that was dropped
+     */
+    pub fn push(
+        &mut self,
+        delivery: PendingDelivery,
+    ) -> Result<Option<PendingDelivery>, std::io::Error> {
+        let pending = self
+            .deliveries
+            .iter()
+            .filter(|pending| pending.url == delivery.url)
+            .count();
+        let dropped = if pending >= MAX_PENDING_PER_URL {
+            self.deliveries
+                .iter()
+                .position(|pending| pending.url == delivery.url)
+                .map(|index| self.deliveries.remove(index))
+        } else {
+            None
+        };
+        self.deliveries.push(delivery);
+        self.save()?;
+        Ok(dropped)
+    }
+
+    /**
+     * Deliveries whose next attempt is due, oldest first
+     */
+    pub fn due(&self, now: u64) -> Vec<PendingDelivery> {
+        self.deliveries
+            .iter()
+            .filter(|delivery| delivery.next_attempt <= now)
+            .cloned()
+            .collect()
+    }
+
+    /**
+     * Due deliveries grouped by URL, oldest first within each URL
+     */
+    pub fn due_by_url(&self, now: u64) -> Vec<(String, Vec<PendingDelivery>)> {
+        let mut urls: Vec<(String, Vec<PendingDelivery>)> = Vec::new();
+        for delivery in self.due(now) {
+            match urls.iter_mut().find(|(url, _)| *url == delivery.url) {
+                Some((_, deliveries)) => deliveries.push(delivery),
+                None => urls.push((delivery.url.clone(), vec![delivery])),
+            }
+        }
+        urls
+    }
+
+    /**
+     * When the next delivery is due, if there is one, ignoring the URLs
+     * that are already being delivered to
+     */
+    pub fn next_due(&self, busy: &HashSet<String>) -> Option<u64> {
+        self.deliveries
+            .iter()
+            .filter(|delivery| !busy.contains(&delivery.url))
+            .map(|delivery| delivery.next_attempt)
+            .min()
+    }
+
+    pub fn remove(&mut self, id: &str) -> Result<(), std::io::Error> {
+        self.deliveries.retain(|delivery| delivery.id != id);
+        self.save()
+    }
+
+    pub fn reschedule(
+        &mut self,
+        id: &str,
+        attempts: u32,
+        next_attempt: u64,
+    ) -> Result<(), std::io::Error> {
+        if let Some(delivery) = self
+            .deliveries
+            .iter_mut()
+            .find(|delivery| delivery.id == id)
+        {
+            delivery.attempts = attempts;
+            delivery.next_attempt = next_attempt;
+        }
+        self.save()
+    }
+
+    /**
+     * Hold back every delivery to a URL until at least `until`, so a failing
+     * endpoint is not tried once for each delivery waiting for it
+     */
+    pub fn postpone(&mut self, url: &str, until: u64) -> Result<(), std::io::Error> {
+        for delivery in self
+            .deliveries
+            .iter_mut()
+            .filter(|delivery| delivery.url == url)
+        {
+            delivery.next_attempt = delivery.next_attempt.max(until);
+        }
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_dir;
+
+    fn delivery(id: &str, next_attempt: u64) -> PendingDelivery {
+        PendingDelivery {
+            id: id.to_owned(),
+            url: "http://127.0.0.1:9/hook".to_owned(),
+            secret: "whsec_test".to_owned(),
+            event: "connectionChanged".to_owned(),
+            body: r#"{"type":"connectionChanged","connected":true}"#.to_owned(),
+            attempts: 0,
+            next_attempt,
+        }
+    }
+
+    #[test]
+    fn pending_deliveries_are_replayed_after_reload() {
+        let dir = test_dir();
+        let mut queue = WebhookQueue::load_from(&dir).unwrap();
+        queue.push(delivery("a", 1_000)).unwrap();
+        queue.push(delivery("b", 2_000)).unwrap();
+        queue.push(delivery("c", 3_000)).unwrap();
+        queue.reschedule("a", 3, 5_000).unwrap();
+        queue.remove("c").unwrap();
+
+        let queue = WebhookQueue::load_from(&dir).unwrap();
+        assert_eq!(queue.next_due(&HashSet::new()), Some(2_000));
+        let due = queue.due(5_000);
+        let ids: Vec<&str> = due.iter().map(|delivery| delivery.id.as_str()).collect();
+        assert_eq!(ids, ["a", "b"]);
+        assert_eq!(due[0].attempts, 3);
+        assert!(queue.due(1_999).is_empty());
+    }
+
+    #[test]
+    fn the_oldest_delivery_to_a_url_is_dropped_when_full() {
+        let dir = test_dir();
+        let mut queue = WebhookQueue::load_from(&dir).unwrap();
+        let other = PendingDelivery {
+            url: "http://127.0.0.1:9/other".to_owned(),
+            ..delivery("other", 0)
+        };
+        queue.push(other).unwrap();
+        for index in 0..MAX_PENDING_PER_URL {
+            assert!(queue
+                .push(delivery(&index.to_string(), index as u64))
+                .unwrap()
+                .is_none());
+        }
+        let dropped = queue.push(delivery("newest", 0)).unwrap();
+        assert_eq!(dropped.map(|delivery| delivery.id), Some("0".to_owned()));
+        let due = queue.due(u64::MAX);
+        assert_eq!(due.len(), MAX_PENDING_PER_URL + 1);
+        assert!(due.iter().any(|delivery| delivery.id == "other"));
+        assert!(due.iter().any(|delivery| delivery.id == "newest"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_queue_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = test_dir();
+        let mut queue = WebhookQueue::load_from(&dir).unwrap();
+        queue.push(delivery("a", 0)).unwrap();
+        let metadata = std::fs::metadata(dir.join("webhook-queue.json")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn deliveries_are_grouped_and_postponed_by_url() {
+        let dir = test_dir();
+        let mut queue = WebhookQueue::load_from(&dir).unwrap();
+        let other = |id: &str, next_attempt| PendingDelivery {
+            url: "http://127.0.0.1:9/other".to_owned(),
+            ..delivery(id, next_attempt)
+        };
+        queue.push(delivery("a", 1_000)).unwrap();
+        queue.push(other("b", 2_000)).unwrap();
+        queue.push(delivery("c", 3_000)).unwrap();
+        queue.push(other("d", 9_000)).unwrap();
+
+        let due = queue.due_by_url(5_000);
+        let grouped: Vec<(&str, Vec<&str>)> = due
+            .iter()
+            .map(|(url, deliveries)| {
+                let ids = deliveries.iter().map(|delivery| delivery.id.as_str());
+                (url.as_str(), ids.collect())
+            })
+            .collect();
+        assert_eq!(
+            grouped,
+            [
+                ("http://127.0.0.1:9/hook", vec!["a", "c"]),
+                ("http://127.0.0.1:9/other", vec!["b"]),
+            ]
+        );
+
+        queue.postpone("http://127.0.0.1:9/other", 4_000).unwrap();
+        let busy = HashSet::from(["http://127.0.0.1:9/hook".to_owned()]);
+        assert_eq!(queue.next_due(&busy), Some(4_000));
+        // Later deliveries keep their own time
+        assert_eq!(
+            queue
+                .due(u64::MAX)
+                .iter()
+                .find(|delivery| delivery.id == "d")
+                .map(|delivery| delivery.next_attempt),
+            Some(9_000)
+        );
+    }
+}
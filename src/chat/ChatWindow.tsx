@@ -1,5 +1,6 @@
 import { invoke } from "@tauri-apps/api/primitives";
-import { useState } from "preact/hooks";
+import { useRef, useState } from "preact/hooks";
+import { checkReachability, ReachabilityStatus } from "../ipc";
 
 function sendMessage(message: string, to: string) {
   invoke("send_message", { message, to })
@@ -11,29 +12,78 @@ function sendMessage(message: string, to: string) {
     });
 }
 
+/**
+ * A warning for a recipient that can't be messaged, or null if it can or
+ * we can't tell
+ */
+async function recipientWarning(to: string): Promise<string | null> {
+  if (!to.trim()) {
+    return null;
+  }
+  const result = await checkReachability([to]);
+  if (result.tag === "err") {
+    console.error(result.val);
+    return null;
+  }
+  const [reachability] = result.val;
+  // The generated bindings deserialize enums to their names
+  const status =
+    reachability.status as unknown as keyof typeof ReachabilityStatus;
+  switch (status) {
+    case "Invalid":
+      return `${to} is not a valid phone number or email address`;
+    case "Unreachable":
+      return `${reachability.normalized ?? to} is not on iMessage`;
+    default:
+      return null;
+  }
+}
+
 export function ChatWindow() {
   const [message, setMessage] = useState("");
   const [to, setTo] = useState("");
+  const [warning, setWarning] = useState<string | null>(null);
+  // What is in the field now, as a check may finish after it was edited
+  const currentTo = useRef(to);
 
   return (
-    <form
-      class="row"
-      onSubmit={(e) => {
-        e.preventDefault();
-        sendMessage(message, to);
-      }}
-    >
-      <input
-        id="message-input"
-        onInput={(e) => setMessage(e.currentTarget.value)}
-        placeholder="Enter a message..."
-      />
-      <input
-        id="to-input"
-        onInput={(e) => setTo(e.currentTarget.value)}
-        placeholder="Enter a recipient..."
-      />
-      <button type="submit">Send</button>
-    </form>
+    <>
+      <form
+        class="row"
+        onSubmit={(e) => {
+          e.preventDefault();
+          sendMessage(message, to);
+        }}
+      >
+        <input
+          id="message-input"
+          onInput={(e) => setMessage(e.currentTarget.value)}
+          placeholder="Enter a message..."
+        />
+        <input
+          id="to-input"
+          onInput={(e) => {
+            currentTo.current = e.currentTarget.value;
+            setTo(e.currentTarget.value);
+            setWarning(null);
+          }}
+          onBlur={() => {
+            const checked = to;
+            recipientWarning(checked)
+              .then((result) => {
+                if (currentTo.current === checked) {
+                  setWarning(result);
+                }
+              })
+              .catch((err) => {
+                console.error(err);
+              });
+          }}
+          placeholder="Enter a recipient..."
+        />
+        <button type="submit">Send</button>
+      </form>
+      {warning && <p class="warning">{warning}</p>}
+    </>
   );
 }
@@ -274,6 +274,42 @@ function deserializeSelectHandleErrorCode(de) {
       throw new Error(`unknown enum case ${tag}`);
   }
 }
+function deserializeReachabilityStatus(de) {
+  const tag = deserializeU32(de);
+
+  switch (tag) {
+    case 0:
+      return "Reachable";
+    case 1:
+      return "Unreachable";
+    case 2:
+      return "Invalid";
+
+    default:
+      throw new Error(`unknown enum case ${tag}`);
+  }
+}
+function deserializeReachabilityErrorCode(de) {
+  const tag = deserializeU32(de);
+
+  switch (tag) {
+    case 0:
+      return "NotLoggedIn";
+    case 1:
+      return "LookupFailed";
+
+    default:
+      throw new Error(`unknown enum case ${tag}`);
+  }
+}
+function deserializeHandleReachability(de) {
+  return {
+    handle: deserializeString(de),
+    normalized: deserializeOption(de, (de) => deserializeString(de)),
+    status: deserializeReachabilityStatus(de),
+    checkedAt: deserializeOption(de, (de) => deserializeU64(de)),
+  };
+}
 
 export enum LoginErrorCode {
   TwoFactorRequired,
@@ -388,3 +424,49 @@ export async function selectHandle(
       );
     }) as Promise<SelectHandleErrorCode | null>;
 }
+
+export enum ReachabilityStatus {
+  Reachable,
+
+  Unreachable,
+
+  Invalid,
+}
+
+export enum ReachabilityErrorCode {
+  NotLoggedIn,
+
+  LookupFailed,
+}
+
+export interface HandleReachability {
+  handle: string;
+
+  normalized: string | null;
+
+  status: ReachabilityStatus;
+
+  checkedAt: bigint | null;
+}
+
+export async function checkReachability(
+  handles: string[]
+): Promise<Result<HandleReachability[], ReachabilityErrorCode>> {
+  const out = [];
+  serializeList(out, (out, v) => serializeString(out, v), handles);
+
+  return fetch("ipc://localhost/ipc/check_reachability", {
+    method: "POST",
+    body: Uint8Array.from(out),
+  })
+    .then((r) => r.arrayBuffer())
+    .then((bytes) => {
+      const de = new Deserializer(new Uint8Array(bytes));
+
+      return deserializeResult(
+        de,
+        (de) => deserializeList(de, (de) => deserializeHandleReachability(de)),
+        (de) => deserializeReachabilityErrorCode(de)
+      );
+    }) as Promise<Result<HandleReachability[], ReachabilityErrorCode>>;
+}